@@ -1,8 +1,65 @@
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=src/proto/telemetry.proto");
+    println!("cargo:rerun-if-changed=src/proto/metrics.proto");
+    // google/protobuf/*.proto come from protoc's bundled include path and map
+    // onto prost-types, so only our own files are compiled here
     prost_build::compile_protos(
-        &["src/proto/telemetry.proto"],
+        &["src/proto/telemetry.proto", "src/proto/metrics.proto"],
         &["src/proto"],
     )?;
+
+    build_info();
     Ok(())
 }
+
+// Identifies the build on /health, through BUILD_GIT_COMMIT,
+// BUILD_TIMESTAMP and BUILD_RUSTC_VERSION
+fn build_info() {
+    // Image builds have no .git, so the commit can be passed in instead
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| git(&["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    // Rerun on every commit or checkout, not just when the protos change
+    if let Some(log) = git(&["rev-parse", "--git-path", "logs/HEAD"]) {
+        println!("cargo:rerun-if-changed={}", log);
+    }
+
+    // Unix seconds; SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version =
+        output(Command::new(rustc).arg("--version")).unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version);
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    output(Command::new("git").args(args))
+}
+
+fn output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (output.status.success() && !stdout.is_empty()).then(|| stdout.to_string())
+}
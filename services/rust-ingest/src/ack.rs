@@ -0,0 +1,135 @@
+use crate::request_metrics;
+use axum::http::{HeaderMap, StatusCode};
+use prometheus::{IntCounterVec, Registry};
+use serde::Deserialize;
+
+// Header a client sets to pick the acknowledgment mode for its request
+pub const ACK_HEADER: &str = "x-ack-mode";
+
+// What the client waits for before getting its response. Weaker modes
+// answer sooner and promise less:
+// - none: 202 straight away; the record is processed in the background and
+//   sent with Kafka acks=0, so failures are only logged
+// - queued: 202 once the record is validated and in the producer's queue;
+//   it is still sent with acks=all, but a failed delivery is only logged
+// - leader: 200 once the partition leader has the record (acks=1)
+// - all: 200 once every in-sync replica has it (acks=all)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckMode {
+    None,
+    Queued,
+    Leader,
+    #[default]
+    All,
+}
+
+impl AckMode {
+    const ALL: [AckMode; 4] = [Self::None, Self::Queued, Self::Leader, Self::All];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Queued => "queued",
+            Self::Leader => "leader",
+            Self::All => "all",
+        }
+    }
+
+    // The request's mode from its header, or `default` when it has none
+    pub fn from_headers(headers: &HeaderMap, default: AckMode) -> Result<Self, String> {
+        let Some(value) = headers.get(ACK_HEADER) else {
+            return Ok(default);
+        };
+        let value = value.to_str().unwrap_or_default().trim();
+        Self::ALL
+            .into_iter()
+            .find(|mode| value.eq_ignore_ascii_case(mode.as_str()))
+            .ok_or_else(|| {
+                format!(
+                    "unknown {} {:?}; expected none, queued, leader or all",
+                    ACK_HEADER, value
+                )
+            })
+    }
+
+    // Accepted-but-not-yet-delivered modes answer 202
+    pub fn success_status(self) -> StatusCode {
+        match self {
+            Self::None | Self::Queued => StatusCode::ACCEPTED,
+            Self::Leader | Self::All => StatusCode::OK,
+        }
+    }
+}
+
+// Requests per ack mode, for the `mode` label on /metrics
+pub struct AckModeCounters {
+    counts: IntCounterVec,
+}
+
+impl Default for AckModeCounters {
+    fn default() -> Self {
+        let counts = request_metrics::int_counter_vec(
+            "rust_ingest_requests_by_ack_mode_total",
+            "Telemetry requests by acknowledgment mode",
+            &["mode"],
+        );
+        // Every mode shows up, even before its first request
+        for mode in AckMode::ALL {
+            counts.with_label_values(&[mode.as_str()]);
+        }
+        Self { counts }
+    }
+}
+
+impl AckModeCounters {
+    pub fn record(&self, mode: AckMode) {
+        self.counts.with_label_values(&[mode.as_str()]).inc();
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(mode: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACK_HEADER, mode.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_mode_comes_from_header_or_default() {
+        assert_eq!(
+            AckMode::from_headers(&HeaderMap::new(), AckMode::Leader),
+            Ok(AckMode::Leader)
+        );
+        assert_eq!(
+            AckMode::from_headers(&headers("Queued"), AckMode::All),
+            Ok(AckMode::Queued)
+        );
+        assert!(AckMode::from_headers(&headers("sometimes"), AckMode::All).is_err());
+    }
+
+    #[test]
+    fn test_response_status_per_mode() {
+        assert_eq!(AckMode::None.success_status(), StatusCode::ACCEPTED);
+        assert_eq!(AckMode::Queued.success_status(), StatusCode::ACCEPTED);
+        assert_eq!(AckMode::Leader.success_status(), StatusCode::OK);
+        assert_eq!(AckMode::All.success_status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_counters_are_labelled_by_mode() {
+        let counters = AckModeCounters::default();
+        counters.record(AckMode::Queued);
+        counters.record(AckMode::Queued);
+        let metrics = request_metrics::rendered(|registry| counters.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_requests_by_ack_mode_total{mode=\"queued\"} 2"));
+        assert!(metrics.contains("rust_ingest_requests_by_ack_mode_total{mode=\"none\"} 0"));
+    }
+}
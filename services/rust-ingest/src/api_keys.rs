@@ -0,0 +1,210 @@
+use crate::server::{constant_time_eq, ApiError};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApiKeyStats {
+    // Last four characters of the key, enough to tell keys apart
+    pub key_hint: String,
+    pub total_records: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub validation_warnings: u64,
+    // Unix millis of the last record sent with this key
+    pub last_seen: Option<i64>,
+}
+
+// Per-key accounting for the configured API keys. Keys are identified by
+// their position in config, so the store is bounded by the key list and a
+// caller can only ever be resolved to its own entry.
+pub struct ApiKeyRegistry {
+    // SHA-256 of each key. Comparing digests rather than the keys means
+    // every comparison is over the same length, so a mismatch in length
+    // can't return early and give away how long the keys are.
+    digests: Vec<[u8; 32]>,
+    stats: Mutex<Vec<ApiKeyStats>>,
+}
+
+impl ApiKeyRegistry {
+    pub fn new(keys: Vec<String>) -> Self {
+        let stats = keys
+            .iter()
+            .map(|key| ApiKeyStats {
+                key_hint: key_hint(key),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            digests: keys.iter().map(|key| digest(key)).collect(),
+            stats: Mutex::new(stats),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.digests.is_empty()
+    }
+
+    // Resolve the bearer token in `headers` to a key id. Every key is
+    // compared so the time taken doesn't reveal which one matched.
+    pub fn identify(&self, headers: &HeaderMap) -> Option<usize> {
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))?;
+        let provided = digest(provided);
+        self.digests
+            .iter()
+            .enumerate()
+            .fold(None, |found, (id, key)| {
+                if constant_time_eq(&provided, key) {
+                    Some(id)
+                } else {
+                    found
+                }
+            })
+    }
+
+    pub fn record_accepted(&self, key: usize, warnings: usize) {
+        self.record(key, |stats| {
+            stats.accepted += 1;
+            stats.validation_warnings += warnings as u64;
+        });
+    }
+
+    pub fn record_rejected(&self, key: usize) {
+        self.record(key, |stats| stats.rejected += 1);
+    }
+
+    fn record(&self, key: usize, update: impl FnOnce(&mut ApiKeyStats)) {
+        let mut all = self.stats.lock().unwrap();
+        if let Some(stats) = all.get_mut(key) {
+            stats.total_records += 1;
+            stats.last_seen = Some(chrono::Utc::now().timestamp_millis());
+            update(stats);
+        }
+    }
+
+    pub fn stats(&self, key: usize) -> Option<ApiKeyStats> {
+        self.stats.lock().unwrap().get(key).cloned()
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+// Turns away requests without a valid bearer key before the body is read
+pub async fn require_api_key(
+    State(keys): State<Arc<ApiKeyRegistry>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if keys.identify(request.headers()).is_none() {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid API key",
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+fn key_hint(key: &str) -> String {
+    let tail: String = key
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("****{}", tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn bearer(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", key)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_key_only_sees_its_own_stats() {
+        let registry = ApiKeyRegistry::new(vec!["key-alpha-1111".into(), "key-beta-2222".into()]);
+
+        let alpha = registry.identify(&bearer("key-alpha-1111")).unwrap();
+        registry.record_accepted(alpha, 2);
+        registry.record_accepted(alpha, 0);
+        registry.record_rejected(alpha);
+
+        let beta = registry.identify(&bearer("key-beta-2222")).unwrap();
+        assert_ne!(alpha, beta);
+        let beta_stats = registry.stats(beta).unwrap();
+        assert_eq!(beta_stats.key_hint, "****2222");
+        assert_eq!(beta_stats.total_records, 0);
+        assert_eq!(beta_stats.last_seen, None);
+
+        let alpha_stats = registry.stats(alpha).unwrap();
+        assert_eq!(alpha_stats.total_records, 3);
+        assert_eq!(alpha_stats.accepted, 2);
+        assert_eq!(alpha_stats.rejected, 1);
+        assert_eq!(alpha_stats.validation_warnings, 2);
+        assert!(alpha_stats.last_seen.is_some());
+    }
+
+    async fn status_with(headers: HeaderMap) -> StatusCode {
+        use axum::{body::Body, middleware, routing::post, Router};
+        use tower::ServiceExt;
+
+        let keys = Arc::new(ApiKeyRegistry::new(vec!["key-alpha-1111".into()]));
+        let app = Router::new()
+            .route("/telemetry", post(|| async { StatusCode::ACCEPTED }))
+            .route_layer(middleware::from_fn_with_state(keys, require_api_key));
+        let mut request = Request::post("/telemetry").body(Body::empty()).unwrap();
+        *request.headers_mut() = headers;
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_a_valid_key() {
+        assert_eq!(
+            status_with(HeaderMap::new()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_with(bearer("key-beta-2222")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let mut basic = HeaderMap::new();
+        basic.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic key-alpha-1111"),
+        );
+        assert_eq!(status_with(basic).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status_with(bearer("key-alpha-1111")).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    #[test]
+    fn test_unknown_or_missing_key_is_not_identified() {
+        let registry = ApiKeyRegistry::new(vec!["key-alpha-1111".into()]);
+        assert_eq!(registry.identify(&bearer("key-alpha-111")), None);
+        assert_eq!(registry.identify(&bearer("wrong")), None);
+        assert_eq!(registry.identify(&HeaderMap::new()), None);
+    }
+}
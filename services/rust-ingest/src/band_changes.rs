@@ -0,0 +1,257 @@
+use crate::{bounded_store::BoundedStore, proto::telemetry::Telemetry, sink::TelemetrySink};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BandChangeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    // metric -> level thresholds splitting its range into bands,
+    // e.g. tank_level = [25, 50, 75]
+    #[serde(default)]
+    pub metrics: HashMap<String, Vec<f64>>,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for BandChangeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            topic: default_topic(),
+            metrics: HashMap::new(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_topic() -> String {
+    "telemetry.band_changes".to_string()
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+// A band between two thresholds; open-ended bands have no min or max
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Band {
+    pub index: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+// Published to the band-change topic when a metric moves to another band
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BandChange {
+    pub device_id: String,
+    pub metric: String,
+    pub ts: i64,
+    pub value: f64,
+    pub from: Band,
+    pub to: Band,
+}
+
+// Emits an event whenever a metric crosses into a different level band.
+// Unlike validation warnings this says nothing about whether a value is
+// acceptable, only that it moved between the configured levels. The first
+// reading of a metric only establishes its band.
+pub struct BandTracker {
+    topic: String,
+    // Thresholds per metric, sorted ascending
+    thresholds: HashMap<String, Vec<f64>>,
+    // device -> metric -> current band
+    bands: Mutex<BoundedStore<HashMap<String, usize>>>,
+}
+
+impl BandTracker {
+    pub fn new(config: BandChangeConfig) -> Self {
+        let thresholds = config
+            .metrics
+            .into_iter()
+            .map(|(metric, mut levels)| {
+                levels.retain(|level| level.is_finite());
+                levels.sort_by(f64::total_cmp);
+                levels.dedup();
+                (metric, levels)
+            })
+            .collect();
+        Self {
+            topic: config.topic,
+            thresholds,
+            bands: Mutex::new(BoundedStore::new(
+                config.max_devices,
+                Duration::from_secs(config.idle_eviction_secs),
+            )),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.bands.lock().unwrap().remove(device_id)
+    }
+
+    pub async fn emit(&self, sink: &dyn TelemetrySink, telemetry: &Telemetry) {
+        for change in self.detect_at(telemetry, Instant::now()) {
+            let result = match serde_json::to_vec(&change) {
+                Ok(payload) => {
+                    sink.publish_raw(&self.topic, &change.device_id, &payload)
+                        .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Failed to publish band change for device {}: {:?}",
+                    change.device_id, e
+                );
+            }
+        }
+    }
+
+    fn detect_at(&self, telemetry: &Telemetry, now: Instant) -> Vec<BandChange> {
+        // The record's readings in time order, samples included
+        let mut readings: Vec<(i64, &HashMap<String, f64>)> = telemetry
+            .samples
+            .iter()
+            .map(|sample| (sample.ts, &sample.metrics))
+            .collect();
+        readings.push((telemetry.ts, &telemetry.metrics));
+        readings.sort_by_key(|(ts, _)| *ts);
+
+        let mut changes = Vec::new();
+        let mut bands = self.bands.lock().unwrap();
+        for (ts, metrics) in readings {
+            for (metric, &value) in metrics {
+                let Some(levels) = self.thresholds.get(metric) else {
+                    continue;
+                };
+                let to = levels.iter().filter(|level| value >= **level).count();
+                let device = bands.get_or_insert_with(&telemetry.device_id, now, HashMap::new);
+                match device.insert(metric.clone(), to) {
+                    Some(from) if from != to => changes.push(BandChange {
+                        device_id: telemetry.device_id.clone(),
+                        metric: metric.clone(),
+                        ts,
+                        value,
+                        from: band(levels, from),
+                        to: band(levels, to),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        changes
+    }
+}
+
+// Band `index` spans [levels[index - 1], levels[index])
+fn band(levels: &[f64], index: usize) -> Band {
+    Band {
+        index,
+        min: index.checked_sub(1).map(|i| levels[i]),
+        max: levels.get(index).copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+
+    fn tracker() -> BandTracker {
+        BandTracker::new(BandChangeConfig {
+            enabled: true,
+            metrics: HashMap::from([("tank_level".to_string(), vec![75.0, 25.0, 50.0])]),
+            ..Default::default()
+        })
+    }
+
+    fn reading(ts: i64, level: f64) -> Telemetry {
+        Telemetry {
+            device_id: "tank-1".to_string(),
+            ts,
+            metrics: HashMap::from([
+                ("tank_level".to_string(), level),
+                ("temperature".to_string(), level),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crossing_a_threshold_emits_a_change() {
+        let tracker = tracker();
+        let now = Instant::now();
+
+        // First reading only establishes the band
+        assert!(tracker.detect_at(&reading(1, 40.0), now).is_empty());
+        // Moving within the band is not a change
+        assert!(tracker.detect_at(&reading(2, 49.9), now).is_empty());
+
+        let changes = tracker.detect_at(&reading(3, 80.0), now);
+        assert_eq!(changes.len(), 1);
+        let change = &changes[0];
+        assert_eq!(change.metric, "tank_level");
+        assert_eq!(change.ts, 3);
+        assert_eq!(
+            change.from,
+            Band {
+                index: 1,
+                min: Some(25.0),
+                max: Some(50.0)
+            }
+        );
+        assert_eq!(
+            change.to,
+            Band {
+                index: 3,
+                min: Some(75.0),
+                max: None
+            }
+        );
+
+        let changes = tracker.detect_at(&reading(4, 10.0), now);
+        assert_eq!(changes[0].to.min, None);
+        assert_eq!(changes[0].to.max, Some(25.0));
+    }
+
+    #[test]
+    fn test_samples_are_checked_in_time_order() {
+        let tracker = tracker();
+        let now = Instant::now();
+        tracker.detect_at(&reading(1, 10.0), now);
+
+        let mut telemetry = reading(30, 60.0);
+        telemetry.samples = vec![
+            Sample {
+                ts: 20,
+                metrics: HashMap::from([("tank_level".to_string(), 30.0)]),
+            },
+            Sample {
+                ts: 10,
+                metrics: HashMap::from([("tank_level".to_string(), 12.0)]),
+            },
+        ];
+        let changes: Vec<(usize, usize)> = tracker
+            .detect_at(&telemetry, now)
+            .iter()
+            .map(|change| (change.from.index, change.to.index))
+            .collect();
+        assert_eq!(changes, vec![(0, 1), (1, 2)]);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{bounded_store::BoundedStore, telemetry_handler::ValidationWarning};
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdaptiveValidationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Metrics validated against a learned baseline instead of static ranges
+    #[serde(default)]
+    pub metrics: Vec<String>,
+    // Number of recent samples per device+metric the baseline is built from
+    #[serde(default = "default_window_size")]
+    pub window_size: usize,
+    // Samples accepted unconditionally while a new baseline forms
+    #[serde(default = "default_learning_samples")]
+    pub learning_samples: usize,
+    // How many spreads (p95 - p5) a value may sit outside the p5..p95 band
+    #[serde(default = "default_spread_multiple")]
+    pub spread_multiple: f64,
+    // Floor for the spread so a perfectly flat signal doesn't flag every wobble
+    #[serde(default = "default_min_spread")]
+    pub min_spread: f64,
+    // Reject outliers instead of only flagging them
+    #[serde(default)]
+    pub reject_outliers: bool,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for AdaptiveValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            metrics: Vec::new(),
+            window_size: default_window_size(),
+            learning_samples: default_learning_samples(),
+            spread_multiple: default_spread_multiple(),
+            min_spread: default_min_spread(),
+            reject_outliers: false,
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_window_size() -> usize {
+    200
+}
+
+fn default_learning_samples() -> usize {
+    30
+}
+
+fn default_spread_multiple() -> f64 {
+    3.0
+}
+
+fn default_min_spread() -> f64 {
+    1.0
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    24 * 3600
+}
+
+// Learns what "normal" looks like per device and metric from a rolling
+// window of recent values, so a freezer sensor and an engine sensor each
+// get their own acceptable range.
+pub struct BaselineTracker {
+    config: AdaptiveValidationConfig,
+    devices: Mutex<BoundedStore<HashMap<String, VecDeque<f64>>>>,
+}
+
+impl BaselineTracker {
+    pub fn new(config: AdaptiveValidationConfig) -> Self {
+        let devices = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            config,
+            devices: Mutex::new(devices),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.devices.lock().unwrap().remove(device_id)
+    }
+
+    pub fn is_adaptive(&self, metric: &str) -> bool {
+        self.config.metrics.iter().any(|m| m == metric)
+    }
+
+    // Check the adaptive metrics of one reading against the device's baseline,
+    // returning warnings for flagged values. In reject mode the first outlier
+    // fails the whole reading and nothing is learned from it.
+    pub fn check(
+        &self,
+        device_id: &str,
+        metrics: &HashMap<String, f64>,
+    ) -> Result<Vec<ValidationWarning>> {
+        self.check_at(device_id, metrics, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        device_id: &str,
+        metrics: &HashMap<String, f64>,
+        now: Instant,
+    ) -> Result<Vec<ValidationWarning>> {
+        let mut devices = self.devices.lock().unwrap();
+        let windows = devices.get_or_insert_with(device_id, now, HashMap::new);
+
+        let mut warnings = Vec::new();
+        for (metric, value) in metrics.iter().filter(|(m, _)| self.is_adaptive(m)) {
+            let Some(window) = windows.get(metric) else {
+                continue;
+            };
+            if window.len() < self.config.learning_samples {
+                continue;
+            }
+            if let Some((low, high)) = self.allowed_range(window) {
+                if !(low..=high).contains(value) {
+                    let message = format!(
+                        "{} value {} outside learned range {:.2}..{:.2} for device {}",
+                        metric, value, low, high, device_id
+                    );
+                    if self.config.reject_outliers {
+                        return Err(anyhow::anyhow!(message));
+                    }
+                    warn!("{}", message);
+                    warnings.push(ValidationWarning::new(metric, *value, low, high));
+                }
+            }
+        }
+
+        for (metric, value) in metrics.iter().filter(|(m, _)| self.is_adaptive(m)) {
+            let window = windows.entry(metric.clone()).or_default();
+            if window.len() >= self.config.window_size {
+                window.pop_front();
+            }
+            window.push_back(*value);
+        }
+
+        Ok(warnings)
+    }
+
+    fn allowed_range(&self, window: &VecDeque<f64>) -> Option<(f64, f64)> {
+        let mut sorted: Vec<f64> = window.iter().copied().filter(|v| v.is_finite()).collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(f64::total_cmp);
+        let p5 = percentile(&sorted, 0.05);
+        let p95 = percentile(&sorted, 0.95);
+        let spread = (p95 - p5).max(self.config.min_spread);
+        let margin = spread * self.config.spread_multiple;
+        Some((p5 - margin, p95 + margin))
+    }
+}
+
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = q * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(reject_outliers: bool) -> BaselineTracker {
+        BaselineTracker::new(AdaptiveValidationConfig {
+            enabled: true,
+            metrics: vec!["temperature".to_string()],
+            window_size: 50,
+            learning_samples: 10,
+            spread_multiple: 2.0,
+            min_spread: 1.0,
+            reject_outliers,
+            max_devices: 2,
+            idle_eviction_secs: 3600,
+        })
+    }
+
+    fn reading(value: f64) -> HashMap<String, f64> {
+        HashMap::from([("temperature".to_string(), value)])
+    }
+
+    fn learn(tracker: &BaselineTracker, device_id: &str, center: f64, now: Instant) {
+        for i in 0..10 {
+            let value = center + (i % 3) as f64 * 0.5;
+            assert!(tracker
+                .check_at(device_id, &reading(value), now)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_learning_period_accepts_everything() {
+        let tracker = tracker(true);
+        let now = Instant::now();
+        for value in [-18.0, 90.0, 400.0, -50.0] {
+            assert!(tracker.check_at("freezer-1", &reading(value), now).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_baselines_are_per_device() {
+        let tracker = tracker(false);
+        let now = Instant::now();
+        learn(&tracker, "freezer-1", -18.0, now);
+        learn(&tracker, "engine-1", 90.0, now);
+
+        // Normal for the engine, an outlier for the freezer
+        assert_eq!(
+            tracker
+                .check_at("freezer-1", &reading(90.0), now)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(tracker
+            .check_at("engine-1", &reading(90.5), now)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_reject_mode_fails_outliers() {
+        let tracker = tracker(true);
+        let now = Instant::now();
+        learn(&tracker, "freezer-1", -18.0, now);
+
+        assert!(tracker.check_at("freezer-1", &reading(25.0), now).is_err());
+        assert!(tracker.check_at("freezer-1", &reading(-17.5), now).is_ok());
+    }
+
+    #[test]
+    fn test_non_adaptive_metrics_are_ignored() {
+        let tracker = tracker(true);
+        let now = Instant::now();
+        learn(&tracker, "freezer-1", -18.0, now);
+
+        let metrics = HashMap::from([("humidity".to_string(), 1000.0)]);
+        assert!(tracker.check_at("freezer-1", &metrics, now).is_ok());
+    }
+}
@@ -0,0 +1,569 @@
+use crate::{
+    ack::AckMode,
+    content_encoding,
+    priority::Priority,
+    request_id::RequestId,
+    server::{process_request, run_detached, ApiError, AppState, RequestContext, TelemetryRequest},
+    trace_sampling::TraceDecision,
+    unit_conversion,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{convert::Infallible, future::Future, sync::Arc};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+// Media type a client accepts to get each record's result as it is processed
+const NDJSON: &str = "application/x-ndjson";
+
+// Results ready to go out while the client is slow to read them
+const STREAM_BUFFER: usize = 16;
+
+// A batch is either a bare array of records or an envelope whose `defaults`
+// are shared by every record, so gateways can send common metadata (site,
+// firmware, a shared ts) once instead of repeating it per record.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BatchRequest {
+    Records(Vec<Value>),
+    Envelope {
+        #[serde(default)]
+        defaults: Map<String, Value>,
+        records: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    index: usize,
+    device_id: Option<String>,
+    // HTTP status the record would have got on its own
+    status: u16,
+    pub(crate) success: bool,
+    error: Option<String>,
+}
+
+impl BatchItemResult {
+    pub(crate) fn failed(index: usize, device_id: Option<String>, e: &ApiError) -> Self {
+        Self {
+            index,
+            device_id,
+            status: e.status().as_u16(),
+            success: false,
+            error: Some(e.message().to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    results: Vec<BatchItemResult>,
+}
+
+// Global cap on the bytes held by batches being processed at once, so many
+// concurrent large batches can't exhaust memory even when each one is under
+// the per-request limit. A batch holds budget equal to its body size until
+// it completes.
+pub struct MemoryBudget {
+    total: usize,
+    available: Arc<Semaphore>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BudgetRejection {
+    // Larger than the whole budget; can never be admitted
+    TooLarge,
+    Exhausted,
+}
+
+impl MemoryBudget {
+    pub fn new(total_bytes: usize) -> Self {
+        let total = total_bytes.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            total,
+            available: Arc::new(Semaphore::new(total)),
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    // Reserve `bytes` without waiting; the reservation is released on drop
+    pub fn try_reserve(&self, bytes: usize) -> Result<OwnedSemaphorePermit, BudgetRejection> {
+        let bytes = bytes.max(1);
+        if bytes > self.total {
+            return Err(BudgetRejection::TooLarge);
+        }
+        let permits = u32::try_from(bytes).map_err(|_| BudgetRejection::TooLarge)?;
+        Arc::clone(&self.available)
+            .try_acquire_many_owned(permits)
+            .map_err(|_| BudgetRejection::Exhausted)
+    }
+}
+
+// Merge batch defaults into one record. Fields the record sets win; for
+// object-valued fields (metrics, tags) the maps are merged key by key with
+// the record's entries taking precedence.
+pub fn apply_defaults(record: Value, defaults: &Map<String, Value>) -> Value {
+    let Value::Object(mut record) = record else {
+        return record;
+    };
+    for (key, default) in defaults {
+        match (record.get_mut(key), default) {
+            (None, _) => {
+                record.insert(key.clone(), default.clone());
+            }
+            (Some(Value::Object(fields)), Value::Object(default_fields)) => {
+                for (field, value) in default_fields {
+                    fields.entry(field.clone()).or_insert_with(|| value.clone());
+                }
+            }
+            (Some(_), _) => {}
+        }
+    }
+    Value::Object(record)
+}
+
+// Reads a bulk upload's body, holding it against the batch memory budget
+// until the permit is dropped. The budget is reserved before any of the
+// body is read: for its Content-Length, or without one for the largest body
+// the budget and max_body_bytes allow. A compressed body is inflated inside
+// the same reservation, which then covers both copies while it is decoded.
+// What the body didn't need is handed back once it is in.
+pub(crate) async fn read_reserved_body(
+    state: &AppState,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<(Bytes, OwnedSemaphorePermit), ApiError> {
+    let limit = state.max_body_bytes;
+    let too_large = || {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+            .with_details(format!("bodies are limited to {} bytes", limit))
+    };
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large());
+    }
+    let encoding = content_encoding::content_encoding(headers)?;
+    let reserved = match (encoding, declared) {
+        (Some(_), _) => limit
+            .min(state.max_decompressed_bytes)
+            .min(state.batch_budget.total()),
+        (None, Some(len)) => len,
+        (None, None) => limit.min(state.batch_budget.total()),
+    };
+    let mut reservation = reserve_budget(state, reserved)?;
+    let body = axum::body::to_bytes(body, reserved)
+        .await
+        .map_err(|_| too_large())?;
+    let body = match encoding {
+        Some(encoding) => content_encoding::decode(encoding, &body, reserved - body.len())?,
+        None => body,
+    };
+    let unused = reservation.num_permits().saturating_sub(body.len().max(1));
+    if unused > 0 {
+        drop(reservation.split(unused));
+    }
+    Ok((body, reservation))
+}
+
+fn reserve_budget(state: &AppState, bytes: usize) -> Result<OwnedSemaphorePermit, ApiError> {
+    state
+        .batch_budget
+        .try_reserve(bytes)
+        .map_err(|rejection| match rejection {
+            BudgetRejection::TooLarge => ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "batch is larger than the batch memory budget",
+            ),
+            BudgetRejection::Exhausted => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "batch memory budget exhausted, retry later",
+            )
+            .with_retry_after(std::time::Duration::from_secs(1)),
+        })
+}
+
+// What every record of a bulk upload is processed with. The response
+// reports each record's outcome, so it has to wait for them: ack mode none
+// is refused.
+pub(crate) fn bulk_request(
+    state: &AppState,
+    headers: &HeaderMap,
+    trace: TraceDecision,
+    request_id: RequestId,
+) -> Result<RequestContext, ApiError> {
+    let ack = AckMode::from_headers(headers, state.default_ack_mode)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    if ack == AckMode::None {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "ack mode none is not supported for batches",
+        ));
+    }
+    let request = RequestContext {
+        api_key: state.api_keys.identify(headers),
+        trace,
+        ack,
+        priority: Priority::from_headers(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        topic: state
+            .topic_overrides
+            .requested(headers)
+            .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?,
+        units: unit_conversion::declared_units(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        request_id,
+    };
+    state.ack_modes.record(ack);
+    Ok(request)
+}
+
+pub fn expand_records(batch: BatchRequest) -> Vec<Result<TelemetryRequest, String>> {
+    let (defaults, records) = match batch {
+        BatchRequest::Records(records) => (Map::new(), records),
+        BatchRequest::Envelope { defaults, records } => (defaults, records),
+    };
+    records
+        .into_iter()
+        .map(|record| {
+            serde_json::from_value(apply_defaults(record, &defaults))
+                .map_err(|e| format!("invalid record: {}", e))
+        })
+        .collect()
+}
+
+pub async fn ingest_batch(
+    State(state): State<Arc<AppState>>,
+    Extension(trace): Extension<TraceDecision>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    // Held until the batch has been fully processed
+    let (body, reservation) = read_reserved_body(&state, &headers, body).await?;
+
+    let batch: BatchRequest = serde_json::from_slice(&body).map_err(|e| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid batch body").with_details(e.to_string())
+    })?;
+    let records = expand_records(batch);
+    if records.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "batch must contain at least one record",
+        ));
+    }
+
+    let request = bulk_request(&state, &headers, trace, request_id)?;
+    let ack = request.ack;
+
+    // Every record of the batch carries the batch's request id
+    let process = move |state: Arc<AppState>, index, record| {
+        let request = request.clone();
+        async move { process_item(&state, index, record, &request).await }
+    };
+
+    if accepts_ndjson(&headers) {
+        let body = stream_results(records, reservation, move |index, record| {
+            process(Arc::clone(&state), index, record)
+        });
+        return Ok((ack.success_status(), [(header::CONTENT_TYPE, NDJSON)], body).into_response());
+    }
+
+    // One bad record must not abort the rest of the batch
+    let mut results = Vec::with_capacity(records.len());
+    for (index, record) in records.into_iter().enumerate() {
+        results.push(process(Arc::clone(&state), index, record).await);
+    }
+    drop(reservation);
+
+    let succeeded = results.iter().filter(|r| r.success).count();
+    Ok((
+        ack.success_status(),
+        Json(BatchResponse {
+            total: results.len(),
+            succeeded,
+            failed: results.len() - succeeded,
+            results,
+        }),
+    )
+        .into_response())
+}
+
+// Each record is processed to the end even if the upload is abandoned
+pub(crate) async fn process_item(
+    state: &Arc<AppState>,
+    index: usize,
+    record: Result<TelemetryRequest, String>,
+    request: &RequestContext,
+) -> BatchItemResult {
+    let payload = match record {
+        Ok(payload) => payload,
+        Err(error) => {
+            if let Some(key) = request.api_key {
+                state.api_keys.record_rejected(key);
+            }
+            return BatchItemResult {
+                index,
+                device_id: None,
+                status: StatusCode::BAD_REQUEST.as_u16(),
+                success: false,
+                error: Some(error),
+            };
+        }
+    };
+    let device_id = payload.device_id.clone();
+    let outcome = {
+        let (state, request) = (Arc::clone(state), request.clone());
+        run_detached(async move { process_request(&state, payload, &request, None).await }).await
+    };
+    match outcome {
+        Ok(_) => BatchItemResult {
+            index,
+            device_id: Some(device_id),
+            status: request.ack.success_status().as_u16(),
+            success: true,
+            error: None,
+        },
+        Err(e) => BatchItemResult::failed(index, Some(device_id), &e),
+    }
+}
+
+fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(NDJSON))
+        })
+}
+
+// Processes records one at a time in a background task, sending each
+// result as an NDJSON line as soon as it is ready, so clients of large
+// batches see progress and can abort early. Processing stops once the
+// client has gone away; `hold` is kept until it does.
+fn stream_results<T, H, F, Fut>(records: Vec<T>, hold: H, mut process: F) -> Body
+where
+    T: Send + 'static,
+    H: Send + 'static,
+    F: FnMut(usize, T) -> Fut + Send + 'static,
+    Fut: Future<Output = BatchItemResult> + Send,
+{
+    let (lines, body) = mpsc::channel::<Result<Bytes, Infallible>>(STREAM_BUFFER);
+    tokio::spawn(async move {
+        let _hold = hold;
+        let total = records.len();
+        for (index, record) in records.into_iter().enumerate() {
+            let result = process(index, record).await;
+            let mut line = serde_json::to_vec(&result).unwrap_or_default();
+            line.push(b'\n');
+            if lines.send(Ok(Bytes::from(line))).await.is_err() {
+                debug!(
+                    "Client left a streamed batch after {} of {} records",
+                    index + 1,
+                    total
+                );
+                return;
+            }
+        }
+    });
+    Body::from_stream(ReceiverStream::new(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric_values::MetricValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_envelope_defaults_are_merged_into_records() {
+        let batch: BatchRequest = serde_json::from_value(json!({
+            "defaults": {
+                "ts": 1700000000000i64,
+                "metrics": {"battery_level": 80.0},
+                "tags": {"site": "plant-7", "firmware": "1.4.2"}
+            },
+            "records": [
+                {"device_id": "sensor-1", "metrics": {"temperature": 21.5}},
+                {
+                    "device_id": "sensor-2",
+                    "ts": 1700000005000i64,
+                    "metrics": {"temperature": 22.0, "battery_level": 40.0},
+                    "tags": {"firmware": "1.5.0"}
+                }
+            ]
+        }))
+        .unwrap();
+
+        let records: Vec<TelemetryRequest> = expand_records(batch)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(records[0].ts, Some(1700000000000));
+        assert_eq!(
+            records[0].metrics["battery_level"],
+            MetricValue::Number(80.0)
+        );
+        assert_eq!(records[0].tags["site"], "plant-7");
+        assert_eq!(records[0].tags["firmware"], "1.4.2");
+
+        // Per-record fields override the batch defaults
+        assert_eq!(records[1].ts, Some(1700000005000));
+        assert_eq!(
+            records[1].metrics["battery_level"],
+            MetricValue::Number(40.0)
+        );
+        assert_eq!(records[1].tags["site"], "plant-7");
+        assert_eq!(records[1].tags["firmware"], "1.5.0");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_large_batches_are_admission_controlled() {
+        let budget = Arc::new(MemoryBudget::new(100));
+
+        let first = budget.try_reserve(60).unwrap();
+        // A second large batch arriving while the first is in flight is turned away
+        assert_eq!(
+            budget.try_reserve(60).unwrap_err(),
+            BudgetRejection::Exhausted
+        );
+        let small = budget.try_reserve(40).unwrap();
+        assert_eq!(
+            budget.try_reserve(150).unwrap_err(),
+            BudgetRejection::TooLarge
+        );
+
+        // Concurrent batches: only as many as fit in the budget get in
+        let admitted: Vec<_> = (0..4)
+            .map(|_| {
+                let budget = Arc::clone(&budget);
+                tokio::spawn(async move { budget.try_reserve(30).is_ok() })
+            })
+            .collect();
+        for batch in admitted {
+            assert!(!batch.await.unwrap());
+        }
+
+        drop(first);
+        drop(small);
+        let holds: Vec<_> = (0..4).map(|_| budget.try_reserve(30)).collect();
+        assert_eq!(holds.iter().filter(|hold| hold.is_ok()).count(), 3);
+    }
+
+    #[test]
+    fn test_plain_array_and_invalid_records() {
+        let batch: BatchRequest = serde_json::from_value(json!([
+            {"device_id": "sensor-1", "metrics": {"temperature": 21.5}},
+            {"metrics": {"temperature": 21.5}}
+        ]))
+        .unwrap();
+
+        let records = expand_records(batch);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_err());
+    }
+
+    fn ok(index: usize) -> BatchItemResult {
+        BatchItemResult {
+            index,
+            device_id: Some(format!("sensor-{}", index)),
+            status: 202,
+            success: true,
+            error: None,
+        }
+    }
+
+    async fn next_line(
+        stream: &mut (impl tokio_stream::Stream<Item = Result<Bytes, axum::Error>> + Unpin),
+    ) -> serde_json::Value {
+        let chunk = tokio_stream::StreamExt::next(stream)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(chunk.ends_with(b"\n"));
+        serde_json::from_slice(&chunk).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_results_are_streamed_as_records_complete() {
+        // Each record completes only when the test releases it
+        let (gates, waits): (Vec<_>, Vec<_>) = (0..3)
+            .map(|_| tokio::sync::oneshot::channel::<()>())
+            .unzip();
+        let body = stream_results(waits, (), |index, wait| async move {
+            wait.await.unwrap();
+            ok(index)
+        });
+        let mut stream = body.into_data_stream();
+        let mut gates = gates.into_iter();
+
+        gates.next().unwrap().send(()).unwrap();
+        let first = next_line(&mut stream).await;
+        assert_eq!(first["index"], 0);
+        assert_eq!(first["status"], 202);
+        // The second record is still pending, so nothing else has arrived
+        assert!(tokio::time::timeout(
+            std::time::Duration::from_millis(20),
+            tokio_stream::StreamExt::next(&mut stream)
+        )
+        .await
+        .is_err());
+
+        for (index, gate) in gates.enumerate() {
+            gate.send(()).unwrap();
+            assert_eq!(next_line(&mut stream).await["index"], index + 1);
+        }
+        assert!(tokio_stream::StreamExt::next(&mut stream).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_stops_processing() {
+        let processed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let budget = Arc::new(MemoryBudget::new(100));
+        let reservation = budget.try_reserve(100).unwrap();
+
+        let counter = Arc::clone(&processed);
+        let body = stream_results(vec![(); 1_000], reservation, move |index, _| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                tokio::task::yield_now().await;
+                ok(index)
+            }
+        });
+        let mut stream = body.into_data_stream();
+        next_line(&mut stream).await;
+        drop(stream);
+
+        // The reservation is released once the task notices the client left
+        let released = async {
+            while budget.try_reserve(100).is_err() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(1), released)
+            .await
+            .unwrap();
+        assert!(processed.load(std::sync::atomic::Ordering::SeqCst) < 1_000);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Keyed per-device state with a hard size cap. Entries untouched for longer
+// than `idle_ttl` are dropped first; if the store is still full the least
+// recently used entry makes room. Not synchronized, wrap in a Mutex to share.
+pub struct BoundedStore<V> {
+    entries: HashMap<String, Entry<V>>,
+    capacity: usize,
+    idle_ttl: Duration,
+}
+
+struct Entry<V> {
+    value: V,
+    last_access: Instant,
+}
+
+impl<V> BoundedStore<V> {
+    pub fn new(capacity: usize, idle_ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity: capacity.max(1),
+            idle_ttl,
+        }
+    }
+
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &str,
+        now: Instant,
+        init: impl FnOnce() -> V,
+    ) -> &mut V {
+        if !self.entries.contains_key(key) {
+            if self.entries.len() >= self.capacity {
+                self.evict(now);
+            }
+            self.entries.insert(
+                key.to_string(),
+                Entry {
+                    value: init(),
+                    last_access: now,
+                },
+            );
+        }
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.last_access = now;
+        &mut entry.value
+    }
+
+    // Lookup that doesn't count as an access, for housekeeping passes
+    pub fn peek_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let idle_ttl = self.idle_ttl;
+        self.entries
+            .retain(|_, entry| now.saturating_duration_since(entry.last_access) < idle_ttl);
+
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capacity_evicts_least_recently_used() {
+        let mut store = BoundedStore::new(2, Duration::from_secs(3600));
+        let now = Instant::now();
+
+        *store.get_or_insert_with("a", now, || 0) += 1;
+        *store.get_or_insert_with("b", now + Duration::from_secs(1), || 0) += 1;
+        // Touch "a" so "b" becomes the least recently used
+        store.get_or_insert_with("a", now + Duration::from_secs(2), || 0);
+        store.get_or_insert_with("c", now + Duration::from_secs(3), || 0);
+
+        assert_eq!(store.entries.len(), 2);
+        assert_eq!(store.entries["a"].value, 1);
+        assert!(!store.entries.contains_key("b"));
+    }
+
+    #[test]
+    fn test_idle_entries_are_dropped_first() {
+        let mut store = BoundedStore::new(2, Duration::from_secs(10));
+        let now = Instant::now();
+
+        store.get_or_insert_with("a", now, || 0);
+        store.get_or_insert_with("b", now, || 0);
+        store.get_or_insert_with("c", now + Duration::from_secs(60), || 0);
+
+        // Both idle entries go, not just the single oldest one
+        assert_eq!(store.entries.len(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::sink::TelemetrySink;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BrokerProbeConfig {
+    // Probe in the background and answer /ready from the last result,
+    // instead of asking the brokers on every request
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    // How long one probe waits for broker metadata
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // Failed probes in a row before the brokers count as down; fewer is
+    // degraded, which still reports ready
+    #[serde(default = "default_failures_before_down")]
+    pub failures_before_down: u32,
+}
+
+impl Default for BrokerProbeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_ms: default_interval_ms(),
+            timeout_ms: default_timeout_ms(),
+            failures_before_down: default_failures_before_down(),
+        }
+    }
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_failures_before_down() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerHealth {
+    Healthy,
+    // The last probe failed, but not enough in a row to call it an outage
+    Degraded,
+    Down,
+}
+
+impl BrokerHealth {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Healthy,
+            1 => Self::Degraded,
+            _ => Self::Down,
+        }
+    }
+}
+
+impl fmt::Display for BrokerHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::Down => "down",
+        })
+    }
+}
+
+// The brokers' state as of the last probe, cheap to read from every /ready
+// request. Down until the first probe has answered, so a node isn't
+// reported ready before it has reached a broker.
+pub struct BrokerMonitor {
+    health: AtomicU8,
+    failures: AtomicU32,
+    failures_before_down: u32,
+    last_error: Mutex<Option<String>>,
+}
+
+impl BrokerMonitor {
+    pub fn new(config: &BrokerProbeConfig) -> Self {
+        Self {
+            health: AtomicU8::new(BrokerHealth::Down as u8),
+            failures: AtomicU32::new(0),
+            failures_before_down: config.failures_before_down.max(1),
+            last_error: Mutex::new(Some("brokers not probed yet".to_string())),
+        }
+    }
+
+    pub fn health(&self) -> BrokerHealth {
+        BrokerHealth::from_u8(self.health.load(Ordering::Relaxed))
+    }
+
+    // Why the last probe failed, unless it succeeded
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    fn record(&self, probe: Result<()>) {
+        let next = match &probe {
+            Ok(()) => {
+                self.failures.store(0, Ordering::Relaxed);
+                BrokerHealth::Healthy
+            }
+            Err(_) => {
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.failures_before_down {
+                    BrokerHealth::Down
+                } else {
+                    BrokerHealth::Degraded
+                }
+            }
+        };
+        let error = probe.err().map(|e| format!("{:#}", e));
+        let previous = BrokerHealth::from_u8(self.health.swap(next as u8, Ordering::Relaxed));
+        if previous != next {
+            match &error {
+                None => info!("Broker health {} -> {}", previous, next),
+                Some(e) => warn!("Broker health {} -> {}: {}", previous, next, e),
+            }
+        }
+        *self.last_error.lock().unwrap() = error;
+    }
+}
+
+pub fn spawn_prober(
+    monitor: Arc<BrokerMonitor>,
+    sink: Arc<dyn TelemetrySink>,
+    config: &BrokerProbeConfig,
+) {
+    let interval = Duration::from_millis(config.interval_ms.max(1));
+    let timeout = Duration::from_millis(config.timeout_ms);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // A probe that outlasts the interval shouldn't be followed by a burst
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            monitor.record(sink.check_ready(timeout).await);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed() -> Result<()> {
+        Err(anyhow::anyhow!("no brokers"))
+    }
+
+    #[test]
+    fn test_consecutive_failures_go_from_degraded_to_down() {
+        let monitor = BrokerMonitor::new(&BrokerProbeConfig {
+            enabled: true,
+            failures_before_down: 2,
+            ..Default::default()
+        });
+        assert_eq!(monitor.health(), BrokerHealth::Down);
+
+        monitor.record(Ok(()));
+        assert_eq!(monitor.health(), BrokerHealth::Healthy);
+        assert_eq!(monitor.last_error(), None);
+
+        monitor.record(failed());
+        assert_eq!(monitor.health(), BrokerHealth::Degraded);
+        assert_eq!(monitor.last_error().as_deref(), Some("no brokers"));
+        monitor.record(failed());
+        assert_eq!(monitor.health(), BrokerHealth::Down);
+
+        // One success is enough to recover, and restarts the count
+        monitor.record(Ok(()));
+        assert_eq!(monitor.health(), BrokerHealth::Healthy);
+        monitor.record(failed());
+        assert_eq!(monitor.health(), BrokerHealth::Degraded);
+    }
+}
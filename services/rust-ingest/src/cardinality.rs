@@ -0,0 +1,249 @@
+use crate::bounded_store::BoundedStore;
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardinalityAction {
+    // Strip the unseen metric names and keep the rest of the reading
+    #[default]
+    Drop,
+    // Reject the whole reading
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CardinalityGuardConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Distinct metric names a device may report within one window
+    #[serde(default = "default_max_distinct_names")]
+    pub max_distinct_names: usize,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    #[serde(default)]
+    pub action: CardinalityAction,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for CardinalityGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_distinct_names: default_max_distinct_names(),
+            window_secs: default_window_secs(),
+            action: CardinalityAction::default(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_max_distinct_names() -> usize {
+    100
+}
+
+fn default_window_secs() -> u64 {
+    3600
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    86_400
+}
+
+struct DeviceNames {
+    names: HashSet<String>,
+    window_start: Instant,
+    alerted: bool,
+}
+
+// Protects downstream schemas from metric-name explosions, e.g. firmware that
+// embeds a timestamp in the key. Each device may introduce at most
+// `max_distinct_names` names per window; the name set never grows beyond the
+// cap, and the device count is bounded by the store.
+pub struct CardinalityGuard {
+    config: CardinalityGuardConfig,
+    window: Duration,
+    devices: Mutex<BoundedStore<DeviceNames>>,
+}
+
+impl CardinalityGuard {
+    pub fn new(config: CardinalityGuardConfig) -> Self {
+        let devices = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            devices: Mutex::new(devices),
+            config,
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.devices.lock().unwrap().remove(device_id)
+    }
+
+    pub fn check(&self, device_id: &str, metrics: &mut HashMap<String, f64>) -> Result<()> {
+        self.check_at(device_id, metrics, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        device_id: &str,
+        metrics: &mut HashMap<String, f64>,
+        now: Instant,
+    ) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_or_insert_with(device_id, now, || DeviceNames {
+            names: HashSet::new(),
+            window_start: now,
+            alerted: false,
+        });
+        if now.saturating_duration_since(device.window_start) >= self.window {
+            device.names.clear();
+            device.window_start = now;
+            device.alerted = false;
+        }
+
+        let unseen: Vec<&String> = metrics
+            .keys()
+            .filter(|name| !device.names.contains(*name))
+            .collect();
+        let room = self
+            .config
+            .max_distinct_names
+            .saturating_sub(device.names.len());
+        let mut rejected: Vec<String> = unseen
+            .iter()
+            .skip(room)
+            .map(|name| name.to_string())
+            .collect();
+        // A rejected reading is never stored, so its names take up no room
+        if rejected.is_empty() || self.config.action == CardinalityAction::Drop {
+            device.names.extend(unseen.into_iter().take(room).cloned());
+        }
+        if rejected.is_empty() {
+            return Ok(());
+        }
+
+        rejected.sort_unstable();
+        if !device.alerted {
+            device.alerted = true;
+            warn!(
+                "ALERT: device {} exceeded {} distinct metric names within {}s; new names such as [{}] are being {}",
+                device_id,
+                self.config.max_distinct_names,
+                self.config.window_secs,
+                rejected.join(", "),
+                match self.config.action {
+                    CardinalityAction::Drop => "dropped",
+                    CardinalityAction::Reject => "rejected",
+                }
+            );
+        }
+
+        match self.config.action {
+            CardinalityAction::Drop => {
+                for name in &rejected {
+                    metrics.remove(name);
+                }
+                Ok(())
+            }
+            CardinalityAction::Reject => Err(anyhow::anyhow!(
+                "Device {} exceeded the limit of {} distinct metric names: [{}]",
+                device_id,
+                self.config.max_distinct_names,
+                rejected.join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(action: CardinalityAction) -> CardinalityGuard {
+        CardinalityGuard::new(CardinalityGuardConfig {
+            enabled: true,
+            max_distinct_names: 3,
+            window_secs: 60,
+            action,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_runaway_metric_names_are_dropped() {
+        let guard = guard(CardinalityAction::Drop);
+        let start = Instant::now();
+
+        // Firmware bug: every reading carries a fresh timestamped key
+        for i in 0..10 {
+            let mut metrics = HashMap::from([
+                ("temperature".to_string(), 21.0),
+                (format!("temp_{}", 1700000000 + i), 21.0),
+            ]);
+            guard.check_at("dev-1", &mut metrics, start).unwrap();
+            assert!(metrics.contains_key("temperature"));
+            assert_eq!(metrics.len(), if i < 2 { 2 } else { 1 });
+        }
+
+        // The tracked name set stays at the cap
+        let mut devices = guard.devices.lock().unwrap();
+        let device = devices.get_or_insert_with("dev-1", start, || unreachable!());
+        assert_eq!(device.names.len(), 3);
+        drop(devices);
+
+        // A new window lets the device introduce names again
+        let mut metrics = HashMap::from([("humidity".to_string(), 40.0)]);
+        guard
+            .check_at("dev-1", &mut metrics, start + Duration::from_secs(61))
+            .unwrap();
+        assert!(metrics.contains_key("humidity"));
+    }
+
+    #[test]
+    fn test_reject_mode_fails_the_reading() {
+        let guard = guard(CardinalityAction::Reject);
+        let now = Instant::now();
+
+        let mut metrics: HashMap<String, f64> = (0..3).map(|i| (format!("m{}", i), 1.0)).collect();
+        assert!(guard.check_at("dev-1", &mut metrics, now).is_ok());
+
+        let mut metrics = HashMap::from([("m0".to_string(), 1.0), ("m9".to_string(), 1.0)]);
+        let err = guard.check_at("dev-1", &mut metrics, now).unwrap_err();
+        assert!(err.to_string().contains("m9"));
+
+        // Names from a rejected reading aren't counted against the device
+        let guard = CardinalityGuard::new(CardinalityGuardConfig {
+            max_distinct_names: 2,
+            ..guard.config
+        });
+        let mut metrics = HashMap::from([("m0".to_string(), 1.0)]);
+        assert!(guard.check_at("dev-1", &mut metrics, now).is_ok());
+        let mut metrics = HashMap::from([("m8".to_string(), 1.0), ("m9".to_string(), 1.0)]);
+        assert!(guard.check_at("dev-1", &mut metrics, now).is_err());
+        let mut metrics = HashMap::from([("m7".to_string(), 1.0)]);
+        assert!(guard.check_at("dev-1", &mut metrics, now).is_ok());
+
+        // Other devices have their own budget
+        let mut metrics = HashMap::from([("m9".to_string(), 1.0)]);
+        assert!(guard.check_at("dev-2", &mut metrics, now).is_ok());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{
+    request_metrics,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::{IntGaugeVec, Registry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Consecutive send failures to a topic before its breaker opens
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    // How long an open breaker rejects sends before letting a probe through
+    #[serde(default = "default_open_secs")]
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            failure_threshold: default_failure_threshold(),
+            open_secs: default_open_secs(),
+        }
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_open_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    // Gauge value on /metrics
+    fn as_metric(self) -> u8 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+// Returned by the sink while a topic's breaker is open
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub topic: String,
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open for topic {}", self.topic)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+}
+
+impl Breaker {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: now,
+        }
+    }
+}
+
+// One breaker per destination topic, so a topic that keeps failing (say a
+// partition leader election that never settles) short-circuits on its own
+// while sends to healthy topics carry on.
+pub struct TopicBreakers {
+    config: CircuitBreakerConfig,
+    topics: Mutex<HashMap<String, Breaker>>,
+}
+
+impl TopicBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_secs)
+    }
+
+    // Ok if a send to `topic` may go ahead, otherwise how long until it may
+    fn admit_at(&self, topic: &str, now: Instant) -> Result<(), Duration> {
+        let mut topics = self.topics.lock().unwrap();
+        let Some(breaker) = topics.get_mut(topic) else {
+            return Ok(());
+        };
+        let elapsed = now.saturating_duration_since(breaker.opened_at);
+        match breaker.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open | BreakerState::HalfOpen if elapsed < self.open_duration() => {
+                // While half-open, `opened_at` is when the probe went out
+                Err(self.open_duration() - elapsed)
+            }
+            // Open period over, or the last probe never reported back
+            // (e.g. its request was cancelled): send a new probe
+            BreakerState::Open | BreakerState::HalfOpen => {
+                info!("Circuit for topic {} half-open, sending a probe", topic);
+                breaker.state = BreakerState::HalfOpen;
+                breaker.opened_at = now;
+                Ok(())
+            }
+        }
+    }
+
+    fn record_at(&self, topic: &str, success: bool, now: Instant) {
+        let mut topics = self.topics.lock().unwrap();
+        if success {
+            // Healthy topics are the common case; don't track them at all
+            if let Some(breaker) = topics.remove(topic) {
+                if breaker.state != BreakerState::Closed {
+                    info!("Circuit for topic {} closed", topic);
+                }
+            }
+            return;
+        }
+
+        let breaker = topics
+            .entry(topic.to_string())
+            .or_insert_with(|| Breaker::new(now));
+        breaker.consecutive_failures += 1;
+        let trip = breaker.state == BreakerState::HalfOpen
+            || breaker.consecutive_failures >= self.config.failure_threshold;
+        if trip && breaker.state != BreakerState::Open {
+            warn!(
+                "Circuit for topic {} opened after {} consecutive failures",
+                topic, breaker.consecutive_failures
+            );
+            breaker.state = BreakerState::Open;
+            breaker.opened_at = now;
+        }
+    }
+
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        let breakers = Arc::clone(self);
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge_vec(
+                "rust_ingest_topic_circuit_state",
+                "Per-topic circuit breaker state (0 closed, 1 open, 2 half-open)",
+                &["topic"],
+            ),
+            move |states: &IntGaugeVec| {
+                states.reset();
+                for (topic, breaker) in breakers.topics.lock().unwrap().iter() {
+                    states
+                        .with_label_values(&[topic])
+                        .set(breaker.state.as_metric().into());
+                }
+            },
+        )
+    }
+}
+
+// Wraps the configured sink, short-circuiting records for topics whose
+// breaker is open instead of waiting on sends that are bound to fail
+pub struct CircuitBreakerSink {
+    inner: Arc<dyn TelemetrySink>,
+    breakers: Arc<TopicBreakers>,
+}
+
+impl CircuitBreakerSink {
+    pub fn new(inner: Arc<dyn TelemetrySink>, breakers: Arc<TopicBreakers>) -> Self {
+        Self { inner, breakers }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for CircuitBreakerSink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let topic = record.topic;
+        if let Err(retry_after) = self.breakers.admit_at(topic, Instant::now()) {
+            return Err(CircuitOpen {
+                topic: topic.to_string(),
+                retry_after,
+            }
+            .into());
+        }
+        let result = self.inner.publish(record).await;
+        self.breakers
+            .record_at(topic, result.is_ok(), Instant::now());
+        result
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        self.inner.publish_raw(topic, key, payload).await
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        self.inner.publish_tombstone(topic, key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id)
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+
+    fn state(breakers: &TopicBreakers, topic: &str) -> BreakerState {
+        breakers
+            .topics
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map_or(BreakerState::Closed, |breaker| breaker.state)
+    }
+
+    fn breakers() -> Arc<TopicBreakers> {
+        Arc::new(TopicBreakers::new(CircuitBreakerConfig {
+            enabled: true,
+            failure_threshold: 3,
+            open_secs: 10,
+        }))
+    }
+
+    // Fails every send to one topic
+    struct BrokenTopicSink(&'static str);
+
+    #[async_trait]
+    impl TelemetrySink for BrokenTopicSink {
+        fn name(&self) -> &'static str {
+            "broken-topic"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if record.topic == self.0 {
+                Err(anyhow::anyhow!("leader not available"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    async fn send(sink: &CircuitBreakerSink, topic: &str) -> Result<()> {
+        let telemetry = Telemetry::default();
+        sink.publish(SinkRecord {
+            topic,
+            key: "sensor-1",
+            payload: &[],
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_failing_topic_does_not_affect_healthy_topic() {
+        let breakers = breakers();
+        let sink = CircuitBreakerSink::new(
+            Arc::new(BrokenTopicSink("telemetry.eu")),
+            Arc::clone(&breakers),
+        );
+
+        for _ in 0..3 {
+            let err = send(&sink, "telemetry.eu").await.unwrap_err();
+            assert!(err.downcast_ref::<CircuitOpen>().is_none());
+            assert!(send(&sink, "telemetry.us").await.is_ok());
+        }
+        assert_eq!(state(&breakers, "telemetry.eu"), BreakerState::Open);
+        assert_eq!(state(&breakers, "telemetry.us"), BreakerState::Closed);
+
+        // Further sends to the failing topic are short-circuited
+        let err = send(&sink, "telemetry.eu").await.unwrap_err();
+        assert!(err.downcast_ref::<CircuitOpen>().is_some());
+        assert!(send(&sink, "telemetry.us").await.is_ok());
+
+        let metrics = request_metrics::rendered(|registry| breakers.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_topic_circuit_state{topic=\"telemetry.eu\"} 1"));
+        assert!(!metrics.contains("telemetry.us"));
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breakers = breakers();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breakers.record_at("telemetry.eu", false, start);
+        }
+        assert_eq!(
+            breakers.admit_at("telemetry.eu", start + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+
+        // After the open period one probe goes through, others wait for it
+        let later = start + Duration::from_secs(10);
+        assert!(breakers.admit_at("telemetry.eu", later).is_ok());
+        assert_eq!(state(&breakers, "telemetry.eu"), BreakerState::HalfOpen);
+        assert!(breakers.admit_at("telemetry.eu", later).is_err());
+
+        // A failed probe reopens the breaker
+        breakers.record_at("telemetry.eu", false, later);
+        assert_eq!(state(&breakers, "telemetry.eu"), BreakerState::Open);
+
+        // A successful one closes it
+        let much_later = later + Duration::from_secs(10);
+        assert!(breakers.admit_at("telemetry.eu", much_later).is_ok());
+        breakers.record_at("telemetry.eu", true, much_later);
+        assert_eq!(state(&breakers, "telemetry.eu"), BreakerState::Closed);
+        assert!(breakers.admit_at("telemetry.eu", much_later).is_ok());
+    }
+}
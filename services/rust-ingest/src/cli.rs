@@ -0,0 +1,205 @@
+use crate::{
+    ack::AckMode,
+    config::Config,
+    priority::Priority,
+    sink::{Position, SinkRecord, TelemetrySink},
+    telemetry_handler::create_telemetry_from_json,
+};
+use anyhow::Result;
+use std::sync::OnceLock;
+
+pub const USAGE: &str = "\
+Usage: rust-ingestion [COMMAND]
+
+Commands:
+  (none)          Run the ingestion server
+  publish-test    Send one synthetic telemetry record through the configured
+                  sinks and exit
+    --device-id ID    Device the record is from [default: publish-test]
+    --metrics JSON    Metrics object [default: {\"test\": 1}]
+    --topic TOPIC     Destination topic [default: kafka_topic]
+  help            Show this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    PublishTest(PublishTest),
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PublishTest {
+    pub device_id: String,
+    pub metrics: String,
+    pub topic: Option<String>,
+}
+
+impl Default for PublishTest {
+    fn default() -> Self {
+        Self {
+            device_id: "publish-test".to_string(),
+            metrics: r#"{"test": 1}"#.to_string(),
+            topic: None,
+        }
+    }
+}
+
+// Arguments after the program name
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let command = match args.next().as_deref() {
+        None => return Ok(Command::Serve),
+        Some("help" | "--help" | "-h") => return Ok(Command::Help),
+        Some("publish-test") => {
+            let mut options = PublishTest::default();
+            while let Some(flag) = args.next() {
+                let mut value = || {
+                    args.next()
+                        .ok_or_else(|| anyhow::anyhow!("{} needs a value", flag))
+                };
+                match flag.as_str() {
+                    "--device-id" => options.device_id = value()?,
+                    "--metrics" => options.metrics = value()?,
+                    "--topic" => options.topic = Some(value()?),
+                    other => {
+                        return Err(anyhow::anyhow!(
+                            "Unknown publish-test option {}\n\n{}",
+                            other,
+                            USAGE
+                        ))
+                    }
+                }
+            }
+            Command::PublishTest(options)
+        }
+        Some(other) => {
+            return Err(anyhow::anyhow!("Unknown command {}\n\n{}", other, USAGE));
+        }
+    };
+    Ok(command)
+}
+
+// Sends the record as the server would with acks=all, skipping the
+// pipeline's checks, and waits until the sinks have it
+pub async fn publish_test(
+    cfg: &Config,
+    sink: &dyn TelemetrySink,
+    options: &PublishTest,
+) -> Result<Option<Position>> {
+    let telemetry = create_telemetry_from_json(
+        &options.metrics,
+        &options.device_id,
+        cfg.large_integers,
+        cfg.duplicate_metric_keys,
+    )?;
+    let topic = options.topic.as_deref().unwrap_or(&cfg.kafka_topic);
+    let payload = cfg.encoding.encode_for(&telemetry, topic)?;
+    let position = OnceLock::new();
+    sink.publish(SinkRecord {
+        topic,
+        key: &telemetry.device_id,
+        payload: &payload,
+        telemetry: &telemetry,
+        expires_at: None,
+        ack: AckMode::All,
+        priority: Priority::Normal,
+        receipt: None,
+        request_id: None,
+        position: Some(&position),
+    })
+    .await?;
+    sink.flush().await?;
+    Ok(position.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Telemetry;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(str::to_string)
+    }
+
+    #[test]
+    fn test_no_command_runs_the_server() {
+        assert_eq!(parse(args("")).unwrap(), Command::Serve);
+        assert_eq!(parse(args("--help")).unwrap(), Command::Help);
+    }
+
+    #[test]
+    fn test_publish_test_options() {
+        assert_eq!(
+            parse(args("publish-test")).unwrap(),
+            Command::PublishTest(PublishTest::default())
+        );
+        assert_eq!(
+            parse(args(
+                r#"publish-test --device-id pump-7 --metrics {"rpm":1200} --topic telemetry.test"#
+            ))
+            .unwrap(),
+            Command::PublishTest(PublishTest {
+                device_id: "pump-7".to_string(),
+                metrics: r#"{"rpm":1200}"#.to_string(),
+                topic: Some("telemetry.test".to_string()),
+            })
+        );
+        assert!(parse(args("publish-test --device-id")).is_err());
+        assert!(parse(args("publish-test --verbose")).is_err());
+        assert!(parse(args("publish")).is_err());
+    }
+
+    // Keeps what it was given and says it wrote it at offset 9
+    #[derive(Default)]
+    struct CaptureSink {
+        records: Mutex<Vec<(String, Telemetry)>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for CaptureSink {
+        fn name(&self) -> &'static str {
+            "capture"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            self.records
+                .lock()
+                .unwrap()
+                .push((record.topic.to_string(), record.telemetry.clone()));
+            if let Some(slot) = record.position {
+                let _ = slot.set(Position {
+                    partition: 0,
+                    offset: Some(9),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_test_sends_one_record() {
+        let cfg = crate::config::from_toml(
+            r#"
+            listen_addr = "127.0.0.1:0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            "#,
+        )
+        .unwrap();
+        let sink = CaptureSink::default();
+        let options = PublishTest {
+            metrics: r#"{"rpm": 1200, "running": true}"#.to_string(),
+            ..Default::default()
+        };
+        let position = publish_test(&cfg, &sink, &options).await.unwrap();
+        assert_eq!(position.unwrap().offset, Some(9));
+
+        let records = sink.records.lock().unwrap();
+        let (topic, telemetry) = &records[0];
+        assert_eq!(topic, "telemetry");
+        assert_eq!(telemetry.device_id, "publish-test");
+        assert_eq!(telemetry.metrics["rpm"], 1200.0);
+        assert!(telemetry.flag_metrics["running"]);
+    }
+}
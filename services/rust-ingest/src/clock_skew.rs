@@ -0,0 +1,110 @@
+use crate::server::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+// Request header carrying the client's clock, in unix seconds
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClockSkewConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Largest accepted difference between X-Timestamp and server time
+    #[serde(default = "default_max_skew_secs")]
+    pub max_skew_secs: u64,
+}
+
+impl Default for ClockSkewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_skew_secs: default_max_skew_secs(),
+        }
+    }
+}
+
+fn default_max_skew_secs() -> u64 {
+    300
+}
+
+// Rejects ingest requests whose X-Timestamp is missing or too far from
+// server time, before the body is read. This is about the client's clock,
+// not the records' `ts`. X-Signature covers only the body, so this doesn't
+// stop a captured signed request being replayed with a fresh timestamp.
+pub async fn reject_skewed(
+    State(config): State<ClockSkewConfig>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let now = chrono::Utc::now().timestamp();
+    check_timestamp(request.headers(), config.max_skew_secs, now)?;
+    Ok(next.run(request).await)
+}
+
+fn check_timestamp(headers: &HeaderMap, max_skew_secs: u64, now: i64) -> Result<(), ApiError> {
+    let timestamp = headers
+        .get(TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid X-Timestamp header",
+            )
+        })?;
+
+    let skew = now.abs_diff(timestamp);
+    if skew > max_skew_secs {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "X-Timestamp outside the allowed clock skew",
+        )
+        .with_details(format!(
+            "client clock is {}s {} server time, limit is {}s",
+            skew,
+            if timestamp < now {
+                "behind"
+            } else {
+                "ahead of"
+            },
+            max_skew_secs
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    fn headers(timestamp: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMESTAMP_HEADER, timestamp.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_timestamp_within_window_is_accepted() {
+        let now = 1_700_000_000;
+        assert!(check_timestamp(&headers("1700000000"), 300, now).is_ok());
+        assert!(check_timestamp(&headers("1699999700"), 300, now).is_ok());
+        assert!(check_timestamp(&headers("1700000300"), 300, now).is_ok());
+    }
+
+    #[test]
+    fn test_timestamp_outside_window_is_rejected() {
+        let now = 1_700_000_000;
+        for timestamp in ["1699999699", "1700000301", "soon"] {
+            let err = check_timestamp(&headers(timestamp), 300, now).unwrap_err();
+            assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+        }
+        let err = check_timestamp(&HeaderMap::new(), 300, now).unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,489 @@
+use crate::{
+    kafka,
+    load_shedding::FreshnessWeights,
+    proto::telemetry::Telemetry,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::Registry;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+// Cap on how much sooner than `retry_interval` a high-freshness record
+// tries again
+const MAX_RETRY_SPEEDUP: f64 = 10.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CoalescingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Metrics where only the latest value matters. A waiting record made up
+    // of these alone can be dropped in favour of a newer one.
+    #[serde(default)]
+    pub latest_wins: Vec<String>,
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+    // How long a record waits for queue space before the send fails
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl Default for CoalescingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            latest_wins: Vec::new(),
+            retry_interval_ms: default_retry_interval_ms(),
+            max_wait_ms: default_max_wait_ms(),
+        }
+    }
+}
+
+fn default_retry_interval_ms() -> u64 {
+    50
+}
+
+fn default_max_wait_ms() -> u64 {
+    5000
+}
+
+// The newest record seen for a device that has records waiting
+struct Newest {
+    id: u64,
+    metrics: HashSet<String>,
+}
+
+struct DeviceWaiters {
+    waiting: usize,
+    newest: Newest,
+    // The device was decommissioned; its waiting records give up
+    forgotten: bool,
+}
+
+// Rides out a full producer queue instead of failing each send straight
+// away: a refused record waits and tries again until there is room. While it
+// waits, a newer record for the same device that is queued or delivered
+// replaces it, so the request is answered without sending the stale reading,
+// but only if every metric it carries is a latest_wins metric and the newer
+// record has them all. Records with samples are history and always wait.
+// Nothing happens while the queue has room. Records with high freshness
+// weights try again more often, so they are first to take freed space.
+pub struct CoalescingSink {
+    inner: Arc<dyn TelemetrySink>,
+    latest_wins: HashSet<String>,
+    freshness: FreshnessWeights,
+    retry_interval: Duration,
+    max_wait: Duration,
+    next_id: AtomicU64,
+    devices: Mutex<HashMap<String, DeviceWaiters>>,
+}
+
+impl CoalescingSink {
+    pub fn new(
+        inner: Arc<dyn TelemetrySink>,
+        config: &CoalescingConfig,
+        freshness: FreshnessWeights,
+    ) -> Self {
+        Self {
+            inner,
+            latest_wins: config.latest_wins.iter().cloned().collect(),
+            freshness,
+            retry_interval: Duration::from_millis(config.retry_interval_ms),
+            max_wait: Duration::from_millis(config.max_wait_ms),
+            next_id: AtomicU64::new(0),
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn newest(telemetry: &Telemetry, id: u64) -> Newest {
+        Newest {
+            id,
+            metrics: telemetry.metrics.keys().cloned().collect(),
+        }
+    }
+
+    // Called once a record is queued or about to wait; only matters when
+    // older records for the device are waiting
+    fn note(&self, telemetry: &Telemetry, id: u64) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(&telemetry.device_id) {
+            if id > device.newest.id {
+                device.newest = Self::newest(telemetry, id);
+            }
+        }
+    }
+
+    fn start_waiting<'a>(&'a self, telemetry: &'a Telemetry, id: u64) -> Waiting<'a> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices
+            .entry(telemetry.device_id.clone())
+            .or_insert_with(|| DeviceWaiters {
+                waiting: 0,
+                newest: Self::newest(telemetry, id),
+                forgotten: false,
+            });
+        device.waiting += 1;
+        if id > device.newest.id {
+            device.newest = Self::newest(telemetry, id);
+        }
+        Waiting {
+            sink: self,
+            device_id: &telemetry.device_id,
+        }
+    }
+
+    fn stop_waiting(&self, device_id: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(device_id) {
+            device.waiting -= 1;
+            if device.waiting == 0 {
+                devices.remove(device_id);
+            }
+        }
+    }
+
+    // Makes the device's waiting records give up rather than go out after
+    // its tombstone; returns whether it had any
+    fn forget(&self, device_id: &str) -> bool {
+        let mut devices = self.devices.lock().unwrap();
+        match devices.get_mut(device_id) {
+            Some(device) => {
+                device.forgotten = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forgotten(&self, device_id: &str) -> bool {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .get(device_id)
+            .is_some_and(|device| device.forgotten)
+    }
+
+    fn superseded(&self, telemetry: &Telemetry, id: u64) -> bool {
+        if !telemetry.samples.is_empty()
+            || !telemetry
+                .metrics
+                .keys()
+                .all(|metric| self.latest_wins.contains(metric))
+        {
+            return false;
+        }
+        let devices = self.devices.lock().unwrap();
+        devices.get(&telemetry.device_id).is_some_and(|device| {
+            device.newest.id > id
+                && telemetry
+                    .metrics
+                    .keys()
+                    .all(|metric| device.newest.metrics.contains(metric))
+        })
+    }
+
+    fn retry_interval(&self, telemetry: &Telemetry) -> Duration {
+        let weight = self
+            .freshness
+            .record_weight(telemetry.metrics.keys())
+            .min(MAX_RETRY_SPEEDUP);
+        self.retry_interval.div_f64(weight)
+    }
+
+    async fn wait_for_room(
+        &self,
+        record: SinkRecord<'_>,
+        id: u64,
+        refused: anyhow::Error,
+    ) -> Result<()> {
+        let deadline = Instant::now() + self.max_wait;
+        let mut last_error = refused;
+        let retry_interval = self.retry_interval(record.telemetry);
+        loop {
+            tokio::time::sleep(retry_interval).await;
+            if self.forgotten(&record.telemetry.device_id) {
+                return Err(anyhow::anyhow!(
+                    "device {} was decommissioned before the record could be sent",
+                    record.telemetry.device_id
+                ));
+            }
+            if self.superseded(record.telemetry, id) {
+                debug!(
+                    "Coalesced waiting record for device {} into a newer one",
+                    record.telemetry.device_id
+                );
+                if let Some(receipt) = record.receipt {
+                    receipt.failed("superseded by a newer reading before it was sent");
+                }
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(last_error);
+            }
+            match self.inner.publish(record).await {
+                Ok(()) => {
+                    self.note(record.telemetry, id);
+                    return Ok(());
+                }
+                Err(e) if kafka::is_queue_full(&e) => last_error = e,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Deregisters a waiting record however its wait ends, including when the
+// request is dropped part way
+struct Waiting<'a> {
+    sink: &'a CoalescingSink,
+    device_id: &'a str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.sink.stop_waiting(self.device_id);
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for CoalescingSink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let refused = match self.inner.publish(record).await {
+            Ok(()) => {
+                self.note(record.telemetry, id);
+                return Ok(());
+            }
+            Err(e) if kafka::is_queue_full(&e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let _waiting = self.start_waiting(record.telemetry, id);
+        self.wait_for_room(record, id, refused).await
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        self.inner.publish_raw(topic, key, payload).await
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        self.inner.publish_tombstone(topic, key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id) + usize::from(self.forget(device_id))
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ack::AckMode, load_shedding::FreshnessConfig, pipeline_retry::TransientError,
+        priority::Priority,
+    };
+    use std::sync::atomic::AtomicBool;
+
+    // Refuses every send with a queue-full error while `full` is set
+    #[derive(Default)]
+    struct QueueSink {
+        full: AtomicBool,
+        sent: Mutex<Vec<(String, HashMap<String, f64>)>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for QueueSink {
+        fn name(&self) -> &'static str {
+            "queue"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if self.full.load(Ordering::SeqCst) {
+                return Err(TransientError {
+                    stage: kafka::QUEUE_FULL_STAGE,
+                    reason: "Local: Queue full".to_string(),
+                }
+                .into());
+            }
+            self.sent.lock().unwrap().push((
+                record.telemetry.device_id.clone(),
+                record.telemetry.metrics.clone(),
+            ));
+            Ok(())
+        }
+    }
+
+    fn coalescing(queue: &Arc<QueueSink>, max_wait_ms: u64) -> Arc<CoalescingSink> {
+        Arc::new(CoalescingSink::new(
+            Arc::clone(queue) as Arc<dyn TelemetrySink>,
+            &CoalescingConfig {
+                enabled: true,
+                latest_wins: vec!["temperature".to_string()],
+                retry_interval_ms: 2,
+                max_wait_ms,
+            },
+            FreshnessWeights::new(&FreshnessConfig::default()),
+        ))
+    }
+
+    fn send(
+        sink: &Arc<CoalescingSink>,
+        metric: &str,
+        value: f64,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let sink = Arc::clone(sink);
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            metrics: [(metric.to_string(), value)].into(),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            sink.publish(SinkRecord {
+                topic: "telemetry",
+                key: &telemetry.device_id,
+                payload: b"",
+                telemetry: &telemetry,
+                expires_at: None,
+                ack: AckMode::All,
+                priority: Priority::Normal,
+                receipt: None,
+                request_id: None,
+                position: None,
+            })
+            .await
+        })
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+    }
+
+    #[tokio::test]
+    async fn test_newer_reading_replaces_one_waiting_for_queue_space() {
+        let queue = Arc::new(QueueSink::default());
+        let sink = coalescing(&queue, 5000);
+        queue.full.store(true, Ordering::SeqCst);
+
+        let older = send(&sink, "temperature", 20.0);
+        settle().await;
+        let newer = send(&sink, "temperature", 21.0);
+        settle().await;
+        queue.full.store(false, Ordering::SeqCst);
+
+        older.await.unwrap().unwrap();
+        newer.await.unwrap().unwrap();
+        let sent = queue.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1["temperature"], 21.0);
+        assert!(sink.devices.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_other_metrics_wait_and_are_all_sent() {
+        let queue = Arc::new(QueueSink::default());
+        let sink = coalescing(&queue, 5000);
+        queue.full.store(true, Ordering::SeqCst);
+
+        // Counters need every reading, so neither replaces the other
+        let first = send(&sink, "energy_total", 1.0);
+        settle().await;
+        let second = send(&sink, "energy_total", 2.0);
+        // A newer reading without the waiting record's metric doesn't replace it
+        let unrelated = send(&sink, "temperature", 30.0);
+        settle().await;
+        queue.full.store(false, Ordering::SeqCst);
+
+        for task in [first, second, unrelated] {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(queue.sent.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_once_the_wait_runs_out() {
+        let queue = Arc::new(QueueSink::default());
+        let sink = coalescing(&queue, 20);
+        queue.full.store(true, Ordering::SeqCst);
+
+        let err = send(&sink, "temperature", 20.0).await.unwrap().unwrap_err();
+        assert!(kafka::is_queue_full(&err));
+        assert!(queue.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_waiting_records_give_up_when_the_device_is_forgotten() {
+        let queue = Arc::new(QueueSink::default());
+        let sink = coalescing(&queue, 5000);
+        queue.full.store(true, Ordering::SeqCst);
+
+        let waiting = send(&sink, "energy_total", 1.0);
+        settle().await;
+        assert_eq!(sink.forget_device("sensor-1"), 1);
+        queue.full.store(false, Ordering::SeqCst);
+
+        let err = waiting.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("decommissioned"), "{}", err);
+        assert!(queue.sent.lock().unwrap().is_empty());
+        assert!(sink.devices.lock().unwrap().is_empty());
+        assert_eq!(sink.forget_device("sensor-1"), 0);
+    }
+
+    #[test]
+    fn test_high_freshness_records_retry_sooner() {
+        let sink = CoalescingSink::new(
+            Arc::new(QueueSink::default()),
+            &CoalescingConfig {
+                retry_interval_ms: 100,
+                ..CoalescingConfig::default()
+            },
+            FreshnessWeights::new(&FreshnessConfig {
+                weights: [
+                    ("vibration".to_string(), 4.0),
+                    ("humidity".to_string(), 0.5),
+                ]
+                .into(),
+                default_weight: 1.0,
+            }),
+        );
+        let record = |metrics: &[&str]| Telemetry {
+            metrics: metrics.iter().map(|m| (m.to_string(), 1.0)).collect(),
+            ..Default::default()
+        };
+        let interval = |metrics: &[&str]| sink.retry_interval(&record(metrics));
+        assert_eq!(
+            interval(&["vibration", "humidity"]),
+            Duration::from_millis(25)
+        );
+        // Low weights never slow a record below the configured interval
+        assert_eq!(interval(&["humidity"]), Duration::from_millis(100));
+        assert_eq!(interval(&["temperature"]), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::request_metrics;
+use prometheus::{IntCounter, IntGauge, Registry};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConcurrencyLimitConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // POST /telemetry requests processed at once
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    // How long a request waits for a slot before getting a 503
+    #[serde(default = "default_acquire_timeout_ms")]
+    pub acquire_timeout_ms: u64,
+}
+
+impl Default for ConcurrencyLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_concurrent: default_max_concurrent(),
+            acquire_timeout_ms: default_acquire_timeout_ms(),
+        }
+    }
+}
+
+fn default_max_concurrent() -> usize {
+    1024
+}
+
+fn default_acquire_timeout_ms() -> u64 {
+    100
+}
+
+// Bounds the single-record requests in progress, so a traffic spike queues
+// briefly and is then turned away instead of piling up records in memory
+// and in the producer's queue
+pub struct ConcurrencyLimit {
+    max: usize,
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+    rejected: IntCounter,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(config: &ConcurrencyLimitConfig) -> Self {
+        let max = config.max_concurrent.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+            timeout: Duration::from_millis(config.acquire_timeout_ms),
+            rejected: request_metrics::int_counter(
+                "rust_ingest_concurrency_rejected_total",
+                "Requests turned away because every concurrency slot stayed taken",
+            ),
+        }
+    }
+
+    // A slot held until the permit is dropped, or None when none freed up
+    // within the timeout
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let acquired =
+            tokio::time::timeout(self.timeout, Arc::clone(&self.permits).acquire_owned()).await;
+        match acquired {
+            Ok(Ok(permit)) => Some(permit),
+            // The semaphore is never closed, so only the timeout gets here
+            _ => {
+                self.rejected.inc();
+                None
+            }
+        }
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.rejected)?;
+        let (max, permits) = (self.max, Arc::clone(&self.permits));
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge(
+                "rust_ingest_concurrency_in_use",
+                "Concurrency slots held by requests in progress",
+            ),
+            move |in_use: &IntGauge| in_use.set((max - permits.available_permits()) as i64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_past_the_limit_time_out() {
+        let limit = ConcurrencyLimit::new(&ConcurrencyLimitConfig {
+            enabled: true,
+            max_concurrent: 2,
+            acquire_timeout_ms: 20,
+        });
+        let first = limit.acquire().await.unwrap();
+        let _second = limit.acquire().await.unwrap();
+        assert!(limit.acquire().await.is_none());
+        let metrics = request_metrics::rendered(|registry| limit.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_concurrency_rejected_total 1\n"));
+        assert!(metrics.contains("rust_ingest_concurrency_in_use 2\n"));
+
+        // A slot freed while waiting is handed over
+        let waiting = tokio::spawn(async move { limit.acquire().await.is_some() });
+        drop(first);
+        assert!(waiting.await.unwrap());
+    }
+}
@@ -1,17 +1,789 @@
-use serde::Deserialize;
+use crate::{
+    ack::AckMode,
+    band_changes::BandChangeConfig,
+    baseline::AdaptiveValidationConfig,
+    broker_health::BrokerProbeConfig,
+    cardinality::CardinalityGuardConfig,
+    circuit_breaker::CircuitBreakerConfig,
+    clock_skew::ClockSkewConfig,
+    coalescing::CoalescingConfig,
+    concurrency_limit::ConcurrencyLimitConfig,
+    connections::ConnectionReaperConfig,
+    content_dedup::ContentDedupConfig,
+    content_encoding::ContentEncodingConfig,
+    csv_ingest::CsvConfig,
+    delayed_delivery::DelayedDeliveryConfig,
+    device_attributes::DeviceAttributesConfig,
+    device_metrics::DeviceMetricsConfig,
+    device_rate_limit::DeviceRateLimitConfig,
+    device_types::DeviceTypeConfig,
+    dlq_replay::DlqReplayConfig,
+    duplicate_backoff::DuplicateBackoffConfig,
+    encoding::EncodingConfig,
+    health::HealthConfig,
+    heartbeat::HeartbeatConfig,
+    histograms::HistogramConfig,
+    imputation::ImputationConfig,
+    ingest_sequence::IngestSequenceConfig,
+    kafka::ProducerSettings,
+    key_pseudonyms::PartitionKeyConfig,
+    line_sink::FileSinkConfig,
+    load_shedding::{FreshnessConfig, LoadSheddingConfig},
+    logging::LogFormat,
+    maintenance::MaintenanceConfig,
+    metric_renames::MetricRenameRule,
+    metric_values::{DuplicateKeyPolicy, LargeIntegerPolicy, MetricCoercion},
+    mqtt_sink::MqttSinkConfig,
+    openapi::OpenApiConfig,
+    ordering::OrderingConfig,
+    parquet_sink::ParquetSinkConfig,
+    pipeline_retry::PipelineRetryConfig,
+    priority::PriorityConfig,
+    provisioning::AutoProvisionConfig,
+    quality::QualityStreamConfig,
+    rate_of_change::RateOfChangeConfig,
+    receipts::ReceiptConfig,
+    redis_sink::RedisSinkConfig,
+    request_signing::RequestSigningConfig,
+    resend_dedup::ResendDedupConfig,
+    routing::TopicRoute,
+    sample_window::SampleWindowConfig,
+    schema_registry::SchemaRegistryConfig,
+    shutdown::ShutdownConfig,
+    size_budget::{SizeBudgetConfig, TagLimitsConfig},
+    spillover::{DeliveryMode, SpilloverConfig},
+    telemetry_handler::{EnrichmentConfig, MetricKeyCase, MetricRule},
+    tenancy::TenancyConfig,
+    time_grid::TimeGridConfig,
+    trace_sampling::TraceSamplingConfig,
+    ts_window::TimestampWindowConfig,
+    ttl::TtlConfig,
+    unit_conversion::UnitConversionConfig,
+    validation::ValidationMode,
+    validation_profiles::ValidationProfilesConfig,
+    websocket::WebSocketConfig,
+    worker_pool::ValidationPoolConfig,
+};
 use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub listen_addr: String,
+    // The three required keys default to empty only so that `validate` can
+    // say which one is missing
+    #[serde(default)]
+    pub listen_addr: ListenAddrs,
+    #[serde(default)]
     pub kafka_brokers: String,
+    #[serde(default)]
     pub kafka_topic: String,
+    // Per-record topic such as "telemetry.{device_type}.{region}"; variables
+    // are device_type, region (tag) and tenant. Falls back to kafka_topic.
+    #[serde(default)]
+    pub topic_template: Option<String>,
+    // Rules tried before topic_template, first match wins, e.g. records with
+    // a latitude metric to telemetry.gps or truck- devices to telemetry.fleet
+    #[serde(default)]
+    pub topic_routes: Vec<TopicRoute>,
+    // Topics a request may be sent to instead with the X-Kafka-Topic
+    // header; a header naming any other topic gets a 403
+    #[serde(default)]
+    pub header_topics: Vec<String>,
+    #[serde(default)]
+    pub kafka_producer: ProducerSettings,
+    // Names this node in the headers, metadata and logs of the records it
+    // handles; defaults to the system hostname
+    #[serde(default)]
+    pub ingestion_node_id: Option<String>,
+    // Bearer token for /admin endpoints; admin endpoints are disabled when unset
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    // Serve GET /debug/last-messages, which shows the payloads of recently
+    // published records to holders of the admin token
+    #[serde(default)]
+    pub enable_debug_endpoints: bool,
+    // Records /debug/last-messages keeps
+    #[serde(default = "default_debug_last_messages")]
+    pub debug_last_messages: usize,
+    // API keys clients may present as bearer tokens; usage is tracked per key
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    // With api_keys set, everything but /health, /ready and the admin endpoints
+    // answers 401 without one of them; turn off to only attribute usage
+    #[serde(default = "default_require_api_key")]
+    pub require_api_key: bool,
+    // Browser origins allowed to call the API, e.g. https://app.example.com;
+    // other origins get no CORS headers back
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    // Allow every origin, method and header instead; only for development
+    #[serde(default)]
+    pub cors_permissive: bool,
+    #[serde(default)]
+    pub tenancy: TenancyConfig,
+    // Sinks every record is published to: any of "kafka", "parquet", "redis",
+    // "mqtt", "stdout" and "file"
+    #[serde(default = "default_sinks")]
+    pub sinks: Vec<String>,
+    #[serde(default)]
+    pub parquet: ParquetSinkConfig,
+    #[serde(default)]
+    pub redis: RedisSinkConfig,
+    #[serde(default)]
+    pub file_sink: FileSinkConfig,
+    #[serde(default)]
+    pub mqtt: MqttSinkConfig,
+    // Case applied to metric keys before validation: none, lower or upper
+    #[serde(default)]
+    pub metric_key_case: MetricKeyCase,
+    // Regex renames applied after key case, e.g. sensor_(\d+)_temp ->
+    // temperature with the index moved into a tag
+    #[serde(default)]
+    pub metric_renames: Vec<MetricRenameRule>,
+    // Convert metrics sent in another unit (X-Units, or per device) to the
+    // canonical one, e.g. fahrenheit to celsius
+    #[serde(default)]
+    pub unit_conversion: UnitConversionConfig,
+    #[serde(default)]
+    pub adaptive_validation: AdaptiveValidationConfig,
+    // Offload CPU-bound validation to a bounded blocking pool
+    #[serde(default)]
+    pub validation_pool: ValidationPoolConfig,
+    // How device types are recognized (id prefixes and metric signatures)
+    #[serde(default)]
+    pub device_types: DeviceTypeConfig,
+    #[serde(default)]
+    pub auto_provision: AutoProvisionConfig,
+    // Cap on distinct metric names per device and window
+    #[serde(default)]
+    pub cardinality_guard: CardinalityGuardConfig,
+    #[serde(default)]
+    pub ttl: TtlConfig,
+    // Topic validation warnings are published to as structured events; off when unset
+    #[serde(default)]
+    pub quality_topic: Option<String>,
+    #[serde(default)]
+    pub quality_stream: QualityStreamConfig,
+    // Topic records that fail validation are copied to, with the reason; off when unset
+    #[serde(default)]
+    pub dead_letter_topic: Option<String>,
+    // POST /admin/replay, for re-running dead-lettered records once whatever
+    // rejected them is fixed
+    #[serde(default)]
+    pub dlq_replay: DlqReplayConfig,
+    // Defaults for expected metrics a device type left out of a reading
+    #[serde(default)]
+    pub imputation: ImputationConfig,
+    // Close client connections that have gone silent
+    #[serde(default)]
+    pub connection_reaper: ConnectionReaperConfig,
+    // Output format (protobuf, json, avro, messagepack) per destination topic
+    #[serde(default)]
+    pub encoding: EncodingConfig,
+    // Confluent-compatible registry to register the Avro schema with; Avro
+    // records then go out in its wire format, prefixed with the schema id
+    #[serde(default)]
+    pub schema_registry: SchemaRegistryConfig,
+    // Largest request body accepted on the ingest endpoints, single records
+    // and batches alike, once decompressed; larger ones get a 413. Also caps
+    // WebSocket messages.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    // Total body bytes that concurrently processed batches may hold
+    #[serde(default = "default_batch_memory_budget_bytes")]
+    pub batch_memory_budget_bytes: usize,
+    // Sample out a share of devices when too many records are in flight
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    // Cap on POST /telemetry requests processed at once; the rest wait
+    // briefly for a slot, then get a 503
+    #[serde(default)]
+    pub concurrency_limit: ConcurrencyLimitConfig,
+    // Keep the outcome of 202 requests for clients to look up later at
+    // /receipts/{request_id}
+    #[serde(default)]
+    pub receipts: ReceiptConfig,
+    // Limits for compressed (Content-Encoding: gzip) ingest bodies
+    #[serde(default)]
+    pub content_encoding: ContentEncodingConfig,
+    // Streaming ingestion over a WebSocket at /telemetry/ws
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    // CSV uploads from legacy gateways at /telemetry/csv
+    #[serde(default)]
+    pub csv: CsvConfig,
+    // Per-metric weights for how much lateness costs; with any set, load
+    // shedding drops low-weight metrics first and coalescing retries
+    // high-weight records sooner
+    #[serde(default)]
+    pub freshness: FreshnessConfig,
+    // Serialize each device's sends so retries can't reorder its records
+    #[serde(default)]
+    pub ordering: OrderingConfig,
+    // Wait out a full producer queue, keeping only the latest of a device's
+    // waiting readings for latest-wins metrics
+    #[serde(default)]
+    pub coalescing: CoalescingConfig,
+    // Retry sends that failed before anything was handed off for delivery
+    #[serde(default)]
+    pub pipeline_retry: PipelineRetryConfig,
+    // Key records by an HMAC of the device id instead of the id itself
+    #[serde(default)]
+    pub partition_keys: PartitionKeyConfig,
+    // Stop sending to a topic that keeps failing, without affecting others
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    // Hold records in memory while the sink is down instead of failing them
+    #[serde(default)]
+    pub spillover: SpilloverConfig,
+    // sync or async_buffered; async_buffered turns on the spillover buffer
+    // and answers /telemetry with 202
+    #[serde(default)]
+    pub delivery_mode: DeliveryMode,
+    // Send critical devices' records ahead of routine ones under congestion
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    // Always include the `interpreted` echo in /telemetry responses; clients
+    // can also ask for it per request with X-Echo-Interpretation: true
+    #[serde(default)]
+    pub echo_interpretation: bool,
+    // Work out the format of /telemetry bodies sent without a Content-Type,
+    // or as application/octet-stream, from their first bytes
+    #[serde(default)]
+    pub detect_body_format: bool,
+    // Reject ingest requests whose X-Timestamp is too far from server time
+    #[serde(default)]
+    pub clock_skew: ClockSkewConfig,
+    // Require an X-Signature HMAC of the body on ingest requests
+    #[serde(default)]
+    pub request_signing: RequestSigningConfig,
+    // Turn ingest requests away during scheduled downstream maintenance
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    // Drop readings a device sends again with the same ts
+    #[serde(default)]
+    pub resend_dedup: ResendDedupConfig,
+    // Drop resends of content a device sent moments ago under a fresh ts
+    #[serde(default)]
+    pub content_dedup: ContentDedupConfig,
+    // Backlog thresholds at which /health reports the node as degraded
+    #[serde(default)]
+    pub health: HealthConfig,
+    // Probe the brokers in the background and answer /ready from the result
+    #[serde(default)]
+    pub broker_probe: BrokerProbeConfig,
+    // Report of what happened during shutdown, for deploy tooling
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    // Static attributes from a lookup table, merged into each record's metadata
+    #[serde(default)]
+    pub device_attributes: DeviceAttributesConfig,
+    // Node id and per-node sequence number in each forwarded record's metadata
+    #[serde(default)]
+    pub ingest_sequence: IngestSequenceConfig,
+    // Ingestion time and node id in each forwarded record's metadata
+    #[serde(default)]
+    pub enrichment: EnrichmentConfig,
+    // Per-device-type rule sets replacing the built-in range checks
+    #[serde(default)]
+    pub validation_profiles: ValidationProfilesConfig,
+    // Flag or reject readings that change faster than a metric physically can
+    #[serde(default)]
+    pub rate_of_change: RateOfChangeConfig,
+    // Flag or reject samples whose ts is too far from the record's ts
+    #[serde(default)]
+    pub sample_window: SampleWindowConfig,
+    // Range checks for devices without a validation profile, by metric.
+    // They add to the defaults for temperature, humidity and battery_level,
+    // or replace the default for the same metric.
+    #[serde(default)]
+    pub metric_rules: HashMap<String, MetricRule>,
+    // Decimal places to round metrics to before encoding, by metric, e.g.
+    // temperature = 1. Metrics not listed keep their full precision.
+    #[serde(default)]
+    pub metric_precision: HashMap<String, u32>,
+    // Metric names a record may carry, after key case and renames; a
+    // record with any other is rejected. Empty accepts every name.
+    #[serde(default)]
+    pub allowed_metrics: Vec<String>,
+    // Metric values (samples and flag/text metrics included) a record may
+    // carry; unset means no limit. Larger records get a 413.
+    #[serde(default)]
+    pub max_metrics_per_message: Option<usize>,
+    // Number and length (in bytes) of the tags a client may attach to a
+    // record; records past them get a 413
+    #[serde(default)]
+    pub tag_limits: TagLimitsConfig,
+    // Largest encoded record handed to the sink. The default is the Kafka
+    // producer's own message.max.bytes; raise both together.
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    // "fail_fast" stops at a record's first validation failure; "collect_all"
+    // runs every check and reports all of them
+    #[serde(default)]
+    pub validation_mode: ValidationMode,
+    // Forward ever fewer repeats of an unchanging metric value
+    #[serde(default)]
+    pub duplicate_backoff: DuplicateBackoffConfig,
+    // Synthetic records for devices whose readings are all being held back
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    // Cap on the `samples` time series a single message may carry
+    #[serde(default = "default_max_samples_per_message")]
+    pub max_samples_per_message: usize,
+    // On/off metrics; 1/0, true/false and "on"/"off" all become 1.0 or 0.0
+    #[serde(default)]
+    pub boolean_metrics: Vec<String>,
+    // "strict" rejects non-numeric metric values; "lenient" parses numeric
+    // strings; "typed" keeps booleans and strings as flag and text metrics
+    #[serde(default)]
+    pub metric_coercion: MetricCoercion,
+    // Integers too large for an f64 to hold exactly: "warn" stores them
+    // rounded and logs it, "reject" fails the request
+    #[serde(default)]
+    pub large_integers: LargeIntegerPolicy,
+    // A metric repeated in a JSON object: "warn" keeps the last value and
+    // logs it, "reject" fails the record
+    #[serde(default)]
+    pub duplicate_metric_keys: DuplicateKeyPolicy,
+    // Token bucket per device, optionally adapting to each device's normal rate
+    #[serde(default)]
+    pub device_rate_limit: DeviceRateLimitConfig,
+    // Reject (or clamp) record timestamps implausibly far from server time
+    #[serde(default)]
+    pub ts_window: TimestampWindowConfig,
+    // Snap record timestamps to a fixed grid, per device type
+    #[serde(default)]
+    pub time_grid: TimeGridConfig,
+    // Largest record each device type may send
+    #[serde(default)]
+    pub size_budgets: SizeBudgetConfig,
+    // Send latency and payload size histograms; optionally native (exponential)
+    #[serde(default)]
+    pub histograms: HistogramConfig,
+    // Hold records with a future deliver_at and release them on time
+    #[serde(default)]
+    pub delayed_delivery: DelayedDeliveryConfig,
+    // Describe the ingest API at /openapi.json
+    #[serde(default)]
+    pub openapi: OpenApiConfig,
+    // Events on a separate topic when a metric crosses into another level band
+    #[serde(default)]
+    pub band_changes: BandChangeConfig,
+    // Ack mode for requests without an X-Ack-Mode header: none, queued, leader or all
+    #[serde(default)]
+    pub default_ack_mode: AckMode,
+    // Head-based sampling of request spans, with per-request and per-device overrides
+    #[serde(default)]
+    pub trace_sampling: TraceSamplingConfig,
+    // Log the per-record info lines for one in this many records; warnings
+    // and errors are always logged. 1 logs every record.
+    #[serde(default = "default_log_sample_rate")]
+    pub log_sample_rate: u64,
+    // Records per device on /metrics, for the most active devices only
+    #[serde(default)]
+    pub device_metrics: DeviceMetricsConfig,
 }
 
-pub fn load_config() -> Result<Config> {
-    let settings = config::Config::builder()
+fn default_require_api_key() -> bool {
+    true
+}
+
+fn default_debug_last_messages() -> usize {
+    20
+}
+
+fn default_log_sample_rate() -> u64 {
+    1
+}
+
+fn default_max_body_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_max_message_bytes() -> usize {
+    1_000_000
+}
+
+fn default_batch_memory_budget_bytes() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_max_samples_per_message() -> usize {
+    1000
+}
+
+fn default_sinks() -> Vec<String> {
+    vec!["kafka".to_string()]
+}
+
+// Flat keys from the single-level config format and where they live now.
+// Deployments still using them keep working; each use logs a deprecation
+// warning naming the new key.
+const LEGACY_KEYS: &[(&str, &str)] = &[
+    (
+        "queue_buffering_max_messages",
+        "kafka_producer.queue_buffering_max_messages",
+    ),
+    (
+        "queue_buffering_max_kbytes",
+        "kafka_producer.queue_buffering_max_kbytes",
+    ),
+    (
+        "socket_send_buffer_bytes",
+        "kafka_producer.socket_send_buffer_bytes",
+    ),
+    ("parquet_directory", "parquet.directory"),
+    ("parquet_batch_size", "parquet.batch_size"),
+    ("parquet_flush_interval_secs", "parquet.flush_interval_secs"),
+    ("tenant_prefixes", "tenancy.device_prefixes"),
+    ("default_tenant", "tenancy.default_tenant"),
+    ("validation_workers", "validation_pool.size"),
+    ("default_ttl_ms", "ttl.default_ms"),
+    ("output_format", "encoding.default"),
+    (
+        "quality_events_per_minute",
+        "quality_stream.events_per_minute",
+    ),
+    (
+        "reaper_idle_timeout_secs",
+        "connection_reaper.idle_timeout_secs",
+    ),
+];
+
+// Where the server listens: one address, a comma-separated string of them
+// (handy in LISTEN_ADDR) or a list, e.g. an internal IPv4 interface plus an
+// IPv6 one. Every address is served, with the same state.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ListenAddrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ListenAddrs {
+    pub fn addrs(&self) -> Vec<&str> {
+        let addrs: Vec<&str> = match self {
+            Self::One(addrs) => addrs.split(',').collect(),
+            Self::Many(addrs) => addrs.iter().map(String::as_str).collect(),
+        };
+        addrs
+            .into_iter()
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .collect()
+    }
+}
+
+impl Default for ListenAddrs {
+    fn default() -> Self {
+        Self::Many(Vec::new())
+    }
+}
+
+impl std::fmt::Display for ListenAddrs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.addrs().join(", "))
+    }
+}
+
+impl Config {
+    pub fn node_id(&self) -> String {
+        self.ingestion_node_id
+            .clone()
+            .or_else(system_hostname)
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    // Checks what deserializing can't, so a bad deployment fails at startup
+    // naming the key and the environment variable that sets it
+    pub fn validate(&self) -> Result<()> {
+        let addrs = self.listen_addr.addrs();
+        if addrs.is_empty() {
+            return Err(missing("listen_addr"));
+        }
+        for addr in addrs {
+            if addr.parse::<std::net::SocketAddr>().is_err() {
+                return Err(anyhow::anyhow!(
+                    "listen_addr {:?} is not an IP address and port, e.g. 0.0.0.0:8080 or [::]:8080",
+                    addr
+                ));
+            }
+        }
+        if self.kafka_brokers.trim().is_empty() {
+            return Err(missing("kafka_brokers"));
+        }
+        if self.kafka_topic.trim().is_empty() {
+            return Err(missing("kafka_topic"));
+        }
+        // A WebSocket message has no header to carry its signature
+        if self.request_signing.enabled && self.websocket.enabled {
+            return Err(anyhow::anyhow!(
+                "websocket can't be enabled with request_signing: WebSocket messages aren't signed"
+            ));
+        }
+        self.csv.validate()?;
+        self.schema_registry.validate()?;
+        self.tenancy.validate()?;
+        self.quality_stream.validate()?;
+        self.device_rate_limit.validate()?;
+        Ok(())
+    }
+}
+
+// The kernel's hostname, which in a container is the pod's name
+fn system_hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .into_iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+fn missing(key: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} is not set; set it in the Config file or with the {} environment variable",
+        key,
+        key.to_ascii_uppercase()
+    )
+}
+
+// A config from a TOML snippet, for tests
+#[cfg(test)]
+pub fn from_toml(toml: &str) -> Result<Config> {
+    load_from(
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml)),
+    )
+}
+
+fn sources() -> config::ConfigBuilder<config::builder::DefaultState> {
+    config::Config::builder()
         .add_source(config::File::with_name("Config").required(false))
         .add_source(config::Environment::default())
-        .build()?;
-    Ok(settings.try_deserialize::<Config>()?)
+}
+
+pub fn load_config() -> Result<Config> {
+    load_from(sources())
+}
+
+// `log_format`: "text" (the default) for people, "json" for log
+// aggregation. Read on its own ahead of the rest, since logging has to be
+// set up before loading the full config can log anything.
+pub fn load_log_format() -> Result<LogFormat> {
+    match sources().build()?.get::<LogFormat>("log_format") {
+        Ok(format) => Ok(format),
+        Err(config::ConfigError::NotFound(_)) => Ok(LogFormat::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// `otlp_endpoint`: base URL of an OTLP/HTTP collector to export request
+// spans to, e.g. "http://otel-collector:4318". Read early for the same
+// reason as log_format.
+pub fn load_otlp_endpoint() -> Result<Option<String>> {
+    match sources().build()?.get::<String>("otlp_endpoint") {
+        Ok(endpoint) if endpoint.trim().is_empty() => Ok(None),
+        Ok(endpoint) => Ok(Some(endpoint)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Builds the final Config, moving legacy flat keys to their nested homes first.
+// Keys from the environment go through the same table as the file.
+fn load_from(builder: config::ConfigBuilder<config::builder::DefaultState>) -> Result<Config> {
+    let raw = builder.build()?;
+    let mut migrated = config::Config::builder().add_source(raw.clone());
+    for (legacy, current) in LEGACY_KEYS {
+        let Ok(value) = raw.get::<config::Value>(legacy) else {
+            continue;
+        };
+        if raw.get::<config::Value>(current).is_ok() {
+            warn!(
+                "Ignoring deprecated config key {} because {} is also set",
+                legacy, current
+            );
+            continue;
+        }
+        warn!(
+            "Config key {} is deprecated; move it to {}",
+            legacy, current
+        );
+        migrated = migrated.set_override(*current, value)?;
+    }
+    let cfg = migrated.build()?.try_deserialize::<Config>()?;
+    cfg.validate()?;
+    Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::OutputFormat, kafka::CompressionType};
+
+    fn load(toml: &str) -> Config {
+        from_toml(toml).unwrap()
+    }
+
+    const BASE: &str = r#"
+        listen_addr = "0.0.0.0:8080"
+        kafka_brokers = "localhost:9092"
+        kafka_topic = "telemetry"
+    "#;
+
+    #[test]
+    fn test_listen_addr_takes_one_or_many() {
+        assert_eq!(load(BASE).listen_addr.addrs(), vec!["0.0.0.0:8080"]);
+
+        let cfg = load(
+            r#"
+            listen_addr = ["10.0.0.5:8080", "[::]:8080"]
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            "#,
+        );
+        assert_eq!(cfg.listen_addr.addrs(), vec!["10.0.0.5:8080", "[::]:8080"]);
+
+        // As it comes from LISTEN_ADDR
+        let cfg = load(
+            r#"
+            listen_addr = "10.0.0.5:8080, [::]:8080"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            "#,
+        );
+        assert_eq!(cfg.listen_addr.addrs(), vec!["10.0.0.5:8080", "[::]:8080"]);
+    }
+
+    #[test]
+    fn test_missing_or_bad_required_keys_are_named() {
+        let err = from_toml(
+            r#"
+            listen_addr = "0.0.0.0:8080"
+            kafka_topic = "telemetry"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("kafka_brokers"), "{}", err);
+        assert!(err.contains("KAFKA_BROKERS"), "{}", err);
+
+        let err = from_toml(
+            r#"
+            listen_addr = ""
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("LISTEN_ADDR"), "{}", err);
+
+        let err = from_toml(
+            r#"
+            listen_addr = "0.0.0.0:8080, 0.0.0.0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("\"0.0.0.0\""), "{}", err);
+    }
+
+    #[test]
+    fn test_node_id_defaults_to_the_hostname() {
+        let required = r#"
+            listen_addr = "0.0.0.0:8080"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+        "#;
+        let cfg = from_toml(&format!("{}\ningestion_node_id = \" ingest-3 \"", required)).unwrap();
+        assert_eq!(cfg.node_id(), "ingest-3");
+
+        let cfg = from_toml(required).unwrap();
+        if let Some(hostname) = system_hostname() {
+            assert_eq!(cfg.node_id(), hostname);
+        }
+        assert!(!cfg.node_id().is_empty());
+    }
+
+    #[test]
+    fn test_websocket_is_refused_with_request_signing() {
+        let signed = format!(
+            "{}\n[request_signing]\nenabled = true\nsecret = \"s3cret\"\n",
+            BASE
+        );
+        assert!(from_toml(&signed).is_ok());
+        let err = from_toml(&format!("{}[websocket]\nenabled = true\n", signed))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("request_signing"), "{}", err);
+    }
+
+    #[test]
+    fn test_legacy_flat_keys_are_migrated() {
+        let cfg = load(&format!(
+            r#"{}
+            queue_buffering_max_messages = 5000
+            parquet_directory = "/data/parquet"
+            default_ttl_ms = 30000
+            output_format = "json"
+            [tenant_prefixes]
+            "acme-" = "acme"
+            "#,
+            BASE
+        ));
+        assert_eq!(cfg.kafka_producer.queue_buffering_max_messages, 5000);
+        assert_eq!(cfg.parquet.directory, "/data/parquet");
+        assert_eq!(cfg.ttl.default_ms, Some(30_000));
+        assert_eq!(cfg.encoding.default, OutputFormat::Json);
+        assert_eq!(cfg.tenancy.device_prefixes["acme-"], "acme");
+    }
+
+    #[test]
+    fn test_current_format_loads_and_wins_over_legacy() {
+        let cfg = load(&format!(
+            r#"{}
+            queue_buffering_max_messages = 5000
+            [kafka_producer]
+            queue_buffering_max_messages = 20000
+            [ttl]
+            default_ms = 1000
+            "#,
+            BASE
+        ));
+        assert_eq!(cfg.kafka_producer.queue_buffering_max_messages, 20000);
+        assert_eq!(cfg.ttl.default_ms, Some(1000));
+        assert_eq!(cfg.kafka_topic, "telemetry");
+    }
+
+    #[test]
+    fn test_producer_throughput_settings_load() {
+        let cfg = load(&format!(
+            r#"{}
+            [kafka_producer]
+            compression_type = "lz4"
+            linger_ms = 20
+            batch_size = 262144
+            "#,
+            BASE
+        ));
+        assert_eq!(cfg.kafka_producer.compression_type, CompressionType::Lz4);
+        assert_eq!(cfg.kafka_producer.linger_ms, 20);
+        assert_eq!(cfg.kafka_producer.batch_size, 262144);
+
+        let err = from_toml(&format!(
+            "{}\n[kafka_producer]\ncompression_type = \"brotli\"",
+            BASE
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("brotli"), "{:#}", err);
+    }
 }
@@ -0,0 +1,296 @@
+use crate::request_metrics;
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntGauge, Registry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectionReaperConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Connections without a request for this long are closed
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for ConnectionReaperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_timeout_secs: default_idle_timeout_secs(),
+            check_interval_secs: default_check_interval_secs(),
+        }
+    }
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_check_interval_secs() -> u64 {
+    30
+}
+
+// Upper bounds (seconds) of the connection age histogram on /metrics
+const AGE_BUCKETS: [f64; 5] = [60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+struct ConnectionState {
+    opened: Instant,
+    last_activity: Instant,
+    in_flight: usize,
+    close: Arc<Notify>,
+}
+
+// Tracks every open client connection so silent devices holding long-lived
+// HTTP/1 keep-alive, HTTP/2 or WebSocket connections can be reaped.
+// An upgraded WebSocket session registers on its own, since the HTTP
+// connection it came in on ends with the upgrade. A connection with
+// requests in flight is never reaped, however long ago it started.
+pub struct ConnectionTracker {
+    connections: Mutex<HashMap<u64, ConnectionState>>,
+    next_id: AtomicU64,
+    reaped: IntCounter,
+}
+
+impl Default for ConnectionTracker {
+    fn default() -> Self {
+        Self {
+            connections: Mutex::default(),
+            next_id: AtomicU64::new(0),
+            reaped: request_metrics::int_counter(
+                "rust_ingest_connections_reaped_total",
+                "Idle connections closed by the reaper",
+            ),
+        }
+    }
+}
+
+impl ConnectionTracker {
+    pub fn register(self: &Arc<Self>) -> ConnectionHandle {
+        self.register_at(Instant::now())
+    }
+
+    fn register_at(self: &Arc<Self>, now: Instant) -> ConnectionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let close = Arc::new(Notify::new());
+        self.connections.lock().unwrap().insert(
+            id,
+            ConnectionState {
+                opened: now,
+                last_activity: now,
+                in_flight: 0,
+                close: Arc::clone(&close),
+            },
+        );
+        ConnectionHandle {
+            id,
+            tracker: Arc::clone(self),
+            close,
+        }
+    }
+
+    fn request_started(&self, id: u64, now: Instant) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&id) {
+            conn.in_flight += 1;
+            conn.last_activity = now;
+        }
+    }
+
+    fn touch(&self, id: u64, now: Instant) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&id) {
+            conn.last_activity = now;
+        }
+    }
+
+    fn request_finished(&self, id: u64, now: Instant) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&id) {
+            conn.in_flight = conn.in_flight.saturating_sub(1);
+            conn.last_activity = now;
+        }
+    }
+
+    // Signal every idle connection to close; returns how many were reaped
+    pub fn reap_idle(&self, idle_timeout: Duration, now: Instant) -> usize {
+        let mut connections = self.connections.lock().unwrap();
+        let idle: Vec<u64> = connections
+            .iter()
+            .filter(|(_, conn)| {
+                conn.in_flight == 0
+                    && now.saturating_duration_since(conn.last_activity) >= idle_timeout
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &idle {
+            if let Some(conn) = connections.remove(id) {
+                // notify_one stores a permit, so a close signalled before the
+                // connection task starts waiting is not lost
+                conn.close.notify_one();
+            }
+        }
+        self.reaped.inc_by(idle.len() as u64);
+        idle.len()
+    }
+
+    // Signal every connection to close once its current request is done
+    pub fn close_all(&self) {
+        for conn in self.connections.lock().unwrap().values() {
+            conn.close.notify_one();
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|conn| conn.in_flight)
+            .sum()
+    }
+
+    // Open connections and their ages are read when the registry is gathered
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.reaped)?;
+        let tracker = Arc::clone(self);
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge(
+                "rust_ingest_open_connections",
+                "Currently open client connections",
+            ),
+            move |open: &IntGauge| open.set(tracker.open() as i64),
+        )?;
+        let tracker = Arc::clone(self);
+        let ages = HistogramVec::new(
+            HistogramOpts::new(
+                "rust_ingest_connection_age_seconds",
+                "Age of currently open client connections",
+            )
+            .buckets(AGE_BUCKETS.to_vec()),
+            &[],
+        )?;
+        request_metrics::register_live(registry, ages, move |ages: &HistogramVec| {
+            // Rebuilt from scratch, since connections that closed leave it
+            ages.reset();
+            let histogram = ages.with_label_values(&[]);
+            let now = Instant::now();
+            for conn in tracker.connections.lock().unwrap().values() {
+                histogram.observe(now.saturating_duration_since(conn.opened).as_secs() as f64);
+            }
+        })
+    }
+}
+
+// Held by a connection's task for as long as the connection is open
+pub struct ConnectionHandle {
+    id: u64,
+    tracker: Arc<ConnectionTracker>,
+    close: Arc<Notify>,
+}
+
+impl ConnectionHandle {
+    // Marks a request in flight until the returned guard is dropped
+    pub fn start_request(&self) -> RequestGuard {
+        self.tracker.request_started(self.id, Instant::now());
+        RequestGuard {
+            id: self.id,
+            tracker: Arc::clone(&self.tracker),
+        }
+    }
+
+    // Counts as activity without a request, e.g. a WebSocket frame
+    pub fn touch(&self) {
+        self.tracker.touch(self.id, Instant::now());
+    }
+
+    // Resolves once the reaper or a shutdown decides this connection should close
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.tracker.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct RequestGuard {
+    id: u64,
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.tracker.request_finished(self.id, Instant::now());
+    }
+}
+
+pub fn spawn_reaper(tracker: Arc<ConnectionTracker>, config: &ConnectionReaperConfig) {
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let interval = Duration::from_secs(config.check_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let reaped = tracker.reap_idle(idle_timeout, Instant::now());
+            if reaped > 0 {
+                tracing::info!("Reaped {} idle connections", reaped);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_connection_is_reaped() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let start = Instant::now();
+        let idle = tracker.register_at(start);
+        let busy = tracker.register_at(start);
+        let _in_flight = busy.start_request();
+
+        let timeout = Duration::from_secs(60);
+        assert_eq!(
+            tracker.reap_idle(timeout, start + Duration::from_secs(30)),
+            0
+        );
+
+        // The busy connection started its request before the cutoff but is
+        // still serving it, so only the silent one goes
+        let later = Instant::now() + Duration::from_secs(120);
+        assert_eq!(tracker.reap_idle(timeout, later), 1);
+        tokio::time::timeout(Duration::from_secs(1), idle.closed())
+            .await
+            .expect("idle connection was not signalled");
+
+        let metrics = request_metrics::rendered(|registry| tracker.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_open_connections 1"));
+        assert!(metrics.contains("rust_ingest_connections_reaped_total 1"));
+        assert!(metrics.contains("rust_ingest_connection_age_seconds_count 1"));
+    }
+
+    #[test]
+    fn test_closed_connections_are_forgotten() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let handle = tracker.register();
+        drop(handle.start_request());
+        drop(handle);
+        assert!(tracker.connections.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{bounded_store::BoundedStore, proto::telemetry::Telemetry, request_metrics};
+use prometheus::{IntCounter, Registry};
+use serde::Deserialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContentDedupConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // How long after a record identical content counts as a resend
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // Record fields that make up the content; ts is never part of it
+    #[serde(default = "default_fields")]
+    pub fields: Vec<ContentField>,
+    // Metrics left out of the hash, e.g. uptime counters that tick on every resend
+    #[serde(default)]
+    pub ignore_metrics: Vec<String>,
+    // Recent hashes remembered per device, so a replayed backlog is caught too
+    #[serde(default = "default_max_hashes_per_device")]
+    pub max_hashes_per_device: usize,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for ContentDedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: default_window_secs(),
+            fields: default_fields(),
+            ignore_metrics: Vec::new(),
+            max_hashes_per_device: default_max_hashes_per_device(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentField {
+    Metrics,
+    Samples,
+    Tags,
+}
+
+fn default_window_secs() -> u64 {
+    30
+}
+
+fn default_fields() -> Vec<ContentField> {
+    vec![ContentField::Metrics]
+}
+
+fn default_max_hashes_per_device() -> usize {
+    16
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+// Collapses records that repeat content a device sent moments ago under a
+// fresh timestamp, as some devices do after a reconnect. The timestamp plays
+// no part, so a genuinely new value always passes, and content is only
+// remembered for the window, so a sensor reporting the same steady value
+// still gets through once per window.
+pub struct ContentDedup {
+    window: Duration,
+    fields: Vec<ContentField>,
+    ignore_metrics: HashSet<String>,
+    max_hashes: usize,
+    // device -> content hashes with when they were first seen, oldest first
+    seen: Mutex<BoundedStore<VecDeque<(u64, Instant)>>>,
+    dropped: IntCounter,
+}
+
+impl ContentDedup {
+    pub fn new(config: ContentDedupConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            fields: config.fields,
+            ignore_metrics: config.ignore_metrics.into_iter().collect(),
+            max_hashes: config.max_hashes_per_device.max(1),
+            seen: Mutex::new(BoundedStore::new(
+                config.max_devices,
+                Duration::from_secs(config.idle_eviction_secs),
+            )),
+            dropped: request_metrics::int_counter(
+                "rust_ingest_content_dedup_dropped_total",
+                "Records dropped as resends of recent identical content",
+            ),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.seen.lock().unwrap().remove(device_id)
+    }
+
+    // True when the record repeats content the device sent within the window
+    pub fn is_duplicate(&self, telemetry: &Telemetry) -> bool {
+        self.is_duplicate_at(telemetry, Instant::now())
+    }
+
+    fn is_duplicate_at(&self, telemetry: &Telemetry, now: Instant) -> bool {
+        let hash = self.content_hash(telemetry);
+        let mut seen = self.seen.lock().unwrap();
+        let hashes = seen.get_or_insert_with(&telemetry.device_id, now, VecDeque::new);
+        hashes.retain(|(_, first_seen)| now.duration_since(*first_seen) < self.window);
+
+        if hashes.iter().any(|(seen_hash, _)| *seen_hash == hash) {
+            self.dropped.inc();
+            return true;
+        }
+        if hashes.len() >= self.max_hashes {
+            hashes.pop_front();
+        }
+        hashes.push_back((hash, now));
+        false
+    }
+
+    fn content_hash(&self, telemetry: &Telemetry) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for field in &self.fields {
+            field.hash(&mut hasher);
+            match field {
+                ContentField::Metrics => self.hash_metrics(&telemetry.metrics, &mut hasher),
+                ContentField::Samples => {
+                    // Sample timestamps are shifted on a resend just like ts
+                    telemetry.samples.len().hash(&mut hasher);
+                    for sample in &telemetry.samples {
+                        self.hash_metrics(&sample.metrics, &mut hasher);
+                    }
+                }
+                ContentField::Tags => {
+                    let mut tags: Vec<_> = telemetry.tags.iter().collect();
+                    tags.sort();
+                    tags.hash(&mut hasher);
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    // Order-independent; -0.0 and 0.0 count as the same reading
+    fn hash_metrics(&self, metrics: &HashMap<String, f64>, hasher: &mut impl Hasher) {
+        let mut entries: Vec<(&String, u64)> = metrics
+            .iter()
+            .filter(|(metric, _)| !self.ignore_metrics.contains(*metric))
+            .map(|(metric, value)| (metric, (value + 0.0).to_bits()))
+            .collect();
+        entries.sort();
+        entries.hash(hasher);
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(config: ContentDedupConfig) -> ContentDedup {
+        ContentDedup::new(ContentDedupConfig {
+            enabled: true,
+            window_secs: 10,
+            ..config
+        })
+    }
+
+    fn reading(ts: i64, temperature: f64, uptime: f64) -> Telemetry {
+        Telemetry {
+            device_id: "meter-1".to_string(),
+            ts,
+            metrics: HashMap::from([
+                ("temperature".to_string(), temperature),
+                ("uptime".to_string(), uptime),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_identical_content_with_fresh_ts_is_dropped() {
+        let dedup = dedup(Default::default());
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate_at(&reading(1, 21.5, 100.0), now));
+        assert!(dedup.is_duplicate_at(&reading(2, 21.5, 100.0), now));
+        // Changed content passes
+        assert!(!dedup.is_duplicate_at(&reading(3, 21.6, 100.0), now));
+        // An earlier record replayed after a reconnect is still caught
+        assert!(dedup.is_duplicate_at(&reading(4, 21.5, 100.0), now));
+
+        let mut other_device = reading(5, 21.5, 100.0);
+        other_device.device_id = "meter-2".to_string();
+        assert!(!dedup.is_duplicate_at(&other_device, now));
+        assert!(
+            request_metrics::rendered(|registry| dedup.register_metrics(registry))
+                .contains("rust_ingest_content_dedup_dropped_total 2")
+        );
+    }
+
+    #[test]
+    fn test_content_is_forgotten_after_the_window() {
+        let dedup = dedup(Default::default());
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate_at(&reading(1, 21.5, 100.0), now));
+        assert!(dedup.is_duplicate_at(&reading(2, 21.5, 100.0), now + Duration::from_secs(9)));
+        assert!(!dedup.is_duplicate_at(&reading(3, 21.5, 100.0), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_hashing_scope_is_configurable() {
+        let now = Instant::now();
+        let ignoring_uptime = dedup(ContentDedupConfig {
+            ignore_metrics: vec!["uptime".to_string()],
+            ..Default::default()
+        });
+        assert!(!ignoring_uptime.is_duplicate_at(&reading(1, 21.5, 100.0), now));
+        assert!(ignoring_uptime.is_duplicate_at(&reading(2, 21.5, 160.0), now));
+
+        let with_tags = dedup(ContentDedupConfig {
+            fields: vec![ContentField::Metrics, ContentField::Tags],
+            ..Default::default()
+        });
+        let mut tagged = reading(1, 21.5, 100.0);
+        tagged
+            .tags
+            .insert("firmware".to_string(), "1.2".to_string());
+        assert!(!with_tags.is_duplicate_at(&tagged, now));
+        tagged
+            .tags
+            .insert("firmware".to_string(), "1.3".to_string());
+        assert!(!with_tags.is_duplicate_at(&tagged, now));
+        assert!(with_tags.is_duplicate_at(&tagged, now));
+
+        // Tags aren't part of the content by default
+        let metrics_only = dedup(Default::default());
+        tagged.tags.clear();
+        assert!(!metrics_only.is_duplicate_at(&tagged, now));
+        tagged
+            .tags
+            .insert("firmware".to_string(), "1.4".to_string());
+        assert!(metrics_only.is_duplicate_at(&tagged, now));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::server::ApiError;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+use std::io::Read;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContentEncodingConfig {
+    // Largest body accepted once decompressed; the compressed body can't be
+    // larger either
+    #[serde(default = "default_max_decompressed_bytes")]
+    pub max_decompressed_bytes: usize,
+}
+
+impl Default for ContentEncodingConfig {
+    fn default() -> Self {
+        Self {
+            max_decompressed_bytes: default_max_decompressed_bytes(),
+        }
+    }
+}
+
+fn default_max_decompressed_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+// Decompresses gzip request bodies (Content-Encoding: gzip) before they
+// reach the ingest handlers, which then see an ordinary body. Bodies
+// without a Content-Encoding pass through untouched. Bulk uploads don't go
+// through here: they decompress inside their batch budget reservation.
+pub async fn decompress_request(
+    State(config): State<ContentEncodingConfig>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(encoding) = content_encoding(request.headers())? else {
+        return Ok(next.run(request).await);
+    };
+    let limit = config.max_decompressed_bytes;
+    let (mut parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| too_large(limit))?;
+    let body = decode(encoding, &body, limit)?;
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Gzip,
+}
+
+pub(crate) fn content_encoding(headers: &HeaderMap) -> Result<Option<Encoding>, ApiError> {
+    let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+        return Ok(None);
+    };
+    match value
+        .to_str()
+        .map(|value| value.trim().to_ascii_lowercase())
+    {
+        Ok(encoding) if encoding == "identity" => Ok(None),
+        Ok(encoding) if encoding == "gzip" || encoding == "x-gzip" => Ok(Some(Encoding::Gzip)),
+        _ => Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported Content-Encoding",
+        )
+        .with_details("send gzip or an uncompressed body")),
+    }
+}
+
+pub(crate) fn decode(encoding: Encoding, body: &[u8], limit: usize) -> Result<Bytes, ApiError> {
+    match encoding {
+        Encoding::Gzip => gunzip(body, limit),
+    }
+}
+
+// Stops reading one byte past `limit`, so a small body that inflates to
+// gigabytes costs no more than the limit
+fn gunzip(body: &[u8], limit: usize) -> Result<Bytes, ApiError> {
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(body)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid gzip body").with_details(e.to_string())
+        })?;
+    if decompressed.len() > limit {
+        return Err(too_large(limit));
+    }
+    Ok(decompressed.into())
+}
+
+fn too_large(limit: usize) -> ApiError {
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request body too large").with_details(format!(
+        "bodies are limited to {} bytes decompressed",
+        limit
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn status(err: ApiError) -> StatusCode {
+        err.into_response().status()
+    }
+
+    #[test]
+    fn test_gzip_body_is_decompressed() {
+        let json = br#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#;
+        assert_eq!(gunzip(&gzip(json), 1024).unwrap(), &json[..]);
+    }
+
+    #[test]
+    fn test_corrupt_or_oversized_gzip_is_rejected() {
+        let mut corrupt = gzip(b"{\"device_id\": \"sensor-1\"}");
+        corrupt.truncate(corrupt.len() - 6);
+        assert_eq!(
+            status(gunzip(&corrupt, 1024).unwrap_err()),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(gunzip(b"{\"plain\": true}", 1024).unwrap_err()),
+            StatusCode::BAD_REQUEST
+        );
+
+        // A few kilobytes that inflate to a megabyte
+        let bomb = gzip(&vec![b' '; 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+        assert_eq!(
+            status(gunzip(&bomb, 64 * 1024).unwrap_err()),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_content_encoding_is_parsed() {
+        let headers = |encoding: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+            headers
+        };
+        assert_eq!(content_encoding(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(content_encoding(&headers("identity")).unwrap(), None);
+        assert_eq!(
+            content_encoding(&headers("GZIP")).unwrap(),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            status(content_encoding(&headers("br")).unwrap_err()),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+}
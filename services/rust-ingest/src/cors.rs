@@ -0,0 +1,115 @@
+use crate::{
+    ack::ACK_HEADER, clock_skew::TIMESTAMP_HEADER, priority::PRIORITY_HEADER,
+    request_id::REQUEST_ID_HEADER, request_signing::SIGNATURE_HEADER, routing::TOPIC_HEADER,
+    server::ECHO_HEADER, trace_sampling::TRACEPARENT_HEADER, unit_conversion::UNITS_HEADER,
+};
+use anyhow::Result;
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+// Request headers a browser client may need on top of the CORS-safelisted ones
+const ALLOWED_HEADERS: &[&str] = &[
+    ACK_HEADER,
+    ECHO_HEADER,
+    PRIORITY_HEADER,
+    REQUEST_ID_HEADER,
+    SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+    TOPIC_HEADER,
+    TRACEPARENT_HEADER,
+    UNITS_HEADER,
+];
+
+// Builds the CORS policy from cors_allowed_origins and cors_permissive.
+// Only the listed origins get CORS headers back; with no origins and no
+// opt-in to permissive mode there is no CORS layer at all, so browsers on
+// other origins can't read responses.
+pub fn cors_layer(allowed_origins: &[String], permissive: bool) -> Result<Option<CorsLayer>> {
+    if permissive {
+        if !allowed_origins.is_empty() {
+            return Err(anyhow::anyhow!(
+                "cors_permissive allows every origin; leave cors_allowed_origins empty with it"
+            ));
+        }
+        tracing::warn!("CORS is permissive: any origin may call the API");
+        return Ok(Some(CorsLayer::permissive()));
+    }
+    if allowed_origins.is_empty() {
+        return Ok(None);
+    }
+
+    let origins = allowed_origins
+        .iter()
+        .map(|origin| parse_origin(origin))
+        .collect::<Result<Vec<_>>>()?;
+    let headers = [
+        header::AUTHORIZATION,
+        header::CONTENT_ENCODING,
+        header::CONTENT_TYPE,
+    ]
+    .into_iter()
+    .chain(
+        ALLOWED_HEADERS
+            .iter()
+            .map(|name| HeaderName::from_static(name)),
+    );
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers(headers.collect::<Vec<_>>())
+            .expose_headers([
+                header::RETRY_AFTER,
+                HeaderName::from_static(REQUEST_ID_HEADER),
+            ]),
+    ))
+}
+
+// An origin is matched exactly as the browser sends it: scheme, host and
+// port, no path
+fn parse_origin(origin: &str) -> Result<HeaderValue> {
+    let trimmed = origin.trim().trim_end_matches('/');
+    if trimmed == "*" {
+        return Err(anyhow::anyhow!(
+            "cors_allowed_origins can't contain \"*\"; set cors_permissive to allow every origin"
+        ));
+    }
+    let has_scheme = trimmed.starts_with("http://") || trimmed.starts_with("https://");
+    if !has_scheme || trimmed.splitn(4, '/').nth(3).is_some() {
+        return Err(anyhow::anyhow!(
+            "cors_allowed_origins entry {:?} must be a scheme and host, like https://app.example.com",
+            origin
+        ));
+    }
+    HeaderValue::from_str(trimmed)
+        .map_err(|_| anyhow::anyhow!("cors_allowed_origins entry {:?} is not valid", origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(origins: &[&str]) -> Vec<String> {
+        origins.iter().map(|origin| origin.to_string()).collect()
+    }
+
+    #[test]
+    fn test_origins_are_validated() {
+        assert!(cors_layer(&origins(&["https://app.example.com"]), false)
+            .unwrap()
+            .is_some());
+        assert!(cors_layer(&origins(&["http://localhost:3000/"]), false)
+            .unwrap()
+            .is_some());
+        assert!(cors_layer(&origins(&["*"]), false).is_err());
+        assert!(cors_layer(&origins(&["app.example.com"]), false).is_err());
+        assert!(cors_layer(&origins(&["https://app.example.com/dashboard"]), false).is_err());
+    }
+
+    #[test]
+    fn test_permissive_is_opt_in() {
+        assert!(cors_layer(&[], false).unwrap().is_none());
+        assert!(cors_layer(&[], true).unwrap().is_some());
+        assert!(cors_layer(&origins(&["https://app.example.com"]), true).is_err());
+    }
+}
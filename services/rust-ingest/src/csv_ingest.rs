@@ -0,0 +1,416 @@
+use crate::{
+    batch::{self, BatchItemResult},
+    metric_values::MetricValue,
+    request_id::RequestId,
+    server::{ApiError, AppState, TelemetryRequest},
+    timestamps::{self, Timestamp},
+    trace_sampling::TraceDecision,
+};
+use axum::{
+    body::Body,
+    extract::{Extension, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CsvConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Whether the first line of an upload names the columns. A client can
+    // say otherwise per request with `Content-Type: text/csv; header=present`
+    // (or `absent`).
+    #[serde(default)]
+    pub header_row: bool,
+    // Column names in order, for uploads without a header row
+    #[serde(default)]
+    pub columns: Vec<String>,
+    // Column name -> metric name, e.g. a gateway's "temp_c" -> "temperature"
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+    #[serde(default = "default_device_id_column")]
+    pub device_id_column: String,
+    #[serde(default = "default_ts_column")]
+    pub ts_column: String,
+    // Columns that become tags rather than metrics
+    #[serde(default)]
+    pub tag_columns: Vec<String>,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            header_row: false,
+            columns: Vec::new(),
+            rename: HashMap::new(),
+            device_id_column: default_device_id_column(),
+            ts_column: default_ts_column(),
+            tag_columns: Vec::new(),
+            delimiter: default_delimiter(),
+        }
+    }
+}
+
+fn default_device_id_column() -> String {
+    "device_id".to_string()
+}
+
+fn default_ts_column() -> String {
+    "ts".to_string()
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+impl CsvConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.header_row && !self.columns.contains(&self.device_id_column) {
+            return Err(anyhow::anyhow!(
+                "csv.columns must name the {:?} column unless csv.header_row is set",
+                self.device_id_column
+            ));
+        }
+        if self.delimiter == '"' || self.delimiter == '\n' || self.delimiter == '\r' {
+            return Err(anyhow::anyhow!(
+                "csv.delimiter can't be a quote or a line break"
+            ));
+        }
+        Ok(())
+    }
+
+    // A data line as a record
+    fn parse_row(&self, columns: &[String], line: &str) -> Result<TelemetryRequest, String> {
+        let cells = split_cells(line, self.delimiter)?;
+        if cells.len() != columns.len() {
+            return Err(format!(
+                "expected {} columns, found {}",
+                columns.len(),
+                cells.len()
+            ));
+        }
+
+        let mut record = TelemetryRequest {
+            device_id: String::new(),
+            ts: None,
+            metrics: HashMap::new(),
+            samples: Vec::new(),
+            raw: None,
+            tags: HashMap::new(),
+            ttl_ms: None,
+            deliver_at: None,
+        };
+        for (column, cell) in columns.iter().zip(cells) {
+            let cell = cell.trim();
+            // An empty cell is a reading the gateway didn't have
+            if cell.is_empty() {
+                continue;
+            }
+            if *column == self.device_id_column {
+                record.device_id = cell.to_string();
+            } else if *column == self.ts_column {
+                let ts = Timestamp::parse(cell)
+                    .ok_or_else(|| format!("ts {:?} is not {}", cell, timestamps::EXPECTED))?;
+                record.ts = Some(ts.0);
+            } else if self.tag_columns.contains(column) {
+                record.tags.insert(column.clone(), cell.to_string());
+            } else {
+                let name = self.rename.get(column).unwrap_or(column);
+                record.metrics.insert(name.clone(), metric_value(cell));
+            }
+        }
+        if record.device_id.is_empty() {
+            return Err("device_id is empty".to_string());
+        }
+        Ok(record)
+    }
+}
+
+// Numbers and booleans as such; anything else is left for metric_coercion
+// to accept or reject, as with JSON
+fn metric_value(cell: &str) -> MetricValue {
+    if let Ok(number) = cell.parse::<f64>() {
+        return MetricValue::Number(number);
+    }
+    match cell {
+        "true" | "TRUE" | "True" => MetricValue::Bool(true),
+        "false" | "FALSE" | "False" => MetricValue::Bool(false),
+        _ => MetricValue::Text(cell.to_string()),
+    }
+}
+
+// Splits one line into cells. A cell may be double-quoted to hold the
+// delimiter, with "" for a quote inside it; quoted line breaks aren't
+// supported, as every row is one line.
+fn split_cells(line: &str, delimiter: char) -> Result<Vec<String>, String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    cell.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if cell.trim().is_empty() => {
+                cell.clear();
+                quoted = true;
+            }
+            c if c == delimiter && !quoted => cells.push(std::mem::take(&mut cell)),
+            c => cell.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted cell".to_string());
+    }
+    cells.push(cell);
+    Ok(cells)
+}
+
+// The RFC 4180 `header` parameter of a text/csv Content-Type, if sent
+fn header_param(headers: &HeaderMap) -> Option<bool> {
+    let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("header") {
+            return None;
+        }
+        match value.trim().to_ascii_lowercase().as_str() {
+            "present" => Some(true),
+            "absent" => Some(false),
+            _ => None,
+        }
+    })
+}
+
+// A data row's line number, counting from 1, and what it parsed to
+type ParsedRow = (usize, Result<TelemetryRequest, String>);
+
+// Blank lines are skipped
+fn parse_upload(
+    config: &CsvConfig,
+    text: &str,
+    header_row: bool,
+) -> Result<Vec<ParsedRow>, ApiError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let columns: Vec<String> = if header_row {
+        let Some((_, header)) = lines.next() else {
+            return Ok(Vec::new());
+        };
+        let columns: Vec<String> = split_cells(header, config.delimiter)
+            .map_err(|e| {
+                ApiError::new(StatusCode::BAD_REQUEST, "invalid CSV header row").with_details(e)
+            })?
+            .into_iter()
+            .map(|column| column.trim().to_string())
+            .collect();
+        if !columns.contains(&config.device_id_column) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("CSV header row has no {:?} column", config.device_id_column),
+            ));
+        }
+        columns
+    } else {
+        config.columns.clone()
+    };
+
+    Ok(lines
+        .map(|(number, line)| (number, config.parse_row(&columns, line)))
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+pub struct CsvRowResult {
+    // Line of the upload the row was on, counting from 1
+    line: usize,
+    #[serde(flatten)]
+    result: BatchItemResult,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CsvResponse {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    results: Vec<CsvRowResult>,
+}
+
+// CSV uploads from gateways that can't send JSON: one record per line,
+// columns mapped per `CsvConfig`. A row that fails to parse or publish is
+// reported by its line number; the rest of the upload still goes through.
+pub async fn ingest_csv(
+    State(state): State<Arc<AppState>>,
+    Extension(trace): Extension<TraceDecision>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<CsvResponse>), ApiError> {
+    let (body, _reservation) = batch::read_reserved_body(&state, &headers, body).await?;
+    let text = std::str::from_utf8(&body).map_err(|e| {
+        ApiError::new(StatusCode::BAD_REQUEST, "CSV body is not UTF-8").with_details(e.to_string())
+    })?;
+    let header_row = header_param(&headers).unwrap_or(state.csv.header_row);
+    let rows = parse_upload(&state.csv, text, header_row)?;
+    if rows.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "CSV upload must contain at least one row",
+        ));
+    }
+
+    let request = batch::bulk_request(&state, &headers, trace, request_id)?;
+    let mut results = Vec::with_capacity(rows.len());
+    for (index, (line, record)) in rows.into_iter().enumerate() {
+        let result = batch::process_item(&state, index, record, &request).await;
+        results.push(CsvRowResult { line, result });
+    }
+
+    let succeeded = results.iter().filter(|row| row.result.success).count();
+    Ok((
+        request.ack.success_status(),
+        Json(CsvResponse {
+            total: results.len(),
+            succeeded,
+            failed: results.len() - succeeded,
+            results,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CsvConfig {
+        CsvConfig {
+            enabled: true,
+            columns: ["device_id", "ts", "temperature", "humidity"]
+                .map(String::from)
+                .to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cells_are_split_with_quotes() {
+        assert_eq!(
+            split_cells(r#"a,"b,c",,"say ""hi""""#, ',').unwrap(),
+            vec!["a", "b,c", "", r#"say "hi""#]
+        );
+        assert!(split_cells(r#"a,"b"#, ',').is_err());
+    }
+
+    #[test]
+    fn test_rows_map_to_records_by_column() {
+        let config = CsvConfig {
+            rename: HashMap::from([("humidity".to_string(), "relative_humidity".to_string())]),
+            ..config()
+        };
+        let rows = parse_upload(
+            &config,
+            "sensor-1,1700000000000,21.5,40\n\nsensor-2,,22.0,\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let (line, first) = &rows[0];
+        let first = first.as_ref().unwrap();
+        assert_eq!(*line, 1);
+        assert_eq!(first.device_id, "sensor-1");
+        assert_eq!(first.ts, Some(1700000000000));
+        assert_eq!(first.metrics["temperature"], MetricValue::Number(21.5));
+        assert_eq!(
+            first.metrics["relative_humidity"],
+            MetricValue::Number(40.0)
+        );
+
+        // Blank lines still count; empty cells are left out
+        let (line, second) = &rows[1];
+        let second = second.as_ref().unwrap();
+        assert_eq!(*line, 3);
+        assert_eq!(second.ts, None);
+        assert!(!second.metrics.contains_key("humidity"));
+    }
+
+    #[test]
+    fn test_header_row_names_the_columns() {
+        let config = CsvConfig {
+            tag_columns: vec!["site".to_string()],
+            ..config()
+        };
+        let rows = parse_upload(
+            &config,
+            "site,device_id,pressure\nplant-7,sensor-1,1013\n",
+            true,
+        )
+        .unwrap();
+        let (line, record) = &rows[0];
+        let record = record.as_ref().unwrap();
+        assert_eq!(*line, 2);
+        assert_eq!(record.tags["site"], "plant-7");
+        assert_eq!(record.metrics["pressure"], MetricValue::Number(1013.0));
+
+        assert!(parse_upload(&config, "site,pressure\nplant-7,1013\n", true).is_err());
+    }
+
+    #[test]
+    fn test_bad_rows_are_reported_by_line() {
+        let rows = parse_upload(
+            &config(),
+            "sensor-1,1700000000000,21.5,40\nsensor-2,yesterday,21.5,40\nsensor-3,1\n,1,2,3\n",
+            false,
+        )
+        .unwrap();
+        let errors: Vec<_> = rows
+            .iter()
+            .filter_map(|(line, row)| row.as_ref().err().map(|e| (*line, e.as_str())))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    2,
+                    "ts \"yesterday\" is not unix seconds, unix millis or an RFC 3339 time"
+                ),
+                (3, "expected 4 columns, found 2"),
+                (4, "device_id is empty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_parameter_of_content_type() {
+        let with = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(header_param(&with("text/csv; header=present")), Some(true));
+        assert_eq!(
+            header_param(&with("text/csv;charset=utf-8; Header=Absent")),
+            Some(false)
+        );
+        assert_eq!(header_param(&with("text/csv")), None);
+        assert_eq!(header_param(&HeaderMap::new()), None);
+    }
+}
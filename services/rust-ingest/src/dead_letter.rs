@@ -0,0 +1,155 @@
+use crate::{
+    encoding::{self, OutputFormat},
+    proto::telemetry::{Sample, Telemetry},
+    sink::TelemetrySink,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+// A record the pipeline rejected, as published to the dead-letter topic
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    device_id: &'a str,
+    reason: &'a str,
+    rejected_at: i64,
+    // The record as it reached validation, in the JSON output layout
+    record: serde_json::Value,
+}
+
+// The same, read back for replay
+#[derive(Debug, Deserialize)]
+pub struct StoredDeadLetter {
+    pub reason: String,
+    pub rejected_at: i64,
+    record: StoredRecord,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredRecord {
+    device_id: String,
+    ts: i64,
+    #[serde(default)]
+    metrics: HashMap<String, f64>,
+    #[serde(default)]
+    raw: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    samples: Vec<StoredSample>,
+    #[serde(default)]
+    flag_metrics: HashMap<String, bool>,
+    #[serde(default)]
+    text_metrics: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredSample {
+    ts: i64,
+    metrics: HashMap<String, f64>,
+}
+
+impl StoredDeadLetter {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+
+    // The record as it reached validation the first time
+    pub fn into_telemetry(self) -> Telemetry {
+        let record = self.record;
+        Telemetry {
+            device_id: record.device_id,
+            ts: record.ts,
+            metrics: record.metrics,
+            raw: record.raw.into_bytes(),
+            tags: record.tags,
+            metadata: record.metadata.as_ref().map(encoding::json_to_struct),
+            samples: record
+                .samples
+                .into_iter()
+                .map(|sample| Sample {
+                    ts: sample.ts,
+                    metrics: sample.metrics,
+                })
+                .collect(),
+            flag_metrics: record.flag_metrics,
+            text_metrics: record.text_metrics,
+        }
+    }
+}
+
+// Keeps a copy of every record that failed validation on a topic of its
+// own, so what a misbehaving device actually sent can be looked at later.
+// Writing there is best effort: a failure is logged and the client still
+// gets the validation error, never the dead-letter one.
+pub struct DeadLetterQueue {
+    topic: String,
+}
+
+impl DeadLetterQueue {
+    pub fn new(topic: String) -> Self {
+        Self { topic }
+    }
+
+    pub async fn publish(&self, sink: &dyn TelemetrySink, telemetry: &Telemetry, reason: &str) {
+        let result = match dead_letter(telemetry, reason) {
+            Ok(payload) => {
+                sink.publish_raw(&self.topic, &telemetry.device_id, &payload)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to dead-letter telemetry for device {}: {:?}",
+                telemetry.device_id, e
+            );
+        }
+    }
+}
+
+fn dead_letter(telemetry: &Telemetry, reason: &str) -> Result<Vec<u8>> {
+    let record = encoding::encode(telemetry, OutputFormat::Json)?;
+    Ok(serde_json::to_vec(&DeadLetter {
+        device_id: &telemetry.device_id,
+        reason,
+        rejected_at: chrono::Utc::now().timestamp_millis(),
+        record: serde_json::from_slice(&record)?,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dead_letters_read_back_as_sent() {
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: 1_700_000_000_000,
+            metrics: HashMap::from([("temperature".to_string(), 212.5)]),
+            raw: br#"{"temperature": 212.5}"#.to_vec(),
+            tags: HashMap::from([("site".to_string(), "north".to_string())]),
+            metadata: Some(encoding::json_to_struct(
+                serde_json::json!({"coerced_metrics": ["temperature"]})
+                    .as_object()
+                    .unwrap(),
+            )),
+            samples: vec![Sample {
+                ts: 1_699_999_999_000,
+                metrics: HashMap::from([("temperature".to_string(), 211.0)]),
+            }],
+            flag_metrics: HashMap::from([("door_open".to_string(), true)]),
+            text_metrics: HashMap::from([("mode".to_string(), "eco".to_string())]),
+        };
+        let payload = dead_letter(&telemetry, "temperature out of range").unwrap();
+
+        let stored = StoredDeadLetter::parse(&payload).unwrap();
+        assert_eq!(stored.reason, "temperature out of range");
+        assert_eq!(stored.into_telemetry(), telemetry);
+        assert!(StoredDeadLetter::parse(b"not json").is_err());
+    }
+}
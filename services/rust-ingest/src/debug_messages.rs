@@ -0,0 +1,110 @@
+use crate::{
+    encoding::{self, OutputFormat},
+    proto::telemetry::Telemetry,
+};
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex};
+
+struct Published {
+    topic: String,
+    published_at: i64,
+    request_id: String,
+    telemetry: Telemetry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugMessage {
+    topic: String,
+    // Unix millis
+    published_at: i64,
+    request_id: String,
+    // The record in the JSON output layout, whatever the topic's format
+    telemetry: serde_json::Value,
+}
+
+// The most recently published records, for looking at live traffic without
+// a Kafka consumer. Records are kept as they are and only turned into JSON
+// when asked for, so keeping them costs a clone and a short lock.
+pub struct LastMessages {
+    capacity: usize,
+    messages: Mutex<VecDeque<Published>>,
+}
+
+impl LastMessages {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, topic: &str, request_id: &str, telemetry: &Telemetry) {
+        let published = Published {
+            topic: topic.to_string(),
+            published_at: chrono::Utc::now().timestamp_millis(),
+            request_id: request_id.to_string(),
+            telemetry: telemetry.clone(),
+        };
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(published);
+    }
+
+    // Drops the device's records; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+        let before = messages.len();
+        messages.retain(|published| published.telemetry.device_id != device_id);
+        messages.len() != before
+    }
+
+    // Newest first
+    pub fn snapshot(&self) -> Vec<DebugMessage> {
+        let messages = self.messages.lock().unwrap();
+        messages
+            .iter()
+            .rev()
+            .map(|published| DebugMessage {
+                topic: published.topic.clone(),
+                published_at: published.published_at,
+                request_id: published.request_id.clone(),
+                telemetry: encoding::encode(&published.telemetry, OutputFormat::Json)
+                    .ok()
+                    .and_then(|json| serde_json::from_slice(&json).ok())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(device_id: &str) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            metrics: [("temperature".to_string(), 21.5)].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_only_the_last_messages_are_kept() {
+        let last = LastMessages::new(2);
+        for device in ["sensor-1", "sensor-2", "sensor-3"] {
+            last.record("telemetry", "req-1", &reading(device));
+        }
+        let snapshot = last.snapshot();
+        let devices: Vec<_> = snapshot
+            .iter()
+            .map(|message| message.telemetry["device_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(devices, vec!["sensor-3", "sensor-2"]);
+        assert_eq!(snapshot[0].telemetry["metrics"]["temperature"], 21.5);
+        assert_eq!(snapshot[0].topic, "telemetry");
+    }
+}
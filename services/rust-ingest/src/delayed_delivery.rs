@@ -0,0 +1,403 @@
+use crate::{
+    ack::AckMode,
+    priority::Priority,
+    proto::telemetry::Telemetry,
+    request_metrics,
+    sink::{SinkRecord, TelemetrySink},
+    telemetry_handler::HandlerContext,
+    timing_wheel::TimingWheel,
+};
+use prometheus::{IntCounter, IntGauge, Registry};
+use prost::Message;
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DelayedDeliveryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // How far ahead `deliver_at` may be
+    #[serde(default = "default_max_delay_secs")]
+    pub max_delay_secs: u64,
+    // Memory held records may take up; further records are refused
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    // Release granularity
+    #[serde(default = "default_tick_ms")]
+    pub tick_ms: u64,
+}
+
+impl Default for DelayedDeliveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_delay_secs: default_max_delay_secs(),
+            max_bytes: default_max_bytes(),
+            tick_ms: default_tick_ms(),
+        }
+    }
+}
+
+fn default_max_delay_secs() -> u64 {
+    3600
+}
+
+fn default_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_tick_ms() -> u64 {
+    100
+}
+
+const WHEEL_SLOTS: usize = 512;
+
+// Returned by the handler when a record can't be held for later delivery
+#[derive(Debug)]
+pub enum DelayRejection {
+    TooFarAhead { max_delay: Duration },
+    QueueFull,
+}
+
+impl fmt::Display for DelayRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFarAhead { max_delay } => write!(
+                f,
+                "deliver_at may be at most {}s in the future",
+                max_delay.as_secs()
+            ),
+            Self::QueueFull => write!(f, "delayed delivery queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for DelayRejection {}
+
+// A validated, encoded record waiting for its delivery time
+pub struct DelayedRecord {
+    pub topic: String,
+    pub telemetry: Telemetry,
+    pub payload: Vec<u8>,
+    pub expires_at: Option<i64>,
+    pub ack: AckMode,
+    pub priority: Priority,
+    pub request_id: Option<String>,
+}
+
+impl DelayedRecord {
+    fn size(&self) -> usize {
+        self.payload.len() + self.telemetry.encoded_len()
+    }
+}
+
+struct Held {
+    deadline: Instant,
+    record: DelayedRecord,
+}
+
+// Holds records whose `deliver_at` is in the future and hands them back
+// once it has passed. Memory is capped; nothing is spilled to disk, so
+// held records are lost if the process exits before releasing them.
+pub struct DelayQueue {
+    max_delay: Duration,
+    max_bytes: usize,
+    state: Mutex<DelayState>,
+    released: IntCounter,
+    // Follow the state's totals
+    held_records: IntGauge,
+    held_bytes: IntGauge,
+}
+
+struct DelayState {
+    wheel: TimingWheel<Held>,
+    bytes: usize,
+    held: usize,
+}
+
+impl DelayQueue {
+    pub fn new(config: &DelayedDeliveryConfig) -> Self {
+        Self::new_at(config, Instant::now())
+    }
+
+    fn new_at(config: &DelayedDeliveryConfig, now: Instant) -> Self {
+        Self {
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            max_bytes: config.max_bytes,
+            state: Mutex::new(DelayState {
+                wheel: TimingWheel::new(Duration::from_millis(config.tick_ms), WHEEL_SLOTS, now),
+                bytes: 0,
+                held: 0,
+            }),
+            released: request_metrics::int_counter(
+                "rust_ingest_delayed_released_total",
+                "Held records released for delivery",
+            ),
+            held_records: request_metrics::int_gauge(
+                "rust_ingest_delayed_records",
+                "Records held for scheduled delivery",
+            ),
+            held_bytes: request_metrics::int_gauge(
+                "rust_ingest_delayed_bytes",
+                "Memory taken by records held for scheduled delivery",
+            ),
+        }
+    }
+
+    fn tick(&self) -> Duration {
+        self.state.lock().unwrap().wheel.tick()
+    }
+
+    // Fails fast, before validation, for a `deliver_at` beyond the cap
+    pub fn check_delay(&self, deliver_at: i64) -> Result<(), DelayRejection> {
+        let delay = deliver_at - chrono::Utc::now().timestamp_millis();
+        if delay > self.max_delay.as_millis() as i64 {
+            return Err(DelayRejection::TooFarAhead {
+                max_delay: self.max_delay,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn hold(&self, record: DelayedRecord, deliver_at: i64) -> Result<(), DelayRejection> {
+        self.check_delay(deliver_at)?;
+        let delay = deliver_at - chrono::Utc::now().timestamp_millis();
+        self.hold_at(
+            record,
+            Duration::from_millis(delay.max(0) as u64),
+            Instant::now(),
+        )
+    }
+
+    fn hold_at(
+        &self,
+        record: DelayedRecord,
+        delay: Duration,
+        now: Instant,
+    ) -> Result<(), DelayRejection> {
+        let size = record.size();
+        let mut state = self.state.lock().unwrap();
+        if state.bytes + size > self.max_bytes {
+            return Err(DelayRejection::QueueFull);
+        }
+        state.bytes += size;
+        state.held += 1;
+        self.update_gauges(&state);
+        let deadline = now + delay;
+        state.wheel.schedule(Held { deadline, record }, deadline);
+        Ok(())
+    }
+
+    // Records whose delivery time has come by `now`
+    fn due_at(&self, now: Instant) -> Vec<DelayedRecord> {
+        let mut state = self.state.lock().unwrap();
+        let mut due = Vec::new();
+        for held in state.wheel.advance(now) {
+            if now < held.deadline {
+                // Was past the wheel's horizon; goes around again
+                let deadline = held.deadline;
+                state.wheel.schedule(held, deadline);
+                continue;
+            }
+            state.bytes -= held.record.size();
+            state.held -= 1;
+            due.push(held.record);
+        }
+        self.update_gauges(&state);
+        self.released.inc_by(due.len() as u64);
+        due
+    }
+
+    // Drops the device's held records, so none goes out after it is
+    // decommissioned; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let removed = state
+            .wheel
+            .remove_where(|held| held.record.telemetry.device_id == device_id);
+        for held in &removed {
+            state.bytes -= held.record.size();
+            state.held -= 1;
+        }
+        self.update_gauges(&state);
+        !removed.is_empty()
+    }
+
+    pub fn held(&self) -> usize {
+        self.state.lock().unwrap().held
+    }
+
+    fn update_gauges(&self, state: &DelayState) {
+        self.held_records.set(state.held as i64);
+        self.held_bytes.set(state.bytes as i64);
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.held_records)?;
+        request_metrics::register(registry, &self.held_bytes)?;
+        request_metrics::register(registry, &self.released)
+    }
+}
+
+pub fn spawn_releaser(ctx: Arc<HandlerContext>, sink: Arc<dyn TelemetrySink>) {
+    let Some(queue) = ctx.delay_queue.as_ref() else {
+        return;
+    };
+    let tick = queue.tick();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(tick);
+        loop {
+            ticker.tick().await;
+            let Some(queue) = ctx.delay_queue.as_ref() else {
+                return;
+            };
+            for record in queue.due_at(Instant::now()) {
+                let telemetry = &record.telemetry;
+                let now = chrono::Utc::now().timestamp_millis();
+                if record
+                    .expires_at
+                    .is_some_and(|expires_at| now >= expires_at)
+                {
+                    ctx.request_metrics.expired.inc();
+                    warn!(
+                        "Dropped scheduled telemetry for device {}: TTL elapsed before release",
+                        telemetry.device_id
+                    );
+                    continue;
+                }
+                let result = sink
+                    .publish(SinkRecord {
+                        topic: &record.topic,
+                        key: &telemetry.device_id,
+                        payload: &record.payload,
+                        telemetry,
+                        expires_at: record.expires_at,
+                        ack: record.ack,
+                        priority: record.priority,
+                        receipt: None,
+                        request_id: record.request_id.as_deref(),
+                        position: None,
+                    })
+                    .await;
+                match result {
+                    Ok(()) => debug!(
+                        "Released scheduled telemetry for device {}",
+                        telemetry.device_id
+                    ),
+                    Err(e) => warn!(
+                        "Failed to send scheduled telemetry for device {}: {}",
+                        telemetry.device_id, e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ts: i64) -> DelayedRecord {
+        DelayedRecord {
+            topic: "setpoints".to_string(),
+            telemetry: Telemetry {
+                device_id: "hvac-1".to_string(),
+                ts,
+                ..Default::default()
+            },
+            payload: vec![0; 16],
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            request_id: None,
+        }
+    }
+
+    fn released(queue: &DelayQueue, now: Instant) -> Vec<i64> {
+        queue
+            .due_at(now)
+            .into_iter()
+            .map(|record| record.telemetry.ts)
+            .collect()
+    }
+
+    #[test]
+    fn test_records_are_released_at_their_time() {
+        let start = Instant::now();
+        let ms = |n: u64| start + Duration::from_millis(n);
+        let queue = DelayQueue::new_at(&DelayedDeliveryConfig::default(), start);
+        queue
+            .hold_at(record(1), Duration::from_millis(250), start)
+            .unwrap();
+        queue
+            .hold_at(record(2), Duration::from_millis(120), start)
+            .unwrap();
+        // Far past the wheel's 51.2s horizon
+        queue
+            .hold_at(record(3), Duration::from_secs(120), start)
+            .unwrap();
+
+        assert!(released(&queue, ms(100)).is_empty());
+        assert_eq!(released(&queue, ms(200)), vec![2]);
+        assert!(released(&queue, ms(249)).is_empty());
+        assert_eq!(released(&queue, ms(300)), vec![1]);
+
+        // Goes around the wheel twice before it's due
+        for secs in [30, 60, 90, 119] {
+            assert!(released(&queue, start + Duration::from_secs(secs)).is_empty());
+        }
+        assert_eq!(
+            released(&queue, start + Duration::from_millis(120_100)),
+            vec![3]
+        );
+        let metrics = request_metrics::rendered(|registry| queue.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_delayed_records 0\n"));
+        assert!(metrics.contains("rust_ingest_delayed_released_total 3\n"));
+    }
+
+    #[test]
+    fn test_memory_cap_refuses_records() {
+        let start = Instant::now();
+        let size = record(1).size();
+        let queue = DelayQueue::new_at(
+            &DelayedDeliveryConfig {
+                enabled: true,
+                max_bytes: size * 2,
+                ..Default::default()
+            },
+            start,
+        );
+        let delay = Duration::from_secs(1);
+        queue.hold_at(record(1), delay, start).unwrap();
+        queue.hold_at(record(2), delay, start).unwrap();
+        assert!(matches!(
+            queue.hold_at(record(3), delay, start),
+            Err(DelayRejection::QueueFull)
+        ));
+
+        // Releasing frees the room again
+        assert_eq!(released(&queue, start + delay * 2).len(), 2);
+        queue.hold_at(record(3), delay, start + delay * 2).unwrap();
+    }
+
+    #[test]
+    fn test_max_delay_is_enforced() {
+        let queue = DelayQueue::new(&DelayedDeliveryConfig {
+            enabled: true,
+            max_delay_secs: 60,
+            ..Default::default()
+        });
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!(queue.check_delay(now + 30_000).is_ok());
+        assert!(matches!(
+            queue.check_delay(now + 120_000),
+            Err(DelayRejection::TooFarAhead { .. })
+        ));
+    }
+}
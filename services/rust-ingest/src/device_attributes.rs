@@ -0,0 +1,288 @@
+use crate::{proto::telemetry::Telemetry, telemetry_handler::HandlerContext};
+use anyhow::Result;
+use prost_types::{value::Kind, Struct, Value};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+// Metadata field the matched attributes are nested under
+pub const METADATA_FIELD: &str = "device_attributes";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceAttributesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // CSV with a header row and a device_id column; every other column is
+    // an attribute. Re-read on SIGHUP.
+    #[serde(default)]
+    pub path: String,
+    // A table with more devices than this is refused
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    // Attributes for devices missing from the table; empty adds nothing
+    #[serde(default)]
+    pub default: BTreeMap<String, String>,
+}
+
+impl Default for DeviceAttributesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::new(),
+            max_devices: default_max_devices(),
+            default: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_max_devices() -> usize {
+    100_000
+}
+
+type AttributeTable = HashMap<String, BTreeMap<String, String>>;
+
+// Static device attributes (site, owner, install date, ...) kept in memory
+// and merged into each record's metadata, so thin device payloads arrive
+// downstream with the context they need. A reload swaps the whole table;
+// one that fails to load leaves the current table in place.
+pub struct DeviceAttributes {
+    config: DeviceAttributesConfig,
+    table: RwLock<Arc<AttributeTable>>,
+}
+
+impl DeviceAttributes {
+    pub fn load(config: DeviceAttributesConfig) -> Result<Self> {
+        let table = read_table(&config)?;
+        info!(
+            "Loaded attributes for {} devices from {}",
+            table.len(),
+            config.path
+        );
+        Ok(Self {
+            config,
+            table: RwLock::new(Arc::new(table)),
+        })
+    }
+
+    pub fn reload(&self) -> Result<usize> {
+        let table = read_table(&self.config)?;
+        let devices = table.len();
+        *self.table.write().unwrap() = Arc::new(table);
+        Ok(devices)
+    }
+
+    // Adds the device's attributes to the record; returns whether any were added
+    pub fn enrich(&self, telemetry: &mut Telemetry) -> bool {
+        let table = Arc::clone(&self.table.read().unwrap());
+        let attributes = table
+            .get(&telemetry.device_id)
+            .unwrap_or(&self.config.default);
+        if attributes.is_empty() {
+            return false;
+        }
+        let fields = attributes
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.clone(),
+                    Value {
+                        kind: Some(Kind::StringValue(value.clone())),
+                    },
+                )
+            })
+            .collect();
+        telemetry
+            .metadata
+            .get_or_insert_with(Default::default)
+            .fields
+            .insert(
+                METADATA_FIELD.to_string(),
+                Value {
+                    kind: Some(Kind::StructValue(Struct { fields })),
+                },
+            );
+        true
+    }
+}
+
+fn read_table(config: &DeviceAttributesConfig) -> Result<AttributeTable> {
+    let text = std::fs::read_to_string(&config.path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", config.path, e))?;
+    parse_table(&text, config.max_devices)
+}
+
+fn parse_table(text: &str, max_devices: usize) -> Result<AttributeTable> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header = split_row(
+        lines
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("device attributes table is empty"))?,
+    );
+    let id_column = header
+        .iter()
+        .position(|column| column == "device_id")
+        .ok_or_else(|| anyhow::anyhow!("device attributes table has no device_id column"))?;
+
+    let mut table = HashMap::new();
+    for (line, row) in lines.enumerate() {
+        let row = split_row(row);
+        if row.len() != header.len() {
+            return Err(anyhow::anyhow!(
+                "device attributes row {} has {} columns, expected {}",
+                line + 2,
+                row.len(),
+                header.len()
+            ));
+        }
+        let attributes = header
+            .iter()
+            .zip(&row)
+            .enumerate()
+            .filter(|(column, (_, value))| *column != id_column && !value.is_empty())
+            .map(|(_, (name, value))| (name.clone(), value.clone()))
+            .collect();
+        table.insert(row[id_column].clone(), attributes);
+        if table.len() > max_devices {
+            return Err(anyhow::anyhow!(
+                "device attributes table has more than {} devices",
+                max_devices
+            ));
+        }
+    }
+    Ok(table)
+}
+
+// One CSV row; fields may be double-quoted, with "" for a literal quote
+fn split_row(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+// Re-reads the table whenever the process gets SIGHUP
+pub fn spawn_reloader(ctx: Arc<HandlerContext>) {
+    if ctx.device_attributes.is_none() {
+        return;
+    }
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let Some(attributes) = ctx.device_attributes.as_ref() else {
+                return;
+            };
+            match attributes.reload() {
+                Ok(devices) => info!("Reloaded attributes for {} devices", devices),
+                Err(e) => warn!("Keeping current device attributes: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding;
+
+    const TABLE: &str = "\
+device_id,site,owner,install_date
+pump-1,Plant A,\"Ops, North\",2021-04-01
+pump-2,Plant B,,2022-09-15
+";
+
+    fn load_table(default: BTreeMap<String, String>) -> (DeviceAttributes, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.csv");
+        std::fs::write(&path, TABLE).unwrap();
+        let attributes = DeviceAttributes::load(DeviceAttributesConfig {
+            enabled: true,
+            path: path.to_string_lossy().into_owned(),
+            default,
+            ..Default::default()
+        })
+        .unwrap();
+        (attributes, dir)
+    }
+
+    fn enriched(attributes: &DeviceAttributes, device_id: &str) -> serde_json::Value {
+        let mut telemetry = Telemetry {
+            device_id: device_id.to_string(),
+            ..Default::default()
+        };
+        attributes.enrich(&mut telemetry);
+        telemetry
+            .metadata
+            .map(|metadata| encoding::struct_to_json(&metadata)[METADATA_FIELD].clone())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_matched_device_is_enriched() {
+        let (attributes, _dir) = load_table(BTreeMap::new());
+        let pump = enriched(&attributes, "pump-1");
+        assert_eq!(pump["site"], "Plant A");
+        assert_eq!(pump["owner"], "Ops, North");
+        assert_eq!(pump["install_date"], "2021-04-01");
+        assert!(pump.get("device_id").is_none());
+
+        // Empty cells are left out
+        let pump = enriched(&attributes, "pump-2");
+        assert!(pump.get("owner").is_none());
+    }
+
+    #[test]
+    fn test_unmatched_device_gets_the_default() {
+        let (attributes, _dir) = load_table(BTreeMap::new());
+        assert!(enriched(&attributes, "valve-9").is_null());
+
+        let default = BTreeMap::from([("site".to_string(), "unassigned".to_string())]);
+        let (attributes, _dir) = load_table(default);
+        assert_eq!(enriched(&attributes, "valve-9")["site"], "unassigned");
+        assert_eq!(enriched(&attributes, "pump-2")["site"], "Plant B");
+    }
+
+    #[test]
+    fn test_bad_reload_keeps_the_current_table() {
+        let (attributes, dir) = load_table(BTreeMap::new());
+        let path = dir.path().join("devices.csv");
+
+        std::fs::write(&path, "site,owner\nPlant C,Ops\n").unwrap();
+        assert!(attributes.reload().is_err());
+        assert_eq!(enriched(&attributes, "pump-1")["site"], "Plant A");
+
+        std::fs::write(&path, "device_id,site\npump-1,Plant C\n").unwrap();
+        assert_eq!(attributes.reload().unwrap(), 1);
+        assert_eq!(enriched(&attributes, "pump-1")["site"], "Plant C");
+        assert!(enriched(&attributes, "pump-2").is_null());
+    }
+
+    #[test]
+    fn test_oversized_table_is_refused() {
+        assert!(parse_table(TABLE, 1).is_err());
+        assert_eq!(parse_table(TABLE, 2).unwrap().len(), 2);
+    }
+}
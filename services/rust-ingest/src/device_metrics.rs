@@ -0,0 +1,214 @@
+use crate::request_metrics;
+use prometheus::{IntGaugeVec, Registry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+// Label every device outside the top N is counted under
+const OTHER_DEVICES: &str = "other";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceMetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Devices given their own series on /metrics, by volume
+    #[serde(default = "default_top_n")]
+    pub top_n: usize,
+    // Devices counted at once to find the top N among; the least active is
+    // replaced when a new one shows up
+    #[serde(default = "default_candidates")]
+    pub candidates: usize,
+}
+
+impl Default for DeviceMetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_n: default_top_n(),
+            candidates: default_candidates(),
+        }
+    }
+}
+
+fn default_top_n() -> usize {
+    20
+}
+
+fn default_candidates() -> usize {
+    1000
+}
+
+#[derive(Default)]
+struct Counts {
+    by_device: HashMap<String, u64>,
+    total: u64,
+}
+
+// Records per device for the most active devices, in bounded memory
+// however many devices there are. Candidates are kept Space-Saving style: a
+// new device takes over the least active one's count, so a device that
+// becomes busy rises into the top N, at the price of counts that can be
+// overestimated by what the replaced device had.
+pub struct DeviceCounts {
+    top_n: usize,
+    candidates: usize,
+    counts: Mutex<Counts>,
+}
+
+impl DeviceCounts {
+    pub fn new(config: &DeviceMetricsConfig) -> Self {
+        Self {
+            top_n: config.top_n,
+            candidates: config.candidates.max(config.top_n).max(1),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    pub fn record(&self, device_id: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total += 1;
+        if let Some(count) = counts.by_device.get_mut(device_id) {
+            *count += 1;
+            return;
+        }
+        let mut inherited = 0;
+        if counts.by_device.len() >= self.candidates {
+            // A scan, but only for a device not already counted while full
+            let least = counts
+                .by_device
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(device, count)| (device.clone(), *count));
+            if let Some((device, count)) = least {
+                counts.by_device.remove(&device);
+                inherited = count;
+            }
+        }
+        counts
+            .by_device
+            .insert(device_id.to_string(), inherited + 1);
+    }
+
+    // Drops the device's count; its records stay in the total, so they are
+    // counted under "other" from then on. Returns whether it was counted.
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.counts
+            .lock()
+            .unwrap()
+            .by_device
+            .remove(device_id)
+            .is_some()
+    }
+
+    // The top N are picked again each time the registry is gathered
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        let counts = Arc::clone(self);
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge_vec(
+                "rust_ingest_device_records",
+                "Records received per device since start, for the most active devices; the rest are counted under device_id=\"other\"",
+                &["device_id"],
+            ),
+            move |records: &IntGaugeVec| counts.refresh(records),
+        )
+    }
+
+    fn refresh(&self, records: &IntGaugeVec) {
+        let counts = self.counts.lock().unwrap();
+        let mut top: Vec<(&String, u64)> = counts
+            .by_device
+            .iter()
+            .map(|(device, count)| (device, *count))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        top.truncate(self.top_n);
+        let in_top: u64 = top.iter().map(|(_, count)| count).sum();
+
+        // Devices that dropped out of the top N lose their series
+        records.reset();
+        for (device, count) in top {
+            records.with_label_values(&[device]).set(count as i64);
+        }
+        records
+            .with_label_values(&[OTHER_DEVICES])
+            .set(counts.total.saturating_sub(in_top) as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(top_n: usize, candidates: usize) -> Arc<DeviceCounts> {
+        Arc::new(DeviceCounts::new(&DeviceMetricsConfig {
+            enabled: true,
+            top_n,
+            candidates,
+        }))
+    }
+
+    fn render(counts: &Arc<DeviceCounts>) -> String {
+        request_metrics::rendered(|registry| counts.register_metrics(registry))
+    }
+
+    // Sorted by label, as the registry renders them
+    fn series(metrics: &str) -> Vec<&str> {
+        metrics
+            .lines()
+            .filter(|line| line.starts_with("rust_ingest_device_records{"))
+            .collect()
+    }
+
+    #[test]
+    fn test_busiest_devices_get_their_own_series() {
+        let counts = counts(2, 10);
+        for (device, records) in [("pump-1", 5), ("pump-2", 3), ("pump-3", 1)] {
+            for _ in 0..records {
+                counts.record(device);
+            }
+        }
+        assert_eq!(
+            series(&render(&counts)),
+            vec![
+                "rust_ingest_device_records{device_id=\"other\"} 1",
+                "rust_ingest_device_records{device_id=\"pump-1\"} 5",
+                "rust_ingest_device_records{device_id=\"pump-2\"} 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cardinality_stays_capped_with_many_devices() {
+        let counts = counts(5, 50);
+        for i in 0..10_000 {
+            if i % 20 == 0 {
+                counts.record("busy-1");
+            }
+            counts.record(&format!("sensor-{}", i));
+        }
+
+        let metrics = render(&counts);
+        let series = series(&metrics);
+        // The top 5 plus other, however many devices were seen
+        assert_eq!(series.len(), 6);
+        assert_eq!(counts.counts.lock().unwrap().by_device.len(), 50);
+        assert!(series.contains(&"rust_ingest_device_records{device_id=\"busy-1\"} 500"));
+
+        // Nothing is lost: the series add up to every record seen
+        let sum: u64 = series
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(sum, 10_500);
+    }
+
+    #[test]
+    fn test_device_ids_are_escaped() {
+        let counts = counts(1, 1);
+        counts.record("a\"b\\c");
+        assert!(render(&counts).contains("{device_id=\"a\\\"b\\\\c\"} 1\n"));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{bounded_store::BoundedStore, rate_limit::TokenBucket};
+use serde::Deserialize;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Admitted requests are counted per interval of this length, and each
+// finished interval is folded into the device's baseline
+const BASELINE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Devices are spread over this many independently locked stores, so
+// requests from different devices rarely wait on each other and a full
+// store's eviction pass only scans its own share of the devices
+const SHARDS: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceRateLimitConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Static limit, and the whole limit unless `adaptive` is set
+    #[serde(
+        default = "default_rate_per_sec",
+        alias = "max_requests_per_device_per_sec"
+    )]
+    pub rate_per_sec: f64,
+    #[serde(default = "default_burst")]
+    pub burst: f64,
+    // Replace the static limit with a multiple of each device's own
+    // learned rate once its learning period is over
+    #[serde(default)]
+    pub adaptive: bool,
+    // Roughly how far back the baseline looks; older traffic fades out
+    #[serde(default = "default_baseline_window_secs")]
+    pub baseline_window_secs: u64,
+    // How long a newly seen device stays on the static limit
+    #[serde(default = "default_learning_secs")]
+    pub learning_secs: u64,
+    // How far above its baseline a device may go before it is throttled
+    #[serde(default = "default_anomaly_multiple")]
+    pub anomaly_multiple: f64,
+    // Floor for the adaptive limit, so a device that is nearly silent isn't
+    // throttled to a crawl the first time it has something to say
+    #[serde(default = "default_min_rate_per_sec")]
+    pub min_rate_per_sec: f64,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for DeviceRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rate_per_sec: default_rate_per_sec(),
+            burst: default_burst(),
+            adaptive: false,
+            baseline_window_secs: default_baseline_window_secs(),
+            learning_secs: default_learning_secs(),
+            anomaly_multiple: default_anomaly_multiple(),
+            min_rate_per_sec: default_min_rate_per_sec(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+impl DeviceRateLimitConfig {
+    // Zero rates would never refill a bucket, and the adaptive burst is
+    // scaled by burst / rate_per_sec
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for (key, value) in [
+            ("rate_per_sec", self.rate_per_sec),
+            ("min_rate_per_sec", self.min_rate_per_sec),
+        ] {
+            if value.is_nan() || value <= 0.0 {
+                return Err(anyhow::anyhow!(
+                    "device_rate_limit.{} must be above 0, got {}",
+                    key,
+                    value
+                ));
+            }
+        }
+        if self.burst.is_nan() || self.burst < 1.0 {
+            return Err(anyhow::anyhow!(
+                "device_rate_limit.burst must be at least 1, got {}",
+                self.burst
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_rate_per_sec() -> f64 {
+    10.0
+}
+
+fn default_burst() -> f64 {
+    20.0
+}
+
+fn default_baseline_window_secs() -> u64 {
+    3600
+}
+
+fn default_learning_secs() -> u64 {
+    600
+}
+
+fn default_anomaly_multiple() -> f64 {
+    10.0
+}
+
+fn default_min_rate_per_sec() -> f64 {
+    1.0
+}
+
+fn default_max_devices() -> usize {
+    100_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+struct DeviceState {
+    bucket: TokenBucket,
+    first_seen: Instant,
+    interval_start: Instant,
+    interval_count: u32,
+    // Learned requests per second, a moving average over finished intervals
+    baseline: f64,
+    // Limit the bucket currently enforces
+    limit: f64,
+}
+
+// Token bucket per device. With `adaptive` off every device gets the static
+// rate. With it on, each device's limit follows its own history: a moving
+// average of the rate it has been admitted at, times `anomaly_multiple`.
+// A device that normally posts once a minute is stopped when it suddenly
+// posts a hundred times a minute, while one that always posts ten times a
+// second keeps going. Only admitted requests feed the baseline, so a flood
+// can't teach the limiter that flooding is normal.
+pub struct DeviceRateLimiter {
+    config: DeviceRateLimitConfig,
+    shards: Vec<Mutex<BoundedStore<DeviceState>>>,
+}
+
+impl DeviceRateLimiter {
+    pub fn new(config: DeviceRateLimitConfig) -> Self {
+        let idle_ttl = Duration::from_secs(config.idle_eviction_secs);
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(BoundedStore::new(
+                    config.max_devices.div_ceil(SHARDS),
+                    idle_ttl,
+                ))
+            })
+            .collect();
+        Self { config, shards }
+    }
+
+    fn shard(&self, device_id: &str) -> &Mutex<BoundedStore<DeviceState>> {
+        let mut hasher = DefaultHasher::new();
+        device_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // Err carries how long the device should wait before its next request
+    pub fn check(&self, device_id: &str, now: Instant) -> Result<(), Duration> {
+        let config = &self.config;
+        let mut devices = self.shard(device_id).lock().unwrap();
+        let device = devices.get_or_insert_with(device_id, now, || DeviceState {
+            bucket: TokenBucket::new(config.rate_per_sec, config.burst, now),
+            first_seen: now,
+            interval_start: now,
+            interval_count: 0,
+            baseline: 0.0,
+            limit: config.rate_per_sec,
+        });
+
+        if config.adaptive {
+            self.update_baseline(device, now);
+        }
+        let result = device.bucket.try_take(now);
+        if result.is_ok() {
+            device.interval_count += 1;
+        }
+        result
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.shard(device_id).lock().unwrap().remove(device_id)
+    }
+
+    fn update_baseline(&self, device: &mut DeviceState, now: Instant) {
+        let elapsed = now.saturating_duration_since(device.interval_start);
+        if elapsed < BASELINE_INTERVAL {
+            return;
+        }
+
+        // Intervals without a single request count as zero traffic
+        let finished = (elapsed.as_secs_f64() / BASELINE_INTERVAL.as_secs_f64()).floor();
+        let alpha = (BASELINE_INTERVAL.as_secs_f64() / self.config.baseline_window_secs as f64)
+            .clamp(0.0, 1.0);
+        let rate = f64::from(device.interval_count) / BASELINE_INTERVAL.as_secs_f64();
+        device.baseline += alpha * (rate - device.baseline);
+        device.baseline *= (1.0 - alpha).powf(finished - 1.0);
+        device.interval_start += BASELINE_INTERVAL.mul_f64(finished);
+        device.interval_count = 0;
+
+        let learning = Duration::from_secs(self.config.learning_secs);
+        if now.saturating_duration_since(device.first_seen) < learning {
+            return;
+        }
+        let limit =
+            (device.baseline * self.config.anomaly_multiple).max(self.config.min_rate_per_sec);
+        // Bursts scale with the limit, in the proportion the static settings use
+        let burst = (limit * self.config.burst / self.config.rate_per_sec).max(1.0);
+        device.bucket.set_rate(limit, burst);
+        device.limit = limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(adaptive: bool) -> DeviceRateLimiter {
+        DeviceRateLimiter::new(DeviceRateLimitConfig {
+            enabled: true,
+            rate_per_sec: 10.0,
+            burst: 10.0,
+            adaptive,
+            baseline_window_secs: 600,
+            learning_secs: 600,
+            anomaly_multiple: 10.0,
+            min_rate_per_sec: 0.1,
+            ..DeviceRateLimitConfig::default()
+        })
+    }
+
+    // Sends `count` requests evenly spread over `over`, returning how many
+    // were admitted
+    fn send(
+        limiter: &DeviceRateLimiter,
+        device_id: &str,
+        start: Instant,
+        over: Duration,
+        count: u32,
+    ) -> u32 {
+        (0..count)
+            .filter(|i| {
+                let at = start + over.mul_f64(f64::from(*i) / f64::from(count));
+                limiter.check(device_id, at).is_ok()
+            })
+            .count() as u32
+    }
+
+    #[test]
+    fn test_static_limit_applies_while_learning() {
+        let limiter = limiter(true);
+        let now = Instant::now();
+        // The burst goes through, then the device is held to 10/s
+        assert_eq!(send(&limiter, "sensor-1", now, Duration::ZERO, 15), 10);
+        let wait = limiter.check("sensor-1", now).unwrap_err();
+        assert!(wait <= Duration::from_millis(100), "{:?}", wait);
+
+        // Devices are limited independently
+        assert!(limiter.check("sensor-2", now).is_ok());
+    }
+
+    #[test]
+    fn test_baseline_is_learned_from_normal_traffic() {
+        let limiter = limiter(true);
+        let start = Instant::now();
+        // One request every 10 seconds through the learning period and beyond
+        let learning = Duration::from_secs(1200);
+        assert_eq!(send(&limiter, "sensor-1", start, learning, 120), 120);
+
+        let mut devices = limiter.shard("sensor-1").lock().unwrap();
+        let device = devices.peek_mut("sensor-1").unwrap();
+        assert!(
+            (device.baseline - 0.1).abs() < 0.02,
+            "baseline {}",
+            device.baseline
+        );
+        assert!((device.limit - 1.0).abs() < 0.2, "limit {}", device.limit);
+    }
+
+    #[test]
+    fn test_anomalous_rate_is_throttled() {
+        let limiter = limiter(true);
+        let start = Instant::now();
+        let learning = Duration::from_secs(1200);
+        send(&limiter, "sensor-1", start, learning, 120);
+        send(&limiter, "steady", start, learning, 6_000);
+
+        // Both now send 100x what sensor-1 normally does. sensor-1 is cut to
+        // roughly its 1/s adaptive limit; for "steady" that is normal traffic.
+        let spike = start + learning;
+        let minute = Duration::from_secs(60);
+        let admitted = send(&limiter, "sensor-1", spike, minute, 600);
+        assert!(admitted < 100, "admitted {}", admitted);
+        assert_eq!(send(&limiter, "steady", spike, minute, 600), 600);
+    }
+
+    #[test]
+    fn test_config_accepts_max_requests_per_device_per_sec() {
+        let config: DeviceRateLimitConfig =
+            serde_json::from_str(r#"{"enabled": true, "max_requests_per_device_per_sec": 2.5}"#)
+                .unwrap();
+        assert_eq!(config.rate_per_sec, 2.5);
+        assert!(!config.adaptive);
+    }
+
+    #[test]
+    fn test_limits_that_would_never_admit_are_rejected() {
+        let config = |overrides: &str| -> DeviceRateLimitConfig {
+            serde_json::from_str(&format!(r#"{{"enabled": true{}}}"#, overrides)).unwrap()
+        };
+        assert!(config("").validate().is_ok());
+        for (overrides, key) in [
+            (r#", "rate_per_sec": 0"#, "device_rate_limit.rate_per_sec"),
+            (r#", "burst": 0.5"#, "device_rate_limit.burst"),
+            (
+                r#", "min_rate_per_sec": -1"#,
+                "device_rate_limit.min_rate_per_sec",
+            ),
+        ] {
+            let err = config(overrides).validate().unwrap_err().to_string();
+            assert!(err.contains(key), "{}", err);
+        }
+        let disabled: DeviceRateLimitConfig =
+            serde_json::from_str(r#"{"rate_per_sec": 0}"#).unwrap();
+        assert!(disabled.validate().is_ok());
+    }
+
+    #[test]
+    fn test_forgotten_devices_start_over() {
+        let limiter = limiter(false);
+        let now = Instant::now();
+        assert_eq!(send(&limiter, "sensor-1", now, Duration::ZERO, 15), 10);
+        assert!(limiter.forget("sensor-1"));
+        assert!(!limiter.forget("sensor-1"));
+        assert!(limiter.check("sensor-1", now).is_ok());
+    }
+
+    #[test]
+    fn test_static_limit_without_adaptive() {
+        let limiter = limiter(false);
+        let start = Instant::now();
+        send(&limiter, "sensor-1", start, Duration::from_secs(1200), 120);
+        // Still the static 10/s long after any learning period would be over
+        let spike = start + Duration::from_secs(1200);
+        let admitted = send(&limiter, "sensor-1", spike, Duration::from_secs(60), 600);
+        assert_eq!(admitted, 600);
+    }
+}
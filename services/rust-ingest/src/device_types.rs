@@ -0,0 +1,99 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeviceTypeConfig {
+    // device_id prefix -> device type, longest prefix wins
+    #[serde(default)]
+    pub prefixes: HashMap<String, String>,
+    // device type -> metric names a reading must contain to match that type
+    #[serde(default)]
+    pub signatures: HashMap<String, Vec<String>>,
+}
+
+// Works out a device's type, first from its id prefix and otherwise from the
+// metrics it reports. When several signatures match, the most specific one
+// (most metrics) wins, with ties broken by type name.
+pub struct DeviceClassifier {
+    config: DeviceTypeConfig,
+}
+
+impl DeviceClassifier {
+    pub fn new(config: DeviceTypeConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn classify<'a>(
+        &self,
+        device_id: &str,
+        metric_names: impl IntoIterator<Item = &'a String> + Clone,
+    ) -> Option<String> {
+        let by_prefix = self
+            .config
+            .prefixes
+            .iter()
+            .filter(|(prefix, _)| device_id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, device_type)| device_type.clone());
+        if by_prefix.is_some() {
+            return by_prefix;
+        }
+
+        self.config
+            .signatures
+            .iter()
+            .filter(|(_, required)| {
+                !required.is_empty()
+                    && required
+                        .iter()
+                        .all(|metric| metric_names.clone().into_iter().any(|m| m == metric))
+            })
+            .max_by(|(a_type, a), (b_type, b)| a.len().cmp(&b.len()).then(b_type.cmp(a_type)))
+            .map(|(device_type, _)| device_type.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classifier() -> DeviceClassifier {
+        let mut config = DeviceTypeConfig::default();
+        config
+            .prefixes
+            .insert("tstat-".to_string(), "thermostat".to_string());
+        config.signatures.insert(
+            "gps".to_string(),
+            vec!["lat".to_string(), "lon".to_string()],
+        );
+        config.signatures.insert(
+            "vehicle".to_string(),
+            vec!["lat".to_string(), "lon".to_string(), "speed".to_string()],
+        );
+        DeviceClassifier::new(config)
+    }
+
+    #[test]
+    fn test_prefix_takes_precedence() {
+        let metrics = ["lat".to_string(), "lon".to_string()];
+        assert_eq!(
+            classifier().classify("tstat-1", &metrics),
+            Some("thermostat".to_string())
+        );
+    }
+
+    #[test]
+    fn test_most_specific_signature_wins() {
+        let classifier = classifier();
+        let gps = ["lat".to_string(), "lon".to_string()];
+        let vehicle = ["lat".to_string(), "lon".to_string(), "speed".to_string()];
+        let unknown = ["temperature".to_string()];
+
+        assert_eq!(classifier.classify("dev-1", &gps), Some("gps".to_string()));
+        assert_eq!(
+            classifier.classify("dev-2", &vehicle),
+            Some("vehicle".to_string())
+        );
+        assert_eq!(classifier.classify("dev-3", &unknown), None);
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DlqReplayConfig {
+    // Serve POST /admin/replay, which re-runs records from dead_letter_topic
+    // through the pipeline
+    #[serde(default)]
+    pub enabled: bool,
+    // Consumer group whose committed offsets mark what has been replayed
+    #[serde(default = "default_group_id")]
+    pub group_id: String,
+    // Most records one request replays; a request may ask for fewer
+    #[serde(default = "default_max_records")]
+    pub max_records: usize,
+    // A replay stops once no record has arrived for this long
+    #[serde(default = "default_poll_timeout_ms")]
+    pub poll_timeout_ms: u64,
+}
+
+impl Default for DlqReplayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group_id: default_group_id(),
+            max_records: default_max_records(),
+            poll_timeout_ms: default_poll_timeout_ms(),
+        }
+    }
+}
+
+fn default_group_id() -> String {
+    "rust-ingest-dlq-replay".to_string()
+}
+
+fn default_max_records() -> usize {
+    100
+}
+
+fn default_poll_timeout_ms() -> u64 {
+    5000
+}
+
+// A record read from the dead-letter topic, with where it was read from
+#[derive(Debug, Clone)]
+pub struct DeadLetterMessage {
+    pub partition: i32,
+    pub offset: i64,
+    pub payload: Vec<u8>,
+}
+
+impl DeadLetterMessage {
+    // The same on every replay of this record, so whatever dedups on the
+    // request id downstream can drop a record replayed twice
+    pub fn request_id(&self) -> String {
+        format!("dlq-replay-{}-{}", self.partition, self.offset)
+    }
+}
+
+// Where replayed records come from: the dead-letter topic, or a fixture in
+// tests
+#[async_trait]
+pub trait DeadLetterSource: Send + Sync {
+    // Up to `max` records after the last commit, stopping early when none
+    // arrives within `idle`
+    async fn fetch(&self, max: usize, idle: Duration) -> Result<Vec<DeadLetterMessage>>;
+    // Marks `replayed` as done, so no later replay reads them again. Always a
+    // run of the records last fetched, in the order they came.
+    async fn commit(&self, replayed: &[DeadLetterMessage]) -> Result<()>;
+    // Goes back to the last commit, so records fetched but never committed
+    // are fetched again by the next replay
+    async fn rewind(&self) -> Result<()>;
+}
+
+pub struct KafkaDeadLetterSource {
+    consumer: Arc<StreamConsumer>,
+    topic: String,
+}
+
+impl KafkaDeadLetterSource {
+    pub fn new(brokers: &str, topic: &str, config: &DlqReplayConfig) -> Result<Self> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", &config.group_id)
+            // Offsets are committed only once a replay has run
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[topic])?;
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topic: topic.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl DeadLetterSource for KafkaDeadLetterSource {
+    async fn fetch(&self, max: usize, idle: Duration) -> Result<Vec<DeadLetterMessage>> {
+        let mut messages = Vec::with_capacity(max);
+        while messages.len() < max {
+            let Ok(received) = tokio::time::timeout(idle, self.consumer.recv()).await else {
+                break;
+            };
+            let message = received?;
+            messages.push(DeadLetterMessage {
+                partition: message.partition(),
+                offset: message.offset(),
+                payload: message.payload().unwrap_or_default().to_vec(),
+            });
+        }
+        Ok(messages)
+    }
+
+    async fn commit(&self, replayed: &[DeadLetterMessage]) -> Result<()> {
+        // The offset committed for a partition is the next one to read
+        let mut next = BTreeMap::new();
+        for message in replayed {
+            let offset = next.entry(message.partition).or_insert(message.offset + 1);
+            *offset = (*offset).max(message.offset + 1);
+        }
+        if next.is_empty() {
+            return Ok(());
+        }
+        let mut offsets = TopicPartitionList::new();
+        for (partition, offset) in next {
+            offsets.add_partition_offset(&self.topic, partition, Offset::Offset(offset))?;
+        }
+        let consumer = Arc::clone(&self.consumer);
+        tokio::task::spawn_blocking(move || consumer.commit(&offsets, CommitMode::Sync)).await??;
+        Ok(())
+    }
+
+    async fn rewind(&self) -> Result<()> {
+        let consumer = Arc::clone(&self.consumer);
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let timeout = Duration::from_secs(10);
+            for committed in consumer.committed(timeout)?.elements() {
+                // Nothing committed yet: back to where auto.offset.reset starts
+                let offset = match committed.offset() {
+                    Offset::Invalid => Offset::Beginning,
+                    offset => offset,
+                };
+                consumer.seek(committed.topic(), committed.partition(), offset, timeout)?;
+            }
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+// Serves POST /admin/replay, one replay at a time: two running together
+// would read past each other's uncommitted records
+pub struct DlqReplayer {
+    source: Mutex<Arc<dyn DeadLetterSource>>,
+    max_records: usize,
+    idle: Duration,
+}
+
+impl DlqReplayer {
+    pub fn new(source: Arc<dyn DeadLetterSource>, config: &DlqReplayConfig) -> Self {
+        Self {
+            source: Mutex::new(source),
+            max_records: config.max_records.max(1),
+            idle: Duration::from_millis(config.poll_timeout_ms),
+        }
+    }
+
+    // The source, unless a replay is already running
+    pub fn try_begin(&self) -> Option<MutexGuard<'_, Arc<dyn DeadLetterSource>>> {
+        self.source.try_lock().ok()
+    }
+
+    // How many records a request asking for `requested` replays
+    pub fn limit(&self, requested: Option<usize>) -> usize {
+        requested
+            .unwrap_or(self.max_records)
+            .clamp(1, self.max_records)
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoRecords;
+
+    #[async_trait]
+    impl DeadLetterSource for NoRecords {
+        async fn fetch(&self, _: usize, _: Duration) -> Result<Vec<DeadLetterMessage>> {
+            Ok(Vec::new())
+        }
+
+        async fn commit(&self, _: &[DeadLetterMessage]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn rewind(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_replay_at_a_time_within_the_limit() {
+        let replayer = DlqReplayer::new(
+            Arc::new(NoRecords),
+            &DlqReplayConfig {
+                enabled: true,
+                max_records: 50,
+                ..Default::default()
+            },
+        );
+        assert_eq!(replayer.limit(None), 50);
+        assert_eq!(replayer.limit(Some(10)), 10);
+        assert_eq!(replayer.limit(Some(500)), 50);
+        assert_eq!(replayer.limit(Some(0)), 1);
+
+        let running = replayer.try_begin();
+        assert!(running.is_some());
+        assert!(replayer.try_begin().is_none());
+        drop(running);
+        assert!(replayer.try_begin().is_some());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{bounded_store::BoundedStore, request_metrics};
+use prometheus::{IntCounter, Registry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DuplicateBackoffConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Consecutive identical values forwarded before backing off
+    #[serde(default = "default_after_repeats")]
+    pub after_repeats: u32,
+    // Backoff stops doubling here: at most 1 in `max_interval` is forwarded
+    #[serde(default = "default_max_interval")]
+    pub max_interval: u32,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for DuplicateBackoffConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after_repeats: default_after_repeats(),
+            max_interval: default_max_interval(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_after_repeats() -> u32 {
+    10
+}
+
+fn default_max_interval() -> u32 {
+    64
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+// The current run of identical values for one device+metric
+struct Run {
+    value: u64,
+    repeats: u32,
+    // Forward 1 in `interval` once past `after_repeats`
+    interval: u32,
+    since_forward: u32,
+}
+
+impl Run {
+    fn new(value: f64) -> Self {
+        Self {
+            value: value.to_bits(),
+            repeats: 0,
+            interval: 2,
+            since_forward: 0,
+        }
+    }
+}
+
+// Thins out frozen sensors: once a device+metric has repeated the same value
+// `after_repeats` times, only 1 in 2 further repeats is forwarded, then 1 in
+// 4 and so on, so the device still shows up as alive. A new value resets the
+// run immediately. Unlike deadband this only reacts to exact repeats, and
+// unlike dedup it works per metric rather than per record.
+pub struct DuplicateBackoff {
+    config: DuplicateBackoffConfig,
+    runs: Mutex<BoundedStore<HashMap<String, Run>>>,
+    suppressed: IntCounter,
+}
+
+impl DuplicateBackoff {
+    pub fn new(config: DuplicateBackoffConfig) -> Self {
+        let runs = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            config,
+            runs: Mutex::new(runs),
+            suppressed: request_metrics::int_counter(
+                "rust_ingest_duplicate_backoff_suppressed_total",
+                "Repeated metric values not forwarded",
+            ),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.runs.lock().unwrap().remove(device_id)
+    }
+
+    // Removes metrics that are backed off; returns how many were removed
+    pub fn apply(&self, device_id: &str, metrics: &mut HashMap<String, f64>) -> usize {
+        self.apply_at(device_id, metrics, Instant::now())
+    }
+
+    fn apply_at(&self, device_id: &str, metrics: &mut HashMap<String, f64>, now: Instant) -> usize {
+        let mut runs = self.runs.lock().unwrap();
+        let device_runs = runs.get_or_insert_with(device_id, now, HashMap::new);
+
+        let before = metrics.len();
+        metrics.retain(|metric, value| {
+            let Some(run) = device_runs.get_mut(metric) else {
+                device_runs.insert(metric.clone(), Run::new(*value));
+                return true;
+            };
+            if run.value != value.to_bits() {
+                *run = Run::new(*value);
+                return true;
+            }
+            run.repeats += 1;
+            if run.repeats <= self.config.after_repeats {
+                return true;
+            }
+
+            run.since_forward += 1;
+            if run.since_forward < run.interval.min(self.config.max_interval) {
+                return false;
+            }
+            run.since_forward = 0;
+            run.interval = (run.interval * 2).min(self.config.max_interval);
+            true
+        });
+
+        let removed = before - metrics.len();
+        self.suppressed.inc_by(removed as u64);
+        removed
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> DuplicateBackoff {
+        DuplicateBackoff::new(DuplicateBackoffConfig {
+            enabled: true,
+            after_repeats: 2,
+            max_interval: 4,
+            ..Default::default()
+        })
+    }
+
+    // Sends `value` once and reports whether it was forwarded
+    fn forwarded(backoff: &DuplicateBackoff, value: f64, now: Instant) -> bool {
+        let mut metrics = HashMap::from([("temperature".to_string(), value)]);
+        backoff.apply_at("sensor-1", &mut metrics, now);
+        !metrics.is_empty()
+    }
+
+    #[test]
+    fn test_repeats_back_off_exponentially() {
+        let backoff = backoff();
+        let now = Instant::now();
+        let pattern: Vec<bool> = (0..14).map(|_| forwarded(&backoff, 20.0, now)).collect();
+        assert_eq!(
+            pattern,
+            vec![
+                // first value plus two allowed repeats
+                true, true, true, // then 1 in 2, then capped at 1 in 4
+                false, true, false, false, false, true, false, false, false, true, false,
+            ]
+        );
+        assert!(
+            request_metrics::rendered(|registry| backoff.register_metrics(registry))
+                .contains("rust_ingest_duplicate_backoff_suppressed_total 8")
+        );
+    }
+
+    #[test]
+    fn test_change_resets_immediately() {
+        let backoff = backoff();
+        let now = Instant::now();
+        for _ in 0..6 {
+            forwarded(&backoff, 20.0, now);
+        }
+        assert!(!forwarded(&backoff, 20.0, now));
+        assert!(forwarded(&backoff, 20.5, now));
+        assert!(forwarded(&backoff, 20.5, now));
+        assert!(forwarded(&backoff, 20.5, now));
+    }
+
+    #[test]
+    fn test_metrics_are_tracked_independently() {
+        let backoff = backoff();
+        let now = Instant::now();
+        let mut suppressed = 0;
+        for i in 0..10 {
+            let mut metrics = HashMap::from([
+                ("temperature".to_string(), 20.0),
+                ("counter".to_string(), i as f64),
+            ]);
+            suppressed += backoff.apply_at("sensor-1", &mut metrics, now);
+            assert!(metrics.contains_key("counter"));
+        }
+        assert!(suppressed > 0);
+    }
+}
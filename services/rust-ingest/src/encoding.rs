@@ -0,0 +1,437 @@
+use crate::{proto::telemetry::Telemetry, schema_registry};
+use anyhow::Result;
+use apache_avro::{types::Value as AvroValue, Schema};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashMap, sync::OnceLock};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Protobuf,
+    Json,
+    Avro,
+    MessagePack,
+}
+
+// Wire format per destination topic, so consumers can migrate one topic at
+// a time. Topics without an entry use `default`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncodingConfig {
+    #[serde(default)]
+    pub default: OutputFormat,
+    #[serde(default)]
+    pub topics: HashMap<String, OutputFormat>,
+    // Id the schema registry gave the Avro schema; Avro records are framed
+    // with it when set. Filled in at startup, not configured.
+    #[serde(skip)]
+    pub schema_id: Option<u32>,
+}
+
+// Version of the record layout the encoders write, sent along with every
+// record; bump it on a change consumers can't read with their current schema
+pub const SCHEMA_VERSION: u32 = 1;
+
+impl OutputFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Protobuf => "application/x-protobuf",
+            Self::Json => "application/json",
+            Self::Avro => "avro/binary",
+            Self::MessagePack => "application/msgpack",
+        }
+    }
+}
+
+impl EncodingConfig {
+    pub fn format_for(&self, topic: &str) -> OutputFormat {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+
+    // Encodes a record in `topic`'s format, ready to send
+    pub fn encode_for(&self, telemetry: &Telemetry, topic: &str) -> Result<Vec<u8>> {
+        let format = self.format_for(topic);
+        let encoded = encode(telemetry, format)?;
+        match (format, self.schema_id) {
+            (OutputFormat::Avro, Some(schema_id)) => {
+                Ok(schema_registry::frame(schema_id, &encoded))
+            }
+            _ => Ok(encoded),
+        }
+    }
+}
+
+pub const AVRO_SCHEMA: &str = r#"{
+    "type": "record",
+    "name": "Telemetry",
+    "namespace": "iot.telemetry",
+    "fields": [
+        {"name": "device_id", "type": "string"},
+        {"name": "ts", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+        {"name": "metrics", "type": {"type": "map", "values": "double"}},
+        {"name": "raw", "type": "bytes"},
+        {"name": "tags", "type": {"type": "map", "values": "string"}},
+        {"name": "metadata", "type": ["null", "string"], "default": null, "doc": "JSON object"},
+        {"name": "samples", "default": [], "type": {"type": "array", "items": {
+            "type": "record",
+            "name": "Sample",
+            "fields": [
+                {"name": "ts", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+                {"name": "metrics", "type": {"type": "map", "values": "double"}}
+            ]
+        }}},
+        {"name": "flag_metrics", "default": {}, "type": {"type": "map", "values": "boolean"}},
+        {"name": "text_metrics", "default": {}, "type": {"type": "map", "values": "string"}}
+    ]
+}"#;
+
+pub fn avro_schema() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::parse_str(AVRO_SCHEMA).expect("built-in Avro schema is valid"))
+}
+
+// Field layout shared by the self-describing formats (JSON, MessagePack)
+#[derive(Serialize)]
+pub struct TelemetryDocument<'a> {
+    device_id: &'a str,
+    ts: i64,
+    metrics: &'a HashMap<String, f64>,
+    raw: Cow<'a, str>,
+    tags: &'a HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    samples: Vec<SampleDocument<'a>>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    flag_metrics: &'a HashMap<String, bool>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    text_metrics: &'a HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct SampleDocument<'a> {
+    ts: i64,
+    metrics: &'a HashMap<String, f64>,
+}
+
+impl<'a> From<&'a Telemetry> for TelemetryDocument<'a> {
+    fn from(telemetry: &'a Telemetry) -> Self {
+        Self {
+            device_id: &telemetry.device_id,
+            ts: telemetry.ts,
+            metrics: &telemetry.metrics,
+            raw: String::from_utf8_lossy(&telemetry.raw),
+            tags: &telemetry.tags,
+            metadata: telemetry.metadata.as_ref().map(struct_to_json),
+            samples: telemetry
+                .samples
+                .iter()
+                .map(|sample| SampleDocument {
+                    ts: sample.ts,
+                    metrics: &sample.metrics,
+                })
+                .collect(),
+            flag_metrics: &telemetry.flag_metrics,
+            text_metrics: &telemetry.text_metrics,
+        }
+    }
+}
+
+// google.protobuf.Struct -> plain JSON, for the non-protobuf formats
+pub fn struct_to_json(value: &prost_types::Struct) -> serde_json::Value {
+    serde_json::Value::Object(
+        value
+            .fields
+            .iter()
+            .map(|(k, v)| (k.clone(), value_to_json(v)))
+            .collect(),
+    )
+}
+
+fn value_to_json(value: &prost_types::Value) -> serde_json::Value {
+    use prost_types::value::Kind;
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::NumberValue(n)) => serde_json::json!(n),
+        Some(Kind::StringValue(s)) => serde_json::Value::String(s.clone()),
+        Some(Kind::BoolValue(b)) => serde_json::Value::Bool(*b),
+        Some(Kind::StructValue(s)) => struct_to_json(s),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.iter().map(value_to_json).collect())
+        }
+    }
+}
+
+// Plain JSON -> google.protobuf.Struct, for reading records back from the
+// JSON layout
+pub fn json_to_struct(object: &serde_json::Map<String, serde_json::Value>) -> prost_types::Struct {
+    prost_types::Struct {
+        fields: object
+            .iter()
+            .map(|(k, v)| (k.clone(), json_to_value(v)))
+            .collect(),
+    }
+}
+
+fn json_to_value(value: &serde_json::Value) -> prost_types::Value {
+    use prost_types::value::Kind;
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(*b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s.clone()),
+        serde_json::Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.iter().map(json_to_value).collect(),
+        }),
+        serde_json::Value::Object(object) => Kind::StructValue(json_to_struct(object)),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+fn avro_metrics(metrics: &HashMap<String, f64>) -> AvroValue {
+    AvroValue::Map(
+        metrics
+            .iter()
+            .map(|(k, v)| (k.clone(), AvroValue::Double(*v)))
+            .collect(),
+    )
+}
+
+pub fn encode(telemetry: &Telemetry, format: OutputFormat) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Protobuf => {
+            let mut buf = Vec::with_capacity(telemetry.encoded_len());
+            telemetry.encode(&mut buf)?;
+            Ok(buf)
+        }
+        OutputFormat::Json => Ok(serde_json::to_vec(&TelemetryDocument::from(telemetry))?),
+        OutputFormat::MessagePack => Ok(rmp_serde::to_vec_named(&TelemetryDocument::from(
+            telemetry,
+        ))?),
+        OutputFormat::Avro => {
+            let record = AvroValue::Record(vec![
+                (
+                    "device_id".to_string(),
+                    AvroValue::String(telemetry.device_id.clone()),
+                ),
+                ("ts".to_string(), AvroValue::TimestampMillis(telemetry.ts)),
+                ("metrics".to_string(), avro_metrics(&telemetry.metrics)),
+                ("raw".to_string(), AvroValue::Bytes(telemetry.raw.clone())),
+                (
+                    "tags".to_string(),
+                    AvroValue::Map(
+                        telemetry
+                            .tags
+                            .iter()
+                            .map(|(k, v)| (k.clone(), AvroValue::String(v.clone())))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "metadata".to_string(),
+                    match &telemetry.metadata {
+                        Some(metadata) => AvroValue::Union(
+                            1,
+                            Box::new(AvroValue::String(struct_to_json(metadata).to_string())),
+                        ),
+                        None => AvroValue::Union(0, Box::new(AvroValue::Null)),
+                    },
+                ),
+                (
+                    "samples".to_string(),
+                    AvroValue::Array(
+                        telemetry
+                            .samples
+                            .iter()
+                            .map(|sample| {
+                                AvroValue::Record(vec![
+                                    ("ts".to_string(), AvroValue::TimestampMillis(sample.ts)),
+                                    ("metrics".to_string(), avro_metrics(&sample.metrics)),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                (
+                    "flag_metrics".to_string(),
+                    AvroValue::Map(
+                        telemetry
+                            .flag_metrics
+                            .iter()
+                            .map(|(k, v)| (k.clone(), AvroValue::Boolean(*v)))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "text_metrics".to_string(),
+                    AvroValue::Map(
+                        telemetry
+                            .text_metrics
+                            .iter()
+                            .map(|(k, v)| (k.clone(), AvroValue::String(v.clone())))
+                            .collect(),
+                    ),
+                ),
+            ]);
+            Ok(apache_avro::to_avro_datum(avro_schema(), record)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+
+    fn telemetry() -> Telemetry {
+        Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: 1700000000000,
+            metrics: HashMap::from([("temperature".to_string(), 21.5)]),
+            raw: Vec::new(),
+            tags: HashMap::from([("site".to_string(), "plant-7".to_string())]),
+            metadata: None,
+            samples: Vec::new(),
+            flag_metrics: HashMap::new(),
+            text_metrics: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_avro_records_carry_the_registered_schema_id() {
+        let config = EncodingConfig {
+            default: OutputFormat::Protobuf,
+            topics: HashMap::from([("telemetry.v2".to_string(), OutputFormat::Avro)]),
+            schema_id: Some(7),
+        };
+        let telemetry = telemetry();
+        let avro = config.encode_for(&telemetry, "telemetry.v2").unwrap();
+        assert_eq!(avro[..5], [0, 0, 0, 0, 7]);
+        let decoded = apache_avro::from_avro_datum(avro_schema(), &mut &avro[5..], None);
+        assert!(decoded.is_ok());
+
+        // Other formats go out as before
+        let protobuf = config.encode_for(&telemetry, "telemetry").unwrap();
+        assert_eq!(Telemetry::decode(protobuf.as_slice()).unwrap(), telemetry);
+    }
+
+    #[test]
+    fn test_each_topic_gets_its_format() {
+        let config = EncodingConfig {
+            default: OutputFormat::Protobuf,
+            topics: HashMap::from([
+                ("telemetry.v2".to_string(), OutputFormat::Avro),
+                ("telemetry.json".to_string(), OutputFormat::Json),
+                ("telemetry.msgpack".to_string(), OutputFormat::MessagePack),
+            ]),
+            schema_id: None,
+        };
+        let telemetry = telemetry();
+        let encoded = |topic: &str| encode(&telemetry, config.format_for(topic)).unwrap();
+
+        let legacy = encoded("telemetry");
+        assert_eq!(Telemetry::decode(legacy.as_slice()).unwrap(), telemetry);
+
+        let avro = encoded("telemetry.v2");
+        let decoded = apache_avro::from_avro_datum(avro_schema(), &mut avro.as_slice(), None);
+        let AvroValue::Record(fields) = decoded.unwrap() else {
+            panic!("expected an Avro record");
+        };
+        assert_eq!(
+            fields[0],
+            (
+                "device_id".to_string(),
+                AvroValue::String("sensor-1".into())
+            )
+        );
+
+        let json: serde_json::Value = serde_json::from_slice(&encoded("telemetry.json")).unwrap();
+        assert_eq!(json["metrics"]["temperature"], 21.5);
+        assert_eq!(json["tags"]["site"], "plant-7");
+
+        let msgpack: serde_json::Value =
+            rmp_serde::from_slice(&encoded("telemetry.msgpack")).unwrap();
+        assert_eq!(msgpack["device_id"], "sensor-1");
+        assert_eq!(msgpack["ts"], 1700000000000i64);
+    }
+
+    #[test]
+    fn test_samples_survive_every_format() {
+        let mut telemetry = telemetry();
+        telemetry.samples = vec![
+            Sample {
+                ts: 1700000001000,
+                metrics: HashMap::from([("temperature".to_string(), 21.6)]),
+            },
+            Sample {
+                ts: 1700000002000,
+                metrics: HashMap::from([("temperature".to_string(), 21.7)]),
+            },
+        ];
+
+        let protobuf = encode(&telemetry, OutputFormat::Protobuf).unwrap();
+        assert_eq!(Telemetry::decode(protobuf.as_slice()).unwrap(), telemetry);
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&encode(&telemetry, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["samples"][1]["ts"], 1700000002000i64);
+        assert_eq!(json["samples"][1]["metrics"]["temperature"], 21.7);
+
+        let avro = encode(&telemetry, OutputFormat::Avro).unwrap();
+        let decoded = apache_avro::from_avro_datum(avro_schema(), &mut avro.as_slice(), None);
+        let AvroValue::Record(fields) = decoded.unwrap() else {
+            panic!("expected an Avro record");
+        };
+        let AvroValue::Array(samples) = &fields[6].1 else {
+            panic!("expected a samples array");
+        };
+        assert_eq!(samples.len(), 2);
+    }
+
+    #[test]
+    fn test_typed_metrics_survive_every_format() {
+        let mut telemetry = telemetry();
+        telemetry.flag_metrics = HashMap::from([("door_open".to_string(), true)]);
+        telemetry.text_metrics = HashMap::from([("status".to_string(), "charging".to_string())]);
+
+        let protobuf = encode(&telemetry, OutputFormat::Protobuf).unwrap();
+        assert_eq!(Telemetry::decode(protobuf.as_slice()).unwrap(), telemetry);
+
+        for format in [OutputFormat::Json, OutputFormat::MessagePack] {
+            let encoded = encode(&telemetry, format).unwrap();
+            let document: serde_json::Value = match format {
+                OutputFormat::Json => serde_json::from_slice(&encoded).unwrap(),
+                _ => rmp_serde::from_slice(&encoded).unwrap(),
+            };
+            assert_eq!(document["flag_metrics"]["door_open"], true);
+            assert_eq!(document["text_metrics"]["status"], "charging");
+        }
+
+        let avro = encode(&telemetry, OutputFormat::Avro).unwrap();
+        let decoded = apache_avro::from_avro_datum(avro_schema(), &mut avro.as_slice(), None);
+        let AvroValue::Record(fields) = decoded.unwrap() else {
+            panic!("expected an Avro record");
+        };
+        assert_eq!(
+            fields[7].1,
+            AvroValue::Map(HashMap::from([(
+                "door_open".to_string(),
+                AvroValue::Boolean(true)
+            )]))
+        );
+        assert_eq!(
+            fields[8].1,
+            AvroValue::Map(HashMap::from([(
+                "status".to_string(),
+                AvroValue::String("charging".to_string())
+            )]))
+        );
+
+        // Left out of the self-describing formats when empty
+        let json: serde_json::Value =
+            serde_json::from_slice(&encode(&self::telemetry(), OutputFormat::Json).unwrap())
+                .unwrap();
+        assert!(json.get("flag_metrics").is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::proto::prometheus::{
+    Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType, Untyped,
+};
+use axum::http::{header, HeaderMap};
+use prost::Message;
+
+// What Prometheus asks for when native histograms are enabled on its side
+pub const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+pub fn accepts_protobuf(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| {
+            accept.split(',').any(|media| {
+                media.trim().starts_with("application/vnd.google.protobuf")
+                    && media.contains("proto=io.prometheus.client.MetricFamily")
+            })
+        })
+}
+
+// Length-delimited MetricFamily messages, the protobuf exposition format
+pub fn encode_delimited(families: &[MetricFamily]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for family in families {
+        // Writing into a Vec can't run out of space
+        family.encode_length_delimited(&mut body).unwrap();
+    }
+    body
+}
+
+// Converts the registry's text exposition into metric families, so a
+// protobuf scrape still gets every counter and gauge. Classic histograms
+// are folded back together from their _bucket/_sum/_count samples.
+pub fn families_from_text(text: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            family_mut(&mut families, name).help = help.to_string();
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap_or((rest, ""));
+            let kind = match kind {
+                "counter" => MetricType::Counter,
+                "gauge" => MetricType::Gauge,
+                "histogram" => MetricType::Histogram,
+                _ => MetricType::Untyped,
+            };
+            family_mut(&mut families, name).r#type = kind as i32;
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some((name, labels, value)) = parse_sample(line) {
+                add_sample(&mut families, name, labels, value);
+            }
+        }
+    }
+    families
+}
+
+fn family_mut<'a>(families: &'a mut Vec<MetricFamily>, name: &str) -> &'a mut MetricFamily {
+    match families.iter().position(|family| family.name == name) {
+        Some(index) => &mut families[index],
+        None => {
+            families.push(MetricFamily {
+                name: name.to_string(),
+                r#type: MetricType::Untyped as i32,
+                ..Default::default()
+            });
+            families.last_mut().unwrap()
+        }
+    }
+}
+
+fn add_sample(families: &mut Vec<MetricFamily>, name: &str, labels: Vec<LabelPair>, value: f64) {
+    // A histogram's series share the family name minus their suffix
+    for suffix in ["_bucket", "_sum", "_count"] {
+        let Some(base) = name.strip_suffix(suffix) else {
+            continue;
+        };
+        let is_histogram = families
+            .iter()
+            .any(|family| family.name == base && family.r#type == MetricType::Histogram as i32);
+        if is_histogram {
+            add_histogram_sample(family_mut(families, base), suffix, labels, value);
+            return;
+        }
+    }
+
+    let family = family_mut(families, name);
+    let mut metric = Metric {
+        label: labels,
+        ..Default::default()
+    };
+    match MetricType::from_i32(family.r#type) {
+        Some(MetricType::Counter) => metric.counter = Some(Counter { value }),
+        Some(MetricType::Gauge) => metric.gauge = Some(Gauge { value }),
+        _ => metric.untyped = Some(Untyped { value }),
+    }
+    family.metric.push(metric);
+}
+
+fn add_histogram_sample(
+    family: &mut MetricFamily,
+    suffix: &str,
+    mut labels: Vec<LabelPair>,
+    value: f64,
+) {
+    let upper_bound = labels
+        .iter()
+        .position(|label| label.name == "le")
+        .map(|index| labels.remove(index).value);
+    let index = match family.metric.iter().position(|m| m.label == labels) {
+        Some(index) => index,
+        None => {
+            family.metric.push(Metric {
+                label: labels,
+                histogram: Some(Histogram::default()),
+                ..Default::default()
+            });
+            family.metric.len() - 1
+        }
+    };
+    let histogram = family.metric[index]
+        .histogram
+        .get_or_insert_with(Default::default);
+    match (suffix, upper_bound) {
+        // +Inf is implied by sample_count
+        ("_bucket", Some(bound)) if bound != "+Inf" => {
+            if let Ok(upper_bound) = bound.parse() {
+                histogram.bucket.push(Bucket {
+                    cumulative_count: value as u64,
+                    upper_bound,
+                });
+            }
+        }
+        ("_sum", _) => histogram.sample_sum = value,
+        ("_count", _) => histogram.sample_count = value as u64,
+        _ => {}
+    }
+}
+
+// `name{label="value",...} 1.5`
+fn parse_sample(line: &str) -> Option<(&str, Vec<LabelPair>, f64)> {
+    let (series, value) = line.rsplit_once(' ')?;
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        other => other.parse().ok()?,
+    };
+    let Some((name, labels)) = series.split_once('{') else {
+        return Some((series, Vec::new(), value));
+    };
+    let labels = labels.strip_suffix('}')?;
+    Some((name, parse_labels(labels)?, value))
+}
+
+fn parse_labels(mut rest: &str) -> Option<Vec<LabelPair>> {
+    let mut labels = Vec::new();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once("=\"")?;
+        let mut value = String::new();
+        let mut chars = after.char_indices();
+        let end = loop {
+            match chars.next()? {
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    escaped => value.push(escaped),
+                },
+                (index, '"') => break index,
+                (_, c) => value.push(c),
+            }
+        };
+        labels.push(LabelPair {
+            name: name.trim().to_string(),
+            value,
+        });
+        rest = after[end + 1..].trim_start_matches(',');
+    }
+    Some(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::histograms::{HistogramConfig, PipelineHistograms};
+
+    #[test]
+    fn test_text_exposition_converts_to_families() {
+        let text = "# HELP requests_total Requests\n\
+                    # TYPE requests_total counter\n\
+                    requests_total 3\n\
+                    # TYPE circuit_state gauge\n\
+                    circuit_state{topic=\"a \\\"b\\\"\"} 1\n\
+                    # TYPE age_seconds histogram\n\
+                    age_seconds_bucket{le=\"1\"} 2\n\
+                    age_seconds_bucket{le=\"+Inf\"} 5\n\
+                    age_seconds_sum 12\n\
+                    age_seconds_count 5\n";
+        let families = families_from_text(text);
+        assert_eq!(families.len(), 3);
+
+        assert_eq!(families[0].help, "Requests");
+        assert_eq!(families[0].metric[0].counter, Some(Counter { value: 3.0 }));
+
+        let gauge = &families[1].metric[0];
+        assert_eq!(gauge.label[0].value, "a \"b\"");
+        assert_eq!(gauge.gauge, Some(Gauge { value: 1.0 }));
+
+        let histogram = families[2].metric[0].histogram.as_ref().unwrap();
+        assert_eq!(histogram.sample_count, 5);
+        assert_eq!(histogram.sample_sum, 12.0);
+        assert_eq!(histogram.bucket.len(), 1);
+    }
+
+    #[test]
+    fn test_protobuf_scrape_carries_native_histograms() {
+        let histograms = PipelineHistograms::new(&HistogramConfig {
+            native: true,
+            ..Default::default()
+        });
+        histograms.send_latency.observe(0.012);
+        histograms.send_latency.observe(0.3);
+
+        let body = encode_delimited(&histograms.families());
+        let mut buf = body.as_slice();
+        let family = MetricFamily::decode_length_delimited(&mut buf).unwrap();
+        assert_eq!(family.name, "rust_ingest_send_latency_seconds");
+        assert_eq!(family.r#type, MetricType::Histogram as i32);
+        let histogram = family.metric[0].histogram.as_ref().unwrap();
+        assert_eq!(histogram.schema, 3);
+        assert!(histogram.zero_threshold > 0.0);
+        assert_eq!(histogram.positive_span.len(), 2);
+        assert_eq!(histogram.positive_delta, vec![1, 0]);
+
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_protobuf(&headers));
+        headers.insert(
+            header::ACCEPT,
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.8,text/plain;version=0.0.4;q=0.3"
+                .parse()
+                .unwrap(),
+        );
+        assert!(accepts_protobuf(&headers));
+    }
+}
@@ -0,0 +1,156 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Backlog thresholds past which /health reports "degraded" instead of
+// "healthy", so an orchestrator notices a slowing node before it fails
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    // Records between receipt and publish
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+    // Records accepted by the sink but not yet written out, e.g. sitting in
+    // the Kafka producer queue because the brokers are slow
+    #[serde(default)]
+    pub max_sink_pending: Option<usize>,
+    // Answer 503 while degraded so load balancers steer traffic away;
+    // otherwise degraded is reported with a 200
+    #[serde(default)]
+    pub fail_when_degraded: bool,
+    // How long /ready waits for the sinks' backends, e.g. a Kafka broker,
+    // to answer
+    #[serde(default = "default_ready_timeout_ms")]
+    pub ready_timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: None,
+            max_sink_pending: None,
+            fail_when_degraded: false,
+            ready_timeout_ms: default_ready_timeout_ms(),
+        }
+    }
+}
+
+fn default_ready_timeout_ms() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+}
+
+// Current depth of the node's internal queues
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Backlog {
+    pub in_flight: usize,
+    pub sink_pending: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Health {
+    pub status: HealthStatus,
+    // Which thresholds were exceeded, for the response body
+    pub reasons: Vec<String>,
+}
+
+impl HealthConfig {
+    pub fn assess(&self, backlog: Backlog) -> Health {
+        let checks = [
+            ("in-flight records", backlog.in_flight, self.max_in_flight),
+            (
+                "sink pending records",
+                backlog.sink_pending,
+                self.max_sink_pending,
+            ),
+        ];
+        let reasons: Vec<String> = checks
+            .into_iter()
+            .filter_map(|(what, depth, limit)| {
+                let limit = limit?;
+                (depth > limit).then(|| format!("{} {} over {}", depth, what, limit))
+            })
+            .collect();
+        let status = if reasons.is_empty() {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded
+        };
+        Health { status, reasons }
+    }
+
+    pub fn ready_timeout(&self) -> Duration {
+        Duration::from_millis(self.ready_timeout_ms)
+    }
+
+    pub fn status_code(&self, health: &Health) -> StatusCode {
+        if health.status == HealthStatus::Degraded && self.fail_when_degraded {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fail_when_degraded: bool) -> HealthConfig {
+        HealthConfig {
+            max_in_flight: Some(100),
+            max_sink_pending: Some(1_000),
+            fail_when_degraded,
+            ..HealthConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_backlog_flips_status_to_degraded() {
+        let config = config(false);
+        let calm = config.assess(Backlog {
+            in_flight: 100,
+            sink_pending: 10,
+        });
+        assert_eq!(calm.status, HealthStatus::Healthy);
+        assert!(calm.reasons.is_empty());
+
+        // Kafka is slow: the producer queue backs up
+        let backed_up = config.assess(Backlog {
+            in_flight: 20,
+            sink_pending: 5_000,
+        });
+        assert_eq!(backed_up.status, HealthStatus::Degraded);
+        assert_eq!(
+            backed_up.reasons,
+            vec!["5000 sink pending records over 1000"]
+        );
+        assert_eq!(config.status_code(&backed_up), StatusCode::OK);
+
+        let config = self::config(true);
+        let overloaded = config.assess(Backlog {
+            in_flight: 101,
+            sink_pending: 1_001,
+        });
+        assert_eq!(overloaded.reasons.len(), 2);
+        assert_eq!(
+            config.status_code(&overloaded),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(config.status_code(&calm), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_no_thresholds_is_always_healthy() {
+        let health = HealthConfig::default().assess(Backlog {
+            in_flight: usize::MAX,
+            sink_pending: usize::MAX,
+        });
+        assert_eq!(health.status, HealthStatus::Healthy);
+    }
+}
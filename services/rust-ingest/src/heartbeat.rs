@@ -0,0 +1,332 @@
+use crate::{
+    ack::AckMode,
+    bounded_store::BoundedStore,
+    priority::Priority,
+    proto::telemetry::Telemetry,
+    request_metrics,
+    sink::{SinkRecord, TelemetrySink},
+    telemetry_handler::HandlerContext,
+    timing_wheel::TimingWheel,
+};
+use prometheus::{IntCounter, Registry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HeartbeatConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // A device with nothing forwarded for this long gets a heartbeat
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: default_interval_secs(),
+            max_devices: default_max_devices(),
+        }
+    }
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+// Slots in the timing wheel; one interval spans all but two of them
+const WHEEL_SLOTS: usize = 64;
+
+struct DeviceLiveness {
+    topic: String,
+    last_values: HashMap<String, f64>,
+    last_received: Instant,
+    last_forwarded: Instant,
+    scheduled: bool,
+}
+
+struct HeartbeatState {
+    devices: BoundedStore<DeviceLiveness>,
+    wheel: TimingWheel<String>,
+}
+
+// A synthetic record ready to publish
+pub struct Heartbeat {
+    pub topic: String,
+    pub telemetry: Telemetry,
+}
+
+// Keeps stable devices visible to liveness-based consumers. When sampling
+// transforms (e.g. duplicate backoff) hold back everything a device sends,
+// a heartbeat carrying its last forwarded values is emitted once per
+// interval, tagged `heartbeat=true`. A device that has stopped sending
+// altogether gets no heartbeats, so they never mask a real outage.
+pub struct HeartbeatTracker {
+    interval: Duration,
+    state: Mutex<HeartbeatState>,
+    emitted: IntCounter,
+}
+
+impl HeartbeatTracker {
+    pub fn new(config: &HeartbeatConfig) -> Self {
+        Self::new_at(config, Instant::now())
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.state.lock().unwrap().devices.remove(device_id)
+    }
+
+    fn new_at(config: &HeartbeatConfig, now: Instant) -> Self {
+        let interval = Duration::from_secs(config.interval_secs.max(1));
+        Self {
+            interval,
+            state: Mutex::new(HeartbeatState {
+                // Idle eviction after a few silent intervals keeps dead devices from piling up
+                devices: BoundedStore::new(config.max_devices, interval * 3),
+                wheel: TimingWheel::new(interval / (WHEEL_SLOTS as u32 - 2), WHEEL_SLOTS, now),
+            }),
+            emitted: request_metrics::int_counter(
+                "rust_ingest_heartbeats_total",
+                "Synthetic heartbeat records emitted",
+            ),
+        }
+    }
+
+    fn tick(&self) -> Duration {
+        self.state.lock().unwrap().wheel.tick()
+    }
+
+    // A real record for the device went out on `topic`
+    pub fn record_forwarded(&self, topic: &str, telemetry: &Telemetry) {
+        self.record_forwarded_at(topic, telemetry, Instant::now())
+    }
+
+    fn record_forwarded_at(&self, topic: &str, telemetry: &Telemetry, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let device = state
+            .devices
+            .get_or_insert_with(&telemetry.device_id, now, || DeviceLiveness {
+                topic: topic.to_string(),
+                last_values: HashMap::new(),
+                last_received: now,
+                last_forwarded: now,
+                scheduled: false,
+            });
+        if device.topic != topic {
+            device.topic = topic.to_string();
+        }
+        device
+            .last_values
+            .extend(telemetry.metrics.iter().map(|(k, v)| (k.clone(), *v)));
+        device.last_received = now;
+        device.last_forwarded = now;
+        if !device.scheduled {
+            device.scheduled = true;
+            state
+                .wheel
+                .schedule(telemetry.device_id.clone(), now + self.interval);
+        }
+    }
+
+    // A record for the device arrived but nothing was forwarded
+    pub fn record_received(&self, device_id: &str) {
+        self.record_received_at(device_id, Instant::now())
+    }
+
+    fn record_received_at(&self, device_id: &str, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let interval = self.interval;
+        // Devices never forwarded have no last-known values to carry
+        let Some(device) = state.devices.peek_mut(device_id) else {
+            return;
+        };
+        device.last_received = now;
+        if !device.scheduled {
+            device.scheduled = true;
+            let deadline = device.last_forwarded + interval;
+            state.wheel.schedule(device_id.to_string(), deadline);
+        }
+    }
+
+    // Heartbeats due at `now`; `ts` is the wall-clock time to stamp them with
+    fn due_at(&self, now: Instant, ts: i64) -> Vec<Heartbeat> {
+        let mut state = self.state.lock().unwrap();
+        let HeartbeatState { devices, wheel } = &mut *state;
+
+        let mut heartbeats = Vec::new();
+        for device_id in wheel.advance(now) {
+            // Evicted since it was scheduled
+            let Some(device) = devices.peek_mut(&device_id) else {
+                continue;
+            };
+            let deadline = device.last_forwarded + self.interval;
+            if now < deadline {
+                // Something was forwarded since; check again later
+                wheel.schedule(device_id, deadline);
+                continue;
+            }
+            if now.saturating_duration_since(device.last_received) >= self.interval {
+                // Gone silent for real; rescheduled by its next record
+                device.scheduled = false;
+                continue;
+            }
+
+            device.last_forwarded = now;
+            heartbeats.push(Heartbeat {
+                topic: device.topic.clone(),
+                telemetry: Telemetry {
+                    device_id: device_id.clone(),
+                    ts,
+                    metrics: device.last_values.clone(),
+                    raw: Vec::new(),
+                    tags: HashMap::from([("heartbeat".to_string(), "true".to_string())]),
+                    metadata: None,
+                    samples: Vec::new(),
+                    flag_metrics: HashMap::new(),
+                    text_metrics: HashMap::new(),
+                },
+            });
+            wheel.schedule(device_id, now + self.interval);
+        }
+        self.emitted.inc_by(heartbeats.len() as u64);
+        heartbeats
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.emitted)
+    }
+}
+
+pub fn spawn_emitter(ctx: Arc<HandlerContext>, sink: Arc<dyn TelemetrySink>) {
+    let Some(tracker) = ctx.heartbeats.as_ref() else {
+        return;
+    };
+    let tick = tracker.tick();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(tick);
+        loop {
+            ticker.tick().await;
+            let Some(tracker) = ctx.heartbeats.as_ref() else {
+                return;
+            };
+            let ts = chrono::Utc::now().timestamp_millis();
+            for heartbeat in tracker.due_at(Instant::now(), ts) {
+                let telemetry = &heartbeat.telemetry;
+                let result = match ctx.encoding.encode_for(telemetry, &heartbeat.topic) {
+                    Ok(payload) => {
+                        sink.publish(SinkRecord {
+                            topic: &heartbeat.topic,
+                            key: &telemetry.device_id,
+                            payload: &payload,
+                            telemetry,
+                            expires_at: None,
+                            ack: AckMode::All,
+                            priority: Priority::Normal,
+                            receipt: None,
+                            request_id: None,
+                            position: None,
+                        })
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => debug!("Sent heartbeat for device {}", telemetry.device_id),
+                    Err(e) => warn!(
+                        "Failed to send heartbeat for device {}: {}",
+                        telemetry.device_id, e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(device_id: &str, value: f64) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            metrics: HashMap::from([("temperature".to_string(), value)]),
+            ..Default::default()
+        }
+    }
+
+    fn due(tracker: &HeartbeatTracker, now: Instant) -> Vec<String> {
+        tracker
+            .due_at(now, 0)
+            .into_iter()
+            .map(|heartbeat| heartbeat.telemetry.device_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_heartbeat_fires_only_while_nothing_is_forwarded() {
+        let start = Instant::now();
+        let secs = |s: u64| start + Duration::from_secs(s);
+        let tracker = HeartbeatTracker::new_at(
+            &HeartbeatConfig {
+                enabled: true,
+                interval_secs: 10,
+                ..Default::default()
+            },
+            start,
+        );
+
+        // "busy" forwards every 5s; "stable" reports but everything is held back
+        tracker.record_forwarded_at("telemetry", &reading("busy", 1.0), start);
+        tracker.record_forwarded_at("telemetry", &reading("stable", 20.0), start);
+        for s in [5, 10, 15, 20] {
+            tracker.record_forwarded_at("telemetry", &reading("busy", s as f64), secs(s));
+            tracker.record_received_at("stable", secs(s));
+            if s == 10 {
+                assert!(due(&tracker, secs(9)).is_empty());
+            }
+        }
+
+        let heartbeats = tracker.due_at(secs(21), 1234);
+        assert_eq!(heartbeats.len(), 1);
+        let heartbeat = &heartbeats[0].telemetry;
+        assert_eq!(heartbeat.device_id, "stable");
+        assert_eq!(heartbeat.metrics["temperature"], 20.0);
+        assert_eq!(heartbeat.tags["heartbeat"], "true");
+        assert_eq!(heartbeats[0].topic, "telemetry");
+
+        // Once per interval, not on every tick
+        assert!(due(&tracker, secs(25)).is_empty());
+    }
+
+    #[test]
+    fn test_silent_device_gets_no_heartbeat() {
+        let start = Instant::now();
+        let tracker = HeartbeatTracker::new_at(
+            &HeartbeatConfig {
+                enabled: true,
+                interval_secs: 10,
+                ..Default::default()
+            },
+            start,
+        );
+        tracker.record_forwarded_at("telemetry", &reading("dead", 1.0), start);
+        assert!(due(&tracker, start + Duration::from_secs(30)).is_empty());
+
+        // It comes back, but its readings are held back: heartbeats resume
+        tracker.record_received_at("dead", start + Duration::from_secs(35));
+        assert_eq!(due(&tracker, start + Duration::from_secs(36)), vec!["dead"]);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::proto::prometheus::{
+    Bucket, BucketSpan, Histogram as HistogramProto, Metric, MetricFamily, MetricType,
+};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistogramConfig {
+    // Also keep exponential buckets, served to scrapes that negotiate protobuf
+    #[serde(default)]
+    pub native: bool,
+    // Largest ratio between neighbouring native bucket bounds; rounded down
+    // to the nearest factor Prometheus supports (2^(2^-n))
+    #[serde(default = "default_growth_factor")]
+    pub growth_factor: f64,
+    // Past this many native buckets the resolution is halved
+    #[serde(default = "default_max_buckets")]
+    pub max_buckets: usize,
+}
+
+impl Default for HistogramConfig {
+    fn default() -> Self {
+        Self {
+            native: false,
+            growth_factor: default_growth_factor(),
+            max_buckets: default_max_buckets(),
+        }
+    }
+}
+
+fn default_growth_factor() -> f64 {
+    1.1
+}
+
+fn default_max_buckets() -> usize {
+    160
+}
+
+const SEND_LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const PAYLOAD_SIZE_BUCKETS: &[f64] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+];
+
+// Prometheus' bounds on native histogram resolution
+const MIN_SCHEMA: i32 = -4;
+const MAX_SCHEMA: i32 = 8;
+// Values at or below this land in the zero bucket
+const ZERO_THRESHOLD: f64 = 2.938735877055719e-39;
+
+// Schema whose growth factor 2^(2^-schema) is the largest not above `factor`
+fn schema_for(factor: f64) -> i32 {
+    if factor <= 1.0 {
+        return MAX_SCHEMA;
+    }
+    let schema = -(factor.log2().log2().floor()) as i32;
+    schema.clamp(MIN_SCHEMA, MAX_SCHEMA)
+}
+
+// Native bucket `i` covers (base^(i-1), base^i] with base = 2^(2^-schema)
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+struct NativeBuckets {
+    schema: i32,
+    max_buckets: usize,
+    zero_count: u64,
+    // Sparse positive buckets by index
+    positive: BTreeMap<i32, u64>,
+}
+
+impl NativeBuckets {
+    fn observe(&mut self, value: f64) {
+        if value <= ZERO_THRESHOLD {
+            self.zero_count += 1;
+            return;
+        }
+        *self
+            .positive
+            .entry(bucket_index(value, self.schema))
+            .or_default() += 1;
+        while self.positive.len() > self.max_buckets && self.schema > MIN_SCHEMA {
+            // Halving the resolution merges each pair of neighbouring buckets
+            self.schema -= 1;
+            let mut merged = BTreeMap::new();
+            for (index, count) in std::mem::take(&mut self.positive) {
+                *merged.entry((index + 1) >> 1).or_default() += count;
+            }
+            self.positive = merged;
+        }
+    }
+
+    fn fill(&self, histogram: &mut HistogramProto) {
+        histogram.schema = self.schema;
+        histogram.zero_threshold = ZERO_THRESHOLD;
+        histogram.zero_count = self.zero_count;
+
+        let mut previous: Option<(i32, u64)> = None;
+        for (&index, &count) in &self.positive {
+            match previous {
+                Some((last, _)) if index == last + 1 => {
+                    histogram.positive_span.last_mut().unwrap().length += 1;
+                }
+                _ => histogram.positive_span.push(BucketSpan {
+                    offset: previous.map_or(index, |(last, _)| index - last - 1),
+                    length: 1,
+                }),
+            }
+            let last_count = previous.map_or(0, |(_, count)| count);
+            histogram
+                .positive_delta
+                .push(count as i64 - last_count as i64);
+            previous = Some((index, count));
+        }
+    }
+}
+
+struct HistogramState {
+    count: u64,
+    sum: f64,
+    // Per-bucket (not cumulative) counts for the classic bounds
+    classic: Vec<u64>,
+    native: Option<NativeBuckets>,
+}
+
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+impl Histogram {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [f64],
+        config: &HistogramConfig,
+    ) -> Self {
+        let native = config.native.then(|| NativeBuckets {
+            schema: schema_for(config.growth_factor),
+            max_buckets: config.max_buckets.max(1),
+            zero_count: 0,
+            positive: BTreeMap::new(),
+        });
+        Self {
+            name,
+            help,
+            bounds,
+            state: Mutex::new(HistogramState {
+                count: 0,
+                sum: 0.0,
+                classic: vec![0; bounds.len()],
+                native,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        state.sum += value;
+        if let Some(slot) = self.bounds.iter().position(|bound| value <= *bound) {
+            state.classic[slot] += 1;
+        }
+        if let Some(native) = &mut state.native {
+            native.observe(value);
+        }
+    }
+
+    // Classic buckets only; the text format has no native histograms
+    fn render_text(&self, out: &mut String) {
+        let state = self.state.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.classic) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, state.count);
+        let _ = writeln!(out, "{}_sum {}", self.name, state.sum);
+        let _ = writeln!(out, "{}_count {}", self.name, state.count);
+    }
+
+    fn to_family(&self) -> MetricFamily {
+        let state = self.state.lock().unwrap();
+        let mut histogram = HistogramProto {
+            sample_count: state.count,
+            sample_sum: state.sum,
+            ..Default::default()
+        };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.classic) {
+            cumulative += count;
+            histogram.bucket.push(Bucket {
+                cumulative_count: cumulative,
+                upper_bound: *bound,
+            });
+        }
+        if let Some(native) = &state.native {
+            native.fill(&mut histogram);
+        }
+        MetricFamily {
+            name: self.name.to_string(),
+            help: self.help.to_string(),
+            r#type: MetricType::Histogram as i32,
+            metric: vec![Metric {
+                histogram: Some(histogram),
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+// Distributions recorded along the publish path
+pub struct PipelineHistograms {
+    pub native: bool,
+    // Time the sink took to accept a record (the Kafka delivery report, by default)
+    pub send_latency: Histogram,
+    pub payload_size: Histogram,
+}
+
+impl PipelineHistograms {
+    pub fn new(config: &HistogramConfig) -> Self {
+        Self {
+            native: config.native,
+            send_latency: Histogram::new(
+                "rust_ingest_send_latency_seconds",
+                "Time taken to publish a record to the sink",
+                SEND_LATENCY_BUCKETS,
+                config,
+            ),
+            payload_size: Histogram::new(
+                "rust_ingest_payload_size_bytes",
+                "Size of encoded records sent to the sink",
+                PAYLOAD_SIZE_BUCKETS,
+                config,
+            ),
+        }
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut out = String::new();
+        self.send_latency.render_text(&mut out);
+        self.payload_size.render_text(&mut out);
+        out
+    }
+
+    pub fn families(&self) -> Vec<MetricFamily> {
+        vec![self.send_latency.to_family(), self.payload_size.to_family()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn native_config(max_buckets: usize) -> HistogramConfig {
+        HistogramConfig {
+            native: true,
+            growth_factor: 1.1,
+            max_buckets,
+        }
+    }
+
+    #[test]
+    fn test_growth_factor_picks_schema() {
+        assert_eq!(schema_for(1.1), 3);
+        assert_eq!(schema_for(2.0), 0);
+        assert_eq!(schema_for(4.0), -1);
+        assert_eq!(schema_for(1.0001), MAX_SCHEMA);
+        assert_eq!(schema_for(1e300), MIN_SCHEMA);
+    }
+
+    #[test]
+    fn test_native_buckets_are_encoded_as_spans() {
+        let config = HistogramConfig {
+            growth_factor: 2.0,
+            ..native_config(160)
+        };
+        let histogram = Histogram::new("h", "help", &[1.0], &config);
+        // Schema 0: bucket i covers (2^(i-1), 2^i]
+        for value in [0.0, 1.0, 3.0, 4.0, 4.0, 100.0] {
+            histogram.observe(value);
+        }
+        let family = histogram.to_family();
+        let encoded = family.metric[0].histogram.as_ref().unwrap();
+        assert_eq!(encoded.sample_count, 6);
+        assert_eq!(encoded.zero_count, 1);
+        // Buckets 0 (1.0), 2 (3.0, 4.0, 4.0) and 7 (100.0)
+        assert_eq!(
+            encoded.positive_span,
+            vec![
+                BucketSpan {
+                    offset: 0,
+                    length: 1
+                },
+                BucketSpan {
+                    offset: 1,
+                    length: 1
+                },
+                BucketSpan {
+                    offset: 4,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(encoded.positive_delta, vec![1, 2, -2]);
+        // Classic buckets are still there for text scrapes
+        assert_eq!(encoded.bucket[0].cumulative_count, 2);
+    }
+
+    #[test]
+    fn test_resolution_halves_past_max_buckets() {
+        let histogram = Histogram::new("h", "help", &[], &native_config(4));
+        for value in 1..=64 {
+            histogram.observe(value as f64);
+        }
+        let state = histogram.state.lock().unwrap();
+        let native = state.native.as_ref().unwrap();
+        assert!(native.positive.len() <= 4);
+        assert!(native.schema < 3);
+        assert_eq!(native.positive.values().sum::<u64>(), 64);
+    }
+
+    #[test]
+    fn test_classic_only_when_native_disabled() {
+        let histograms = PipelineHistograms::new(&HistogramConfig::default());
+        histograms.payload_size.observe(100.0);
+        let family = &histograms.families()[1];
+        let encoded = family.metric[0].histogram.as_ref().unwrap();
+        assert!(encoded.positive_span.is_empty());
+        assert!(histograms
+            .render_text()
+            .contains("rust_ingest_payload_size_bytes_bucket{le=\"256\"} 1"));
+    }
+}
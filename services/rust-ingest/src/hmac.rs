@@ -0,0 +1,62 @@
+use sha2::{Digest, Sha256};
+
+// SHA-256 block size, which HMAC pads the secret to
+const BLOCK_SIZE: usize = 64;
+
+// HMAC-SHA256 (RFC 2104) with the secret's pads worked out once, for
+// secrets that sign many messages
+pub struct HmacSha256 {
+    inner_pad: [u8; BLOCK_SIZE],
+    outer_pad: [u8; BLOCK_SIZE],
+}
+
+impl HmacSha256 {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut key = [0u8; BLOCK_SIZE];
+        if secret.len() > BLOCK_SIZE {
+            key[..32].copy_from_slice(&Sha256::digest(secret));
+        } else {
+            key[..secret.len()].copy_from_slice(secret);
+        }
+        Self {
+            inner_pad: key.map(|byte| byte ^ 0x36),
+            outer_pad: key.map(|byte| byte ^ 0x5c),
+        }
+    }
+
+    pub fn mac(&self, message: &[u8]) -> [u8; 32] {
+        let inner = Sha256::new()
+            .chain_update(self.inner_pad)
+            .chain_update(message)
+            .finalize();
+        Sha256::new()
+            .chain_update(self.outer_pad)
+            .chain_update(inner)
+            .finalize()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(mac: [u8; 32]) -> String {
+        mac.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_rfc_4231_vectors() {
+        // Test case 2
+        assert_eq!(
+            hex(HmacSha256::new(b"Jefe").mac(b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Test case 6: a secret longer than the block size is hashed first
+        assert_eq!(
+            hex(HmacSha256::new(&[0xaa; 131])
+                .mac(b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// A plain-HTTP server the service posts to (schema registry, OTLP
+// collector), parsed from its base URL
+#[derive(Debug, PartialEq)]
+pub struct Endpoint {
+    // host:port to connect to
+    pub address: String,
+    // Host header, as written in the URL
+    pub host: String,
+    // Path prefix of the server, without a trailing slash
+    pub base_path: String,
+}
+
+impl Endpoint {
+    // `key` names the config key the URL came from, for the errors
+    pub fn parse(key: &str, url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} {:?} must be an http:// URL; HTTPS isn't supported in this build",
+                key,
+                url
+            )
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(anyhow::anyhow!("{} {:?} has no host", key, url));
+        }
+        let address = match authority.rsplit_once(':') {
+            // The colons of a bracketed IPv6 address aren't a port
+            Some((_, port)) if !authority.ends_with(']') => {
+                port.parse::<u16>()
+                    .map_err(|_| anyhow::anyhow!("{} {:?} has a bad port", key, url))?;
+                authority.to_string()
+            }
+            _ => format!("{}:80", authority),
+        };
+        Ok(Self {
+            address,
+            host: authority.to_string(),
+            base_path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // POSTs `body` to `path` under the base path on a connection of its own;
+    // returns the status and the body of the response
+    pub async fn post(
+        &self,
+        path: &str,
+        content_type: &str,
+        accept: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>)> {
+        let head = format!(
+            "POST {}{} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: {}\r\n\
+             Accept: {}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.base_path,
+            path,
+            self.host,
+            content_type,
+            accept,
+            body.len(),
+        );
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        parse_response(&response)
+    }
+}
+
+// Status code and body of an HTTP/1.1 response read to the end of the
+// connection
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>)> {
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("Server sent an incomplete response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Server sent a malformed status line"))?;
+    let chunked = head.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_vec()
+    };
+    Ok((status, body))
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| anyhow::anyhow!("Server sent a truncated chunk"))?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| anyhow::anyhow!("Server sent a bad chunk size {:?}", size))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size {
+            return Err(anyhow::anyhow!("Server sent a truncated chunk"));
+        }
+        out.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_parsing() {
+        let parse = |url| Endpoint::parse("schema_registry.url", url);
+        assert_eq!(
+            parse("http://registry:8081").unwrap(),
+            Endpoint {
+                address: "registry:8081".to_string(),
+                host: "registry:8081".to_string(),
+                base_path: String::new(),
+            }
+        );
+        assert_eq!(
+            parse("http://registry/api/").unwrap(),
+            Endpoint {
+                address: "registry:80".to_string(),
+                host: "registry".to_string(),
+                base_path: "/api".to_string(),
+            }
+        );
+        assert_eq!(parse("http://[::1]").unwrap().address, "[::1]:80");
+        assert!(parse("https://registry:8081").is_err());
+        let err = parse("http://registry:port").unwrap_err().to_string();
+        assert!(err.contains("schema_registry.url"), "{}", err);
+    }
+}
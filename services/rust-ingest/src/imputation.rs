@@ -0,0 +1,203 @@
+use crate::{bounded_store::BoundedStore, proto::telemetry::Telemetry};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+// Where the value for a missing expected metric comes from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum DefaultSource {
+    Constant { value: f64 },
+    // The device's most recent reported value, if there is one
+    LastValue,
+    // Expected, but left missing
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImputationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // device type -> expected metric -> how to fill it when missing
+    #[serde(default)]
+    pub expected: HashMap<String, HashMap<String, DefaultSource>>,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for ImputationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            expected: HashMap::new(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    86_400
+}
+
+// Fills gaps for devices whose type declares an expected metric set, for
+// downstream models that need every metric present. Imputed records are
+// tagged `imputed=true` and list the filled metrics in `imputed_metrics`.
+pub struct Imputer {
+    config: ImputationConfig,
+    last_values: Mutex<BoundedStore<HashMap<String, f64>>>,
+}
+
+impl Imputer {
+    pub fn new(config: ImputationConfig) -> Self {
+        let last_values = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            config,
+            last_values: Mutex::new(last_values),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.last_values.lock().unwrap().remove(device_id)
+    }
+
+    pub fn apply(&self, telemetry: &mut Telemetry, device_type: Option<&str>) {
+        self.apply_at(telemetry, device_type, Instant::now())
+    }
+
+    fn apply_at(&self, telemetry: &mut Telemetry, device_type: Option<&str>, now: Instant) {
+        let Some(expected) = device_type.and_then(|t| self.config.expected.get(t)) else {
+            return;
+        };
+
+        let mut last_values = self.last_values.lock().unwrap();
+        let last = last_values.get_or_insert_with(&telemetry.device_id, now, HashMap::new);
+
+        let mut imputed = Vec::new();
+        for (metric, source) in expected {
+            if let Some(value) = telemetry.metrics.get(metric) {
+                last.insert(metric.clone(), *value);
+                continue;
+            }
+            let value = match source {
+                DefaultSource::Constant { value } => Some(*value),
+                DefaultSource::LastValue => last.get(metric).copied(),
+                DefaultSource::None => None,
+            };
+            if let Some(value) = value {
+                telemetry.metrics.insert(metric.clone(), value);
+                imputed.push(metric.as_str());
+            }
+        }
+
+        if !imputed.is_empty() {
+            imputed.sort_unstable();
+            debug!(
+                "Imputed metrics [{}] for device {}",
+                imputed.join(", "),
+                telemetry.device_id
+            );
+            telemetry
+                .tags
+                .insert("imputed".to_string(), "true".to_string());
+            telemetry
+                .tags
+                .insert("imputed_metrics".to_string(), imputed.join(","));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imputer() -> Imputer {
+        let expected = HashMap::from([(
+            "thermostat".to_string(),
+            HashMap::from([
+                ("temperature".to_string(), DefaultSource::LastValue),
+                (
+                    "humidity".to_string(),
+                    DefaultSource::Constant { value: 50.0 },
+                ),
+                ("setpoint".to_string(), DefaultSource::None),
+            ]),
+        )]);
+        Imputer::new(ImputationConfig {
+            enabled: true,
+            expected,
+            ..Default::default()
+        })
+    }
+
+    fn reading(metrics: &[(&str, f64)]) -> Telemetry {
+        Telemetry {
+            device_id: "tstat-1".to_string(),
+            ts: 0,
+            metrics: metrics.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            raw: Vec::new(),
+            tags: HashMap::new(),
+            metadata: None,
+            samples: Vec::new(),
+            flag_metrics: HashMap::new(),
+            text_metrics: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_constant_imputation() {
+        let imputer = imputer();
+        let mut telemetry = reading(&[("temperature", 21.0)]);
+        imputer.apply_at(&mut telemetry, Some("thermostat"), Instant::now());
+
+        assert_eq!(telemetry.metrics["humidity"], 50.0);
+        assert!(!telemetry.metrics.contains_key("setpoint"));
+        assert_eq!(telemetry.tags["imputed"], "true");
+        assert_eq!(telemetry.tags["imputed_metrics"], "humidity");
+    }
+
+    #[test]
+    fn test_last_value_imputation() {
+        let imputer = imputer();
+        let now = Instant::now();
+
+        // No previous value yet, so temperature stays missing
+        let mut first = reading(&[("humidity", 40.0)]);
+        imputer.apply_at(&mut first, Some("thermostat"), now);
+        assert!(!first.metrics.contains_key("temperature"));
+        assert!(!first.tags.contains_key("imputed"));
+
+        let mut second = reading(&[("temperature", 22.5), ("humidity", 41.0)]);
+        imputer.apply_at(&mut second, Some("thermostat"), now);
+        assert!(second.tags.is_empty());
+
+        let mut third = reading(&[("humidity", 42.0)]);
+        imputer.apply_at(&mut third, Some("thermostat"), now);
+        assert_eq!(third.metrics["temperature"], 22.5);
+        assert_eq!(third.tags["imputed_metrics"], "temperature");
+    }
+
+    #[test]
+    fn test_devices_without_expected_set_are_untouched() {
+        let imputer = imputer();
+        let mut telemetry = reading(&[("temperature", 21.0)]);
+        imputer.apply_at(&mut telemetry, None, Instant::now());
+        imputer.apply_at(&mut telemetry, Some("gps"), Instant::now());
+        assert_eq!(telemetry.metrics.len(), 1);
+        assert!(telemetry.tags.is_empty());
+    }
+}
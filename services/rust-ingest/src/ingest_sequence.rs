@@ -0,0 +1,128 @@
+use crate::proto::telemetry::Telemetry;
+use prost_types::{value::Kind, Value};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Metadata fields the node id and sequence number are written to
+pub const NODE_FIELD: &str = "ingestion_node";
+pub const SEQUENCE_FIELD: &str = "ingest_sequence";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IngestSequenceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Defaults to ingestion_node_id
+    #[serde(default)]
+    pub node_id: Option<String>,
+}
+
+// Stamps each forwarded record with this node's id and a node-scoped
+// sequence number, so consumers can order records within a node and
+// deduplicate without relying on Kafka offsets. This is unrelated to any
+// sequence numbers devices send themselves.
+//
+// The counter lives in memory only: it starts again from 1 whenever the
+// process starts, so a consumer seeing a node's sequence go backwards should
+// treat it as a restart, not as reordering. Numbers are taken when the
+// record is encoded, so one that then fails to send leaves a gap.
+pub struct IngestSequencer {
+    node_id: String,
+    next: AtomicU64,
+}
+
+impl IngestSequencer {
+    // `default_node_id` is the ingestion node id, used unless the config
+    // names one of its own
+    pub fn new(config: &IngestSequenceConfig, default_node_id: &str) -> Self {
+        let node_id = config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| default_node_id.to_string());
+        Self {
+            node_id,
+            next: AtomicU64::new(1),
+        }
+    }
+
+    // Returns the sequence number the record was given
+    pub fn stamp(&self, telemetry: &mut Telemetry) -> u64 {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let fields = &mut telemetry
+            .metadata
+            .get_or_insert_with(Default::default)
+            .fields;
+        fields.insert(
+            NODE_FIELD.to_string(),
+            Value {
+                kind: Some(Kind::StringValue(self.node_id.clone())),
+            },
+        );
+        // Exact as a double up to 2^53, far beyond what a node will reach
+        fields.insert(
+            SEQUENCE_FIELD.to_string(),
+            Value {
+                kind: Some(Kind::NumberValue(sequence as f64)),
+            },
+        );
+        sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, sync::Arc};
+
+    fn sequencer() -> IngestSequencer {
+        IngestSequencer::new(
+            &IngestSequenceConfig {
+                enabled: true,
+                node_id: Some("ingest-0".to_string()),
+            },
+            "ignored",
+        )
+    }
+
+    #[test]
+    fn test_records_carry_node_and_sequence() {
+        let sequencer = sequencer();
+        let mut telemetry = Telemetry::default();
+        assert_eq!(sequencer.stamp(&mut telemetry), 1);
+        assert_eq!(sequencer.stamp(&mut telemetry), 2);
+
+        let fields = &telemetry.metadata.unwrap().fields;
+        assert_eq!(
+            fields[NODE_FIELD].kind,
+            Some(Kind::StringValue("ingest-0".to_string()))
+        );
+        assert_eq!(fields[SEQUENCE_FIELD].kind, Some(Kind::NumberValue(2.0)));
+
+        // A restarted node starts over
+        assert_eq!(self::sequencer().stamp(&mut Telemetry::default()), 1);
+    }
+
+    #[test]
+    fn test_sequence_is_monotonic_across_concurrent_sends() {
+        let sequencer = Arc::new(sequencer());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let sequencer = Arc::clone(&sequencer);
+                std::thread::spawn(move || {
+                    (0..1_000)
+                        .map(|_| sequencer.stamp(&mut Telemetry::default()))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for thread in threads {
+            let sequences = thread.join().unwrap();
+            // Each sender sees its own records in increasing order
+            assert!(sequences.windows(2).all(|pair| pair[0] < pair[1]));
+            seen.extend(sequences);
+        }
+        // No number is handed out twice and none is skipped
+        assert_eq!(seen, (1..=8_000).collect());
+    }
+}
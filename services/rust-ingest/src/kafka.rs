@@ -1,29 +1,1046 @@
+use crate::{
+    ack::AckMode,
+    encoding::{EncodingConfig, SCHEMA_VERSION},
+    kafka_stats::{ProducerStats, StatsContext},
+    pipeline_retry::TransientError,
+    receipts::ReceiptTicket,
+    sink::{Position, SinkRecord, TelemetrySink},
+    unit_conversion,
+};
 use anyhow::Result;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use async_trait::async_trait;
+use prometheus::Registry;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
 use rdkafka::ClientConfig;
-use std::time::Duration;
+use serde::Deserialize;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
 
-pub fn create_producer(brokers: &str) -> Result<FutureProducer> {
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", brokers)
-        .set("message.timeout.ms", "5000")
-        .create()?;
+// Producer queue tuning for high-throughput deployments. Defaults match
+// librdkafka's own defaults, so an unset section changes nothing.
+//
+// The local queue is where records wait while librdkafka batches them per
+// partition (linger.ms / batch.size) and while delivery reports are pending.
+// When it fills, sends fail with QueueFull, so raise these together with any
+// increase in linger or batch size: a longer linger holds more records in the
+// queue at once. max_messages and max_kbytes are both hard caps, whichever
+// is hit first wins. socket_send_buffer_bytes only matters for large batches
+// over high-latency links; 0 keeps the OS default.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProducerSettings {
+    #[serde(default = "default_queue_buffering_max_messages")]
+    pub queue_buffering_max_messages: u32,
+    #[serde(default = "default_queue_buffering_max_kbytes")]
+    pub queue_buffering_max_kbytes: u32,
+    #[serde(default)]
+    pub socket_send_buffer_bytes: u32,
+    // How long librdkafka keeps trying to deliver a record before failing it
+    #[serde(default = "default_message_timeout_ms")]
+    pub message_timeout_ms: u32,
+    // How long a send waits for room when the local queue is full; 0 fails
+    // it with QueueFull straight away
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+    // Attempts per send, the first included. Sends failing with a broker
+    // error that usually clears by itself (a leader election, a broker
+    // restarting) are tried again after an exponential backoff with jitter;
+    // 1 turns this off. A send that timed out may still have reached the
+    // broker, so retrying can write a record twice.
+    #[serde(default = "default_send_max_attempts")]
+    pub send_max_attempts: u32,
+    // Doubled after every attempt, up to send_retry_max_backoff_ms
+    #[serde(default = "default_send_retry_backoff_ms")]
+    pub send_retry_backoff_ms: u64,
+    #[serde(default = "default_send_retry_max_backoff_ms")]
+    pub send_retry_max_backoff_ms: u64,
+    // How often librdkafka reports its queue and broker statistics, which
+    // show up on /metrics; 0 turns the reports off
+    #[serde(default)]
+    pub statistics_interval_ms: u32,
+    // Batching and compression trade latency for throughput: records wait
+    // up to linger_ms for a batch of up to batch_size bytes per partition,
+    // which is then compressed as a whole
+    #[serde(default)]
+    pub compression_type: CompressionType,
+    #[serde(default = "default_linger_ms")]
+    pub linger_ms: u32,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+    // Has the broker drop the duplicates librdkafka's own retries would
+    // otherwise write, and keep each partition's records in send order
+    // across those retries. It needs acks=all, so it applies to the default
+    // producer; requests with a weaker ack mode go out without it. In-flight
+    // requests per broker are capped at 5, which can lower throughput on
+    // high-latency links. A send_max_attempts retry is a new send the broker
+    // can't tell from the first, so it can still write a record twice.
+    #[serde(default)]
+    pub enable_idempotence: bool,
+    // Further librdkafka properties, e.g. "client.id". Ones set elsewhere in
+    // this config can't be overridden here.
+    #[serde(default)]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Default for ProducerSettings {
+    fn default() -> Self {
+        Self {
+            queue_buffering_max_messages: default_queue_buffering_max_messages(),
+            queue_buffering_max_kbytes: default_queue_buffering_max_kbytes(),
+            socket_send_buffer_bytes: 0,
+            message_timeout_ms: default_message_timeout_ms(),
+            queue_timeout_ms: 0,
+            send_max_attempts: default_send_max_attempts(),
+            send_retry_backoff_ms: default_send_retry_backoff_ms(),
+            send_retry_max_backoff_ms: default_send_retry_max_backoff_ms(),
+            statistics_interval_ms: 0,
+            compression_type: CompressionType::default(),
+            linger_ms: default_linger_ms(),
+            batch_size: default_batch_size(),
+            enable_idempotence: false,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+// Codecs the bundled librdkafka is built with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionType {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+}
+
+impl CompressionType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Snappy => "snappy",
+            Self::Lz4 => "lz4",
+        }
+    }
+}
+
+// Properties the service sets itself
+const MANAGED_PROPERTIES: [&str; 11] = [
+    "bootstrap.servers",
+    "acks",
+    "message.timeout.ms",
+    "queue.buffering.max.messages",
+    "queue.buffering.max.kbytes",
+    "socket.send.buffer.bytes",
+    "statistics.interval.ms",
+    "compression.type",
+    "linger.ms",
+    "batch.size",
+    "enable.idempotence",
+];
+
+// The most librdkafka allows with idempotence on
+const IDEMPOTENT_MAX_IN_FLIGHT: u32 = 5;
+
+fn default_queue_buffering_max_messages() -> u32 {
+    100_000
+}
+
+fn default_queue_buffering_max_kbytes() -> u32 {
+    1_048_576
+}
+
+fn default_message_timeout_ms() -> u32 {
+    5_000
+}
+
+fn default_linger_ms() -> u32 {
+    5
+}
+
+fn default_batch_size() -> u32 {
+    1_000_000
+}
+
+fn default_send_max_attempts() -> u32 {
+    1
+}
+
+fn default_send_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_send_retry_max_backoff_ms() -> u64 {
+    2_000
+}
+
+impl ProducerSettings {
+    pub fn validate(&self) -> Result<()> {
+        // Ranges follow librdkafka's accepted values
+        if !(1..=2_147_483_647).contains(&self.queue_buffering_max_messages) {
+            return Err(anyhow::anyhow!(
+                "queue_buffering_max_messages must be between 1 and 2147483647, got {}",
+                self.queue_buffering_max_messages
+            ));
+        }
+        if !(1..=2_147_483_647).contains(&self.queue_buffering_max_kbytes) {
+            return Err(anyhow::anyhow!(
+                "queue_buffering_max_kbytes must be between 1 and 2147483647, got {}",
+                self.queue_buffering_max_kbytes
+            ));
+        }
+        if self.socket_send_buffer_bytes != 0
+            && !(1024..=100_000_000).contains(&self.socket_send_buffer_bytes)
+        {
+            return Err(anyhow::anyhow!(
+                "socket_send_buffer_bytes must be 0 (OS default) or between 1024 and 100000000, got {}",
+                self.socket_send_buffer_bytes
+            ));
+        }
+        if self.message_timeout_ms > 2_147_483_647 {
+            return Err(anyhow::anyhow!(
+                "message_timeout_ms must be at most 2147483647, got {}",
+                self.message_timeout_ms
+            ));
+        }
+        if self.statistics_interval_ms > 86_400_000 {
+            return Err(anyhow::anyhow!(
+                "statistics_interval_ms must be at most 86400000, got {}",
+                self.statistics_interval_ms
+            ));
+        }
+        if self.linger_ms > 900_000 {
+            return Err(anyhow::anyhow!(
+                "linger_ms must be at most 900000, got {}",
+                self.linger_ms
+            ));
+        }
+        if !(1..=2_147_483_647).contains(&self.batch_size) {
+            return Err(anyhow::anyhow!(
+                "batch_size must be between 1 and 2147483647, got {}",
+                self.batch_size
+            ));
+        }
+        if self.send_max_attempts == 0 {
+            return Err(anyhow::anyhow!(
+                "send_max_attempts must be at least 1, got 0"
+            ));
+        }
+        if let Some(in_flight) = self.extra.get("max.in.flight.requests.per.connection") {
+            if self.enable_idempotence
+                && !in_flight
+                    .parse::<u32>()
+                    .is_ok_and(|n| n <= IDEMPOTENT_MAX_IN_FLIGHT)
+            {
+                return Err(anyhow::anyhow!(
+                    "max.in.flight.requests.per.connection must be at most {} with enable_idempotence, got {}",
+                    IDEMPOTENT_MAX_IN_FLIGHT,
+                    in_flight
+                ));
+            }
+        }
+        if let Some(property) = self
+            .extra
+            .keys()
+            .find(|property| MANAGED_PROPERTIES.contains(&property.as_str()))
+        {
+            return Err(anyhow::anyhow!(
+                "kafka_producer.extra can't set {}; it is configured elsewhere",
+                property
+            ));
+        }
+        Ok(())
+    }
+
+    // Whether a producer with this acks setting is idempotent
+    pub fn idempotent_for(&self, acks: &str) -> bool {
+        self.enable_idempotence && acks == "all"
+    }
+
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_ms)
+    }
+
+    fn apply(&self, config: &mut ClientConfig) {
+        for (property, value) in &self.extra {
+            config.set(property, value);
+        }
+        config
+            .set("message.timeout.ms", self.message_timeout_ms.to_string())
+            .set(
+                "queue.buffering.max.messages",
+                self.queue_buffering_max_messages.to_string(),
+            )
+            .set(
+                "queue.buffering.max.kbytes",
+                self.queue_buffering_max_kbytes.to_string(),
+            )
+            .set(
+                "socket.send.buffer.bytes",
+                self.socket_send_buffer_bytes.to_string(),
+            )
+            .set(
+                "statistics.interval.ms",
+                self.statistics_interval_ms.to_string(),
+            )
+            .set("compression.type", self.compression_type.as_str())
+            .set("linger.ms", self.linger_ms.to_string())
+            .set("batch.size", self.batch_size.to_string());
+    }
+}
+
+pub type KafkaProducer = FutureProducer<StatsContext>;
+
+// `acks` is librdkafka's setting: "0", "1" or "all"
+pub fn create_producer(
+    brokers: &str,
+    settings: &ProducerSettings,
+    acks: &str,
+    stats: &Arc<ProducerStats>,
+) -> Result<KafkaProducer> {
+    settings.validate()?;
+
+    let producer = client_config(brokers, settings, acks)
+        .create_with_context(StatsContext::new(acks, Arc::clone(stats)))?;
     Ok(producer)
 }
 
+fn client_config(brokers: &str, settings: &ProducerSettings, acks: &str) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config.set("bootstrap.servers", brokers).set("acks", acks);
+    settings.apply(&mut config);
+    // librdkafka lowers max.in.flight to what idempotence allows by itself
+    config.set(
+        "enable.idempotence",
+        settings.idempotent_for(acks).to_string(),
+    );
+    config
+}
+
+// A `None` payload makes a tombstone: a record with no value at all, which
+// log compaction treats as a delete. An empty payload is an ordinary record.
+fn build_record<'a>(
+    topic: &'a str,
+    key: &'a str,
+    payload: Option<&'a [u8]>,
+    headers: Option<OwnedHeaders>,
+) -> FutureRecord<'a, str, [u8]> {
+    let mut record = FutureRecord::to(topic).key(key);
+    if let Some(payload) = payload {
+        record = record.payload(payload);
+    }
+    if let Some(headers) = headers {
+        record = record.headers(headers);
+    }
+    record
+}
+
+// Returns the partition and offset the record was written to. Retryable
+// failures are retried as `settings` allow; the last error is returned.
 pub async fn send_message(
-    producer: &FutureProducer,
+    producer: &KafkaProducer,
     topic: &str,
     key: &str,
-    payload: Vec<u8>,
+    payload: Option<&[u8]>,
+    headers: Option<OwnedHeaders>,
+    settings: &ProducerSettings,
+) -> Result<(i32, i64)> {
+    let mut backoff = Duration::from_millis(settings.send_retry_backoff_ms);
+    let max_backoff = Duration::from_millis(settings.send_retry_max_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        let record = build_record(topic, key, payload, headers.clone());
+        match producer.send(record, settings.queue_timeout()).await {
+            Ok(position) => return Ok(position),
+            Err((err, _)) if attempt < settings.send_max_attempts && is_retryable(&err) => {
+                let delay = with_jitter(backoff);
+                attempt += 1;
+                warn!(
+                    "Retrying Kafka send for {} on {} in {:?} (attempt {} of {}): {}",
+                    key, topic, delay, attempt, settings.send_max_attempts, err
+                );
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(max_backoff);
+            }
+            Err((err, _)) => return Err(send_error(err)),
+        }
+    }
+}
+
+// Errors from a cluster that is briefly unable to take writes. Anything
+// about the record itself, e.g. MessageSizeTooLarge, fails the same way
+// every time. A full local queue is left to queue_timeout_ms and the
+// pipeline retry, which know the record was never enqueued.
+fn is_retryable(err: &KafkaError) -> bool {
+    matches!(
+        err.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::LeaderNotAvailable
+                | RDKafkaErrorCode::NotLeaderForPartition
+                | RDKafkaErrorCode::BrokerNotAvailable
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::NetworkException
+                | RDKafkaErrorCode::NotEnoughReplicas
+                | RDKafkaErrorCode::NotEnoughReplicasAfterAppend
+                | RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::MessageTimedOut
+        )
+    )
+}
+
+// Somewhere between half and all of `backoff`, so sends that failed
+// together don't all come back at once
+fn with_jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff.mul_f64(0.5 + random as f64 / u64::MAX as f64 / 2.0)
+}
+
+// Without broker acks (acks=0) the offset isn't known and comes back negative
+fn record_delivery(receipt: Option<&ReceiptTicket>, (partition, offset): (i32, i64)) {
+    if let Some(receipt) = receipt {
+        receipt.delivered(Some(partition), (offset >= 0).then_some(offset));
+    }
+}
+
+fn report_position(slot: Option<&OnceLock<Position>>, (partition, offset): (i32, i64)) {
+    if let Some(slot) = slot {
+        let _ = slot.set(Position {
+            partition,
+            offset: (offset >= 0).then_some(offset),
+        });
+    }
+}
+
+// TransientError stage of a send refused because the local queue is full
+pub const QUEUE_FULL_STAGE: &str = "kafka send";
+
+// A full local queue means the record was never enqueued, so it is safe to
+// try again; any later failure may have reached the broker
+fn send_error(err: KafkaError) -> anyhow::Error {
+    match err {
+        KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull) => TransientError {
+            stage: QUEUE_FULL_STAGE,
+            reason: err.to_string(),
+        }
+        .into(),
+        err => err.into(),
+    }
+}
+
+pub fn is_queue_full(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TransientError>()
+        .is_some_and(|e| e.stage == QUEUE_FULL_STAGE)
+}
+
+// Blocks until everything queued in the producer has been delivered or
+// `timeout` passes. Run off the async runtime.
+pub fn flush_producer(producer: &KafkaProducer, timeout: Duration) -> Result<()> {
+    let queued = producer.in_flight_count();
+    if queued > 0 {
+        // A large number here means sends were outpacing the brokers
+        info!(
+            "Flushing Kafka producer with {} messages still queued",
+            queued
+        );
+    }
+    producer.flush(timeout)?;
+    Ok(())
+}
+
+// Asks the cluster for its metadata, which needs a broker to answer within
+// `timeout`. Blocks, so run off the async runtime.
+pub fn check_brokers(producer: &KafkaProducer, timeout: Duration) -> Result<()> {
+    let metadata = producer.client().fetch_metadata(None, timeout)?;
+    if metadata.brokers().is_empty() {
+        return Err(anyhow::anyhow!("Kafka cluster reported no brokers"));
+    }
+    Ok(())
+}
+
+// Enqueue without waiting for the delivery report; a failed delivery is
+// logged, and recorded on the receipt when there is one
+fn enqueue(
+    producer: &KafkaProducer,
+    topic: &str,
+    key: &str,
+    payload: &[u8],
+    headers: Option<OwnedHeaders>,
+    receipt: Option<&ReceiptTicket>,
 ) -> Result<()> {
-    producer
-        .send(
-            FutureRecord::to(topic)
-                .key(key)
-                .payload(&payload),
-            Duration::from_secs(0),
+    let record = build_record(topic, key, Some(payload), headers);
+    let delivery = producer
+        .send_result(record)
+        .map_err(|(err, _)| send_error(err))?;
+    let (topic, key) = (topic.to_string(), key.to_string());
+    let receipt = receipt.cloned();
+    if let Some(receipt) = &receipt {
+        receipt.report_later();
+    }
+    tokio::spawn(async move {
+        let failure = match delivery.await {
+            Ok(Ok(position)) => {
+                record_delivery(receipt.as_ref(), position);
+                return;
+            }
+            Ok(Err((e, _))) => {
+                warn!(
+                    "Queued record for {} on {} was not delivered: {}",
+                    key, topic, e
+                );
+                e.to_string()
+            }
+            Err(_) => {
+                warn!(
+                    "Queued record for {} on {} was dropped by the producer",
+                    key, topic
+                );
+                "dropped by the producer".to_string()
+            }
+        };
+        if let Some(receipt) = receipt {
+            receipt.failed(failure);
+        }
+    });
+    Ok(())
+}
+
+// Sends with acks=all by default. Requests asking for a weaker ack mode
+// use a producer configured for it, created on first use.
+pub struct KafkaSink {
+    producer: KafkaProducer,
+    brokers: String,
+    settings: ProducerSettings,
+    leader_producer: OnceCell<KafkaProducer>,
+    unacked_producer: OnceCell<KafkaProducer>,
+    stats: Arc<ProducerStats>,
+    // For the record headers
+    encoding: EncodingConfig,
+    node_id: String,
+}
+
+impl KafkaSink {
+    pub fn new(
+        brokers: &str,
+        settings: &ProducerSettings,
+        encoding: &EncodingConfig,
+        node_id: &str,
+    ) -> Result<Self> {
+        let stats = Arc::new(ProducerStats::default());
+        if settings.enable_idempotence {
+            info!("Kafka idempotent producer is enabled for acks=all sends");
+        } else {
+            info!("Kafka idempotent producer is disabled");
+        }
+        Ok(Self {
+            producer: create_producer(brokers, settings, "all", &stats)?,
+            brokers: brokers.to_string(),
+            settings: settings.clone(),
+            encoding: encoding.clone(),
+            node_id: node_id.to_string(),
+            leader_producer: OnceCell::new(),
+            unacked_producer: OnceCell::new(),
+            stats,
+        })
+    }
+
+    async fn producer_for(&self, ack: AckMode) -> Result<&KafkaProducer> {
+        let (cell, acks) = match ack {
+            AckMode::Queued | AckMode::All => return Ok(&self.producer),
+            AckMode::Leader => (&self.leader_producer, "1"),
+            AckMode::None => (&self.unacked_producer, "0"),
+        };
+        cell.get_or_try_init(|| async {
+            create_producer(&self.brokers, &self.settings, acks, &self.stats)
+        })
+        .await
+    }
+
+    fn producers(&self) -> impl Iterator<Item = &KafkaProducer> {
+        std::iter::once(&self.producer)
+            .chain(self.leader_producer.get())
+            .chain(self.unacked_producer.get())
+    }
+}
+
+// Collects string-valued record headers ahead of the OwnedHeaders librdkafka
+// takes, which can't be inspected as easily
+#[derive(Debug, Default)]
+pub struct HeadersBuilder {
+    headers: Vec<(&'static str, String)>,
+}
+
+impl HeadersBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn header(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.headers.push((key, value.to_string()));
+        self
+    }
+
+    pub fn header_opt(self, key: &'static str, value: Option<impl ToString>) -> Self {
+        match value {
+            Some(value) => self.header(key, value),
+            None => self,
+        }
+    }
+
+    pub fn build(self) -> OwnedHeaders {
+        self.headers
+            .iter()
+            .fold(OwnedHeaders::new(), |owned, (key, value)| {
+                owned.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            })
+    }
+}
+
+// Tells consumers how to read a record and where it came from, so formats
+// and schemas can change without them guessing
+fn record_headers(
+    record: &SinkRecord<'_>,
+    encoding: &EncodingConfig,
+    node_id: &str,
+) -> HeadersBuilder {
+    HeadersBuilder::new()
+        .header(
+            "content-type",
+            encoding.format_for(record.topic).content_type(),
+        )
+        .header("schema-version", SCHEMA_VERSION)
+        .header("ingestion-node", node_id)
+        // Consumers can discard records whose TTL has passed by the time they read them
+        .header_opt("ttl-expires-at", record.expires_at)
+        // Ties the record to the HTTP request (X-Request-Id) it came in with
+        .header_opt("request-id", record.request_id)
+        // Metrics converted to their canonical unit, and what they were sent in
+        .header_opt(
+            "original-units",
+            unit_conversion::original_units(record.telemetry),
+        )
+}
+
+// Upper bound on waiting for outstanding deliveries in a flush
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[async_trait]
+impl TelemetrySink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let headers = Some(record_headers(&record, &self.encoding, &self.node_id).build());
+        if record.ack == AckMode::Queued {
+            return enqueue(
+                &self.producer,
+                record.topic,
+                record.key,
+                record.payload,
+                headers,
+                record.receipt,
+            );
+        }
+        let position = send_message(
+            self.producer_for(record.ack).await?,
+            record.topic,
+            record.key,
+            Some(record.payload),
+            headers,
+            &self.settings,
         )
         .await?;
-    Ok(())
+        record_delivery(record.receipt, position);
+        report_position(record.position, position);
+        Ok(())
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        send_message(
+            &self.producer,
+            topic,
+            key,
+            Some(payload),
+            None,
+            &self.settings,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        send_message(&self.producer, topic, key, None, None, &self.settings).await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for producer in self.producers() {
+            let producer = producer.clone();
+            tokio::task::spawn_blocking(move || flush_producer(&producer, FLUSH_TIMEOUT)).await??;
+        }
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.producers()
+            .map(|producer| producer.in_flight_count().max(0) as usize)
+            .sum()
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.stats.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || check_brokers(&producer, timeout)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::OutputFormat, priority::Priority, proto::telemetry::Telemetry};
+
+    // What `headers` holds, in order, as strings
+    fn header_pairs(headers: &OwnedHeaders) -> Vec<(String, String)> {
+        use rdkafka::message::Headers;
+        headers
+            .iter()
+            .map(|header| {
+                let value = String::from_utf8(header.value.unwrap().to_vec()).unwrap();
+                (header.key.to_string(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_headers_builder_skips_absent_values() {
+        let headers = HeadersBuilder::new()
+            .header("schema-version", 1)
+            .header_opt("request-id", None::<&str>)
+            .header_opt("ttl-expires-at", Some(1700000000000i64))
+            .build();
+        assert_eq!(
+            header_pairs(&headers),
+            vec![
+                ("schema-version".to_string(), "1".to_string()),
+                ("ttl-expires-at".to_string(), "1700000000000".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_headers_describe_the_record() {
+        let encoding = EncodingConfig {
+            default: OutputFormat::Protobuf,
+            topics: [("telemetry.json".to_string(), OutputFormat::Json)].into(),
+            schema_id: None,
+        };
+        let telemetry = Telemetry::default();
+        let record = SinkRecord {
+            topic: "telemetry",
+            key: "dev-1",
+            payload: &[],
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        };
+        let pairs = |record: &SinkRecord<'_>| {
+            header_pairs(&record_headers(record, &encoding, "ingest-0").build())
+        };
+        let expected = |content_type: &str| {
+            vec![
+                ("content-type".to_string(), content_type.to_string()),
+                ("schema-version".to_string(), SCHEMA_VERSION.to_string()),
+                ("ingestion-node".to_string(), "ingest-0".to_string()),
+            ]
+        };
+        assert_eq!(pairs(&record), expected("application/x-protobuf"));
+
+        let mut json = expected("application/json");
+        json.push(("ttl-expires-at".to_string(), "1700000000000".to_string()));
+        json.push(("request-id".to_string(), "trace-42".to_string()));
+        assert_eq!(
+            pairs(&SinkRecord {
+                topic: "telemetry.json",
+                expires_at: Some(1700000000000),
+                request_id: Some("trace-42"),
+                ..record
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn test_converted_units_are_named_in_a_header() {
+        let converter = crate::unit_conversion::UnitConverter::new(
+            &serde_json::from_value(serde_json::json!({
+                "enabled": true,
+                "rules": [{ "metric": "temperature", "from": "fahrenheit", "to": "celsius" }]
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let mut telemetry = Telemetry {
+            metrics: [("temperature".to_string(), 50.0)].into(),
+            ..Default::default()
+        };
+        let units = [("temperature".to_string(), "fahrenheit".to_string())].into();
+        converter.apply(&mut telemetry, &units).unwrap();
+
+        let record = SinkRecord {
+            topic: "telemetry",
+            key: "dev-1",
+            payload: &[],
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        };
+        let headers =
+            header_pairs(&record_headers(&record, &EncodingConfig::default(), "ingest-0").build());
+        assert_eq!(
+            headers.last().unwrap(),
+            &(
+                "original-units".to_string(),
+                "temperature=fahrenheit".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_position_leaves_out_an_unknown_offset() {
+        let slot = OnceLock::new();
+        report_position(Some(&slot), (3, 42));
+        assert_eq!(
+            slot.get(),
+            Some(&Position {
+                partition: 3,
+                offset: Some(42)
+            })
+        );
+
+        // acks=0: the broker never says where the record went
+        let slot = OnceLock::new();
+        report_position(Some(&slot), (1, -1));
+        assert_eq!(slot.get().unwrap().offset, None);
+    }
+
+    #[test]
+    fn test_tombstone_has_no_payload() {
+        let tombstone = build_record("telemetry", "dev-1", None, None);
+        assert_eq!(tombstone.key, Some("dev-1"));
+        assert_eq!(tombstone.payload, None);
+
+        // Not the same as a record with an empty value
+        let empty = build_record("telemetry", "dev-1", Some(&[]), None);
+        assert_eq!(empty.payload, Some(&[][..]));
+    }
+
+    #[test]
+    fn test_only_a_full_queue_is_transient() {
+        let full = send_error(KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull));
+        assert!(full.is::<TransientError>());
+        let timed_out = send_error(KafkaError::MessageProduction(
+            RDKafkaErrorCode::MessageTimedOut,
+        ));
+        assert!(!timed_out.is::<TransientError>());
+    }
+
+    #[test]
+    fn test_only_transient_broker_errors_are_retried() {
+        let production = |code| KafkaError::MessageProduction(code);
+        assert!(is_retryable(&production(
+            RDKafkaErrorCode::NotLeaderForPartition
+        )));
+        assert!(is_retryable(&production(RDKafkaErrorCode::MessageTimedOut)));
+        assert!(!is_retryable(&production(
+            RDKafkaErrorCode::MessageSizeTooLarge
+        )));
+        assert!(!is_retryable(&production(RDKafkaErrorCode::QueueFull)));
+
+        let backoff = Duration::from_millis(100);
+        for _ in 0..20 {
+            let delay = with_jitter(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_sends_give_up_after_max_attempts() {
+        let settings = ProducerSettings {
+            message_timeout_ms: 100,
+            send_max_attempts: 3,
+            send_retry_backoff_ms: 10,
+            ..Default::default()
+        };
+        let producer = create_producer(
+            "127.0.0.1:1",
+            &settings,
+            "all",
+            &Arc::new(ProducerStats::default()),
+        )
+        .unwrap();
+        let started = std::time::Instant::now();
+        let err = send_message(
+            &producer,
+            "telemetry",
+            "dev-1",
+            Some(b"{}"),
+            None,
+            &settings,
+        )
+        .await
+        .unwrap_err();
+        // Every attempt waits out the message timeout before failing
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(err.to_string().contains("timed out"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_brokers_are_not_ready() {
+        let settings = ProducerSettings::default();
+        let sink = KafkaSink::new(
+            "127.0.0.1:1",
+            &settings,
+            &EncodingConfig::default(),
+            "ingest-0",
+        )
+        .unwrap();
+        let started = std::time::Instant::now();
+        assert!(sink.check_ready(Duration::from_millis(200)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_producer_settings_defaults_are_valid() {
+        let settings = ProducerSettings::default();
+        assert!(settings.validate().is_ok());
+
+        let mut config = ClientConfig::new();
+        settings.apply(&mut config);
+        assert_eq!(config.get("queue.buffering.max.messages"), Some("100000"));
+        assert_eq!(config.get("queue.buffering.max.kbytes"), Some("1048576"));
+        assert_eq!(config.get("socket.send.buffer.bytes"), Some("0"));
+        assert_eq!(config.get("message.timeout.ms"), Some("5000"));
+        assert_eq!(config.get("statistics.interval.ms"), Some("0"));
+        assert_eq!(config.get("compression.type"), Some("none"));
+        assert_eq!(config.get("linger.ms"), Some("5"));
+        assert_eq!(config.get("batch.size"), Some("1000000"));
+        assert_eq!(settings.queue_timeout(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_producer_settings_reject_nonsensical_values() {
+        let zero_queue = ProducerSettings {
+            queue_buffering_max_messages: 0,
+            ..Default::default()
+        };
+        assert!(zero_queue.validate().is_err());
+
+        let tiny_socket_buffer = ProducerSettings {
+            socket_send_buffer_bytes: 16,
+            ..Default::default()
+        };
+        assert!(tiny_socket_buffer.validate().is_err());
+
+        let no_attempts = ProducerSettings {
+            send_max_attempts: 0,
+            ..Default::default()
+        };
+        assert!(no_attempts.validate().is_err());
+
+        let overridden_acks = ProducerSettings {
+            extra: BTreeMap::from([("acks".to_string(), "0".to_string())]),
+            ..Default::default()
+        };
+        assert!(overridden_acks.validate().is_err());
+
+        let huge_linger = ProducerSettings {
+            linger_ms: 1_000_000,
+            ..Default::default()
+        };
+        assert!(huge_linger.validate().is_err());
+
+        // Typed now, so only settable through its own key
+        let untyped_compression = ProducerSettings {
+            extra: BTreeMap::from([("compression.type".to_string(), "lz4".to_string())]),
+            ..Default::default()
+        };
+        assert!(untyped_compression.validate().is_err());
+    }
+
+    #[test]
+    fn test_idempotence_applies_to_the_acks_all_producer() {
+        let settings = ProducerSettings {
+            enable_idempotence: true,
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+        let idempotent = |acks: &str| {
+            client_config("localhost:9092", &settings, acks)
+                .get("enable.idempotence")
+                .map(str::to_string)
+        };
+        assert_eq!(idempotent("all").as_deref(), Some("true"));
+        assert_eq!(idempotent("1").as_deref(), Some("false"));
+        assert_eq!(idempotent("0").as_deref(), Some("false"));
+
+        let off = client_config("localhost:9092", &ProducerSettings::default(), "all");
+        assert_eq!(off.get("enable.idempotence"), Some("false"));
+
+        let too_many_in_flight = ProducerSettings {
+            enable_idempotence: true,
+            extra: BTreeMap::from([(
+                "max.in.flight.requests.per.connection".to_string(),
+                "10".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert!(too_many_in_flight.validate().is_err());
+        let without_idempotence = ProducerSettings {
+            enable_idempotence: false,
+            ..too_many_in_flight
+        };
+        assert!(without_idempotence.validate().is_ok());
+    }
+
+    #[test]
+    fn test_extra_properties_are_passed_through() {
+        let settings = ProducerSettings {
+            message_timeout_ms: 30_000,
+            extra: BTreeMap::from([
+                ("client.id".to_string(), "ingest".to_string()),
+                ("retry.backoff.ms".to_string(), "20".to_string()),
+            ]),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_ok());
+
+        let mut config = ClientConfig::new();
+        settings.apply(&mut config);
+        assert_eq!(config.get("client.id"), Some("ingest"));
+        assert_eq!(config.get("retry.backoff.ms"), Some("20"));
+        assert_eq!(config.get("message.timeout.ms"), Some("30000"));
+    }
 }
@@ -0,0 +1,269 @@
+use crate::request_metrics;
+use prometheus::{GaugeVec, Registry};
+use rdkafka::{statistics::Statistics, ClientContext};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, warn};
+
+// Share of the local queue in use at which a stats report is logged as a
+// warning: records are arriving faster than the brokers take them
+const QUEUE_WARN_RATIO: f64 = 0.8;
+
+// What the /metrics page shows from one producer's latest report
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Snapshot {
+    queued_messages: u64,
+    queued_bytes: u64,
+    max_messages: u64,
+    sent_messages: i64,
+    brokers: BTreeMap<String, BrokerSnapshot>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct BrokerSnapshot {
+    up: bool,
+    // Average over the last stats interval
+    rtt_us: i64,
+    // Requests waiting to be sent
+    outbuf: i64,
+}
+
+impl From<&Statistics> for Snapshot {
+    fn from(stats: &Statistics) -> Self {
+        Self {
+            queued_messages: stats.msg_cnt,
+            queued_bytes: stats.msg_size,
+            max_messages: stats.msg_max,
+            sent_messages: stats.txmsgs,
+            brokers: stats
+                .brokers
+                .values()
+                // librdkafka's internal placeholders, not real brokers
+                .filter(|broker| broker.nodeid >= 0)
+                .map(|broker| {
+                    let snapshot = BrokerSnapshot {
+                        up: broker.state == "UP",
+                        rtt_us: broker.rtt.as_ref().map_or(0, |rtt| rtt.avg),
+                        outbuf: broker.outbuf_cnt,
+                    };
+                    (broker.nodename.clone(), snapshot)
+                })
+                .collect(),
+        }
+    }
+}
+
+// Name, help text and value of a gauge
+type Gauge<T> = (&'static str, &'static str, fn(&T) -> f64);
+
+const PRODUCER_GAUGES: [Gauge<Snapshot>; 4] = [
+    (
+        "rust_ingest_kafka_queue_messages",
+        "Records in the producer's local queue",
+        |s| s.queued_messages as f64,
+    ),
+    (
+        "rust_ingest_kafka_queue_bytes",
+        "Bytes in the producer's local queue",
+        |s| s.queued_bytes as f64,
+    ),
+    (
+        "rust_ingest_kafka_queue_max_messages",
+        "Records the producer's local queue holds at most",
+        |s| s.max_messages as f64,
+    ),
+    (
+        "rust_ingest_kafka_sent_messages",
+        "Records sent to brokers since the producer started",
+        |s| s.sent_messages as f64,
+    ),
+];
+
+const BROKER_GAUGES: [Gauge<BrokerSnapshot>; 3] = [
+    (
+        "rust_ingest_kafka_broker_up",
+        "Whether the producer's connection to the broker is up",
+        |b| u8::from(b.up).into(),
+    ),
+    (
+        "rust_ingest_kafka_broker_rtt_seconds",
+        "Average broker round trip over the last stats interval",
+        |b| b.rtt_us as f64 / 1e6,
+    ),
+    (
+        "rust_ingest_kafka_broker_outbuf_requests",
+        "Requests waiting to be sent to the broker",
+        |b| b.outbuf as f64,
+    ),
+];
+
+// Latest librdkafka statistics of each producer, by its acks setting. Only
+// filled in when kafka_producer.statistics_interval_ms is set.
+#[derive(Default)]
+pub struct ProducerStats {
+    producers: Mutex<BTreeMap<String, Snapshot>>,
+}
+
+impl ProducerStats {
+    fn record(&self, acks: &str, stats: &Statistics) {
+        let snapshot = Snapshot::from(stats);
+        let filled = snapshot.queued_messages as f64 / snapshot.max_messages.max(1) as f64;
+        if filled >= QUEUE_WARN_RATIO {
+            warn!(
+                acks,
+                queued = snapshot.queued_messages,
+                max = snapshot.max_messages,
+                "Kafka producer queue is {:.0}% full",
+                filled * 100.0
+            );
+        } else {
+            debug!(
+                acks,
+                queued = snapshot.queued_messages,
+                queued_bytes = snapshot.queued_bytes,
+                sent = snapshot.sent_messages,
+                brokers_up = snapshot.brokers.values().filter(|b| b.up).count(),
+                "Kafka producer stats"
+            );
+        }
+        self.producers
+            .lock()
+            .unwrap()
+            .insert(acks.to_string(), snapshot);
+    }
+
+    // Gauges are read from the latest reports when the registry is
+    // gathered; none shows up before the first report
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        for (name, help, value) in PRODUCER_GAUGES {
+            let stats = Arc::clone(self);
+            request_metrics::register_live(
+                registry,
+                request_metrics::gauge_vec(name, help, &["acks"]),
+                move |gauges: &GaugeVec| {
+                    for (acks, snapshot) in stats.producers.lock().unwrap().iter() {
+                        gauges.with_label_values(&[acks]).set(value(snapshot));
+                    }
+                },
+            )?;
+        }
+        for (name, help, value) in BROKER_GAUGES {
+            let stats = Arc::clone(self);
+            request_metrics::register_live(
+                registry,
+                request_metrics::gauge_vec(name, help, &["acks", "broker"]),
+                move |gauges: &GaugeVec| {
+                    // A broker missing from the latest report loses its series
+                    gauges.reset();
+                    for (acks, snapshot) in stats.producers.lock().unwrap().iter() {
+                        for (broker, broker_snapshot) in &snapshot.brokers {
+                            gauges
+                                .with_label_values(&[acks, broker])
+                                .set(value(broker_snapshot));
+                        }
+                    }
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Client context of every producer, receiving librdkafka's periodic stats
+pub struct StatsContext {
+    acks: String,
+    stats: Arc<ProducerStats>,
+}
+
+impl StatsContext {
+    pub fn new(acks: &str, stats: Arc<ProducerStats>) -> Self {
+        Self {
+            acks: acks.to_string(),
+            stats,
+        }
+    }
+}
+
+impl ClientContext for StatsContext {
+    fn stats(&self, statistics: Statistics) {
+        self.stats.record(&self.acks, &statistics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed-down librdkafka stats report, with one real broker and one
+    // internal placeholder
+    const REPORT: &str = r#"{
+        "name": "rdkafka#producer-1", "client_id": "rdkafka", "type": "producer",
+        "ts": 1, "time": 1700000000, "age": 1, "replyq": 0,
+        "msg_cnt": 0, "msg_size": 0, "msg_max": 100000, "msg_size_max": 1073741824,
+        "tx": 10, "tx_bytes": 1000, "rx": 10, "rx_bytes": 1000,
+        "txmsgs": 500, "txmsg_bytes": 50000, "rxmsgs": 0, "rxmsg_bytes": 0,
+        "simple_cnt": 0, "metadata_cache_cnt": 1,
+        "brokers": {
+            "kafka-1:9092/1": {
+                "name": "kafka-1:9092/1", "nodeid": 1, "nodename": "kafka-1:9092",
+                "source": "configured", "state": "UP", "stateage": 1,
+                "outbuf_cnt": 2, "outbuf_msg_cnt": 0, "waitresp_cnt": 0,
+                "waitresp_msg_cnt": 0, "tx": 10, "txbytes": 1000, "txerrs": 0,
+                "txretries": 0, "txidle": 0, "req_timeouts": 0, "rx": 10,
+                "rxbytes": 1000, "rxerrs": 0, "rxcorriderrs": 0, "rxpartial": 0,
+                "rxidle": 0, "req": {}, "zbuf_grow": 0, "buf_grow": 0,
+                "rtt": {"min": 1000, "max": 3000, "avg": 2500, "sum": 5000,
+                        "cnt": 2, "stddev": 0, "hdrsize": 0, "p50": 2000,
+                        "p75": 3000, "p90": 3000, "p95": 3000, "p99": 3000,
+                        "p99_99": 3000, "outofrange": 0},
+                "toppars": {}
+            },
+            "GroupCoordinator": {
+                "name": "GroupCoordinator", "nodeid": -1, "nodename": "",
+                "source": "internal", "state": "INIT", "stateage": 1,
+                "outbuf_cnt": 0, "outbuf_msg_cnt": 0, "waitresp_cnt": 0,
+                "waitresp_msg_cnt": 0, "tx": 0, "txbytes": 0, "txerrs": 0,
+                "txretries": 0, "txidle": -1, "req_timeouts": 0, "rx": 0,
+                "rxbytes": 0, "rxerrs": 0, "rxcorriderrs": 0, "rxpartial": 0,
+                "rxidle": -1, "req": {}, "zbuf_grow": 0, "buf_grow": 0,
+                "toppars": {}
+            }
+        },
+        "topics": {}
+    }"#;
+
+    fn report(msg_cnt: u64) -> Statistics {
+        let mut report: serde_json::Value = serde_json::from_str(REPORT).unwrap();
+        report["msg_cnt"] = msg_cnt.into();
+        report["msg_size"] = (msg_cnt * 100).into();
+        serde_json::from_value(report).unwrap()
+    }
+
+    #[test]
+    fn test_stats_reports_become_gauges() {
+        let stats = Arc::new(ProducerStats::default());
+        let registry = Registry::new();
+        stats.register_metrics(&registry).unwrap();
+        assert_eq!(request_metrics::render_registry(&registry), "");
+
+        stats.record("all", &report(40));
+        stats.record("1", &report(7));
+        let metrics = request_metrics::render_registry(&registry);
+        assert!(metrics.contains("rust_ingest_kafka_queue_messages{acks=\"all\"} 40\n"));
+        assert!(metrics.contains("rust_ingest_kafka_queue_messages{acks=\"1\"} 7\n"));
+        assert!(metrics.contains("rust_ingest_kafka_queue_bytes{acks=\"all\"} 4000\n"));
+        assert!(metrics.contains(
+            "rust_ingest_kafka_broker_rtt_seconds{acks=\"all\",broker=\"kafka-1:9092\"} 0.0025\n"
+        ));
+        assert!(metrics
+            .contains("rust_ingest_kafka_broker_up{acks=\"all\",broker=\"kafka-1:9092\"} 1\n"));
+        assert!(!metrics.contains("GroupCoordinator"));
+
+        // The latest report replaces the previous one
+        stats.record("all", &report(0));
+        assert!(request_metrics::render_registry(&registry)
+            .contains("rust_ingest_kafka_queue_messages{acks=\"all\"} 0\n"));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    hmac::HmacSha256,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::Registry;
+use serde::Deserialize;
+use std::{fmt, sync::Arc, time::Duration};
+
+// Bytes of the HMAC kept in the key; 128 bits keeps collisions out of reach
+// for any realistic fleet
+const PSEUDONYM_BYTES: usize = 16;
+
+#[derive(Clone, Default, Deserialize)]
+pub struct PartitionKeyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // HMAC secret. Changing it changes every device's key, and with it the
+    // partition its records land on.
+    #[serde(default)]
+    pub secret: String,
+}
+
+// Keeps the secret out of logged configuration
+impl fmt::Debug for PartitionKeyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartitionKeyConfig")
+            .field("enabled", &self.enabled)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+// Derives a stable pseudonym from a device id with HMAC-SHA256, so the same
+// device always gets the same key (and partition) without the id itself
+// appearing as the key
+pub struct KeyPseudonymizer {
+    hmac: HmacSha256,
+}
+
+impl KeyPseudonymizer {
+    pub fn new(secret: &str) -> Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow::anyhow!(
+                "partition_keys.secret must be set when partition_keys is enabled"
+            ));
+        }
+        Ok(Self {
+            hmac: HmacSha256::new(secret.as_bytes()),
+        })
+    }
+
+    pub fn pseudonym(&self, device_id: &str) -> String {
+        self.hmac.mac(device_id.as_bytes())[..PSEUDONYM_BYTES]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+// Replaces record keys with their pseudonyms before they reach the inner
+// sink. The payload still carries the real device id. Tombstones are keyed
+// the same way, so they still match the device's records on a compacted topic.
+pub struct PseudonymousKeySink {
+    inner: Arc<dyn TelemetrySink>,
+    keys: KeyPseudonymizer,
+}
+
+impl PseudonymousKeySink {
+    pub fn new(inner: Arc<dyn TelemetrySink>, keys: KeyPseudonymizer) -> Self {
+        Self { inner, keys }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for PseudonymousKeySink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let key = self.keys.pseudonym(record.key);
+        self.inner
+            .publish(SinkRecord {
+                key: &key,
+                ..record
+            })
+            .await
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        let key = self.keys.pseudonym(key);
+        self.inner.publish_raw(topic, &key, payload).await
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        let key = self.keys.pseudonym(key);
+        self.inner.publish_tombstone(topic, &key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id)
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+    use std::sync::Mutex;
+
+    // Keeps the keys it was given
+    #[derive(Default)]
+    struct KeySink {
+        keys: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for KeySink {
+        fn name(&self) -> &'static str {
+            "keys"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            self.keys.lock().unwrap().push(record.key.to_string());
+            Ok(())
+        }
+
+        async fn publish_tombstone(&self, _topic: &str, key: &str) -> Result<()> {
+            self.keys.lock().unwrap().push(key.to_string());
+            Ok(())
+        }
+    }
+
+    async fn send(sink: &dyn TelemetrySink, device_id: &str) {
+        let telemetry = Telemetry {
+            device_id: device_id.to_string(),
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic: "telemetry",
+            key: &telemetry.device_id,
+            payload: b"",
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_matches_hmac_sha256() {
+        // RFC 4231 test case 2, truncated to the pseudonym length
+        let keys = KeyPseudonymizer::new("Jefe").unwrap();
+        assert_eq!(
+            keys.pseudonym("what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c7"
+        );
+        assert!(KeyPseudonymizer::new("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keys_are_stable_and_hide_the_device_id() {
+        let inner = Arc::new(KeySink::default());
+        let sink =
+            PseudonymousKeySink::new(inner.clone(), KeyPseudonymizer::new("s3cret").unwrap());
+        send(&sink, "sensor-1").await;
+        send(&sink, "sensor-2").await;
+        send(&sink, "sensor-1").await;
+        sink.publish_tombstone("telemetry", "sensor-1")
+            .await
+            .unwrap();
+
+        let keys = inner.keys.lock().unwrap().clone();
+        assert!(keys.iter().all(|key| !key.contains("sensor")));
+        assert_eq!(keys[0], keys[2]);
+        assert_eq!(keys[0], keys[3]);
+        assert_ne!(keys[0], keys[1]);
+
+        // A different secret gives unrelated keys
+        let other = KeyPseudonymizer::new("other").unwrap();
+        assert_ne!(other.pseudonym("sensor-1"), keys[0]);
+    }
+}
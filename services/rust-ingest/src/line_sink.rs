@@ -0,0 +1,167 @@
+use crate::{
+    encoding::TelemetryDocument,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    sync::Mutex,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSinkConfig {
+    // Appended to, and created when missing
+    #[serde(default = "default_path")]
+    pub path: String,
+}
+
+impl Default for FileSinkConfig {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+        }
+    }
+}
+
+fn default_path() -> String {
+    "telemetry.ndjson".to_string()
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    topic: &'a str,
+    key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    telemetry: TelemetryDocument<'a>,
+}
+
+// Writes each record as a line of JSON instead of sending it anywhere, for
+// running without Kafka: "stdout" during local development, "file" on edge
+// nodes that ship the file on by other means. The line carries the record in
+// the JSON layout whatever the topic's configured encoding, so it stays
+// readable. Side-stream messages and tombstones are not written.
+pub struct LineSink {
+    name: &'static str,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl LineSink {
+    // Flushed line by line, so output shows up as records arrive
+    pub fn stdout() -> Self {
+        Self::new("stdout", Box::new(LineWriter::new(io::stdout())))
+    }
+
+    // Also flushed line by line, so an accepted record is in the file even
+    // if the process dies before shutting down
+    pub fn file(config: &FileSinkConfig) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .map_err(|e| anyhow::anyhow!("Failed to open file sink {}: {}", config.path, e))?;
+        Ok(Self::new("file", Box::new(LineWriter::new(file))))
+    }
+
+    fn new(name: &'static str, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            name,
+            out: Mutex::new(out),
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for LineSink {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let mut line = serde_json::to_vec(&Line {
+            topic: record.topic,
+            key: record.key,
+            request_id: record.request_id,
+            telemetry: TelemetryDocument::from(record.telemetry),
+        })?;
+        line.push(b'\n');
+        // One write per line, so lines from concurrent requests don't interleave
+        self.out.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+    use std::collections::HashMap;
+
+    async fn publish(sink: &LineSink, device_id: &str, request_id: Option<&str>) {
+        let telemetry = Telemetry {
+            device_id: device_id.to_string(),
+            ts: 1700000000000,
+            metrics: HashMap::from([("temperature".to_string(), 21.5)]),
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic: "telemetry",
+            key: &telemetry.device_id,
+            payload: b"\x0a\x08encoded",
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id,
+            position: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_a_json_line_per_record() {
+        let path = std::env::temp_dir().join(format!("file-sink-{}.ndjson", uuid::Uuid::new_v4()));
+        let config = FileSinkConfig {
+            path: path.to_string_lossy().into_owned(),
+        };
+        let sink = LineSink::file(&config).unwrap();
+        publish(&sink, "sensor-1", Some("req-1")).await;
+        // Reopening appends rather than truncating
+        let reopened = LineSink::file(&config).unwrap();
+        publish(&reopened, "sensor-2", None).await;
+
+        // Both lines are written without a flush
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["topic"], "telemetry");
+        assert_eq!(lines[0]["key"], "sensor-1");
+        assert_eq!(lines[0]["request_id"], "req-1");
+        assert_eq!(lines[0]["telemetry"]["device_id"], "sensor-1");
+        assert_eq!(lines[0]["telemetry"]["metrics"]["temperature"], 21.5);
+        assert_eq!(lines[1]["key"], "sensor-2");
+        assert!(lines[1].get("request_id").is_none());
+    }
+
+    #[test]
+    fn test_unwritable_path_fails_at_startup() {
+        let config = FileSinkConfig {
+            path: "/nonexistent-dir/telemetry.ndjson".to_string(),
+        };
+        assert!(LineSink::file(&config).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{priority::Priority, request_metrics};
+use prometheus::{Gauge, IntCounter, Registry};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoadSheddingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Records in flight at which shedding starts
+    #[serde(default = "default_start_depth")]
+    pub start_depth: usize,
+    // Records in flight at which the drop rate reaches max_drop_rate
+    #[serde(default = "default_full_depth")]
+    pub full_depth: usize,
+    #[serde(default = "default_max_drop_rate")]
+    pub max_drop_rate: f64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            start_depth: default_start_depth(),
+            full_depth: default_full_depth(),
+            max_drop_rate: default_max_drop_rate(),
+        }
+    }
+}
+
+fn default_start_depth() -> usize {
+    1000
+}
+
+fn default_full_depth() -> usize {
+    5000
+}
+
+fn default_max_drop_rate() -> f64 {
+    0.9
+}
+
+// How much a metric's value suffers from arriving late, relative to other
+// metrics: a vibration reading a minute old is worth little, a humidity
+// reading a minute old nearly as much as a fresh one
+#[derive(Debug, Clone, Deserialize)]
+pub struct FreshnessConfig {
+    #[serde(default)]
+    pub weights: HashMap<String, f64>,
+    #[serde(default = "default_weight")]
+    pub default_weight: f64,
+}
+
+impl Default for FreshnessConfig {
+    fn default() -> Self {
+        Self {
+            weights: HashMap::new(),
+            default_weight: default_weight(),
+        }
+    }
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone)]
+pub struct FreshnessWeights {
+    weights: HashMap<String, f64>,
+    default_weight: f64,
+}
+
+impl FreshnessWeights {
+    pub fn new(config: &FreshnessConfig) -> Self {
+        Self {
+            weights: config.weights.clone(),
+            default_weight: config.default_weight,
+        }
+    }
+
+    // Without any weights every metric counts the same, and shedding works
+    // on whole records
+    pub fn is_uniform(&self) -> bool {
+        self.weights.is_empty()
+    }
+
+    pub fn weight(&self, metric: &str) -> f64 {
+        self.weights
+            .get(metric)
+            .copied()
+            .unwrap_or(self.default_weight)
+            .max(0.0)
+    }
+
+    // How many times the default weight a record is worth, going by its
+    // most time-sensitive metric; never less than 1
+    pub fn record_weight<'a>(&self, metrics: impl IntoIterator<Item = &'a String>) -> f64 {
+        let default = self.default_weight.max(0.0);
+        let top = metrics
+            .into_iter()
+            .map(|metric| self.weight(metric))
+            .fold(default, f64::max);
+        if default > 0.0 {
+            top / default
+        } else {
+            1.0
+        }
+    }
+}
+
+// What the sampler decided for one record
+#[derive(Debug, PartialEq, Eq)]
+pub enum Shedding {
+    Keep,
+    // Forward the record without these metrics
+    Metrics(Vec<String>),
+    Record,
+}
+
+// Graceful degradation under load: once the number of records in flight
+// passes `start_depth`, a growing fraction of devices is sampled out,
+// ramping linearly up to `max_drop_rate` at `full_depth`. Which devices are
+// dropped is decided by a stable hash of the device id, so the ones that
+// stay in keep a coherent series instead of random gaps. With priority
+// lanes, low-priority devices are shed at twice the rate and high-priority
+// ones not at all. With freshness weights, each metric is shed at the
+// device's rate divided by its weight instead, so a device loses its
+// low-weight metrics first and keeps its high-weight ones the longest.
+pub struct LoadSheddingSampler {
+    config: LoadSheddingConfig,
+    freshness: FreshnessWeights,
+    dropped: IntCounter,
+    metrics_dropped: IntCounter,
+    // One minus the current drop rate
+    sampling_rate: Gauge,
+}
+
+impl LoadSheddingSampler {
+    pub fn new(config: LoadSheddingConfig, freshness: FreshnessWeights) -> Self {
+        let sampling_rate = request_metrics::gauge(
+            "rust_ingest_sampling_rate",
+            "Fraction of devices currently ingested",
+        );
+        sampling_rate.set(1.0);
+        Self {
+            config,
+            freshness,
+            dropped: request_metrics::int_counter(
+                "rust_ingest_load_shed_total",
+                "Records dropped by the load-shedding sampler",
+            ),
+            metrics_dropped: request_metrics::int_counter(
+                "rust_ingest_load_shed_metrics_total",
+                "Metrics dropped from records that were otherwise kept",
+            ),
+            sampling_rate,
+        }
+    }
+
+    pub fn drop_rate(&self, depth: usize) -> f64 {
+        if depth <= self.config.start_depth {
+            return 0.0;
+        }
+        let max_rate = self.config.max_drop_rate.clamp(0.0, 1.0);
+        let span = self
+            .config
+            .full_depth
+            .saturating_sub(self.config.start_depth)
+            .max(1);
+        let progress = (depth - self.config.start_depth) as f64 / span as f64;
+        max_rate * progress.min(1.0)
+    }
+
+    fn lane_rate(&self, depth: usize, priority: Priority) -> f64 {
+        let rate = self.drop_rate(depth);
+        // Whole millionths, so the gauge reads 0.2 rather than 0.19999...
+        let ppm = (rate * 1_000_000.0).round() as u64;
+        self.sampling_rate
+            .set(1_000_000u64.saturating_sub(ppm) as f64 / 1_000_000.0);
+        match priority {
+            Priority::High => 0.0,
+            Priority::Normal => rate,
+            Priority::Low => (rate * 2.0).min(1.0),
+        }
+    }
+
+    // Whether a record from `device_id` should be dropped at the current depth
+    pub fn should_drop(&self, device_id: &str, depth: usize, priority: Priority) -> bool {
+        let rate = self.lane_rate(depth, priority);
+        let drop = rate > 0.0 && device_position(device_id) < rate;
+        if drop {
+            self.dropped.inc();
+        }
+        drop
+    }
+
+    // What to drop of a record carrying `metrics`, weighing each metric by
+    // its freshness when weights are configured
+    pub fn shed(
+        &self,
+        device_id: &str,
+        metrics: &[&str],
+        depth: usize,
+        priority: Priority,
+    ) -> Shedding {
+        if self.freshness.is_uniform() {
+            return if self.should_drop(device_id, depth, priority) {
+                Shedding::Record
+            } else {
+                Shedding::Keep
+            };
+        }
+        let rate = self.lane_rate(depth, priority);
+        if rate <= 0.0 {
+            return Shedding::Keep;
+        }
+        let position = device_position(device_id);
+        let shed: Vec<String> = metrics
+            .iter()
+            .filter(|metric| position < rate / self.freshness.weight(metric))
+            .map(|metric| metric.to_string())
+            .collect();
+        if shed.is_empty() {
+            Shedding::Keep
+        } else if shed.len() == metrics.len() {
+            self.dropped.inc();
+            Shedding::Record
+        } else {
+            self.metrics_dropped.inc_by(shed.len() as u64);
+            Shedding::Metrics(shed)
+        }
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.dropped)?;
+        request_metrics::register(registry, &self.metrics_dropped)?;
+        request_metrics::register(registry, &self.sampling_rate)
+    }
+}
+
+// Stable position of a device in [0, 1), independent of process and Rust
+// version so the same devices are shed on every node. FNV-1a followed by a
+// splitmix64 finalizer, since FNV alone spreads similar ids poorly.
+fn device_position(device_id: &str) -> f64 {
+    let mut hash = device_id.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler() -> LoadSheddingSampler {
+        weighted_sampler(&[])
+    }
+
+    fn weighted_sampler(weights: &[(&str, f64)]) -> LoadSheddingSampler {
+        LoadSheddingSampler::new(
+            LoadSheddingConfig {
+                enabled: true,
+                start_depth: 100,
+                full_depth: 500,
+                max_drop_rate: 0.8,
+            },
+            FreshnessWeights::new(&FreshnessConfig {
+                weights: weights
+                    .iter()
+                    .map(|(metric, weight)| (metric.to_string(), *weight))
+                    .collect(),
+                default_weight: 1.0,
+            }),
+        )
+    }
+
+    fn dropped_share(sampler: &LoadSheddingSampler, depth: usize) -> f64 {
+        lane_dropped_share(sampler, depth, Priority::Normal)
+    }
+
+    fn lane_dropped_share(sampler: &LoadSheddingSampler, depth: usize, priority: Priority) -> f64 {
+        let dropped = (0..2000)
+            .filter(|i| sampler.should_drop(&format!("device-{}", i), depth, priority))
+            .count();
+        dropped as f64 / 2000.0
+    }
+
+    #[test]
+    fn test_sampler_ramps_with_load_and_recovers() {
+        let sampler = sampler();
+
+        assert_eq!(dropped_share(&sampler, 50), 0.0);
+        assert_eq!(dropped_share(&sampler, 100), 0.0);
+        assert!((dropped_share(&sampler, 300) - 0.4).abs() < 0.05);
+        assert!((dropped_share(&sampler, 500) - 0.8).abs() < 0.05);
+        assert!((dropped_share(&sampler, 10_000) - 0.8).abs() < 0.05);
+        assert!(
+            request_metrics::rendered(|registry| sampler.register_metrics(registry))
+                .contains("rust_ingest_sampling_rate 0.2")
+        );
+
+        // Load subsides: full ingestion again
+        assert_eq!(dropped_share(&sampler, 80), 0.0);
+        assert!(
+            request_metrics::rendered(|registry| sampler.register_metrics(registry))
+                .contains("rust_ingest_sampling_rate 1\n")
+        );
+    }
+
+    #[test]
+    fn test_sampling_is_deterministic_per_device() {
+        let sampler = sampler();
+        for i in 0..200 {
+            let device = format!("device-{}", i);
+            let first = sampler.should_drop(&device, 300, Priority::Normal);
+            assert_eq!(sampler.should_drop(&device, 300, Priority::Normal), first);
+            // A device shed at some load stays shed as load grows
+            if first {
+                assert!(sampler.should_drop(&device, 400, Priority::Normal));
+            }
+        }
+    }
+
+    #[test]
+    fn test_low_priority_is_shed_first() {
+        let sampler = sampler();
+        assert!((lane_dropped_share(&sampler, 200, Priority::Low) - 0.4).abs() < 0.05);
+        assert!((dropped_share(&sampler, 200) - 0.2).abs() < 0.05);
+        assert_eq!(lane_dropped_share(&sampler, 10_000, Priority::High), 0.0);
+        assert_eq!(lane_dropped_share(&sampler, 10_000, Priority::Low), 1.0);
+    }
+
+    #[test]
+    fn test_high_freshness_metrics_survive_shedding() {
+        let sampler = weighted_sampler(&[("vibration", 4.0), ("humidity", 0.5)]);
+        let metrics = ["vibration", "humidity"];
+        let (mut kept_vibration, mut kept_humidity) = (0, 0);
+        for i in 0..2000 {
+            // At a 0.4 drop rate humidity is shed at 0.8 and vibration at 0.1
+            match sampler.shed(&format!("device-{}", i), &metrics, 300, Priority::Normal) {
+                Shedding::Keep => {
+                    kept_vibration += 1;
+                    kept_humidity += 1;
+                }
+                Shedding::Metrics(shed) => {
+                    // Only ever the low-value metric
+                    assert_eq!(shed, vec!["humidity"]);
+                    kept_vibration += 1;
+                }
+                Shedding::Record => {}
+            }
+        }
+        assert!((kept_vibration as f64 / 2000.0 - 0.9).abs() < 0.05);
+        assert!((kept_humidity as f64 / 2000.0 - 0.2).abs() < 0.05);
+        assert!(
+            !request_metrics::rendered(|registry| sampler.register_metrics(registry))
+                .contains("rust_ingest_load_shed_metrics_total 0\n")
+        );
+
+        // A record of default-weight metrics is shed like any other record
+        let share = (0..2000)
+            .filter(|i| {
+                sampler.shed(
+                    &format!("device-{}", i),
+                    &["temperature"],
+                    300,
+                    Priority::Normal,
+                ) == Shedding::Record
+            })
+            .count() as f64
+            / 2000.0;
+        assert!((share - 0.4).abs() < 0.05);
+    }
+}
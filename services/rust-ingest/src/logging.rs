@@ -0,0 +1,251 @@
+use crate::{http_client::Endpoint, otlp::OtlpLayer};
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{
+        format::{FormatEvent, FormatFields, Writer},
+        FmtContext, FormattedFields,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log aggregation
+    Json,
+}
+
+// Installs the global subscriber; the log level still comes from RUST_LOG.
+// With an OTLP endpoint, info-level spans are also exported there whatever
+// RUST_LOG says; without one, spans cost nothing extra.
+pub fn init_tracing(format: LogFormat, otlp_endpoint: Option<&str>) -> Result<()> {
+    let otlp = otlp_endpoint
+        .map(|url| Endpoint::parse("otlp_endpoint", url))
+        .transpose()?
+        .map(|endpoint| {
+            OtlpLayer::install(endpoint).with_filter(filter_fn(|metadata| {
+                metadata.is_span() && *metadata.level() <= Level::INFO
+            }))
+        });
+    let logs = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .event_format(JsonLines)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(logs.with_filter(EnvFilter::from_default_env()))
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+// Lets one in `rate` records log their success-path lines, to keep per-record
+// info logs down at high volume. Warnings and errors don't go through it.
+pub struct LogSampler {
+    rate: u64,
+    records: AtomicU64,
+}
+
+impl LogSampler {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            records: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sample(&self) -> bool {
+        self.rate == 1
+            || self
+                .records
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.rate)
+    }
+}
+
+// Writes each event as a JSON object: timestamp, level, target, the event's
+// own fields (the text under "message") as top-level keys, and the spans it
+// happened in, outermost first
+struct JsonLines;
+
+impl<S, N> FormatEvent<S, N> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            Value::String(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        );
+        line.insert(
+            "level".to_string(),
+            Value::String(metadata.level().to_string()),
+        );
+        line.insert(
+            "target".to_string(),
+            Value::String(metadata.target().to_string()),
+        );
+
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+        line.extend(fields.0);
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans: Vec<Value> = scope
+                .from_root()
+                .map(|span| {
+                    let mut entry = Map::new();
+                    entry.insert("name".to_string(), Value::String(span.name().to_string()));
+                    // Span fields are kept pre-formatted by the subscriber
+                    if let Some(formatted) = span.extensions().get::<FormattedFields<N>>() {
+                        if !formatted.is_empty() {
+                            entry
+                                .insert("fields".to_string(), Value::String(formatted.to_string()));
+                        }
+                    }
+                    Value::Object(entry)
+                })
+                .collect();
+            if !spans.is_empty() {
+                line.insert("spans".to_string(), Value::Array(spans));
+            }
+        }
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+// A span's or event's fields as JSON values, by name
+#[derive(Default)]
+pub struct JsonFields(pub Map<String, Value>);
+
+impl JsonFields {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(
+            field,
+            serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number),
+        );
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::String(format!("{:?}", value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tracing::info;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_one_in_rate_records_is_logged() {
+        let sampler = LogSampler::new(4);
+        let logged: Vec<bool> = (0..8).map(|_| sampler.sample()).collect();
+        assert_eq!(
+            logged,
+            vec![true, false, false, false, true, false, false, false]
+        );
+
+        // 0 and 1 both log everything
+        for rate in [0, 1] {
+            let sampler = LogSampler::new(rate);
+            assert!((0..8).all(|_| sampler.sample()));
+        }
+    }
+
+    #[test]
+    fn test_events_are_written_as_json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .event_format(JsonLines)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", method = "POST");
+            let _entered = span.enter();
+            info!(
+                device_id = "sensor-1",
+                ts = 1700000000000i64,
+                "Processing telemetry"
+            );
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Processing telemetry");
+        assert_eq!(line["device_id"], "sensor-1");
+        assert_eq!(line["ts"], 1700000000000i64);
+        assert_eq!(line["spans"][0]["name"], "request");
+        assert_eq!(line["spans"][0]["fields"], "method=\"POST\"");
+    }
+}
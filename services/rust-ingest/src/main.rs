@@ -1,17 +1,131 @@
+mod ack;
+mod api_keys;
+mod band_changes;
+mod baseline;
+mod batch;
+mod bounded_store;
+mod broker_health;
+mod cardinality;
+mod circuit_breaker;
+mod cli;
+mod clock_skew;
+mod coalescing;
+mod concurrency_limit;
 mod config;
+mod connections;
+mod content_dedup;
+mod content_encoding;
+mod cors;
+mod csv_ingest;
+mod dead_letter;
+mod debug_messages;
+mod delayed_delivery;
+mod device_attributes;
+mod device_metrics;
+mod device_rate_limit;
+mod device_types;
+mod dlq_replay;
+mod duplicate_backoff;
+mod encoding;
+mod exposition;
+mod health;
+mod heartbeat;
+mod histograms;
+mod hmac;
+mod http_client;
+mod imputation;
+mod ingest_sequence;
 mod kafka;
+mod kafka_stats;
+mod key_pseudonyms;
+mod line_sink;
+mod load_shedding;
+mod logging;
+mod maintenance;
+mod metric_precision;
+mod metric_renames;
+mod metric_values;
+mod mqtt_sink;
+mod ndjson_ingest;
+mod openapi;
+mod ordering;
+mod otlp;
+mod parquet_sink;
+mod pipeline_retry;
+mod priority;
+mod proto;
+mod provisioning;
+mod quality;
+mod rate_limit;
+mod rate_of_change;
+mod receipts;
+mod redis_sink;
+mod request_id;
+mod request_metrics;
+mod request_signing;
+mod resend_dedup;
+mod routing;
+mod sample_window;
+mod schema_registry;
 mod server;
+mod shutdown;
+mod sink;
+mod size_budget;
+mod spillover;
+mod telemetry_body;
 mod telemetry_handler;
-mod proto;
+mod tenancy;
+mod time_grid;
+mod timestamps;
+mod timing_wheel;
+mod trace_sampling;
+mod ts_window;
+mod ttl;
+mod unit_conversion;
+mod validation;
+mod validation_profiles;
+mod websocket;
+mod worker_pool;
 
 use anyhow::Result;
+use cli::Command;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cfg = config::load_config()?;
-    let producer = kafka::create_producer(&cfg.kafka_brokers)?;
+    let command = cli::parse(std::env::args().skip(1))?;
+    if command == Command::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    // Before loading config, so config migration warnings are visible
+    logging::init_tracing(
+        config::load_log_format()?,
+        config::load_otlp_endpoint()?.as_deref(),
+    )?;
+
+    let mut cfg = config::load_config()?;
+    // Before anything encodes a record, so Avro ones all carry the schema id
+    schema_registry::register_schema(&cfg.schema_registry, &mut cfg.encoding, &cfg.kafka_topic)
+        .await?;
+    let sink = sink::build_sink(&cfg)?;
+
+    if let Command::PublishTest(options) = &command {
+        match cli::publish_test(&cfg, sink.as_ref(), options).await? {
+            Some(position) => println!(
+                "Published test telemetry for {} at partition {}, offset {}",
+                options.device_id,
+                position.partition,
+                position
+                    .offset
+                    .map_or_else(|| "unknown".to_string(), |offset| offset.to_string())
+            ),
+            None => println!("Published test telemetry for {}", options.device_id),
+        }
+        return Ok(());
+    }
 
     println!("Starting Rust ingestion server on {}", cfg.listen_addr);
-    server::run_server(cfg, producer).await?;
+    server::run_server(cfg, sink).await?;
     Ok(())
 }
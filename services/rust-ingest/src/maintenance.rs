@@ -0,0 +1,391 @@
+use crate::{config, server::ApiError};
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveTime, TimeZone, Utc, Weekday};
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+// Back-to-back windows are merged when working out when ingestion resumes;
+// this bounds the chain that is followed
+const MAX_CHAINED_WINDOWS: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Offset weekly windows are read in, e.g. "+02:00". Fixed: windows
+    // don't follow daylight saving changes.
+    #[serde(default = "default_utc_offset")]
+    pub utc_offset: String,
+    // Re-read from the config file on SIGHUP; `enabled` takes a restart
+    #[serde(default)]
+    pub windows: Vec<WindowSpec>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            utc_offset: default_utc_offset(),
+            windows: Vec::new(),
+        }
+    }
+}
+
+fn default_utc_offset() -> String {
+    "+00:00".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum WindowSpec {
+    // One-off window between two RFC 3339 instants
+    Once {
+        start: String,
+        end: String,
+    },
+    // Every listed weekday ("sat", "sunday", ...) between two local times
+    // ("02:00"); a window whose end is before its start runs past midnight
+    Weekly {
+        days: Vec<String>,
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug)]
+enum Window {
+    Once {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    Weekly {
+        days: Vec<Weekday>,
+        from: NaiveTime,
+        length: Duration,
+        offset: FixedOffset,
+    },
+}
+
+impl Window {
+    fn parse(spec: &WindowSpec, offset: FixedOffset) -> Result<Self> {
+        match spec {
+            WindowSpec::Once { start, end } => {
+                let instant = |value: &str| {
+                    DateTime::parse_from_rfc3339(value)
+                        .map(|at| at.with_timezone(&Utc))
+                        .map_err(|e| anyhow::anyhow!("invalid window time {:?}: {}", value, e))
+                };
+                let (start, end) = (instant(start)?, instant(end)?);
+                if end <= start {
+                    return Err(anyhow::anyhow!(
+                        "maintenance window ends at {} before it starts at {}",
+                        end,
+                        start
+                    ));
+                }
+                Ok(Self::Once { start, end })
+            }
+            WindowSpec::Weekly { days, from, to } => {
+                let days = days
+                    .iter()
+                    .map(|day| {
+                        day.parse::<Weekday>()
+                            .map_err(|_| anyhow::anyhow!("invalid weekday {:?}", day))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if days.is_empty() {
+                    return Err(anyhow::anyhow!("weekly maintenance window has no days"));
+                }
+                let time = |value: &str| {
+                    NaiveTime::parse_from_str(value, "%H:%M")
+                        .map_err(|e| anyhow::anyhow!("invalid window time {:?}: {}", value, e))
+                };
+                let (from, to) = (time(from)?, time(to)?);
+                let mut length = to - from;
+                if length <= Duration::zero() {
+                    length += Duration::days(1);
+                }
+                Ok(Self::Weekly {
+                    days,
+                    from,
+                    length,
+                    offset,
+                })
+            }
+        }
+    }
+
+    // End of this window if `now` falls inside it
+    fn end_if_active(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Once { start, end } => (*start <= now && now < *end).then_some(*end),
+            Self::Weekly {
+                days,
+                from,
+                length,
+                offset,
+            } => {
+                let today = now.with_timezone(offset).date_naive();
+                // Yesterday's window may still be running past midnight
+                [today.pred_opt()?, today]
+                    .into_iter()
+                    .filter(|date| days.contains(&date.weekday()))
+                    .filter_map(|date| {
+                        let start = offset
+                            .from_local_datetime(&date.and_time(*from))
+                            .single()?
+                            .with_timezone(&Utc);
+                        let end = start + *length;
+                        (start <= now && now < end).then_some(end)
+                    })
+                    .max()
+            }
+        }
+    }
+}
+
+fn parse_windows(config: &MaintenanceConfig) -> Result<Vec<Window>> {
+    let offset: FixedOffset = config
+        .utc_offset
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid utc_offset {:?}: {}", config.utc_offset, e))?;
+    config
+        .windows
+        .iter()
+        .map(|spec| Window::parse(spec, offset))
+        .collect()
+}
+
+// Scheduled windows during which ingest requests are turned away with a
+// 503 and a Retry-After past the window's end, so clients hold on to data
+// rather than send it into a pipeline that is down for maintenance
+pub struct MaintenanceSchedule {
+    windows: RwLock<Arc<Vec<Window>>>,
+}
+
+impl MaintenanceSchedule {
+    pub fn new(config: &MaintenanceConfig) -> Result<Self> {
+        Ok(Self {
+            windows: RwLock::new(Arc::new(parse_windows(config)?)),
+        })
+    }
+
+    // A config with an invalid window leaves the current schedule in place
+    pub fn reload(&self, config: &MaintenanceConfig) -> Result<usize> {
+        let windows = parse_windows(config)?;
+        let count = windows.len();
+        *self.windows.write().unwrap() = Arc::new(windows);
+        Ok(count)
+    }
+
+    // When ingestion resumes, if `now` is inside a window. Windows that
+    // overlap or follow on directly count as one.
+    pub fn resumes_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let windows = Arc::clone(&self.windows.read().unwrap());
+        let active_end = |at| windows.iter().filter_map(|w| w.end_if_active(at)).max();
+        let mut end = active_end(now)?;
+        for _ in 0..MAX_CHAINED_WINDOWS {
+            match active_end(end) {
+                Some(next) => end = next,
+                None => break,
+            }
+        }
+        Some(end)
+    }
+
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), ApiError> {
+        let Some(end) = self.resumes_at(now) else {
+            return Ok(());
+        };
+        Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ingestion is paused for scheduled maintenance",
+        )
+        .with_details(format!("maintenance ends at {}", end.to_rfc3339()))
+        .with_retry_after((end - now).to_std().unwrap_or_default()))
+    }
+}
+
+pub async fn reject_during_maintenance(
+    State(schedule): State<Arc<MaintenanceSchedule>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    schedule.check(Utc::now())?;
+    Ok(next.run(request).await)
+}
+
+// Re-reads the windows from the config whenever the process gets SIGHUP
+pub fn spawn_reloader(schedule: Arc<MaintenanceSchedule>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let reloaded = config::load_config().and_then(|cfg| schedule.reload(&cfg.maintenance));
+            match reloaded {
+                Ok(windows) => info!("Reloaded {} maintenance windows", windows),
+                Err(e) => warn!("Keeping current maintenance windows: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    fn at(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn schedule(utc_offset: &str, windows: Vec<WindowSpec>) -> MaintenanceSchedule {
+        MaintenanceSchedule::new(&MaintenanceConfig {
+            enabled: true,
+            utc_offset: utc_offset.to_string(),
+            windows,
+        })
+        .unwrap()
+    }
+
+    fn once(start: &str, end: &str) -> WindowSpec {
+        WindowSpec::Once {
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    fn weekly(days: &[&str], from: &str, to: &str) -> WindowSpec {
+        WindowSpec::Weekly {
+            days: days.iter().map(|day| day.to_string()).collect(),
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_requests_in_window_are_rejected_until_it_ends() {
+        let schedule = schedule(
+            "+00:00",
+            vec![once(
+                "2026-10-17T02:00:00+02:00",
+                "2026-10-17T04:00:00+02:00",
+            )],
+        );
+        let err = schedule.check(at("2026-10-17T01:30:00Z")).unwrap_err();
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // 30 minutes left of the window
+        assert_eq!(response.headers()["retry-after"], "1800");
+    }
+
+    #[test]
+    fn test_requests_outside_windows_pass() {
+        let schedule = schedule(
+            "+00:00",
+            vec![once(
+                "2026-10-17T02:00:00+02:00",
+                "2026-10-17T04:00:00+02:00",
+            )],
+        );
+        assert!(schedule.check(at("2026-10-16T23:59:59Z")).is_ok());
+        // The end is exclusive
+        assert!(schedule.check(at("2026-10-17T02:00:00Z")).is_ok());
+        assert!(schedule.check(at("2026-10-18T01:00:00Z")).is_ok());
+    }
+
+    #[test]
+    fn test_weekly_windows_follow_the_offset_past_midnight() {
+        // Saturdays 23:00 to 01:00 local, two hours ahead of UTC
+        let schedule = schedule("+02:00", vec![weekly(&["sat"], "23:00", "01:00")]);
+        // 2026-10-17 is a Saturday: 21:00Z is 23:00 local
+        assert!(schedule.resumes_at(at("2026-10-17T20:59:00Z")).is_none());
+        assert_eq!(
+            schedule.resumes_at(at("2026-10-17T21:00:00Z")),
+            Some(at("2026-10-17T23:00:00Z"))
+        );
+        // Sunday 00:30 local is still Saturday's window
+        assert_eq!(
+            schedule.resumes_at(at("2026-10-17T22:30:00Z")),
+            Some(at("2026-10-17T23:00:00Z"))
+        );
+        assert!(schedule.resumes_at(at("2026-10-24T20:00:00Z")).is_none());
+        assert!(schedule.resumes_at(at("2026-10-24T21:30:00Z")).is_some());
+    }
+
+    #[test]
+    fn test_back_to_back_windows_resume_after_the_last() {
+        let schedule = schedule(
+            "+00:00",
+            vec![
+                once("2026-10-17T02:00:00Z", "2026-10-17T03:00:00Z"),
+                once("2026-10-17T03:00:00Z", "2026-10-17T05:00:00Z"),
+            ],
+        );
+        assert_eq!(
+            schedule.resumes_at(at("2026-10-17T02:30:00Z")),
+            Some(at("2026-10-17T05:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_window_kinds_are_told_apart_in_config() {
+        let config: MaintenanceConfig = ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                r#"
+                enabled = true
+                utc_offset = "-05:00"
+                [[windows]]
+                start = "2026-10-17T02:00:00Z"
+                end = "2026-10-17T04:00:00Z"
+                [[windows]]
+                days = ["sat", "Sunday"]
+                from = "22:00"
+                to = "23:30"
+                "#,
+                ::config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert!(matches!(config.windows[0], WindowSpec::Once { .. }));
+        assert!(matches!(config.windows[1], WindowSpec::Weekly { .. }));
+        assert!(MaintenanceSchedule::new(&config).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_windows_keep_the_current_schedule() {
+        let schedule = schedule("+00:00", vec![weekly(&["mon"], "02:00", "03:00")]);
+        let bad = MaintenanceConfig {
+            enabled: true,
+            utc_offset: "+00:00".to_string(),
+            windows: vec![weekly(&["someday"], "02:00", "03:00")],
+        };
+        assert!(schedule.reload(&bad).is_err());
+        // 2026-10-19 is a Monday
+        assert!(schedule.resumes_at(at("2026-10-19T02:30:00Z")).is_some());
+
+        let backwards = MaintenanceConfig {
+            windows: vec![once("2026-10-17T03:00:00Z", "2026-10-17T02:00:00Z")],
+            ..bad
+        };
+        assert!(schedule.reload(&backwards).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::proto::telemetry::Telemetry;
+use anyhow::Result;
+use std::collections::HashMap;
+
+// More decimal places than an f64 reliably holds
+const MAX_PLACES: u32 = 15;
+
+// Rounds configured metrics to a number of decimal places before encoding,
+// so consumers and storage don't see noise digits (21.4999999 next to 21.5).
+// Metrics without a setting pass through untouched.
+pub struct MetricPrecision {
+    places: HashMap<String, u32>,
+}
+
+impl MetricPrecision {
+    pub fn new(places: &HashMap<String, u32>) -> Result<Self> {
+        if let Some((metric, places)) = places.iter().find(|(_, places)| **places > MAX_PLACES) {
+            return Err(anyhow::anyhow!(
+                "metric_precision.{} is {} decimal places; at most {} are supported",
+                metric,
+                places,
+                MAX_PLACES
+            ));
+        }
+        Ok(Self {
+            places: places.clone(),
+        })
+    }
+
+    // Rounds the record's metrics and those of its samples. Returns whether
+    // any value changed.
+    pub fn apply(&self, telemetry: &mut Telemetry) -> bool {
+        let mut changed = false;
+        let metrics = std::iter::once(&mut telemetry.metrics).chain(
+            telemetry
+                .samples
+                .iter_mut()
+                .map(|sample| &mut sample.metrics),
+        );
+        for metrics in metrics {
+            for (metric, value) in metrics.iter_mut() {
+                let Some(&places) = self.places.get(metric) else {
+                    continue;
+                };
+                let rounded = round_to(*value, places);
+                if rounded.to_bits() != value.to_bits() {
+                    *value = rounded;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+// Rounds half away from zero on the value's shortest decimal form, the one
+// it prints as, and parses the result back. Scaling by 10^places and
+// calling round() would go wrong on values like 1.005, which is stored as
+// 1.00499999999999989... and printed as 1.005; dividing back down can land
+// next to the intended value rather than on it.
+pub fn round_to(value: f64, places: u32) -> f64 {
+    if !value.is_finite() {
+        return value;
+    }
+    // Display never uses an exponent for f64
+    let text = value.abs().to_string();
+    let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+    let places = places as usize;
+    if fraction.len() <= places {
+        return value;
+    }
+
+    // A value with digits past `places` is below 2^53, so the whole part has
+    // at most 16 digits and with up to 15 more this fits in a u128
+    let digits = format!("{}{}", whole, &fraction[..places]);
+    let Ok(mut scaled) = digits.parse::<u128>() else {
+        return value;
+    };
+    if fraction.as_bytes()[places] >= b'5' {
+        scaled += 1;
+    }
+    let rounded: f64 = format!("{}e-{}", scaled, places)
+        .parse()
+        .unwrap_or(value.abs());
+    // No -0 for a small negative value rounded away
+    if rounded == 0.0 {
+        return 0.0;
+    }
+    rounded.copysign(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+
+    #[test]
+    fn test_round_to() {
+        assert_eq!(round_to(21.46, 1), 21.5);
+        assert_eq!(round_to(21.44, 1), 21.4);
+        assert_eq!(round_to(1013.256, 2), 1013.26);
+        assert_eq!(round_to(-12.34567, 3), -12.346);
+        assert_eq!(round_to(9.96, 1), 10.0);
+        assert_eq!(round_to(23.5, 0), 24.0);
+        assert_eq!(round_to(42.0, 2), 42.0);
+        assert!(round_to(f64::NAN, 2).is_nan());
+    }
+
+    #[test]
+    fn test_round_to_leaves_no_float_artifacts() {
+        // 1.005 * 100 is 100.49999999999999, so scaling first gives 1.0
+        assert_eq!(round_to(1.005, 2), 1.01);
+        assert_eq!(round_to(1.005, 2).to_string(), "1.01");
+        // 0.1 + 0.2 prints as 0.30000000000000004
+        assert_eq!((0.1 + 0.2_f64).to_string(), "0.30000000000000004");
+        assert_eq!(round_to(0.1 + 0.2, 2).to_string(), "0.3");
+        assert_eq!(round_to(0.000123456, 5).to_string(), "0.00012");
+        assert_eq!(round_to(-0.04, 1).to_string(), "0");
+    }
+
+    #[test]
+    fn test_only_configured_metrics_are_rounded() {
+        let precision = MetricPrecision::new(&HashMap::from([
+            ("temperature".to_string(), 1),
+            ("pressure".to_string(), 2),
+        ]))
+        .unwrap();
+        let mut telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            metrics: HashMap::from([
+                ("temperature".to_string(), 21.456),
+                ("pressure".to_string(), 1013.256),
+                ("humidity".to_string(), 45.123456),
+            ]),
+            samples: vec![Sample {
+                ts: 1,
+                metrics: HashMap::from([("temperature".to_string(), 21.44)]),
+            }],
+            ..Default::default()
+        };
+        assert!(precision.apply(&mut telemetry));
+        assert_eq!(telemetry.metrics["temperature"], 21.5);
+        assert_eq!(telemetry.metrics["pressure"], 1013.26);
+        assert_eq!(telemetry.metrics["humidity"], 45.123456);
+        assert_eq!(telemetry.samples[0].metrics["temperature"], 21.4);
+
+        // Already at the precision: nothing to do
+        assert!(!precision.apply(&mut telemetry));
+    }
+
+    #[test]
+    fn test_precision_is_bounded() {
+        assert!(MetricPrecision::new(&HashMap::from([("temperature".to_string(), 16)])).is_err());
+    }
+}
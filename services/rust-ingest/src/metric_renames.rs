@@ -0,0 +1,219 @@
+use crate::proto::telemetry::Telemetry;
+use anyhow::Result;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+
+// Cap on a compiled pattern's size. The regex crate matches in linear time,
+// so there is no catastrophic backtracking to guard against, but a pattern
+// like `(\w{100}){100}` can still compile into something huge.
+const MAX_COMPILED_SIZE: usize = 1 << 16;
+
+// One renaming rule. `pattern` must match the whole metric name; `name` and
+// each tag value are templates over its capture groups ($1, ${zone}, ...).
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricRenameRule {
+    pub pattern: String,
+    pub name: String,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+struct CompiledRule {
+    pattern: Regex,
+    name: String,
+    tags: BTreeMap<String, String>,
+}
+
+// Pattern-based metric renaming, e.g. `sensor_(\d+)_temp` -> `temperature`
+// with the sensor index moved into a tag. The first matching rule applies;
+// metrics no rule matches pass through unchanged.
+pub struct MetricRenamer {
+    rules: Vec<CompiledRule>,
+}
+
+impl MetricRenamer {
+    pub fn new(rules: &[MetricRenameRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let pattern = RegexBuilder::new(&format!("^(?:{})$", rule.pattern))
+                    .size_limit(MAX_COMPILED_SIZE)
+                    .build()
+                    .map_err(|e| {
+                        anyhow::anyhow!("Invalid metric rename pattern {:?}: {}", rule.pattern, e)
+                    })?;
+                Ok(CompiledRule {
+                    pattern,
+                    name: rule.name.clone(),
+                    tags: rule.tags.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    // Renames the record's metrics and those of its samples, adding the
+    // derived tags. A rename that would overwrite another metric, or set a
+    // tag to a different value than it already has, is skipped with a
+    // warning. Returns whether anything was renamed.
+    pub fn apply(&self, telemetry: &mut Telemetry) -> bool {
+        let device_id = telemetry.device_id.clone();
+        let mut renamed = self.rename(&mut telemetry.metrics, &mut telemetry.tags, &device_id);
+        for sample in &mut telemetry.samples {
+            renamed |= self.rename(&mut sample.metrics, &mut telemetry.tags, &device_id);
+        }
+        renamed
+    }
+
+    fn rename(
+        &self,
+        metrics: &mut HashMap<String, f64>,
+        tags: &mut HashMap<String, String>,
+        device_id: &str,
+    ) -> bool {
+        // Sorted so that collisions resolve the same way every time
+        let mut names: Vec<String> = metrics.keys().cloned().collect();
+        names.sort();
+
+        let mut renamed = false;
+        for name in names {
+            let Some((new_name, new_tags)) = self.resolve(&name) else {
+                continue;
+            };
+            if new_name == name {
+                continue;
+            }
+            if metrics.contains_key(&new_name) {
+                warn!(
+                    "Not renaming metric {} to {} for device {}: name already taken",
+                    name, new_name, device_id
+                );
+                continue;
+            }
+            if let Some((tag, existing)) = new_tags.iter().find_map(|(tag, value)| {
+                tags.get(tag)
+                    .filter(|existing| *existing != value)
+                    .map(|existing| (tag, existing))
+            }) {
+                warn!(
+                    "Not renaming metric {} to {} for device {}: tag {} is already {}",
+                    name, new_name, device_id, tag, existing
+                );
+                continue;
+            }
+
+            let value = metrics.remove(&name).unwrap();
+            metrics.insert(new_name, value);
+            tags.extend(new_tags);
+            renamed = true;
+        }
+        renamed
+    }
+
+    // New name and derived tags from the first rule matching `name`
+    fn resolve(&self, name: &str) -> Option<(String, Vec<(String, String)>)> {
+        self.rules.iter().find_map(|rule| {
+            let captures = rule.pattern.captures(name)?;
+            let expand = |template: &str| {
+                let mut expanded = String::new();
+                captures.expand(template, &mut expanded);
+                expanded
+            };
+            let tags = rule
+                .tags
+                .iter()
+                .map(|(tag, template)| (tag.clone(), expand(template)))
+                .collect();
+            Some((expand(&rule.name), tags))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+
+    fn renamer() -> MetricRenamer {
+        MetricRenamer::new(&[
+            MetricRenameRule {
+                pattern: r"sensor_(\d+)_temp".to_string(),
+                name: "temperature".to_string(),
+                tags: BTreeMap::from([("sensor".to_string(), "$1".to_string())]),
+            },
+            MetricRenameRule {
+                pattern: r"(?P<zone>[a-z]+)\.(?P<metric>\w+)".to_string(),
+                name: "${metric}".to_string(),
+                tags: BTreeMap::from([("zone".to_string(), "${zone}".to_string())]),
+            },
+        ])
+        .unwrap()
+    }
+
+    fn reading(metrics: &[(&str, f64)]) -> Telemetry {
+        Telemetry {
+            device_id: "gateway-1".to_string(),
+            metrics: metrics
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_capture_groups_become_name_and_tags() {
+        let mut telemetry = reading(&[("sensor_7_temp", 21.5), ("battery", 90.0)]);
+        assert!(renamer().apply(&mut telemetry));
+        assert_eq!(
+            telemetry.metrics,
+            HashMap::from([
+                ("temperature".to_string(), 21.5),
+                ("battery".to_string(), 90.0)
+            ])
+        );
+        assert_eq!(telemetry.tags["sensor"], "7");
+
+        let mut telemetry = reading(&[("attic.humidity", 40.0)]);
+        telemetry.samples = vec![Sample {
+            ts: 1,
+            metrics: HashMap::from([("attic.humidity".to_string(), 41.0)]),
+        }];
+        assert!(renamer().apply(&mut telemetry));
+        assert_eq!(telemetry.metrics["humidity"], 40.0);
+        assert_eq!(telemetry.samples[0].metrics["humidity"], 41.0);
+        assert_eq!(telemetry.tags["zone"], "attic");
+    }
+
+    #[test]
+    fn test_non_matching_metrics_pass_through() {
+        // The pattern has to match the whole name
+        let mut telemetry = reading(&[("sensor_7_temp_raw", 1.0), ("Attic.humidity", 2.0)]);
+        let before = telemetry.clone();
+        assert!(!renamer().apply(&mut telemetry));
+        assert_eq!(telemetry, before);
+    }
+
+    #[test]
+    fn test_conflicting_renames_are_skipped() {
+        // Both want `temperature` and a different `sensor` tag; the first wins
+        let mut telemetry = reading(&[("sensor_3_temp", 20.0), ("sensor_7_temp", 22.0)]);
+        assert!(renamer().apply(&mut telemetry));
+        assert_eq!(telemetry.metrics["temperature"], 20.0);
+        assert_eq!(telemetry.metrics["sensor_7_temp"], 22.0);
+        assert_eq!(telemetry.tags["sensor"], "3");
+    }
+
+    #[test]
+    fn test_oversized_patterns_are_rejected() {
+        let rule = |pattern: &str| MetricRenameRule {
+            pattern: pattern.to_string(),
+            name: "x".to_string(),
+            tags: BTreeMap::new(),
+        };
+        assert!(MetricRenamer::new(&[rule(r"(\w{100}){100}")]).is_err());
+        assert!(MetricRenamer::new(&[rule(r"sensor_(")]).is_err());
+    }
+}
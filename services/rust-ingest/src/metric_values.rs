@@ -0,0 +1,405 @@
+use serde::{
+    de::{self, Deserializer, Visitor},
+    Deserialize,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+use tracing::warn;
+
+// A metric value as sent by the client, before it is reduced to f64
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    Number(f64),
+    // An integer that f64 cannot hold exactly, kept as sent
+    LargeInteger(i128),
+    Bool(bool),
+    Text(String),
+}
+
+impl MetricValue {
+    fn integer(value: i128) -> Self {
+        if value as f64 as i128 == value {
+            Self::Number(value as f64)
+        } else {
+            Self::LargeInteger(value)
+        }
+    }
+}
+
+// By hand rather than untagged, so integers are seen before they are
+// rounded into an f64
+impl<'de> Deserialize<'de> for MetricValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MetricValueVisitor;
+
+        impl<'de> Visitor<'de> for MetricValueVisitor {
+            type Value = MetricValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, boolean or string")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<MetricValue, E> {
+                Ok(MetricValue::integer(value.into()))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<MetricValue, E> {
+                Ok(MetricValue::integer(value.into()))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<MetricValue, E> {
+                Ok(MetricValue::Number(value))
+            }
+
+            fn visit_bool<E: de::Error>(self, value: bool) -> Result<MetricValue, E> {
+                Ok(MetricValue::Bool(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<MetricValue, E> {
+                Ok(MetricValue::Text(value.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, value: String) -> Result<MetricValue, E> {
+                Ok(MetricValue::Text(value))
+            }
+        }
+
+        deserializer.deserialize_any(MetricValueVisitor)
+    }
+}
+
+// What to do with integers beyond f64's exact range (about ±2^53), such as
+// nanosecond timestamps or large counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LargeIntegerPolicy {
+    // Round to the nearest f64 and log the loss
+    #[default]
+    Warn,
+    // Fail the request instead of storing a different number
+    Reject,
+}
+
+// The f64 to store for an integer f64 cannot hold exactly
+pub fn large_integer(name: &str, value: i128, policy: LargeIntegerPolicy) -> Result<f64, String> {
+    match policy {
+        LargeIntegerPolicy::Reject => Err(format!(
+            "{} value {} is too large to store without losing precision",
+            name, value
+        )),
+        LargeIntegerPolicy::Warn => {
+            let rounded = value as f64;
+            warn!(
+                "Metric {} value {} loses precision, stored as {}",
+                name, value, rounded
+            );
+            Ok(rounded)
+        }
+    }
+}
+
+// What to do with a metric sent twice in the same JSON object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKeyPolicy {
+    // Keep the last value and log the collision
+    #[default]
+    Warn,
+    // Fail rather than guess which reading was meant
+    Reject,
+}
+
+// A JSON object's entries as sent, repeated keys included, where
+// deserializing into a map would quietly keep one of them
+#[derive(Debug, Default)]
+pub struct ObjectEntries(pub Vec<(String, serde_json::Value)>);
+
+impl<'de> Deserialize<'de> for ObjectEntries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = ObjectEntries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<ObjectEntries, A::Error> {
+                let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(ObjectEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+impl ObjectEntries {
+    // One value per key, the last one sent where a key repeats
+    pub fn into_unique(
+        self,
+        policy: DuplicateKeyPolicy,
+    ) -> Result<HashMap<String, serde_json::Value>, String> {
+        let mut unique = HashMap::with_capacity(self.0.len());
+        for (key, value) in self.0 {
+            if let Some(previous) = unique.insert(key.clone(), value) {
+                match policy {
+                    DuplicateKeyPolicy::Reject => {
+                        return Err(format!("metric {} is sent more than once", key))
+                    }
+                    DuplicateKeyPolicy::Warn => warn!(
+                        "Metric {} is sent more than once, keeping {} over {}",
+                        key, unique[&key], previous
+                    ),
+                }
+            }
+        }
+        Ok(unique)
+    }
+}
+
+// How values that are not plain numbers are treated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricCoercion {
+    // Anything but a JSON number is rejected
+    #[default]
+    Strict,
+    // Numeric strings such as "23.5" are parsed, as long as they are finite
+    Lenient,
+    // Booleans and strings are kept as flag and text metrics, next to the
+    // numeric ones
+    Typed,
+}
+
+#[derive(Debug, Default)]
+pub struct NormalizedMetrics {
+    pub metrics: HashMap<String, f64>,
+    // Metrics whose value had to be coerced under lenient mode
+    pub coerced: Vec<String>,
+    // Non-numeric values kept under typed mode
+    pub flags: HashMap<String, bool>,
+    pub text: HashMap<String, String>,
+}
+
+// Turns client values into the f64 metrics the pipeline works on. Metrics
+// listed in `boolean_metrics` accept the on/off spellings firmware uses
+// (1/0, true/false, "on"/"off", ...) and become 1.0 or 0.0; anything else
+// must already be a number, unless `coercion` allows parsing it.
+pub fn normalize_metrics(
+    metrics: HashMap<String, MetricValue>,
+    boolean_metrics: &HashSet<String>,
+    coercion: MetricCoercion,
+    large_integers: LargeIntegerPolicy,
+) -> Result<NormalizedMetrics, String> {
+    let mut normalized = NormalizedMetrics::default();
+    for (name, value) in metrics {
+        let number = if boolean_metrics.contains(&name) {
+            parse_boolean(&value)
+                .map(|state| if state { 1.0 } else { 0.0 })
+                .ok_or_else(|| format!("{} is not a recognized boolean: {:?}", name, value))?
+        } else {
+            match value {
+                MetricValue::Number(number) => number,
+                MetricValue::LargeInteger(value) => large_integer(&name, value, large_integers)?,
+                MetricValue::Text(text) if coercion == MetricCoercion::Lenient => {
+                    let number = text
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|number| number.is_finite())
+                        .ok_or_else(|| format!("{} is not a finite number: {:?}", name, text))?;
+                    normalized.coerced.push(name.clone());
+                    number
+                }
+                MetricValue::Bool(state) if coercion == MetricCoercion::Typed => {
+                    normalized.flags.insert(name, state);
+                    continue;
+                }
+                MetricValue::Text(text) if coercion == MetricCoercion::Typed => {
+                    normalized.text.insert(name, text);
+                    continue;
+                }
+                other => return Err(format!("{} must be numeric, got {:?}", name, other)),
+            }
+        };
+        normalized.metrics.insert(name, number);
+    }
+    normalized.coerced.sort();
+    Ok(normalized)
+}
+
+fn parse_boolean(value: &MetricValue) -> Option<bool> {
+    match value {
+        MetricValue::Bool(state) => Some(*state),
+        MetricValue::Number(number) if *number == 1.0 => Some(true),
+        MetricValue::Number(number) if *number == 0.0 => Some(false),
+        MetricValue::Number(_) | MetricValue::LargeInteger(_) => None,
+        MetricValue::Text(text) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "on" | "1" => Some(true),
+            "false" | "off" | "0" => Some(false),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn normalize(value: serde_json::Value) -> Result<HashMap<String, f64>, String> {
+        let metrics =
+            serde_json::from_value(json!({ "relay": value, "temperature": 21.5 })).unwrap();
+        normalize_metrics(
+            metrics,
+            &HashSet::from(["relay".to_string()]),
+            MetricCoercion::Strict,
+            LargeIntegerPolicy::Reject,
+        )
+        .map(|normalized| normalized.metrics)
+    }
+
+    fn coerce(
+        value: serde_json::Value,
+        coercion: MetricCoercion,
+    ) -> Result<NormalizedMetrics, String> {
+        let metrics = serde_json::from_value(json!({ "temperature": value })).unwrap();
+        normalize_metrics(
+            metrics,
+            &HashSet::new(),
+            coercion,
+            LargeIntegerPolicy::Reject,
+        )
+    }
+
+    fn large(value: serde_json::Value, policy: LargeIntegerPolicy) -> Result<f64, String> {
+        let metrics = serde_json::from_value(json!({ "counter": value })).unwrap();
+        normalize_metrics(metrics, &HashSet::new(), MetricCoercion::Strict, policy)
+            .map(|normalized| normalized.metrics["counter"])
+    }
+
+    #[test]
+    fn test_boolean_representations() {
+        for (on, off) in [
+            (json!(1), json!(0)),
+            (json!(true), json!(false)),
+            (json!("on"), json!("off")),
+            (json!("ON"), json!("Off")),
+            (json!("true"), json!("false")),
+            (json!("1"), json!("0")),
+        ] {
+            assert_eq!(normalize(on).unwrap()["relay"], 1.0);
+            assert_eq!(normalize(off).unwrap()["relay"], 0.0);
+        }
+        assert_eq!(normalize(json!(true)).unwrap()["temperature"], 21.5);
+    }
+
+    #[test]
+    fn test_unrecognized_tokens_are_rejected() {
+        for value in [json!("maybe"), json!(2), json!(0.5)] {
+            assert!(normalize(value).unwrap_err().contains("relay"));
+        }
+
+        // Non-boolean metrics must stay numeric
+        let metrics = serde_json::from_value(json!({ "temperature": "on" })).unwrap();
+        assert!(normalize_metrics(
+            metrics,
+            &HashSet::new(),
+            MetricCoercion::Strict,
+            LargeIntegerPolicy::Reject
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_lenient_mode_coerces_numeric_strings() {
+        let normalized = coerce(json!(" 23.5 "), MetricCoercion::Lenient).unwrap();
+        assert_eq!(normalized.metrics["temperature"], 23.5);
+        assert_eq!(normalized.coerced, vec!["temperature"]);
+
+        let normalized = coerce(json!(23.5), MetricCoercion::Lenient).unwrap();
+        assert!(normalized.coerced.is_empty());
+
+        // Still nothing that cannot become a finite number
+        for value in [json!("warm"), json!("NaN"), json!("1e999"), json!(true)] {
+            assert!(coerce(value, MetricCoercion::Lenient).is_err());
+        }
+    }
+
+    #[test]
+    fn test_typed_mode_keeps_booleans_and_strings() {
+        let metrics = serde_json::from_value(json!({
+            "temperature": 21.5,
+            "door_open": true,
+            "status": "charging",
+            "level": "42",
+            "relay": "on",
+        }))
+        .unwrap();
+        let normalized = normalize_metrics(
+            metrics,
+            &HashSet::from(["relay".to_string()]),
+            MetricCoercion::Typed,
+            LargeIntegerPolicy::Reject,
+        )
+        .unwrap();
+        assert_eq!(
+            normalized.metrics,
+            HashMap::from([
+                ("temperature".to_string(), 21.5),
+                ("relay".to_string(), 1.0)
+            ])
+        );
+        assert_eq!(
+            normalized.flags,
+            HashMap::from([("door_open".to_string(), true)])
+        );
+        // Kept as sent, numeric or not
+        assert_eq!(normalized.text["status"], "charging");
+        assert_eq!(normalized.text["level"], "42");
+        assert!(normalized.coerced.is_empty());
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_numeric_strings() {
+        let error = coerce(json!("23.5"), MetricCoercion::Strict).unwrap_err();
+        assert!(error.contains("must be numeric"));
+    }
+
+    #[test]
+    fn test_integers_beyond_f64_precision_are_caught() {
+        // 2^53 + 1 would silently become 2^53
+        let value: MetricValue = serde_json::from_str("9007199254740993").unwrap();
+        assert_eq!(value, MetricValue::LargeInteger(9_007_199_254_740_993));
+        let value: MetricValue = serde_json::from_str("-9007199254740993").unwrap();
+        assert_eq!(value, MetricValue::LargeInteger(-9_007_199_254_740_993));
+
+        let error = large(json!(9_007_199_254_740_993u64), LargeIntegerPolicy::Reject).unwrap_err();
+        assert!(error.contains("9007199254740993"));
+        assert!(large(json!(u64::MAX), LargeIntegerPolicy::Reject).is_err());
+        assert_eq!(
+            large(json!(9_007_199_254_740_993u64), LargeIntegerPolicy::Warn).unwrap(),
+            9_007_199_254_740_992.0
+        );
+
+        // Integers f64 holds exactly are plain numbers, however large
+        for exact in [
+            json!(9_007_199_254_740_992u64),
+            json!(1u64 << 60),
+            json!(-42),
+        ] {
+            let expected = exact.as_f64().unwrap();
+            assert_eq!(large(exact, LargeIntegerPolicy::Reject).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,784 @@
+use crate::{
+    device_types::{DeviceClassifier, DeviceTypeConfig},
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+const TEMPLATE_VARIABLES: [&str; 2] = ["device_id", "device_type"];
+
+// Stands in for {device_type} when the classifier doesn't recognize a device
+const UNKNOWN_DEVICE_TYPE: &str = "unknown";
+
+// MQTT 3.1.1 control packet types (the high nibble of the first byte)
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+// Largest remaining length the 4-byte encoding can express
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+// QoS decides how far a record is known to have got when publish returns:
+//
+// - 0: written to the socket, never confirmed. Anything in flight when the
+//   connection drops is lost without an error. Cheapest by far.
+// - 1: the broker has acknowledged it. If the ack goes missing the record
+//   is sent again on a new connection, so subscribers can see duplicates.
+// - 2: acknowledged exactly once, at the cost of two more round trips. A
+//   clean session can't pick up an exchange cut short, so if the connection
+//   drops mid-way the record isn't sent again: publish fails instead, since
+//   the broker may already have delivered it.
+//
+// None of them makes the broker a durable store like Kafka. The broker only
+// keeps messages for subscribers with persistent sessions, and this client
+// connects with a clean session, so nothing is redelivered after a reconnect
+// beyond the single QoS 0 or 1 resend above. Records go out one at a time over one
+// connection, so QoS 1 and 2 also cap throughput at one round trip each.
+#[derive(Clone, Deserialize)]
+pub struct MqttSinkConfig {
+    // host:port of the broker; plain TCP only
+    #[serde(default = "default_broker")]
+    pub broker: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // MQTT topic per record; {device_id} and {device_type} are filled in
+    #[serde(default = "default_topic_template")]
+    pub topic_template: String,
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    // An idle connection is checked with a ping before it is used again
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u16,
+    // Limit on connecting and on waiting for each acknowledgment
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // Wait after a failed connect, doubled per failure up to the maximum;
+    // records arriving meanwhile fail fast instead of each trying to connect
+    #[serde(default = "default_reconnect_backoff_ms")]
+    pub reconnect_backoff_ms: u64,
+    #[serde(default = "default_max_reconnect_backoff_ms")]
+    pub max_reconnect_backoff_ms: u64,
+    // Only records for these Kafka topics are republished; all when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+    // Fail the request when the broker can't be reached. Off by default, so
+    // an MQTT outage only costs the republished copies, not ingestion.
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl Default for MqttSinkConfig {
+    fn default() -> Self {
+        Self {
+            broker: default_broker(),
+            client_id: default_client_id(),
+            username: None,
+            password: None,
+            topic_template: default_topic_template(),
+            qos: default_qos(),
+            retain: false,
+            keep_alive_secs: default_keep_alive_secs(),
+            timeout_ms: default_timeout_ms(),
+            reconnect_backoff_ms: default_reconnect_backoff_ms(),
+            max_reconnect_backoff_ms: default_max_reconnect_backoff_ms(),
+            topics: Vec::new(),
+            required: false,
+        }
+    }
+}
+
+// Keeps the password out of logged configuration
+impl fmt::Debug for MqttSinkConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttSinkConfig")
+            .field("broker", &self.broker)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("topic_template", &self.topic_template)
+            .field("qos", &self.qos)
+            .field("retain", &self.retain)
+            .field("keep_alive_secs", &self.keep_alive_secs)
+            .field("timeout_ms", &self.timeout_ms)
+            .field("reconnect_backoff_ms", &self.reconnect_backoff_ms)
+            .field("max_reconnect_backoff_ms", &self.max_reconnect_backoff_ms)
+            .field("topics", &self.topics)
+            .field("required", &self.required)
+            .finish()
+    }
+}
+
+fn default_broker() -> String {
+    "127.0.0.1:1883".to_string()
+}
+
+fn default_client_id() -> String {
+    "rust-ingest".to_string()
+}
+
+fn default_topic_template() -> String {
+    "telemetry/{device_type}/{device_id}".to_string()
+}
+
+fn default_qos() -> u8 {
+    1
+}
+
+fn default_keep_alive_secs() -> u16 {
+    30
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_reconnect_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_reconnect_backoff_ms() -> u64 {
+    30_000
+}
+
+// The one MQTT operation the sink needs, so tests can swap in a fake
+#[async_trait]
+pub trait MqttClient: Send + Sync {
+    async fn publish(&self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()>;
+}
+
+struct Session {
+    stream: TcpStream,
+    last_used: Instant,
+}
+
+struct ConnectionState {
+    session: Option<Session>,
+    next_packet_id: u16,
+    backoff: Duration,
+    // No connect attempts before this after a failed one
+    retry_at: Option<Instant>,
+}
+
+// Minimal MQTT 3.1.1 publisher over a single TCP connection. Connects on
+// first use and again after the connection fails.
+pub struct MqttConnection {
+    config: MqttSinkConfig,
+    state: Mutex<ConnectionState>,
+}
+
+impl MqttConnection {
+    pub fn new(config: &MqttSinkConfig) -> Self {
+        Self {
+            config: config.clone(),
+            state: Mutex::new(ConnectionState {
+                session: None,
+                next_packet_id: 1,
+                backoff: Duration::from_millis(config.reconnect_backoff_ms),
+                retry_at: None,
+            }),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.config.timeout_ms)
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.config.broker).await?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&connect_packet(&self.config)?).await?;
+        let (header, body) = read_packet(&mut stream).await?;
+        if header >> 4 != CONNACK || body.len() != 2 {
+            return Err(anyhow::anyhow!(
+                "Expected CONNACK from MQTT broker, got packet type {}",
+                header >> 4
+            ));
+        }
+        if body[1] != 0 {
+            return Err(anyhow::anyhow!(
+                "MQTT broker refused the connection (return code {})",
+                body[1]
+            ));
+        }
+        Ok(stream)
+    }
+
+    // Makes sure there is a usable session; true if it was just opened
+    async fn ensure_connected(&self, state: &mut ConnectionState) -> Result<bool> {
+        let keep_alive = Duration::from_secs(self.config.keep_alive_secs.into());
+        if let Some(session) = &mut state.session {
+            // The broker drops connections idle for 1.5x the keep-alive, and
+            // a QoS 0 write to one would be lost without an error
+            if keep_alive.is_zero() || session.last_used.elapsed() < keep_alive {
+                return Ok(false);
+            }
+            match within(self.timeout(), ping(&mut session.stream)).await {
+                Ok(()) => {
+                    session.last_used = Instant::now();
+                    return Ok(false);
+                }
+                Err(e) => {
+                    info!("Idle MQTT connection is gone ({}); reconnecting", e);
+                    state.session = None;
+                }
+            }
+        }
+
+        if let Some(retry_at) = state.retry_at {
+            let wait = retry_at.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                return Err(anyhow::anyhow!(
+                    "MQTT broker {} unavailable; next connect attempt in {:?}",
+                    self.config.broker,
+                    wait
+                ));
+            }
+        }
+        match within(self.timeout(), self.connect()).await {
+            Ok(stream) => {
+                info!("Connected to MQTT broker {}", self.config.broker);
+                state.session = Some(Session {
+                    stream,
+                    last_used: Instant::now(),
+                });
+                state.backoff = Duration::from_millis(self.config.reconnect_backoff_ms);
+                state.retry_at = None;
+                Ok(true)
+            }
+            Err(e) => {
+                state.retry_at = Some(Instant::now() + state.backoff);
+                state.backoff = (state.backoff * 2)
+                    .min(Duration::from_millis(self.config.max_reconnect_backoff_ms));
+                Err(e.context(format!(
+                    "Failed to connect to MQTT broker {}",
+                    self.config.broker
+                )))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MqttClient for MqttConnection {
+    async fn publish(&self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()> {
+        let mut state = self.state.lock().await;
+        loop {
+            let fresh = self.ensure_connected(&mut state).await?;
+            let packet_id = state.next_packet_id;
+            // Packet id 0 is reserved
+            state.next_packet_id = state.next_packet_id.checked_add(1).unwrap_or(1);
+            let session = state.session.as_mut().unwrap();
+            let sent = within(
+                self.timeout(),
+                send_publish(&mut session.stream, topic, payload, qos, retain, packet_id),
+            )
+            .await;
+            match sent {
+                Ok(()) => {
+                    session.last_used = Instant::now();
+                    return Ok(());
+                }
+                // A connection that has worked before may have been dropped
+                // since; give the record one more go on a new one, unless
+                // that could deliver it twice
+                Err(e) if !fresh && qos < 2 => {
+                    warn!("MQTT connection lost ({}); reconnecting", e);
+                    state.session = None;
+                }
+                Err(e) => {
+                    state.session = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+async fn within<T>(timeout: Duration, operation: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(timeout, operation)
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?} waiting for MQTT broker", timeout))?
+}
+
+fn write_remaining_length(packet: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &[u8]) -> Result<()> {
+    let length = u16::try_from(value.len())
+        .map_err(|_| anyhow::anyhow!("MQTT strings are limited to 65535 bytes"))?;
+    buf.extend_from_slice(&length.to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+// Fixed header followed by the variable header and payload
+fn packet(first_byte: u8, body: &[u8]) -> Result<Vec<u8>> {
+    if body.len() > MAX_REMAINING_LENGTH {
+        return Err(anyhow::anyhow!(
+            "MQTT packet of {} bytes is over the protocol limit",
+            body.len()
+        ));
+    }
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(first_byte);
+    write_remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    Ok(packet)
+}
+
+fn connect_packet(config: &MqttSinkConfig) -> Result<Vec<u8>> {
+    // Clean session: the broker keeps no state for us between connections
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    write_string(&mut body, b"MQTT")?;
+    body.push(4); // protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&config.keep_alive_secs.to_be_bytes());
+    write_string(&mut body, config.client_id.as_bytes())?;
+    if let Some(username) = &config.username {
+        write_string(&mut body, username.as_bytes())?;
+    }
+    if let Some(password) = &config.password {
+        write_string(&mut body, password.as_bytes())?;
+    }
+    packet(CONNECT << 4, &body)
+}
+
+fn publish_packet(
+    topic: &str,
+    payload: &[u8],
+    qos: u8,
+    retain: bool,
+    packet_id: u16,
+) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+    write_string(&mut body, topic.as_bytes())?;
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    packet(PUBLISH << 4 | qos << 1 | u8::from(retain), &body)
+}
+
+// Returns the first byte of the fixed header and the rest of the packet
+async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>)> {
+    let header = stream.read_u8().await?;
+    let mut length = 0usize;
+    for shift in (0..4).map(|i| i * 7) {
+        let byte = stream.read_u8().await?;
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            return Ok((header, body));
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Malformed remaining length from MQTT broker"
+    ))
+}
+
+async fn expect_ack<S: AsyncRead + Unpin>(stream: &mut S, kind: u8, packet_id: u16) -> Result<()> {
+    let (header, body) = read_packet(stream).await?;
+    if header >> 4 != kind || body.get(..2) != Some(&packet_id.to_be_bytes()[..]) {
+        return Err(anyhow::anyhow!(
+            "Expected MQTT packet type {} for packet {}, got type {}",
+            kind,
+            packet_id,
+            header >> 4
+        ));
+    }
+    Ok(())
+}
+
+async fn send_publish<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    topic: &str,
+    payload: &[u8],
+    qos: u8,
+    retain: bool,
+    packet_id: u16,
+) -> Result<()> {
+    stream
+        .write_all(&publish_packet(topic, payload, qos, retain, packet_id)?)
+        .await?;
+    match qos {
+        0 => Ok(()),
+        1 => expect_ack(stream, PUBACK, packet_id).await,
+        _ => {
+            expect_ack(stream, PUBREC, packet_id).await?;
+            let mut release = vec![PUBREL << 4 | 0x02, 2];
+            release.extend_from_slice(&packet_id.to_be_bytes());
+            stream.write_all(&release).await?;
+            expect_ack(stream, PUBCOMP, packet_id).await
+        }
+    }
+}
+
+async fn ping<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<()> {
+    stream.write_all(&[PINGREQ << 4, 0]).await?;
+    let (header, _) = read_packet(stream).await?;
+    if header >> 4 != PINGRESP {
+        return Err(anyhow::anyhow!(
+            "Expected PINGRESP from MQTT broker, got packet type {}",
+            header >> 4
+        ));
+    }
+    Ok(())
+}
+
+fn check_template(template: &str) -> Result<()> {
+    if template.contains(['+', '#']) {
+        return Err(anyhow::anyhow!(
+            "MQTT topic template {} contains a wildcard",
+            template
+        ));
+    }
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("Unclosed '{{' in MQTT topic template {}", template))?;
+        let name = &rest[start + 1..start + end];
+        if !TEMPLATE_VARIABLES.contains(&name) {
+            return Err(anyhow::anyhow!(
+                "Unknown variable {{{}}} in MQTT topic template {}; expected one of {:?}",
+                name,
+                template,
+                TEMPLATE_VARIABLES
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+// Keeps a value to a single topic level with no wildcards
+fn topic_level(value: &str) -> String {
+    value.replace(['/', '+', '#'], "_")
+}
+
+// Republishes records to an MQTT broker, for consumers that speak MQTT
+// rather than Kafka. The payload is the same encoded record Kafka gets.
+pub struct MqttSink {
+    config: MqttSinkConfig,
+    classifier: DeviceClassifier,
+    client: Box<dyn MqttClient>,
+}
+
+impl MqttSink {
+    pub fn new(config: MqttSinkConfig, device_types: DeviceTypeConfig) -> Result<Self> {
+        let client = MqttConnection::new(&config);
+        Self::with_client(config, device_types, Box::new(client))
+    }
+
+    fn with_client(
+        config: MqttSinkConfig,
+        device_types: DeviceTypeConfig,
+        client: Box<dyn MqttClient>,
+    ) -> Result<Self> {
+        if config.qos > 2 {
+            return Err(anyhow::anyhow!(
+                "MQTT qos must be 0, 1 or 2, not {}",
+                config.qos
+            ));
+        }
+        check_template(&config.topic_template)?;
+        Ok(Self {
+            config,
+            classifier: DeviceClassifier::new(device_types),
+            client,
+        })
+    }
+
+    fn topic_for(&self, record: &SinkRecord<'_>) -> String {
+        let telemetry = record.telemetry;
+        let device_type = self
+            .classifier
+            .classify(&telemetry.device_id, telemetry.metrics.keys());
+        self.config
+            .topic_template
+            .replace("{device_id}", &topic_level(&telemetry.device_id))
+            .replace(
+                "{device_type}",
+                &topic_level(device_type.as_deref().unwrap_or(UNKNOWN_DEVICE_TYPE)),
+            )
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        if !self.config.topics.is_empty() && !self.config.topics.iter().any(|t| t == record.topic) {
+            return Ok(());
+        }
+        let topic = self.topic_for(&record);
+        let result = self
+            .client
+            .publish(&topic, record.payload, self.config.qos, self.config.retain)
+            .await
+            .with_context(|| format!("Failed to publish to MQTT topic {}", topic));
+        match result {
+            Err(e) if !self.config.required => {
+                warn!(
+                    "Dropped MQTT copy of record for device {}: {:#}",
+                    record.telemetry.device_id, e
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex as StdMutex},
+    };
+    use tokio::net::TcpListener;
+
+    type Published = (String, Vec<u8>, u8, bool);
+
+    #[derive(Default, Clone)]
+    struct FakeClient {
+        published: Arc<StdMutex<Vec<Published>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl MqttClient for FakeClient {
+        async fn publish(&self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()> {
+            if self.fail {
+                return Err(anyhow::anyhow!("connection refused"));
+            }
+            self.published
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), payload.to_vec(), qos, retain));
+            Ok(())
+        }
+    }
+
+    fn sink(config: MqttSinkConfig, client: FakeClient) -> MqttSink {
+        let device_types = DeviceTypeConfig {
+            prefixes: HashMap::from([("thermo-".to_string(), "thermostat".to_string())]),
+            ..Default::default()
+        };
+        MqttSink::with_client(config, device_types, Box::new(client)).unwrap()
+    }
+
+    async fn publish(sink: &MqttSink, topic: &str, device_id: &str) -> Result<()> {
+        let telemetry = Telemetry {
+            device_id: device_id.to_string(),
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic,
+            key: device_id,
+            payload: b"encoded",
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_records_are_published_to_templated_topics() {
+        let client = FakeClient::default();
+        let sink = sink(
+            MqttSinkConfig {
+                qos: 2,
+                retain: true,
+                topics: vec!["telemetry".to_string()],
+                ..Default::default()
+            },
+            client.clone(),
+        );
+
+        publish(&sink, "telemetry", "thermo-1").await.unwrap();
+        publish(&sink, "telemetry", "site/a+b").await.unwrap();
+        publish(&sink, "telemetry.archive", "thermo-2")
+            .await
+            .unwrap();
+
+        let published = client.published.lock().unwrap();
+        assert_eq!(published.len(), 2);
+        assert_eq!(
+            published[0],
+            (
+                "telemetry/thermostat/thermo-1".to_string(),
+                b"encoded".to_vec(),
+                2,
+                true
+            )
+        );
+        // Ids can't add topic levels or wildcards
+        assert_eq!(published[1].0, "telemetry/unknown/site_a_b");
+    }
+
+    #[tokio::test]
+    async fn test_failures_only_fail_the_request_when_required() {
+        let failing = FakeClient {
+            fail: true,
+            ..Default::default()
+        };
+        let optional = sink(MqttSinkConfig::default(), failing.clone());
+        assert!(publish(&optional, "telemetry", "thermo-1").await.is_ok());
+
+        let required = sink(
+            MqttSinkConfig {
+                required: true,
+                ..Default::default()
+            },
+            failing,
+        );
+        assert!(publish(&required, "telemetry", "thermo-1").await.is_err());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let build = |config| {
+            MqttSink::with_client(config, Default::default(), Box::<FakeClient>::default())
+        };
+        assert!(build(MqttSinkConfig {
+            qos: 3,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(build(MqttSinkConfig {
+            topic_template: "telemetry/{site}".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(build(MqttSinkConfig {
+            topic_template: "telemetry/#".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    // Accepts one connection and acknowledges `publishes` QoS 1 messages on
+    // it, returning their topics, then hangs up
+    async fn serve_connection(listener: &TcpListener, publishes: usize) -> Vec<String> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (header, body) = read_packet(&mut stream).await.unwrap();
+        assert_eq!(header >> 4, CONNECT);
+        assert_eq!(&body[..6], b"\x00\x04MQTT");
+        stream.write_all(&[CONNACK << 4, 2, 0, 0]).await.unwrap();
+
+        let mut topics = Vec::new();
+        for _ in 0..publishes {
+            let (header, body) = read_packet(&mut stream).await.unwrap();
+            assert_eq!(header, PUBLISH << 4 | 1 << 1);
+            let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+            topics.push(String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap());
+            let packet_id = &body[2 + topic_len..4 + topic_len];
+            stream
+                .write_all(&[PUBACK << 4, 2, packet_id[0], packet_id[1]])
+                .await
+                .unwrap();
+        }
+        topics
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_after_broker_drops_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttSinkConfig {
+            broker: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let broker = tokio::spawn(async move {
+            let first = serve_connection(&listener, 1).await;
+            let second = serve_connection(&listener, 1).await;
+            (first, second)
+        });
+
+        let client = MqttConnection::new(&config);
+        client.publish("a", b"1", 1, false).await.unwrap();
+        // Sent on the dropped connection first, then again on a new one
+        client.publish("b", b"2", 1, false).await.unwrap();
+
+        let (first, second) = broker.await.unwrap();
+        assert_eq!(first, vec!["a"]);
+        assert_eq!(second, vec!["b"]);
+    }
+
+    #[tokio::test]
+    async fn test_qos_2_records_are_not_resent_after_a_dropped_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttSinkConfig {
+            broker: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let broker = tokio::spawn(async move {
+            let first = serve_connection(&listener, 1).await;
+            let reconnected = tokio::time::timeout(Duration::from_millis(200), listener.accept())
+                .await
+                .is_ok();
+            (first, reconnected)
+        });
+
+        let client = MqttConnection::new(&config);
+        client.publish("a", b"1", 1, false).await.unwrap();
+        assert!(client.publish("b", b"2", 2, false).await.is_err());
+
+        let (first, reconnected) = broker.await.unwrap();
+        assert_eq!(first, vec!["a"]);
+        assert!(!reconnected);
+    }
+}
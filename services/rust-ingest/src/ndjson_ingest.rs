@@ -0,0 +1,226 @@
+use crate::{
+    batch::{self, BatchItemResult},
+    request_id::RequestId,
+    server::{ApiError, AppState, RequestContext, TelemetryRequest},
+    trace_sampling::TraceDecision,
+};
+use axum::{
+    body::Body,
+    extract::{Extension, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+// Failed lines reported individually; past this they are only counted, so a
+// bad backfill can't grow the response without bound
+const MAX_REPORTED_FAILURES: usize = 100;
+
+// A line's number in the upload, counting from 1, and its bytes, or why it
+// was dropped without being parsed
+type Line = (usize, Result<Vec<u8>, String>);
+
+// Splits a body arriving in chunks into lines, holding at most one partial
+// line. A line longer than `max_line_bytes` is skipped up to its newline
+// and reported rather than buffered.
+struct LineSplitter {
+    max_line_bytes: usize,
+    partial: Vec<u8>,
+    overlong: bool,
+    line: usize,
+}
+
+impl LineSplitter {
+    fn new(max_line_bytes: usize) -> Self {
+        Self {
+            max_line_bytes,
+            partial: Vec::new(),
+            overlong: false,
+            line: 0,
+        }
+    }
+
+    // The lines the chunk completes; blank lines are skipped but counted
+    fn push(&mut self, mut chunk: &[u8]) -> Vec<Line> {
+        let mut lines = Vec::new();
+        while let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+            self.append(&chunk[..end]);
+            lines.extend(self.take());
+            chunk = &chunk[end + 1..];
+        }
+        self.append(chunk);
+        lines
+    }
+
+    // The last line, when the body doesn't end with a newline
+    fn finish(mut self) -> Option<Line> {
+        if self.partial.is_empty() && !self.overlong {
+            return None;
+        }
+        self.take()
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        if self.overlong {
+            return;
+        }
+        if self.partial.len() + bytes.len() > self.max_line_bytes {
+            self.overlong = true;
+            self.partial = Vec::new();
+            return;
+        }
+        self.partial.extend_from_slice(bytes);
+    }
+
+    fn take(&mut self) -> Option<Line> {
+        self.line += 1;
+        if std::mem::take(&mut self.overlong) {
+            return Some((
+                self.line,
+                Err(format!("line is longer than {} bytes", self.max_line_bytes)),
+            ));
+        }
+        let line = std::mem::take(&mut self.partial);
+        if line.trim_ascii().is_empty() {
+            return None;
+        }
+        Some((self.line, Ok(line)))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LineResult {
+    line: usize,
+    #[serde(flatten)]
+    result: BatchItemResult,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StreamResponse {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    // The first failures; `failed` counts them all
+    failures: Vec<LineResult>,
+}
+
+impl StreamResponse {
+    fn record(&mut self, result: LineResult) {
+        self.total += 1;
+        if result.result.success {
+            self.succeeded += 1;
+        } else {
+            self.failed += 1;
+            if self.failures.len() < MAX_REPORTED_FAILURES {
+                self.failures.push(result);
+            }
+        }
+    }
+}
+
+async fn publish_line(
+    state: &Arc<AppState>,
+    index: usize,
+    (line, bytes): Line,
+    request: &RequestContext,
+) -> LineResult {
+    let record = bytes.and_then(|bytes| {
+        serde_json::from_slice::<TelemetryRequest>(&bytes)
+            .map_err(|e| format!("invalid record: {}", e))
+    });
+    LineResult {
+        line,
+        result: batch::process_item(state, index, record, request).await,
+    }
+}
+
+// Bulk backfill as newline-delimited JSON, one record per line. Each line is
+// published as soon as it has arrived, so memory stays flat however large
+// the upload; a line that fails to parse or publish is counted and the
+// rest still go through. The summary comes once the body has been read.
+pub async fn ingest_stream(
+    State(state): State<Arc<AppState>>,
+    Extension(trace): Extension<TraceDecision>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<StreamResponse>), ApiError> {
+    let request = batch::bulk_request(&state, &headers, trace, request_id)?;
+    let mut response = StreamResponse::default();
+    let mut lines = LineSplitter::new(state.max_body_bytes);
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        // What was published stays published; the client resends the rest
+        let chunk = chunk.map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "stream body could not be read")
+                .with_details(format!("after line {}: {}", lines.line, e))
+        })?;
+        for line in lines.push(&chunk) {
+            let result = publish_line(&state, response.total, line, &request).await;
+            response.record(result);
+        }
+    }
+    if let Some(line) = lines.finish() {
+        let result = publish_line(&state, response.total, line, &request).await;
+        response.record(result);
+    }
+
+    if response.total == 0 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "stream must contain at least one record",
+        ));
+    }
+    Ok((request.ack.success_status(), Json(response)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(max_line_bytes: usize, chunks: &[&str]) -> Vec<(usize, Result<String, String>)> {
+        let mut splitter = LineSplitter::new(max_line_bytes);
+        let mut lines: Vec<Line> = chunks
+            .iter()
+            .flat_map(|chunk| splitter.push(chunk.as_bytes()))
+            .collect();
+        lines.extend(splitter.finish());
+        lines
+            .into_iter()
+            .map(|(number, line)| (number, line.map(|line| String::from_utf8(line).unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_span_chunks() {
+        assert_eq!(
+            split(100, &["{\"a\"", ":1}\n\n{\"b\":2}\r\n{", "\"c\":3}"]),
+            vec![
+                (1, Ok("{\"a\":1}".to_string())),
+                (3, Ok("{\"b\":2}\r".to_string())),
+                (4, Ok("{\"c\":3}".to_string())),
+            ]
+        );
+        assert!(split(100, &["", "\n  \n"]).is_empty());
+    }
+
+    #[test]
+    fn test_overlong_lines_are_skipped_not_buffered() {
+        let mut splitter = LineSplitter::new(8);
+        assert!(splitter.push(b"0123456").is_empty());
+        assert!(splitter.push(b"789abcdef").is_empty());
+        // Nothing of the overlong line is held while the rest arrives
+        assert!(splitter.partial.is_empty());
+        let lines = splitter.push(b"ghi\nshort\nlong again");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("longer than 8 bytes"));
+        assert_eq!(lines[1], (2, Ok(b"short".to_vec())));
+        assert_eq!(splitter.finish().unwrap().0, 3);
+    }
+}
@@ -0,0 +1,551 @@
+use crate::{
+    ack::ACK_HEADER, clock_skew::TIMESTAMP_HEADER, config::Config, priority::PRIORITY_HEADER,
+    server::ECHO_HEADER,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OpenApiConfig {
+    // Serve GET /openapi.json
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+// The parts of the configuration that change what clients may send or get
+// back, so the document describes this deployment rather than every option
+#[derive(Debug, Clone, Default)]
+pub struct ApiFeatures {
+    pub clock_skew: bool,
+    pub maintenance: bool,
+    pub priority: bool,
+    pub delayed_delivery: bool,
+    pub api_keys: bool,
+    // Keys are needed to call the API at all, not only to attribute usage
+    pub api_keys_required: bool,
+    // Per-device or per-tenant rate limits
+    pub rate_limited: bool,
+    pub max_samples_per_message: usize,
+}
+
+impl ApiFeatures {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            clock_skew: cfg.clock_skew.enabled,
+            maintenance: cfg.maintenance.enabled,
+            priority: cfg.priority.enabled,
+            delayed_delivery: cfg.delayed_delivery.enabled,
+            api_keys: !cfg.api_keys.is_empty(),
+            api_keys_required: !cfg.api_keys.is_empty() && cfg.require_api_key,
+            rate_limited: cfg.device_rate_limit.enabled || cfg.tenancy.enabled,
+            max_samples_per_message: cfg.max_samples_per_message,
+        }
+    }
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema_ref("ErrorResponse") } }
+    })
+}
+
+fn header_parameter(name: &str, description: &str, schema: Value, required: bool) -> Value {
+    json!({
+        "name": name,
+        "in": "header",
+        "required": required,
+        "description": description,
+        "schema": schema,
+    })
+}
+
+fn schemas(features: &ApiFeatures) -> Value {
+    let mut request = json!({
+        "type": "object",
+        "required": ["device_id"],
+        "properties": {
+            "device_id": { "type": "string", "minLength": 1 },
+            "ts": {
+                "allOf": [schema_ref("Timestamp")],
+                "description": "The time of receipt when omitted"
+            },
+            "metrics": {
+                "type": "object",
+                "additionalProperties": schema_ref("MetricValue")
+            },
+            "samples": {
+                "type": "array",
+                "maxItems": features.max_samples_per_message,
+                "items": schema_ref("Sample")
+            },
+            "raw": { "type": "array", "items": { "type": "integer", "minimum": 0, "maximum": 255 } },
+            "tags": { "type": "object", "additionalProperties": { "type": "string" } },
+            "ttl_ms": {
+                "type": "integer",
+                "format": "int64",
+                "minimum": 0,
+                "description": "Drop the record instead of sending it once this many ms have passed"
+            }
+        },
+        "description": "At least one of metrics or samples must be non-empty"
+    });
+    if features.delayed_delivery {
+        request["properties"]["deliver_at"] = json!({
+            "type": "integer",
+            "format": "int64",
+            "description": "Unix millis; a future time holds the record back until then"
+        });
+    }
+
+    json!({
+        "Timestamp": {
+            "description": "Unix seconds or millis, told apart by magnitude (below 1e11 is seconds), as a number or numeric string, or an RFC 3339 time",
+            "oneOf": [{ "type": "number" }, { "type": "string" }]
+        },
+        "MetricValue": {
+            "description": "A number, or a boolean or string where configured to be accepted",
+            "oneOf": [{ "type": "number" }, { "type": "boolean" }, { "type": "string" }]
+        },
+        "Sample": {
+            "type": "object",
+            "required": ["ts", "metrics"],
+            "properties": {
+                "ts": schema_ref("Timestamp"),
+                "metrics": { "type": "object", "additionalProperties": { "type": "number" } }
+            }
+        },
+        "TelemetryRequest": request,
+        "BatchRequest": {
+            "oneOf": [
+                { "type": "array", "items": schema_ref("TelemetryRequest") },
+                {
+                    "type": "object",
+                    "required": ["records"],
+                    "properties": {
+                        "defaults": {
+                            "type": "object",
+                            "description": "Fields applied to every record that doesn't set them"
+                        },
+                        "records": { "type": "array", "items": schema_ref("TelemetryRequest") }
+                    }
+                }
+            ]
+        },
+        "Interpretation": {
+            "type": "object",
+            "properties": {
+                "metrics": { "type": "object", "additionalProperties": { "type": "number" } },
+                "units": { "type": "object", "additionalProperties": { "type": "string" } },
+                "transforms": { "type": "array", "items": { "type": "string" } }
+            }
+        },
+        "TelemetryResponse": {
+            "type": "object",
+            "required": ["success", "message", "device_id"],
+            "properties": {
+                "success": { "type": "boolean" },
+                "message": { "type": "string" },
+                "device_id": { "type": "string" },
+                "interpreted": schema_ref("Interpretation"),
+                "partition": { "type": "integer", "description": "Kafka partition the record was written to" },
+                "offset": { "type": "integer", "description": "Offset of the record in its partition" }
+            }
+        },
+        "BatchItemResult": {
+            "type": "object",
+            "required": ["index", "status", "success"],
+            "properties": {
+                "index": { "type": "integer" },
+                "device_id": { "type": "string", "nullable": true },
+                "status": { "type": "integer", "description": "Status the record would have got on its own" },
+                "success": { "type": "boolean" },
+                "error": { "type": "string", "nullable": true }
+            }
+        },
+        "BatchResponse": {
+            "type": "object",
+            "required": ["total", "succeeded", "failed", "results"],
+            "properties": {
+                "total": { "type": "integer" },
+                "succeeded": { "type": "integer" },
+                "failed": { "type": "integer" },
+                "results": { "type": "array", "items": schema_ref("BatchItemResult") }
+            }
+        },
+        "StreamResponse": {
+            "type": "object",
+            "required": ["total", "succeeded", "failed", "failures"],
+            "properties": {
+                "total": { "type": "integer" },
+                "succeeded": { "type": "integer" },
+                "failed": { "type": "integer" },
+                "failures": {
+                    "type": "array",
+                    "description": "The first 100 failed lines",
+                    "items": {
+                        "allOf": [
+                            schema_ref("BatchItemResult"),
+                            {
+                                "type": "object",
+                                "required": ["line"],
+                                "properties": { "line": { "type": "integer" } }
+                            }
+                        ]
+                    }
+                }
+            }
+        },
+        "ValidationWarning": {
+            "type": "object",
+            "properties": {
+                "metric": { "type": "string" },
+                "value": { "type": "number" },
+                "expected_min": { "type": "number" },
+                "expected_max": { "type": "number" },
+                "severity": { "type": "string", "enum": ["minor", "major"] }
+            }
+        },
+        "ValidationReport": {
+            "type": "object",
+            "required": ["valid", "device_id", "errors", "warnings"],
+            "properties": {
+                "valid": { "type": "boolean" },
+                "device_id": { "type": "string" },
+                "topic": { "type": "string", "description": "Where the record would be published" },
+                "errors": { "type": "array", "items": { "type": "string" } },
+                "warnings": { "type": "array", "items": schema_ref("ValidationWarning") }
+            }
+        },
+        "ErrorResponse": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": { "type": "string" },
+                "details": { "type": "string", "nullable": true }
+            }
+        }
+    })
+}
+
+// Headers and error responses shared by the ingest endpoints
+fn ingest_parameters(features: &ApiFeatures) -> Vec<Value> {
+    let mut parameters = vec![header_parameter(
+        ACK_HEADER,
+        "What to wait for before answering: none, queued, leader or all",
+        json!({ "type": "string", "enum": ["none", "queued", "leader", "all"] }),
+        false,
+    )];
+    if features.priority {
+        parameters.push(header_parameter(
+            PRIORITY_HEADER,
+            "Send lane under congestion",
+            json!({ "type": "string", "enum": ["high", "normal", "low"] }),
+            false,
+        ));
+    }
+    if features.clock_skew {
+        parameters.push(header_parameter(
+            TIMESTAMP_HEADER,
+            "The client's clock in unix seconds; requests too far from server time are refused",
+            json!({ "type": "integer", "format": "int64" }),
+            true,
+        ));
+    }
+    parameters
+}
+
+fn ingest_errors(features: &ApiFeatures) -> Map<String, Value> {
+    let mut responses = Map::new();
+    responses.insert("400".into(), error_response("Malformed or invalid request"));
+    let unauthorized = match (features.api_keys_required, features.clock_skew) {
+        (true, true) => Some(
+            "Missing or invalid API key, or X-Timestamp missing or outside the allowed clock skew",
+        ),
+        (true, false) => Some("Missing or invalid API key"),
+        (false, true) => Some("Missing X-Timestamp, or outside the allowed clock skew"),
+        (false, false) => None,
+    };
+    if let Some(description) = unauthorized {
+        responses.insert("401".into(), error_response(description));
+    }
+    responses.insert("413".into(), error_response("Request or record too large"));
+    responses.insert(
+        "422".into(),
+        error_response("Record failed validation; don't resend it unchanged"),
+    );
+    if features.rate_limited {
+        responses.insert(
+            "429".into(),
+            error_response("Device or tenant over its rate limit; see Retry-After"),
+        );
+    }
+    responses.insert("500".into(), error_response("Processing failed"));
+    let unavailable = if features.maintenance {
+        "Temporarily unable to accept data, including during scheduled maintenance; see Retry-After"
+    } else {
+        "Temporarily unable to accept data; see Retry-After"
+    };
+    responses.insert("503".into(), error_response(unavailable));
+    responses
+}
+
+// OpenAPI 3 description of the ingest API as this deployment is configured
+pub fn document(features: &ApiFeatures) -> Value {
+    let parameters = ingest_parameters(features);
+
+    let mut single = ingest_errors(features);
+    let success = json!({
+        "description": "Accepted (202 for ack modes none and queued and for held records, otherwise 200)",
+        "content": { "application/json": { "schema": schema_ref("TelemetryResponse") } }
+    });
+    single.insert("200".into(), success.clone());
+    single.insert("202".into(), success);
+
+    let mut batch = ingest_errors(features);
+    let batch_success = json!({
+        "description": "Per-record results; a failed record doesn't fail the batch. With Accept: application/x-ndjson, one result per line, streamed as records complete",
+        "content": {
+            "application/json": { "schema": schema_ref("BatchResponse") },
+            "application/x-ndjson": { "schema": schema_ref("BatchItemResult") }
+        }
+    });
+    batch.insert("200".into(), batch_success.clone());
+    batch.insert("202".into(), batch_success);
+
+    let mut stream = ingest_errors(features);
+    let stream_success = json!({
+        "description": "Counts of the lines published and failed, once the whole upload has been read; a failed line doesn't stop the rest",
+        "content": { "application/json": { "schema": schema_ref("StreamResponse") } }
+    });
+    stream.insert("200".into(), stream_success.clone());
+    stream.insert("202".into(), stream_success);
+
+    let report = |description: &str| {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": schema_ref("ValidationReport") } }
+        })
+    };
+    // Nothing is sent, so neither rate limits nor send failures apply
+    let mut dry_run = ingest_errors(features);
+    dry_run.remove("429");
+    dry_run.remove("500");
+    let dry_run_parameters: Vec<Value> = parameters
+        .iter()
+        .filter(|parameter| parameter["name"] == TIMESTAMP_HEADER)
+        .cloned()
+        .collect();
+    dry_run.insert("200".into(), report("The record would be accepted"));
+    dry_run.insert("422".into(), report("The record would be rejected"));
+
+    let mut echo = parameters.clone();
+    echo.push(header_parameter(
+        ECHO_HEADER,
+        "Echo back how the record was interpreted",
+        json!({ "type": "boolean" }),
+        false,
+    ));
+
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Rust ingestion API",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/telemetry": {
+                "post": {
+                    "operationId": "ingestTelemetry",
+                    "summary": "Ingest one telemetry record",
+                    "parameters": echo,
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": schema_ref("TelemetryRequest") },
+                            // An encoded telemetry.Telemetry message (see telemetry.proto)
+                            "application/x-protobuf": {
+                                "schema": { "type": "string", "format": "binary" }
+                            },
+                            // The JSON fields, MessagePack encoded
+                            "application/msgpack": { "schema": schema_ref("TelemetryRequest") }
+                        }
+                    },
+                    "responses": single
+                }
+            },
+            "/telemetry/batch": {
+                "post": {
+                    "operationId": "ingestBatch",
+                    "summary": "Ingest several telemetry records",
+                    "parameters": parameters,
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": schema_ref("BatchRequest") } }
+                    },
+                    "responses": batch
+                }
+            },
+            "/telemetry/stream": {
+                "post": {
+                    "operationId": "ingestStream",
+                    "summary": "Backfill telemetry records, one JSON record per line, published as they arrive",
+                    "parameters": parameters,
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/x-ndjson": { "schema": schema_ref("TelemetryRequest") } }
+                    },
+                    "responses": stream
+                }
+            },
+            "/telemetry/validate": {
+                "post": {
+                    "operationId": "validateTelemetry",
+                    "summary": "Check one telemetry record without publishing it",
+                    "parameters": dry_run_parameters,
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": schema_ref("TelemetryRequest") },
+                            "application/x-protobuf": {
+                                "schema": { "type": "string", "format": "binary" }
+                            },
+                            "application/msgpack": { "schema": schema_ref("TelemetryRequest") }
+                        }
+                    },
+                    "responses": dry_run
+                }
+            }
+        },
+        "components": { "schemas": schemas(features) }
+    });
+    if features.api_keys {
+        document["components"]["securitySchemes"] = json!({
+            "apiKey": { "type": "http", "scheme": "bearer" }
+        });
+        document["security"] = if features.api_keys_required {
+            json!([{ "apiKey": [] }])
+        } else {
+            // Keys are optional: they only attribute usage
+            json!([{}, { "apiKey": [] }])
+        };
+    }
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every $ref in the document, for checking they all resolve
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(target)) = map.get("$ref") {
+                    found.push(target);
+                }
+                map.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(items) => items.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    fn check_document(document: &Value) {
+        // Served as text, so it has to survive a round trip
+        let text = serde_json::to_string(document).unwrap();
+        let document: Value = serde_json::from_str(&text).unwrap();
+
+        assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(document["info"]["title"].is_string());
+        assert!(document["info"]["version"].is_string());
+
+        let mut operation_ids = Vec::new();
+        for (path, item) in document["paths"].as_object().unwrap() {
+            assert!(path.starts_with('/'));
+            for (method, operation) in item.as_object().unwrap() {
+                assert!(["get", "post", "put", "delete"].contains(&method.as_str()));
+                operation_ids.push(operation["operationId"].as_str().unwrap().to_string());
+                let responses = operation["responses"].as_object().unwrap();
+                assert!(!responses.is_empty());
+                for (status, response) in responses {
+                    assert!(status.parse::<u16>().is_ok(), "bad status {}", status);
+                    assert!(response["description"].is_string());
+                }
+                for parameter in operation["parameters"].as_array().unwrap() {
+                    assert!(
+                        ["header", "query", "path"].contains(&parameter["in"].as_str().unwrap())
+                    );
+                }
+            }
+        }
+        let unique: std::collections::HashSet<_> = operation_ids.iter().collect();
+        assert_eq!(unique.len(), operation_ids.len());
+
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "unresolved {}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_document_is_valid_openapi() {
+        let minimal = ApiFeatures {
+            max_samples_per_message: 100,
+            ..Default::default()
+        };
+        check_document(&document(&minimal));
+
+        let everything = ApiFeatures {
+            clock_skew: true,
+            maintenance: true,
+            priority: true,
+            delayed_delivery: true,
+            api_keys: true,
+            api_keys_required: true,
+            rate_limited: true,
+            max_samples_per_message: 100,
+        };
+        check_document(&document(&everything));
+    }
+
+    #[test]
+    fn test_document_follows_enabled_features() {
+        let minimal = document(&ApiFeatures::default());
+        let request = &minimal["components"]["schemas"]["TelemetryRequest"]["properties"];
+        assert!(request.get("deliver_at").is_none());
+        let parameters = minimal["paths"]["/telemetry/batch"]["post"]["parameters"]
+            .as_array()
+            .unwrap();
+        assert_eq!(parameters.len(), 1);
+        assert!(minimal.get("security").is_none());
+
+        let document = document(&ApiFeatures {
+            clock_skew: true,
+            delayed_delivery: true,
+            ..Default::default()
+        });
+        let request = &document["components"]["schemas"]["TelemetryRequest"]["properties"];
+        assert!(request.get("deliver_at").is_some());
+        let operation = &document["paths"]["/telemetry"]["post"];
+        assert!(operation["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(
+                |parameter| parameter["name"] == TIMESTAMP_HEADER && parameter["required"] == true
+            ));
+        assert!(operation["responses"].get("401").is_some());
+    }
+}
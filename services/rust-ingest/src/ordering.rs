@@ -0,0 +1,231 @@
+use crate::sink::{SinkRecord, TelemetrySink};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::Registry;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Idle per-key locks kept around; locks in use are never dropped
+    #[serde(default = "default_max_keys")]
+    pub max_keys: usize,
+}
+
+impl Default for OrderingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_keys: default_max_keys(),
+        }
+    }
+}
+
+fn default_max_keys() -> usize {
+    10_000
+}
+
+// One async lock per record key
+struct KeyLocks {
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    max_keys: usize,
+}
+
+impl KeyLocks {
+    fn new(max_keys: usize) -> Self {
+        Self {
+            locks: Mutex::new(HashMap::new()),
+            max_keys: max_keys.max(1),
+        }
+    }
+
+    async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            match locks.get(key) {
+                Some(lock) => Arc::clone(lock),
+                None => {
+                    if locks.len() >= self.max_keys {
+                        // Only the map holds an idle lock: no send holds or
+                        // waits on it, so dropping it can't let one overtake
+                        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+                    }
+                    let lock = Arc::new(AsyncMutex::new(()));
+                    locks.insert(key.to_string(), Arc::clone(&lock));
+                    lock
+                }
+            }
+        };
+        lock.lock_owned().await
+    }
+
+    // Drops the key's lock unless a send holds or waits on it
+    fn forget(&self, key: &str) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks
+            .get(key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(key);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+// Strict per-device ordering: a key's sends go out one at a time, so a
+// record still being retried by the inner sink can't be overtaken by the
+// next record for the same device. Different keys still send concurrently.
+pub struct KeyOrderedSink {
+    inner: Arc<dyn TelemetrySink>,
+    locks: KeyLocks,
+}
+
+impl KeyOrderedSink {
+    pub fn new(inner: Arc<dyn TelemetrySink>, config: &OrderingConfig) -> Self {
+        Self {
+            inner,
+            locks: KeyLocks::new(config.max_keys),
+        }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for KeyOrderedSink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let _guard = self.locks.lock(record.key).await;
+        self.inner.publish(record).await
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        self.inner.publish_raw(topic, key, payload).await
+    }
+
+    // Keyed like the device's records, so it lands after the ones in flight.
+    // The device is gone afterwards, so its lock goes too.
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        let guard = self.locks.lock(key).await;
+        let sent = self.inner.publish_tombstone(topic, key).await;
+        drop(guard);
+        self.locks.forget(key);
+        sent
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id)
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+
+    // Stands in for a producer that retries internally: "slow" records sit
+    // out a failed attempt and a backoff before they land
+    #[derive(Default)]
+    struct RetryingSink {
+        delivered: Mutex<Vec<i64>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for RetryingSink {
+        fn name(&self) -> &'static str {
+            "retrying"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if record.payload == b"slow" {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            self.delivered.lock().unwrap().push(record.telemetry.ts);
+            Ok(())
+        }
+    }
+
+    async fn send(sink: Arc<dyn TelemetrySink>, ts: i64, payload: &'static [u8]) {
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts,
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic: "telemetry",
+            key: &telemetry.device_id,
+            payload,
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn deliver_concurrently(sink: Arc<dyn TelemetrySink>) {
+        let first = tokio::spawn(send(Arc::clone(&sink), 1, b"slow"));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = tokio::spawn(send(sink, 2, b"fast"));
+        first.await.unwrap();
+        second.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retrying_record_is_not_overtaken() {
+        let inner = Arc::new(RetryingSink::default());
+        deliver_concurrently(inner.clone()).await;
+        assert_eq!(*inner.delivered.lock().unwrap(), vec![2, 1]);
+
+        let inner = Arc::new(RetryingSink::default());
+        let ordered = KeyOrderedSink::new(inner.clone(), &OrderingConfig::default());
+        deliver_concurrently(Arc::new(ordered)).await;
+        assert_eq!(*inner.delivered.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_locks_are_evicted() {
+        let locks = KeyLocks::new(2);
+        let held = locks.lock("a").await;
+        drop(locks.lock("b").await);
+        drop(locks.lock("c").await);
+        // "b" was idle and went; "a" is still held
+        assert_eq!(locks.len(), 2);
+
+        drop(held);
+        drop(locks.lock("d").await);
+        assert_eq!(locks.len(), 1);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::{http_client::Endpoint, logging::JsonFields};
+use serde_json::{json, Map, Value};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{span, warn, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+// Finished spans waiting for export; further spans are dropped, so a slow
+// collector never holds up a request
+const QUEUE_CAPACITY: usize = 4096;
+const MAX_BATCH: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SERVICE_NAME: &str = "rust-ingest";
+const TRACES_PATH: &str = "/v1/traces";
+
+// Span fields that carry the caller's trace context (trace_sampling's
+// request_span) rather than attributes of the span
+const TRACE_ID_FIELD: &str = "trace_id";
+const PARENT_SPAN_ID_FIELD: &str = "parent_span_id";
+
+// OTLP span kinds
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+// What the layer keeps on an open span, in its extensions
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    // No parent in this process, so the caller's traceparent applies
+    root: bool,
+    start_unix_nanos: u128,
+    attributes: Map<String, Value>,
+}
+
+impl OtlpSpan {
+    fn record(&mut self, fields: Map<String, Value>) {
+        for (name, value) in fields {
+            match (name.as_str(), value) {
+                (TRACE_ID_FIELD, Value::String(trace_id)) if self.root => {
+                    self.trace_id = trace_id;
+                }
+                (PARENT_SPAN_ID_FIELD, Value::String(parent)) if self.root => {
+                    self.parent_span_id = Some(parent);
+                }
+                (_, value) => {
+                    self.attributes.insert(name, value);
+                }
+            }
+        }
+    }
+
+    fn into_json(self, name: &str, end_unix_nanos: u128) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+            .collect();
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": name,
+            "kind": if self.root { KIND_SERVER } else { KIND_INTERNAL },
+            // 64-bit integers are strings in OTLP/JSON
+            "startTimeUnixNano": self.start_unix_nanos.to_string(),
+            "endTimeUnixNano": end_unix_nanos.to_string(),
+            "attributes": attributes,
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = Value::String(parent);
+        }
+        span
+    }
+}
+
+fn any_value(value: Value) -> Value {
+    match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(value), _) => json!({ "intValue": value.to_string() }),
+            (None, Some(value)) => json!({ "intValue": value.to_string() }),
+            _ => json!({ "doubleValue": n.as_f64() }),
+        },
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// Random ids in the hex form traceparent and OTLP/JSON both use
+fn new_trace_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+fn new_span_id() -> String {
+    let mut id = new_trace_id();
+    id.truncate(16);
+    id
+}
+
+// Tracing layer that turns closed spans into OTLP spans and queues them for
+// export. Spans under a request share its trace id, which is the caller's
+// when a traceparent came with the request.
+pub struct OtlpLayer {
+    spans: mpsc::Sender<Value>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl OtlpLayer {
+    // Starts the exporter task on the current runtime
+    pub fn install(endpoint: Endpoint) -> Self {
+        let (spans, queued) = mpsc::channel(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        tokio::spawn(export_spans(endpoint, queued, Arc::clone(&dropped)));
+        Self { spans, dropped }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OtlpSpan>()
+                .map(|parent| (parent.trace_id.clone(), parent.span_id.clone()))
+        });
+        let mut otlp = OtlpSpan {
+            trace_id: new_trace_id(),
+            span_id: new_span_id(),
+            parent_span_id: None,
+            root: parent.is_none(),
+            start_unix_nanos: unix_nanos(),
+            attributes: Map::new(),
+        };
+        if let Some((trace_id, parent_span_id)) = parent {
+            otlp.trace_id = trace_id;
+            otlp.parent_span_id = Some(parent_span_id);
+        }
+        let mut fields = JsonFields::default();
+        attrs.record(&mut fields);
+        otlp.record(fields.0);
+        span.extensions_mut().insert(otlp);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = JsonFields::default();
+        values.record(&mut fields);
+        let mut extensions = span.extensions_mut();
+        if let Some(otlp) = extensions.get_mut::<OtlpSpan>() {
+            otlp.record(fields.0);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(otlp) = span.extensions_mut().remove::<OtlpSpan>() else {
+            return;
+        };
+        let exported = otlp.into_json(span.name(), unix_nanos());
+        if self.spans.try_send(exported).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Sends queued spans in batches, when a batch is full or every
+// FLUSH_INTERVAL. Spans still queued when the process exits are lost.
+async fn export_spans(
+    endpoint: Endpoint,
+    mut queued: mpsc::Receiver<Value>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut batch = Vec::new();
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let open = tokio::select! {
+            span = queued.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < MAX_BATCH {
+                        continue;
+                    }
+                    true
+                }
+                None => false,
+            },
+            _ = flush.tick() => true,
+        };
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("Dropped {} spans: the OTLP export queue was full", dropped);
+        }
+        if !batch.is_empty() {
+            let spans = std::mem::take(&mut batch);
+            let count = spans.len();
+            if let Err(e) = export(&endpoint, spans).await {
+                warn!(
+                    "Couldn't export {} spans to the OTLP collector: {:#}",
+                    count, e
+                );
+            }
+        }
+        if !open {
+            return;
+        }
+    }
+}
+
+async fn export(endpoint: &Endpoint, spans: Vec<Value>) -> anyhow::Result<()> {
+    let body = export_request(spans).to_string();
+    let (status, response) = tokio::time::timeout(
+        EXPORT_TIMEOUT,
+        endpoint.post(
+            TRACES_PATH,
+            "application/json",
+            "application/json",
+            body.as_bytes(),
+        ),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("timed out after {:?}", EXPORT_TIMEOUT))??;
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!(
+            "collector answered {}: {}",
+            status,
+            String::from_utf8_lossy(&response)
+        ));
+    }
+    Ok(())
+}
+
+// An OTLP/HTTP ExportTraceServiceRequest in its JSON encoding
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": SERVICE_NAME } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn layer() -> (OtlpLayer, mpsc::Receiver<Value>) {
+        let (spans, queued) = mpsc::channel(16);
+        let layer = OtlpLayer {
+            spans,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (layer, queued)
+    }
+
+    fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+        span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|attribute| attribute["key"] == key)
+            .map(|attribute| &attribute["value"])
+            .unwrap_or(&Value::Null)
+    }
+
+    #[test]
+    fn test_request_spans_continue_the_callers_trace() {
+        let (layer, mut queued) = layer();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!(
+                "request",
+                method = "POST",
+                trace_id = tracing::field::Empty,
+                parent_span_id = tracing::field::Empty,
+            );
+            request.record("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736");
+            request.record("parent_span_id", "00f067aa0ba902b7");
+            let _entered = request.enter();
+            let telemetry = tracing::info_span!(
+                "telemetry",
+                device_id = "sensor-1",
+                topic = tracing::field::Empty,
+                send_ms = tracing::field::Empty,
+            );
+            telemetry.record("topic", "telemetry");
+            telemetry.record("send_ms", 1.5);
+        });
+
+        // Spans are exported as they close, innermost first
+        let telemetry = queued.try_recv().unwrap();
+        let request = queued.try_recv().unwrap();
+        assert_eq!(request["name"], "request");
+        assert_eq!(request["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request["kind"], KIND_SERVER);
+        assert_eq!(attribute(&request, "method")["stringValue"], "POST");
+        assert_eq!(attribute(&request, "trace_id"), &Value::Null);
+
+        assert_eq!(telemetry["traceId"], request["traceId"]);
+        assert_eq!(telemetry["parentSpanId"], request["spanId"]);
+        assert_eq!(telemetry["kind"], KIND_INTERNAL);
+        assert_eq!(
+            attribute(&telemetry, "device_id")["stringValue"],
+            "sensor-1"
+        );
+        assert_eq!(attribute(&telemetry, "topic")["stringValue"], "telemetry");
+        assert_eq!(attribute(&telemetry, "send_ms")["doubleValue"], 1.5);
+        let start: u128 = telemetry["startTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let end: u128 = telemetry["endTimeUnixNano"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(start <= end);
+    }
+
+    #[test]
+    fn test_spans_without_a_caller_start_a_trace() {
+        let (layer, mut queued) = layer();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("request").in_scope(|| {});
+            tracing::info_span!("request").in_scope(|| {});
+        });
+
+        let first = queued.try_recv().unwrap();
+        let second = queued.try_recv().unwrap();
+        for span in [&first, &second] {
+            assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+            assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+            assert!(span.get("parentSpanId").is_none());
+        }
+        assert_ne!(first["traceId"], second["traceId"]);
+    }
+
+    #[tokio::test]
+    async fn test_spans_are_posted_to_the_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/otlp/", listener.local_addr().unwrap());
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read up to the end of the body the request announced
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let endpoint = Endpoint::parse("otlp_endpoint", &url).unwrap();
+        let (spans, queued) = mpsc::channel(16);
+        let exporter = tokio::spawn(export_spans(endpoint, queued, Arc::new(AtomicU64::new(0))));
+        spans
+            .send(json!({ "name": "request", "traceId": "4bf92f3577b34da6a3ce929d0e0e4736" }))
+            .await
+            .unwrap();
+        // Closing the queue flushes what is left
+        drop(spans);
+        exporter.await.unwrap();
+
+        let request = collector.await.unwrap();
+        assert!(
+            request.starts_with("POST /otlp/v1/traces HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        let body: Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            SERVICE_NAME
+        );
+        assert_eq!(resource["scopeSpans"][0]["spans"][0]["name"], "request");
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{
+    proto::telemetry::Telemetry,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use parquet::arrow::ArrowWriter;
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParquetSinkConfig {
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    // Rows buffered before a file is written
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    // Upper bound on how long a partial batch sits in memory
+    #[serde(default = "default_flush_interval_secs")]
+    pub flush_interval_secs: u64,
+}
+
+impl Default for ParquetSinkConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_directory(),
+            batch_size: default_batch_size(),
+            flush_interval_secs: default_flush_interval_secs(),
+        }
+    }
+}
+
+fn default_directory() -> String {
+    "parquet".to_string()
+}
+
+fn default_batch_size() -> usize {
+    10_000
+}
+
+fn default_flush_interval_secs() -> u64 {
+    60
+}
+
+// Buffers telemetry and writes it out as Parquet files, one file per batch.
+//
+// Devices report different metric sets, so each file's schema is the union
+// of the metric names seen in that batch: one nullable Float64 column per
+// metric, null where a row's device didn't report it. Schemas can therefore
+// differ between files; readers should merge schemas by column name.
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    buffer: Mutex<Vec<Telemetry>>,
+    file_seq: AtomicU64,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Result<Self> {
+        if config.batch_size == 0 {
+            return Err(anyhow::anyhow!("parquet.batch_size must be greater than 0"));
+        }
+        std::fs::create_dir_all(&config.directory)?;
+        Ok(Self {
+            config,
+            buffer: Mutex::new(Vec::new()),
+            file_seq: AtomicU64::new(0),
+        })
+    }
+
+    // Create the sink and spawn the background task that flushes partial batches
+    pub fn start(config: ParquetSinkConfig) -> Result<Arc<Self>> {
+        let sink = Arc::new(Self::new(config)?);
+        let interval = Duration::from_secs(sink.config.flush_interval_secs.max(1));
+        let flusher = Arc::downgrade(&sink);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(sink) = flusher.upgrade() else {
+                    break;
+                };
+                if let Err(e) = sink.flush().await {
+                    warn!("Periodic parquet flush failed: {:?}", e);
+                }
+            }
+        });
+        Ok(sink)
+    }
+
+    fn take_buffer(&self) -> Vec<Telemetry> {
+        mem::take(&mut *self.buffer.lock().unwrap())
+    }
+
+    // Rows that couldn't be written go back in front of the buffer, to be
+    // written with the next batch or flush
+    async fn write_rows(&self, rows: Vec<Telemetry>) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let path = self.next_path();
+        let count = rows.len();
+        let written = path.clone();
+        let (rows, result) = tokio::task::spawn_blocking(move || {
+            let result = write_parquet_file(&written, &rows);
+            (rows, result)
+        })
+        .await?;
+        if let Err(e) = result {
+            self.buffer.lock().unwrap().splice(0..0, rows);
+            return Err(e);
+        }
+        info!("Wrote {} telemetry rows to {}", count, path.display());
+        Ok(())
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let seq = self.file_seq.fetch_add(1, Ordering::Relaxed);
+        Path::new(&self.config.directory).join(format!(
+            "telemetry-{}-{:06}.parquet",
+            chrono::Utc::now().timestamp_millis(),
+            seq
+        ))
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for ParquetSink {
+    fn name(&self) -> &'static str {
+        "parquet"
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let full_batch = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.push(record.telemetry.clone());
+            if buffer.len() >= self.config.batch_size {
+                Some(mem::take(&mut *buffer))
+            } else {
+                None
+            }
+        };
+
+        // The record is buffered either way; failing it here would only get
+        // it sent again on top of the copy still in the buffer
+        if let Some(rows) = full_batch {
+            if let Err(e) = self.write_rows(rows).await {
+                warn!(
+                    "Parquet write failed, keeping the rows for the next attempt: {:?}",
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.write_rows(self.take_buffer()).await
+    }
+
+    fn pending(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+}
+
+pub fn build_record_batch(rows: &[Telemetry]) -> Result<RecordBatch> {
+    let metric_names: BTreeSet<&str> = rows
+        .iter()
+        .flat_map(|row| row.metrics.keys().map(String::as_str))
+        .collect();
+
+    let mut fields = vec![
+        Field::new("device_id", DataType::Utf8, false),
+        Field::new(
+            "ts",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|row| row.device_id.as_str()),
+        )),
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(rows.iter().map(|row| row.ts))
+                .with_timezone("UTC"),
+        ),
+    ];
+
+    for name in metric_names {
+        // Keep the fixed columns unambiguous if a device reports a metric with the same name
+        let column = match name {
+            "device_id" | "ts" => format!("metric_{}", name),
+            _ => name.to_string(),
+        };
+        fields.push(Field::new(column, DataType::Float64, true));
+        columns.push(Arc::new(Float64Array::from_iter(
+            rows.iter().map(|row| row.metrics.get(name).copied()),
+        )));
+    }
+
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        columns,
+    )?)
+}
+
+fn write_parquet_file(path: &Path, rows: &[Telemetry]) -> Result<()> {
+    let batch = build_record_batch(rows)?;
+
+    // Write under a temporary name so readers never pick up a partial file
+    let tmp_path = path.with_extension("parquet.tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority};
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::collections::HashMap;
+
+    fn telemetry(device_id: &str, ts: i64, metrics: &[(&str, f64)]) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            ts,
+            metrics: metrics
+                .iter()
+                .map(|(k, v)| (k.to_string(), *v))
+                .collect::<HashMap<_, _>>(),
+            raw: Vec::new(),
+            tags: HashMap::new(),
+            metadata: None,
+            samples: Vec::new(),
+            flag_metrics: HashMap::new(),
+            text_metrics: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_record_batch_uses_sparse_metric_columns() {
+        let rows = vec![
+            telemetry("thermo-1", 1_000, &[("temperature", 21.5)]),
+            telemetry("gps-1", 2_000, &[("lat", 52.1), ("lon", 4.3)]),
+        ];
+
+        let batch = build_record_batch(&rows).unwrap();
+        let schema = batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["device_id", "ts", "lat", "lon", "temperature"]);
+
+        let temperature = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(temperature.value(0), 21.5);
+        assert!(temperature.is_null(1));
+    }
+
+    async fn publish(sink: &ParquetSink, row: &Telemetry) -> Result<()> {
+        sink.publish(SinkRecord {
+            topic: "telemetry",
+            key: &row.device_id,
+            payload: &[],
+            telemetry: row,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+    }
+
+    fn rows_written(dir: &Path) -> usize {
+        let mut total_rows = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let file = File::open(entry.unwrap().path()).unwrap();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+                .unwrap()
+                .build()
+                .unwrap();
+            for batch in reader {
+                total_rows += batch.unwrap().num_rows();
+            }
+        }
+        total_rows
+    }
+
+    #[tokio::test]
+    async fn test_flushes_full_batches_to_parquet_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ParquetSink::new(ParquetSinkConfig {
+            directory: dir.path().to_string_lossy().to_string(),
+            batch_size: 2,
+            flush_interval_secs: 60,
+        })
+        .unwrap();
+
+        for (i, device) in ["a", "b", "c"].iter().enumerate() {
+            let row = telemetry(device, i as i64, &[("temperature", i as f64)]);
+            publish(&sink, &row).await.unwrap();
+        }
+
+        // The first two rows hit batch_size, the third waits for an explicit flush
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        sink.flush().await.unwrap();
+        assert_eq!(rows_written(dir.path()), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_kept_when_a_write_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path().join("out");
+        let sink = ParquetSink::new(ParquetSinkConfig {
+            directory: directory.to_string_lossy().to_string(),
+            batch_size: 2,
+            flush_interval_secs: 60,
+        })
+        .unwrap();
+
+        // With the directory gone, neither a full batch nor a flush can be written
+        std::fs::remove_dir(&directory).unwrap();
+        for (i, device) in ["a", "b", "c"].iter().enumerate() {
+            let row = telemetry(device, i as i64, &[("temperature", i as f64)]);
+            publish(&sink, &row).await.unwrap();
+        }
+        assert_eq!(sink.pending(), 3);
+        assert!(sink.flush().await.is_err());
+        assert_eq!(sink.pending(), 3);
+
+        std::fs::create_dir(&directory).unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(sink.pending(), 0);
+        assert_eq!(rows_written(&directory), 3);
+    }
+}
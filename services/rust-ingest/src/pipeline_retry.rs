@@ -0,0 +1,157 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{fmt, future::Future, time::Duration};
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PipelineRetryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Retries after the first attempt
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // Doubled after every retry, up to max_backoff_ms
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for PipelineRetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_retries: default_max_retries(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    25
+}
+
+fn default_max_backoff_ms() -> u64 {
+    500
+}
+
+// A failure that is likely to clear by itself and happened before anything
+// was handed off for delivery, so trying again can't publish a record twice.
+// Stages raise it for errors they know to be safe to retry; anything else
+// fails the request straight away.
+#[derive(Debug)]
+pub struct TransientError {
+    pub stage: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transient {} failure: {}", self.stage, self.reason)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+pub struct PipelineRetry {
+    config: PipelineRetryConfig,
+}
+
+impl PipelineRetry {
+    pub fn new(config: PipelineRetryConfig) -> Self {
+        Self { config }
+    }
+
+    // Runs `attempt` until it succeeds, fails with anything but a
+    // TransientError, or runs out of retries. `device_id` is for the logs.
+    pub async fn run<T, F, Fut>(&self, device_id: &str, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.is::<TransientError>() && retries < self.config.max_retries => {
+                    retries += 1;
+                    warn!(
+                        "Retrying telemetry for device {} in {:?} (retry {} of {}): {}",
+                        device_id, backoff, retries, self.config.max_retries, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn retry(max_retries: u32) -> PipelineRetry {
+        PipelineRetry::new(PipelineRetryConfig {
+            enabled: true,
+            max_retries,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 2,
+        })
+    }
+
+    fn transient() -> anyhow::Error {
+        TransientError {
+            stage: "send",
+            reason: "local queue full".to_string(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let attempts = Cell::new(0);
+        let result = retry(3)
+            .run("sensor-1", || async {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    return Err(transient());
+                }
+                Ok(attempts.get())
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Still failing after the last retry: the error is passed on
+        attempts.set(0);
+        let result: Result<()> = retry(2)
+            .run("sensor-1", || async {
+                attempts.set(attempts.get() + 1);
+                Err(transient())
+            })
+            .await;
+        assert!(result.unwrap_err().is::<TransientError>());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_fail_immediately() {
+        let attempts = Cell::new(0);
+        let result: Result<()> = retry(3)
+            .run("sensor-1", || async {
+                attempts.set(attempts.get() + 1);
+                Err(anyhow::anyhow!("Message production error: MessageTimedOut"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,494 @@
+use crate::{
+    request_metrics,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use prometheus::{IntCounterVec, IntGaugeVec, Registry};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+// Header a client sets to pick the lane for its request
+pub const PRIORITY_HEADER: &str = "x-priority";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Self::High, Self::Normal, Self::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Normal => "normal",
+            Self::Low => "low",
+        }
+    }
+
+    // The priority the request asks for, if any
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let Some(value) = headers.get(PRIORITY_HEADER) else {
+            return Ok(None);
+        };
+        let value = value.to_str().unwrap_or_default().trim();
+        Self::ALL
+            .into_iter()
+            .find(|priority| value.eq_ignore_ascii_case(priority.as_str()))
+            .map(Some)
+            .ok_or_else(|| {
+                format!(
+                    "unknown {} {:?}; expected high, normal or low",
+                    PRIORITY_HEADER, value
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PriorityConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // device type -> lane; the x-priority header overrides it
+    #[serde(default)]
+    pub device_types: HashMap<String, Priority>,
+    #[serde(default)]
+    pub default_priority: Priority,
+    // Sends allowed at once; records beyond this wait in their lane
+    #[serde(default = "default_max_concurrent_sends")]
+    pub max_concurrent_sends: usize,
+    #[serde(default)]
+    pub weights: LaneWeights,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device_types: HashMap::new(),
+            default_priority: Priority::Normal,
+            max_concurrent_sends: default_max_concurrent_sends(),
+            weights: LaneWeights::default(),
+        }
+    }
+}
+
+fn default_max_concurrent_sends() -> usize {
+    64
+}
+
+// Sends each lane gets per round while lanes are contended
+#[derive(Debug, Clone, Deserialize)]
+pub struct LaneWeights {
+    #[serde(default = "default_high_weight")]
+    pub high: u32,
+    #[serde(default = "default_normal_weight")]
+    pub normal: u32,
+    #[serde(default = "default_low_weight")]
+    pub low: u32,
+}
+
+impl Default for LaneWeights {
+    fn default() -> Self {
+        Self {
+            high: default_high_weight(),
+            normal: default_normal_weight(),
+            low: default_low_weight(),
+        }
+    }
+}
+
+fn default_high_weight() -> u32 {
+    8
+}
+
+fn default_normal_weight() -> u32 {
+    4
+}
+
+fn default_low_weight() -> u32 {
+    1
+}
+
+struct LaneState {
+    free: usize,
+    // Indexed by priority, highest first
+    waiting: [VecDeque<oneshot::Sender<Slot>>; 3],
+    // Sends left to each lane in the current round
+    credits: [u32; 3],
+}
+
+impl LaneState {
+    // Weighted round robin: the highest waiting lane with credit left goes
+    // next, and a new round starts once every waiting lane has used its
+    // share, so low lanes are slowed down under contention but not starved
+    fn next_waiter(&mut self, weights: [u32; 3]) -> Option<(usize, oneshot::Sender<Slot>)> {
+        let waiting = |lane: &usize| !self.waiting[*lane].is_empty();
+        let lane = match (0..3).filter(waiting).find(|&lane| self.credits[lane] > 0) {
+            Some(lane) => lane,
+            None => {
+                self.credits = weights;
+                (0..3).find(waiting)?
+            }
+        };
+        self.credits[lane] -= 1;
+        self.waiting[lane].pop_front().map(|sender| (lane, sender))
+    }
+}
+
+// Priority-aware dispatcher in front of the sink. Up to
+// `max_concurrent_sends` records are sent at once; under congestion the
+// rest queue in a high, normal or low lane and free send slots are handed
+// out by weight, so safety-critical devices get through ahead of routine
+// sensors.
+pub struct PriorityLanes {
+    device_types: HashMap<String, Priority>,
+    default_priority: Priority,
+    weights: [u32; 3],
+    state: Mutex<LaneState>,
+    dispatched: IntCounterVec,
+}
+
+impl PriorityLanes {
+    pub fn new(config: PriorityConfig) -> Self {
+        let weights = [
+            config.weights.high.max(1),
+            config.weights.normal.max(1),
+            config.weights.low.max(1),
+        ];
+        let dispatched = request_metrics::int_counter_vec(
+            "rust_ingest_priority_dispatched_total",
+            "Records given a send slot, by lane",
+            &["lane"],
+        );
+        for priority in Priority::ALL {
+            dispatched.with_label_values(&[priority.as_str()]);
+        }
+        Self {
+            device_types: config.device_types,
+            default_priority: config.default_priority,
+            weights,
+            state: Mutex::new(LaneState {
+                free: config.max_concurrent_sends.max(1),
+                waiting: Default::default(),
+                credits: weights,
+            }),
+            dispatched,
+        }
+    }
+
+    // The requested priority, else the device type's, else the default
+    pub fn priority_of(&self, requested: Option<Priority>, device_type: Option<&str>) -> Priority {
+        requested
+            .or_else(|| {
+                device_type.and_then(|device_type| self.device_types.get(device_type).copied())
+            })
+            .unwrap_or(self.default_priority)
+    }
+
+    // Waits in `priority`'s lane until a send slot is free
+    async fn acquire(self: &Arc<Self>, priority: Priority) -> Result<Slot> {
+        let lane = priority as usize;
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            // A slot is only ever free while no lane has waiters
+            if state.free > 0 {
+                state.free -= 1;
+                self.dispatched
+                    .with_label_values(&[priority.as_str()])
+                    .inc();
+                return Ok(Slot::new(self));
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting[lane].push_back(sender);
+            receiver
+        };
+        receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("priority dispatcher dropped a waiting record"))
+    }
+
+    // Hands a finished send's slot to the next waiter, or frees it
+    fn release(self: &Arc<Self>) {
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                let next = state.next_waiter(self.weights);
+                if next.is_none() {
+                    state.free += 1;
+                }
+                next
+            };
+            let Some((lane, sender)) = next else {
+                return;
+            };
+            match sender.send(Slot::new(self)) {
+                Ok(()) => {
+                    self.dispatched
+                        .with_label_values(&[Priority::ALL[lane].as_str()])
+                        .inc();
+                    return;
+                }
+                // The waiter gave up; try the next one
+                Err(mut slot) => slot.lanes = None,
+            }
+        }
+    }
+
+    fn waiting(&self) -> [usize; 3] {
+        let state = self.state.lock().unwrap();
+        [0, 1, 2].map(|lane| state.waiting[lane].len())
+    }
+
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        let lanes = Arc::clone(self);
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge_vec(
+                "rust_ingest_priority_waiting",
+                "Records waiting for a send slot, by lane",
+                &["lane"],
+            ),
+            move |waiting: &IntGaugeVec| {
+                let counts = lanes.waiting();
+                for priority in Priority::ALL {
+                    waiting
+                        .with_label_values(&[priority.as_str()])
+                        .set(counts[priority as usize] as i64);
+                }
+            },
+        )?;
+        request_metrics::register(registry, &self.dispatched)
+    }
+}
+
+// A send slot, passed on to the next waiter when dropped
+struct Slot {
+    lanes: Option<Arc<PriorityLanes>>,
+}
+
+impl Slot {
+    fn new(lanes: &Arc<PriorityLanes>) -> Self {
+        Self {
+            lanes: Some(Arc::clone(lanes)),
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(lanes) = self.lanes.take() {
+            lanes.release();
+        }
+    }
+}
+
+// Sends each record through its priority lane
+pub struct PrioritySink {
+    inner: Arc<dyn TelemetrySink>,
+    lanes: Arc<PriorityLanes>,
+}
+
+impl PrioritySink {
+    pub fn new(inner: Arc<dyn TelemetrySink>, lanes: Arc<PriorityLanes>) -> Self {
+        Self { inner, lanes }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for PrioritySink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        let _slot = self.lanes.acquire(record.priority).await?;
+        self.inner.publish(record).await
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        self.inner.publish_raw(topic, key, payload).await
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        self.inner.publish_tombstone(topic, key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id)
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, proto::telemetry::Telemetry};
+
+    #[derive(Default)]
+    struct RecordingSink {
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            self.sent.lock().unwrap().push(record.key.to_string());
+            Ok(())
+        }
+    }
+
+    fn lanes(high: u32, low: u32) -> Arc<PriorityLanes> {
+        Arc::new(PriorityLanes::new(PriorityConfig {
+            enabled: true,
+            max_concurrent_sends: 1,
+            weights: LaneWeights {
+                high,
+                normal: 1,
+                low,
+            },
+            ..Default::default()
+        }))
+    }
+
+    // Queues one send per (key, priority) behind a held slot, in order,
+    // then frees the slot and returns the order the sink saw them in
+    async fn contended_order(lanes: Arc<PriorityLanes>, sends: &[(&str, Priority)]) -> Vec<String> {
+        let inner = Arc::new(RecordingSink::default());
+        let sink = Arc::new(PrioritySink::new(inner.clone(), Arc::clone(&lanes)));
+        let held = lanes.acquire(Priority::Normal).await.unwrap();
+
+        let mut tasks = Vec::new();
+        for (queued, (key, priority)) in sends.iter().enumerate() {
+            let sink = Arc::clone(&sink);
+            let (key, priority) = (key.to_string(), *priority);
+            tasks.push(tokio::spawn(async move {
+                let telemetry = Telemetry::default();
+                sink.publish(SinkRecord {
+                    topic: "telemetry",
+                    key: &key,
+                    payload: &[],
+                    telemetry: &telemetry,
+                    expires_at: None,
+                    ack: AckMode::All,
+                    priority,
+                    receipt: None,
+                    request_id: None,
+                    position: None,
+                })
+                .await
+            }));
+            while lanes.waiting().iter().sum::<usize>() <= queued {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        let sent = inner.sent.lock().unwrap().clone();
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_is_sent_ahead_of_low() {
+        let order = contended_order(
+            lanes(8, 1),
+            &[
+                ("sensor-1", Priority::Low),
+                ("sensor-2", Priority::Low),
+                ("sensor-3", Priority::Normal),
+                ("alarm-1", Priority::High),
+                ("alarm-2", Priority::High),
+            ],
+        )
+        .await;
+        assert_eq!(
+            order,
+            vec!["alarm-1", "alarm-2", "sensor-3", "sensor-1", "sensor-2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weights_keep_low_from_starving() {
+        let lanes = lanes(2, 1);
+        let order = contended_order(
+            Arc::clone(&lanes),
+            &[
+                ("low-1", Priority::Low),
+                ("low-2", Priority::Low),
+                ("low-3", Priority::Low),
+                ("high-1", Priority::High),
+                ("high-2", Priority::High),
+                ("high-3", Priority::High),
+            ],
+        )
+        .await;
+        assert_eq!(
+            order,
+            vec!["high-1", "high-2", "low-1", "high-3", "low-2", "low-3"]
+        );
+        let metrics = request_metrics::rendered(|registry| lanes.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_priority_waiting{lane=\"low\"} 0"));
+        assert!(metrics.contains("rust_ingest_priority_dispatched_total{lane=\"high\"} 3"));
+        assert!(metrics.contains("rust_ingest_priority_dispatched_total{lane=\"low\"} 3"));
+    }
+
+    #[test]
+    fn test_priority_comes_from_header_then_device_type() {
+        let lanes = PriorityLanes::new(PriorityConfig {
+            enabled: true,
+            device_types: HashMap::from([("smoke_detector".to_string(), Priority::High)]),
+            default_priority: Priority::Low,
+            ..Default::default()
+        });
+        assert_eq!(
+            lanes.priority_of(None, Some("smoke_detector")),
+            Priority::High
+        );
+        assert_eq!(lanes.priority_of(None, Some("thermostat")), Priority::Low);
+        assert_eq!(lanes.priority_of(None, None), Priority::Low);
+        assert_eq!(
+            lanes.priority_of(Some(Priority::Normal), Some("smoke_detector")),
+            Priority::Normal
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(Priority::from_headers(&headers), Ok(None));
+        headers.insert(PRIORITY_HEADER, "HIGH".parse().unwrap());
+        assert_eq!(Priority::from_headers(&headers), Ok(Some(Priority::High)));
+        headers.insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+        assert!(Priority::from_headers(&headers).is_err());
+    }
+}
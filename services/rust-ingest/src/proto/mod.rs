@@ -1,3 +1,8 @@
 pub mod telemetry {
     include!(concat!(env!("OUT_DIR"), "/telemetry.rs"));
 }
+
+// Prometheus exposition messages, for scrapes that negotiate protobuf
+pub mod prometheus {
+    include!(concat!(env!("OUT_DIR"), "/io.prometheus.client.rs"));
+}
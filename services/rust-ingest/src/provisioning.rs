@@ -0,0 +1,209 @@
+use crate::{bounded_store::BoundedStore, device_types::DeviceClassifier};
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AutoProvisionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // How long after first contact the observed metric set may still grow
+    #[serde(default = "default_grace_period_secs")]
+    pub grace_period_secs: u64,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for AutoProvisionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            grace_period_secs: default_grace_period_secs(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_grace_period_secs() -> u64 {
+    300
+}
+
+fn default_max_devices() -> usize {
+    100_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+struct ProvisionedDevice {
+    device_type: Option<String>,
+    schema: BTreeSet<String>,
+    first_seen: Instant,
+    bound: bool,
+}
+
+// Zero-touch onboarding: the first valid reading from an unknown device
+// registers it with its metric fingerprint and classified type. During the
+// grace period the schema absorbs any new metric names; afterwards it is
+// bound and readings with metrics outside it are rejected. Evicted devices
+// simply re-provision on their next reading.
+pub struct DeviceProvisioner {
+    grace_period: Duration,
+    devices: Mutex<BoundedStore<ProvisionedDevice>>,
+}
+
+impl DeviceProvisioner {
+    pub fn new(config: &AutoProvisionConfig) -> Self {
+        Self {
+            grace_period: Duration::from_secs(config.grace_period_secs),
+            devices: Mutex::new(BoundedStore::new(
+                config.max_devices,
+                Duration::from_secs(config.idle_eviction_secs),
+            )),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.devices.lock().unwrap().remove(device_id)
+    }
+
+    pub fn check(
+        &self,
+        device_id: &str,
+        metrics: &HashMap<String, f64>,
+        classifier: &DeviceClassifier,
+    ) -> Result<()> {
+        self.check_at(device_id, metrics, classifier, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        device_id: &str,
+        metrics: &HashMap<String, f64>,
+        classifier: &DeviceClassifier,
+        now: Instant,
+    ) -> Result<()> {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.get_or_insert_with(device_id, now, || {
+            let device_type = classifier.classify(device_id, metrics.keys());
+            let schema: BTreeSet<String> = metrics.keys().cloned().collect();
+            info!(
+                "Auto-provisioned device {} as type {} with metrics {:?}",
+                device_id,
+                device_type.as_deref().unwrap_or("unknown"),
+                schema
+            );
+            ProvisionedDevice {
+                device_type,
+                schema,
+                first_seen: now,
+                bound: false,
+            }
+        });
+
+        if !device.bound {
+            if now.saturating_duration_since(device.first_seen) < self.grace_period {
+                device.schema.extend(metrics.keys().cloned());
+                return Ok(());
+            }
+            device.bound = true;
+            info!("Bound schema {:?} to device {}", device.schema, device_id);
+        }
+
+        let mut unknown: Vec<&str> = metrics
+            .keys()
+            .filter(|metric| !device.schema.contains(metric.as_str()))
+            .map(String::as_str)
+            .collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort_unstable();
+        Err(anyhow::anyhow!(
+            "Metrics [{}] are not in the schema bound to device {} (type {})",
+            unknown.join(", "),
+            device_id,
+            device.device_type.as_deref().unwrap_or("unknown")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_types::DeviceTypeConfig;
+
+    fn metrics(names: &[&str]) -> HashMap<String, f64> {
+        names.iter().map(|name| (name.to_string(), 1.0)).collect()
+    }
+
+    #[test]
+    fn test_schema_stabilizes_during_grace_then_binds() {
+        let provisioner = DeviceProvisioner::new(&AutoProvisionConfig {
+            enabled: true,
+            grace_period_secs: 60,
+            ..Default::default()
+        });
+        let classifier = DeviceClassifier::new(DeviceTypeConfig::default());
+        let start = Instant::now();
+
+        assert!(provisioner
+            .check_at("dev-1", &metrics(&["temperature"]), &classifier, start)
+            .is_ok());
+        // A metric that shows up during the grace period joins the schema
+        let during_grace = start + Duration::from_secs(30);
+        assert!(provisioner
+            .check_at(
+                "dev-1",
+                &metrics(&["temperature", "humidity"]),
+                &classifier,
+                during_grace
+            )
+            .is_ok());
+
+        let after_grace = start + Duration::from_secs(90);
+        assert!(provisioner
+            .check_at("dev-1", &metrics(&["humidity"]), &classifier, after_grace)
+            .is_ok());
+        let err = provisioner
+            .check_at(
+                "dev-1",
+                &metrics(&["temperature", "pressure"]),
+                &classifier,
+                after_grace,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("pressure"));
+    }
+
+    #[test]
+    fn test_devices_are_bound_independently() {
+        let provisioner = DeviceProvisioner::new(&AutoProvisionConfig {
+            enabled: true,
+            grace_period_secs: 0,
+            ..Default::default()
+        });
+        let classifier = DeviceClassifier::new(DeviceTypeConfig::default());
+        let now = Instant::now();
+
+        assert!(provisioner
+            .check_at("dev-1", &metrics(&["temperature"]), &classifier, now)
+            .is_ok());
+        assert!(provisioner
+            .check_at("dev-2", &metrics(&["lat", "lon"]), &classifier, now)
+            .is_ok());
+        assert!(provisioner
+            .check_at("dev-1", &metrics(&["lat"]), &classifier, now)
+            .is_err());
+    }
+}
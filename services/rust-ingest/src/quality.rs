@@ -0,0 +1,223 @@
+use crate::{
+    bounded_store::BoundedStore, rate_limit::TokenBucket, sink::TelemetrySink,
+    telemetry_handler::ValidationWarning,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QualityStreamConfig {
+    // Warning events one device may emit per minute; excess events are dropped
+    #[serde(default = "default_events_per_minute")]
+    pub events_per_minute: u32,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for QualityStreamConfig {
+    fn default() -> Self {
+        Self {
+            events_per_minute: default_events_per_minute(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+impl QualityStreamConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.events_per_minute == 0 {
+            return Err(anyhow::anyhow!(
+                "quality_stream.events_per_minute must be at least 1; leave quality_topic unset to publish no quality events"
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_events_per_minute() -> u32 {
+    60
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+// One accepted-but-suspicious value, as published to the quality topic
+#[derive(Debug, Serialize)]
+pub struct QualityEvent<'a> {
+    pub device_id: &'a str,
+    pub ts: i64,
+    #[serde(flatten)]
+    pub warning: &'a ValidationWarning,
+}
+
+// Publishes validation warnings as structured events to a dedicated topic,
+// separate from the telemetry flow, so data-quality tooling can query them.
+pub struct QualityStream {
+    topic: String,
+    config: QualityStreamConfig,
+    limiters: Mutex<BoundedStore<TokenBucket>>,
+}
+
+impl QualityStream {
+    pub fn new(topic: String, config: QualityStreamConfig) -> Self {
+        let limiters = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            topic,
+            config,
+            limiters: Mutex::new(limiters),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.limiters.lock().unwrap().remove(device_id)
+    }
+
+    pub async fn emit(
+        &self,
+        sink: &dyn TelemetrySink,
+        device_id: &str,
+        ts: i64,
+        warnings: &[ValidationWarning],
+    ) {
+        let allowed = self.admit(device_id, warnings.len(), Instant::now());
+        if allowed < warnings.len() {
+            debug!(
+                "Rate limited {} quality events for device {}",
+                warnings.len() - allowed,
+                device_id
+            );
+        }
+
+        for warning in &warnings[..allowed] {
+            let event = QualityEvent {
+                device_id,
+                ts,
+                warning,
+            };
+            let result = match serde_json::to_vec(&event) {
+                Ok(payload) => sink.publish_raw(&self.topic, device_id, &payload).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Failed to publish quality event for device {}: {:?}",
+                    device_id, e
+                );
+            }
+        }
+    }
+
+    // How many of `wanted` events the device may emit right now
+    fn admit(&self, device_id: &str, wanted: usize, now: Instant) -> usize {
+        if wanted == 0 {
+            return 0;
+        }
+        let per_sec = f64::from(self.config.events_per_minute) / 60.0;
+        let burst = f64::from(self.config.events_per_minute).max(1.0);
+        let mut limiters = self.limiters.lock().unwrap();
+        let bucket =
+            limiters.get_or_insert_with(device_id, now, || TokenBucket::new(per_sec, burst, now));
+        (0..wanted)
+            .take_while(|_| bucket.try_take(now).is_ok())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::SinkRecord;
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    #[derive(Default)]
+    struct RawCapture {
+        events: Mutex<Vec<(String, serde_json::Value)>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for RawCapture {
+        fn name(&self) -> &'static str {
+            "capture"
+        }
+
+        async fn publish(&self, _record: SinkRecord<'_>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn publish_raw(&self, topic: &str, _key: &str, payload: &[u8]) -> Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), serde_json::from_slice(payload)?));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warnings_are_published_as_structured_events() {
+        let stream = QualityStream::new("quality".to_string(), QualityStreamConfig::default());
+        let sink = RawCapture::default();
+        let warnings = vec![ValidationWarning::new("humidity", 120.0, 0.0, 100.0)];
+
+        stream
+            .emit(&sink, "sensor-1", 1700000000000, &warnings)
+            .await;
+
+        let events = sink.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (topic, event) = &events[0];
+        assert_eq!(topic, "quality");
+        assert_eq!(event["device_id"], "sensor-1");
+        assert_eq!(event["metric"], "humidity");
+        assert_eq!(event["value"], 120.0);
+        assert_eq!(event["expected_min"], 0.0);
+        assert_eq!(event["expected_max"], 100.0);
+        assert_eq!(event["severity"], "minor");
+    }
+
+    #[test]
+    fn test_events_are_rate_limited_per_device() {
+        let stream = QualityStream::new(
+            "quality".to_string(),
+            QualityStreamConfig {
+                events_per_minute: 3,
+                ..Default::default()
+            },
+        );
+        let now = Instant::now();
+
+        assert_eq!(stream.admit("noisy", 5, now), 3);
+        assert_eq!(stream.admit("noisy", 1, now), 0);
+        assert_eq!(stream.admit("quiet", 1, now), 1);
+        // One event's worth of budget comes back every 20s
+        assert_eq!(stream.admit("noisy", 5, now + Duration::from_secs(20)), 1);
+    }
+
+    #[test]
+    fn test_zero_events_per_minute_is_rejected() {
+        let config = QualityStreamConfig {
+            events_per_minute: 0,
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("quality_stream.events_per_minute"), "{}", err);
+        assert!(QualityStreamConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+// Classic token bucket: holds up to `burst` tokens and refills at
+// `rate_per_sec`. Callers pass the clock in so tests can drive time.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_per_sec: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            capacity: burst,
+            refill_per_sec: rate_per_sec,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    // Changes the rate from now on, keeping the tokens already earned
+    pub fn set_rate(&mut self, rate_per_sec: f64, burst: f64) {
+        self.refill_per_sec = rate_per_sec;
+        self.capacity = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            // A bucket that never refills has no token coming
+            let missing = 1.0 - self.tokens;
+            Err(Duration::try_from_secs_f64(missing / self.refill_per_sec).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_a_bucket_that_never_refills_never_admits_again() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(0.0, 1.0, now);
+        assert!(bucket.try_take(now).is_ok());
+        assert_eq!(bucket.try_take(now), Err(Duration::MAX));
+        assert_eq!(
+            bucket.try_take(now + Duration::from_secs(3600)),
+            Err(Duration::MAX)
+        );
+    }
+}
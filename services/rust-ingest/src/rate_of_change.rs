@@ -0,0 +1,222 @@
+use crate::{
+    bounded_store::BoundedStore, proto::telemetry::Telemetry, telemetry_handler::ValidationWarning,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateOfChangeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // metric -> largest believable change per second
+    #[serde(default)]
+    pub metrics: HashMap<String, RateOfChangeRule>,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for RateOfChangeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            metrics: HashMap::new(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateOfChangeRule {
+    pub max_per_sec: f64,
+    // Fail the record instead of flagging it with a warning
+    #[serde(default)]
+    pub reject: bool,
+}
+
+// Catches physically impossible jumps between consecutive readings of a
+// device+metric, e.g. a temperature rising 50°C in a second, which range and
+// baseline checks miss while both values are plausible on their own. The
+// first reading of a metric has nothing to compare against and always
+// passes; readings at or before the previous one are skipped.
+pub struct RateOfChangeChecker {
+    rules: HashMap<String, RateOfChangeRule>,
+    // device -> metric -> last accepted (ts millis, value)
+    last: Mutex<BoundedStore<HashMap<String, (i64, f64)>>>,
+}
+
+impl RateOfChangeChecker {
+    pub fn new(config: RateOfChangeConfig) -> Self {
+        Self {
+            rules: config.metrics,
+            last: Mutex::new(BoundedStore::new(
+                config.max_devices,
+                Duration::from_secs(config.idle_eviction_secs),
+            )),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.last.lock().unwrap().remove(device_id)
+    }
+
+    pub fn check(&self, telemetry: &Telemetry) -> Result<Vec<ValidationWarning>> {
+        self.check_at(telemetry, Instant::now())
+    }
+
+    fn check_at(&self, telemetry: &Telemetry, now: Instant) -> Result<Vec<ValidationWarning>> {
+        let mut readings: Vec<(i64, &HashMap<String, f64>)> = telemetry
+            .samples
+            .iter()
+            .map(|sample| (sample.ts, &sample.metrics))
+            .collect();
+        readings.push((telemetry.ts, &telemetry.metrics));
+        readings.sort_by_key(|(ts, _)| *ts);
+
+        let mut store = self.last.lock().unwrap();
+        let last = store.get_or_insert_with(&telemetry.device_id, now, HashMap::new);
+        // Worked on a copy so a rejected record leaves no trace
+        let mut updated = last.clone();
+        let mut warnings = Vec::new();
+        for (ts, metrics) in readings {
+            for (metric, &value) in metrics {
+                let Some(rule) = self.rules.get(metric) else {
+                    continue;
+                };
+                let Some(&(prev_ts, prev_value)) = updated.get(metric) else {
+                    updated.insert(metric.clone(), (ts, value));
+                    continue;
+                };
+                if ts <= prev_ts {
+                    continue;
+                }
+                let elapsed = (ts - prev_ts) as f64 / 1000.0;
+                let rate = (value - prev_value).abs() / elapsed;
+                if rate > rule.max_per_sec {
+                    if rule.reject {
+                        return Err(anyhow::anyhow!(
+                            "{} changed at {:.2}/s, more than the allowed {}/s",
+                            metric,
+                            rate,
+                            rule.max_per_sec
+                        ));
+                    }
+                    warn!(
+                        "{} for device {} changed at {:.2}/s, more than {}/s",
+                        metric, telemetry.device_id, rate, rule.max_per_sec
+                    );
+                    let reach = rule.max_per_sec * elapsed;
+                    warnings.push(ValidationWarning::new(
+                        metric,
+                        value,
+                        prev_value - reach,
+                        prev_value + reach,
+                    ));
+                }
+                updated.insert(metric.clone(), (ts, value));
+            }
+        }
+        *last = updated;
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checker(reject: bool) -> RateOfChangeChecker {
+        RateOfChangeChecker::new(RateOfChangeConfig {
+            enabled: true,
+            metrics: HashMap::from([(
+                "temperature".to_string(),
+                RateOfChangeRule {
+                    max_per_sec: 2.0,
+                    reject,
+                },
+            )]),
+            ..Default::default()
+        })
+    }
+
+    fn reading(ts: i64, temperature: f64) -> Telemetry {
+        Telemetry {
+            device_id: "probe-1".to_string(),
+            ts,
+            metrics: HashMap::from([
+                ("temperature".to_string(), temperature),
+                ("humidity".to_string(), temperature * 10.0),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_changes_within_the_limit_pass() {
+        let checker = checker(true);
+        let now = Instant::now();
+        // First reading has nothing to compare against
+        assert!(checker.check_at(&reading(0, 20.0), now).unwrap().is_empty());
+        // 4°C over 2s is exactly the limit
+        assert!(checker
+            .check_at(&reading(2_000, 24.0), now)
+            .unwrap()
+            .is_empty());
+        // Same or earlier ts is skipped rather than divided by
+        assert!(checker
+            .check_at(&reading(2_000, 90.0), now)
+            .unwrap()
+            .is_empty());
+        assert!(checker
+            .check_at(&reading(1_000, 90.0), now)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_excessive_rate_is_flagged() {
+        let checker = checker(false);
+        let now = Instant::now();
+        checker.check_at(&reading(0, 20.0), now).unwrap();
+        let warnings = checker.check_at(&reading(1_000, 70.0), now).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].metric, "temperature");
+        assert_eq!(warnings[0].expected_min, 18.0);
+        assert_eq!(warnings[0].expected_max, 22.0);
+        // The flagged value was accepted and is the new reference
+        assert!(checker
+            .check_at(&reading(2_000, 71.0), now)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_excessive_rate_is_rejected() {
+        let checker = checker(true);
+        let now = Instant::now();
+        checker.check_at(&reading(0, 20.0), now).unwrap();
+        assert!(checker.check_at(&reading(1_000, 70.0), now).is_err());
+        // The rejected value left the reference alone
+        assert!(checker
+            .check_at(&reading(2_000, 23.0), now)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::bounded_store::BoundedStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReceiptConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_receipts")]
+    pub max_receipts: usize,
+    // How long a receipt can be looked up after its request
+    #[serde(default = "default_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for ReceiptConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_receipts: default_max_receipts(),
+            ttl_secs: default_ttl_secs(),
+        }
+    }
+}
+
+fn default_max_receipts() -> usize {
+    100_000
+}
+
+fn default_ttl_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Receipt {
+    pub status: ReceiptStatus,
+    // Where Kafka put the record, when the sink knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    created: Instant,
+    // The sink reports the outcome itself once it knows it
+    #[serde(skip)]
+    reported_later: bool,
+}
+
+// Delivery outcomes of accepted-but-unconfirmed (202) requests, by request
+// id (see request_id.rs), so clients that didn't wait can check later. Bounded like the other
+// per-key stores; a receipt is gone once `ttl_secs` have passed since its
+// request, or earlier if the store fills up with newer ones.
+pub struct ReceiptStore {
+    receipts: Mutex<BoundedStore<Receipt>>,
+    ttl: Duration,
+}
+
+impl ReceiptStore {
+    pub fn new(config: &ReceiptConfig) -> Self {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        Self {
+            receipts: Mutex::new(BoundedStore::new(config.max_receipts, ttl)),
+            ttl,
+        }
+    }
+
+    // Starts a pending receipt under the request's id; a reused id starts over
+    pub fn open(self: &Arc<Self>, id: &str, now: Instant) -> ReceiptTicket {
+        let receipt = Receipt {
+            status: ReceiptStatus::Pending,
+            partition: None,
+            offset: None,
+            error: None,
+            created: now,
+            reported_later: false,
+        };
+        let mut receipts = self.receipts.lock().unwrap();
+        receipts.remove(id);
+        receipts.get_or_insert_with(id, now, || receipt);
+        ReceiptTicket {
+            store: Arc::clone(self),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn get(&self, id: &str, now: Instant) -> Option<Receipt> {
+        let mut receipts = self.receipts.lock().unwrap();
+        let receipt = receipts.peek_mut(id)?;
+        if now.saturating_duration_since(receipt.created) >= self.ttl {
+            receipts.remove(id);
+            return None;
+        }
+        Some(receipt.clone())
+    }
+
+    fn update(&self, id: &str, update: impl FnOnce(&mut Receipt)) {
+        // Already evicted: nobody can ask for it any more
+        if let Some(receipt) = self.receipts.lock().unwrap().peek_mut(id) {
+            update(receipt);
+        }
+    }
+}
+
+// A request's handle on its receipt, passed down to the sink with the record
+#[derive(Clone)]
+pub struct ReceiptTicket {
+    store: Arc<ReceiptStore>,
+    id: String,
+}
+
+impl ReceiptTicket {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // For sinks that only learn the outcome after publish returns, e.g.
+    // from a Kafka delivery report
+    pub fn report_later(&self) {
+        self.store
+            .update(&self.id, |receipt| receipt.reported_later = true);
+    }
+
+    pub fn delivered(&self, partition: Option<i32>, offset: Option<i64>) {
+        self.store.update(&self.id, |receipt| {
+            receipt.status = ReceiptStatus::Delivered;
+            receipt.partition = partition;
+            receipt.offset = offset;
+        });
+    }
+
+    pub fn failed(&self, error: impl Into<String>) {
+        let error = error.into();
+        self.store.update(&self.id, |receipt| {
+            receipt.status = ReceiptStatus::Failed;
+            receipt.error = Some(error);
+        });
+    }
+
+    // The sink took the record. Unless it reports later, that is delivery
+    // as far as it will ever say.
+    pub fn sent(&self) {
+        self.store.update(&self.id, |receipt| {
+            if receipt.status == ReceiptStatus::Pending && !receipt.reported_later {
+                receipt.status = ReceiptStatus::Delivered;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(max_receipts: usize) -> Arc<ReceiptStore> {
+        Arc::new(ReceiptStore::new(&ReceiptConfig {
+            enabled: true,
+            max_receipts,
+            ttl_secs: 60,
+        }))
+    }
+
+    #[test]
+    fn test_receipt_goes_from_pending_to_delivered() {
+        let store = store(10);
+        let now = Instant::now();
+        let ticket = store.open("req-1", now);
+        assert_eq!(ticket.id(), "req-1");
+        assert_eq!(
+            store.get("req-1", now).unwrap().status,
+            ReceiptStatus::Pending
+        );
+
+        // Queued in the producer: nothing is known until the delivery report
+        ticket.report_later();
+        ticket.sent();
+        assert_eq!(
+            store.get("req-1", now).unwrap().status,
+            ReceiptStatus::Pending
+        );
+
+        ticket.delivered(Some(3), Some(1042));
+        let receipt = store.get("req-1", now).unwrap();
+        assert_eq!(receipt.status, ReceiptStatus::Delivered);
+        assert_eq!((receipt.partition, receipt.offset), (Some(3), Some(1042)));
+
+        let other = store.open("req-2", now);
+        other.failed("broker unreachable");
+        let receipt = store.get(other.id(), now).unwrap();
+        assert_eq!(receipt.status, ReceiptStatus::Failed);
+        assert_eq!(receipt.error.as_deref(), Some("broker unreachable"));
+    }
+
+    #[test]
+    fn test_receipts_expire_and_are_bounded() {
+        let store = store(2);
+        let now = Instant::now();
+        store.open("a", now);
+        assert!(store.get("a", now + Duration::from_secs(59)).is_some());
+        assert!(store.get("a", now + Duration::from_secs(60)).is_none());
+
+        for (i, id) in ["b", "c", "d"].into_iter().enumerate() {
+            store.open(id, now + Duration::from_secs(i as u64));
+        }
+        assert!(store.get("b", now).is_none());
+        assert!(store.get("d", now).is_some());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::sink::{SinkRecord, TelemetrySink};
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::{aio::ConnectionManager, streams::StreamMaxlen, AsyncCommands};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisSinkConfig {
+    #[serde(default = "default_url")]
+    pub url: String,
+    #[serde(default = "default_stream_key")]
+    pub stream_key: String,
+    // Approximate cap on stream length; Redis trims whole nodes past it
+    #[serde(default = "default_maxlen")]
+    pub maxlen: usize,
+    // Only records for these topics are added; all records when empty
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+impl Default for RedisSinkConfig {
+    fn default() -> Self {
+        Self {
+            url: default_url(),
+            stream_key: default_stream_key(),
+            maxlen: default_maxlen(),
+            topics: Vec::new(),
+        }
+    }
+}
+
+fn default_url() -> String {
+    "redis://127.0.0.1:6379".to_string()
+}
+
+fn default_stream_key() -> String {
+    "telemetry".to_string()
+}
+
+fn default_maxlen() -> usize {
+    100_000
+}
+
+// The one Redis command the sink needs, so tests can swap in a fake
+#[async_trait]
+pub trait StreamConnection: Send + Sync {
+    async fn xadd(&self, stream: &str, maxlen: usize, fields: &[(&str, &[u8])]) -> Result<()>;
+}
+
+// Connects on first use. The connection manager reconnects by itself after
+// a dropped connection; a failed first connect is retried on the next record.
+pub struct RedisConnection {
+    client: redis::Client,
+    manager: OnceCell<ConnectionManager>,
+}
+
+impl RedisConnection {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            manager: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl StreamConnection for RedisConnection {
+    async fn xadd(&self, stream: &str, maxlen: usize, fields: &[(&str, &[u8])]) -> Result<()> {
+        let manager = self
+            .manager
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await?;
+        manager
+            .clone()
+            .xadd_maxlen::<_, _, _, _, ()>(stream, StreamMaxlen::Approx(maxlen), "*", fields)
+            .await?;
+        Ok(())
+    }
+}
+
+// Low-latency tier for dashboards: XADDs each record to a Redis stream with
+// its device id, timestamp, topic and the encoded payload as fields
+pub struct RedisStreamSink {
+    config: RedisSinkConfig,
+    connection: Box<dyn StreamConnection>,
+}
+
+impl RedisStreamSink {
+    pub fn new(config: RedisSinkConfig) -> Result<Self> {
+        let connection = RedisConnection::new(&config.url)?;
+        Ok(Self::with_connection(config, Box::new(connection)))
+    }
+
+    fn with_connection(config: RedisSinkConfig, connection: Box<dyn StreamConnection>) -> Self {
+        Self { config, connection }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for RedisStreamSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        if !self.config.topics.is_empty() && !self.config.topics.iter().any(|t| t == record.topic) {
+            return Ok(());
+        }
+        let ts = record.telemetry.ts.to_string();
+        let fields: [(&str, &[u8]); 4] = [
+            ("device_id", record.key.as_bytes()),
+            ("ts", ts.as_bytes()),
+            ("topic", record.topic.as_bytes()),
+            ("payload", record.payload),
+        ];
+        self.connection
+            .xadd(&self.config.stream_key, self.config.maxlen, &fields)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ack::AckMode, priority::Priority, proto::telemetry::Telemetry};
+    use std::sync::{Arc, Mutex};
+
+    type Entry = (String, usize, Vec<(String, Vec<u8>)>);
+
+    #[derive(Default, Clone)]
+    struct FakeConnection {
+        entries: Arc<Mutex<Vec<Entry>>>,
+    }
+
+    #[async_trait]
+    impl StreamConnection for FakeConnection {
+        async fn xadd(&self, stream: &str, maxlen: usize, fields: &[(&str, &[u8])]) -> Result<()> {
+            let fields = fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_vec()))
+                .collect();
+            self.entries
+                .lock()
+                .unwrap()
+                .push((stream.to_string(), maxlen, fields));
+            Ok(())
+        }
+    }
+
+    async fn publish(sink: &RedisStreamSink, topic: &str) {
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: 1700000000000,
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic,
+            key: &telemetry.device_id,
+            payload: b"encoded",
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_records_are_added_to_stream() {
+        let connection = FakeConnection::default();
+        let sink = RedisStreamSink::with_connection(
+            RedisSinkConfig {
+                stream_key: "dashboard".to_string(),
+                maxlen: 500,
+                topics: vec!["telemetry.live".to_string()],
+                ..Default::default()
+            },
+            Box::new(connection.clone()),
+        );
+
+        publish(&sink, "telemetry.live").await;
+        publish(&sink, "telemetry.archive").await;
+
+        let entries = connection.entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        let (stream, maxlen, fields) = &entries[0];
+        assert_eq!(stream, "dashboard");
+        assert_eq!(*maxlen, 500);
+        assert_eq!(fields[0], ("device_id".to_string(), b"sensor-1".to_vec()));
+        assert_eq!(fields[1], ("ts".to_string(), b"1700000000000".to_vec()));
+        assert_eq!(fields[3], ("payload".to_string(), b"encoded".to_vec()));
+    }
+}
@@ -0,0 +1,69 @@
+use crate::server::ApiError;
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Span;
+
+// Header a request's correlation id comes in and goes back out on
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Correlation id of an ingest request, carried from the HTTP request
+// through the logs to the Kafka record's headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+// Takes the client's X-Request-Id, or makes up a UUID when it sent none,
+// records it on the request span and echoes it on the response
+pub async fn assign_request_id(mut request: Request, next: Next) -> Result<Response, ApiError> {
+    let id = request_id(request.headers())
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    Span::current().record("request_id", id.as_str());
+    // Printable ASCII, so always a valid header value
+    let value = HeaderValue::from_str(&id).expect("request id is a valid header value");
+    request.extensions_mut().insert(RequestId(id));
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    Ok(response)
+}
+
+fn request_id(headers: &HeaderMap) -> Result<Option<String>, String> {
+    let Some(value) = headers.get(REQUEST_ID_HEADER) else {
+        return Ok(None);
+    };
+    let id = value.to_str().unwrap_or_default().trim();
+    if id.is_empty() || id.len() > MAX_REQUEST_ID_LEN || !id.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(format!(
+            "{} must be 1 to {} printable ASCII characters",
+            REQUEST_ID_HEADER, MAX_REQUEST_ID_LEN
+        ));
+    }
+    Ok(Some(id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_request_id(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(id).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_request_id_is_taken_from_the_header() {
+        assert_eq!(request_id(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(
+            request_id(&with_request_id(" req-1 ")).unwrap().as_deref(),
+            Some("req-1")
+        );
+        assert!(request_id(&with_request_id("has space")).is_err());
+        assert!(request_id(&with_request_id(&"x".repeat(129))).is_err());
+    }
+}
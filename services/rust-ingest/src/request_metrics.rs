@@ -0,0 +1,171 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tracing::warn;
+
+// Request latency buckets in seconds, from a fast in-memory accept to a
+// send stuck behind a slow broker
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+// Traffic counters for the ingest endpoints, registered with the server's
+// Prometheus registry
+#[derive(Clone)]
+pub struct RequestMetrics {
+    pub requests: IntCounter,
+    pub failed: IntCounter,
+    // Publishes the sink reported as failed; short-circuited sends that
+    // never reached it are not counted
+    pub send_failures: IntCounter,
+    // Records dropped because their TTL elapsed before the send step
+    pub expired: IntCounter,
+    pub latency: Histogram,
+}
+
+impl RequestMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            requests: IntCounter::new(
+                "rust_ingest_requests_total",
+                "Total number of telemetry requests",
+            )?,
+            failed: IntCounter::new(
+                "rust_ingest_requests_failed_total",
+                "Telemetry requests answered with an error",
+            )?,
+            send_failures: IntCounter::new(
+                "rust_ingest_send_failures_total",
+                "Records the sink failed to publish",
+            )?,
+            expired: IntCounter::new(
+                "rust_ingest_expired_dropped_total",
+                "Records dropped because their TTL elapsed before send",
+            )?,
+            latency: Histogram::with_opts(
+                HistogramOpts::new(
+                    "rust_ingest_request_duration_seconds",
+                    "Time to answer a telemetry request",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            )?,
+        };
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.failed.clone()))?;
+        registry.register(Box::new(metrics.send_failures.clone()))?;
+        registry.register(Box::new(metrics.expired.clone()))?;
+        registry.register(Box::new(metrics.latency.clone()))?;
+        Ok(metrics)
+    }
+}
+
+// Metric names and help text are constants, so these can only fail on a typo
+pub fn int_counter(name: &str, help: &str) -> IntCounter {
+    IntCounter::new(name, help).expect("valid metric name")
+}
+
+pub fn int_counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric name")
+}
+
+pub fn int_gauge(name: &str, help: &str) -> IntGauge {
+    IntGauge::new(name, help).expect("valid metric name")
+}
+
+pub fn int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    IntGaugeVec::new(Opts::new(name, help), labels).expect("valid metric name")
+}
+
+pub fn gauge(name: &str, help: &str) -> Gauge {
+    Gauge::new(name, help).expect("valid metric name")
+}
+
+pub fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+    GaugeVec::new(Opts::new(name, help), labels).expect("valid metric name")
+}
+
+// Registers a handle to `metric`; the registry and the caller then share
+// its value
+pub fn register<M: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: &M,
+) -> prometheus::Result<()> {
+    registry.register(Box::new(metric.clone()))
+}
+
+// A metric set from live state each time the registry is gathered, for
+// values like queue depths that are simpler to look at than to keep current
+struct Live<M> {
+    metric: M,
+    refresh: Box<dyn Fn(&M) + Send + Sync>,
+}
+
+impl<M: Collector> Collector for Live<M> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metric.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        (self.refresh)(&self.metric);
+        self.metric.collect()
+    }
+}
+
+pub fn register_live<M: Collector + 'static>(
+    registry: &Registry,
+    metric: M,
+    refresh: impl Fn(&M) + Send + Sync + 'static,
+) -> prometheus::Result<()> {
+    registry.register(Box::new(Live {
+        metric,
+        refresh: Box::new(refresh),
+    }))
+}
+
+// The registry's current values in the text exposition format
+pub fn render_registry(registry: &Registry) -> String {
+    let mut text = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&registry.gather(), &mut text) {
+        warn!("Failed to encode metrics registry: {}", e);
+        return String::new();
+    }
+    String::from_utf8(text).unwrap_or_default()
+}
+
+// What `register` puts in a fresh registry, rendered, for module tests
+#[cfg(test)]
+pub fn rendered(register: impl FnOnce(&Registry) -> prometheus::Result<()>) -> String {
+    let registry = Registry::new();
+    register(&registry).unwrap();
+    render_registry(&registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_reflects_live_values() {
+        let registry = Registry::new();
+        let metrics = RequestMetrics::register(&registry).unwrap();
+        metrics.requests.inc();
+        metrics.requests.inc();
+        metrics.failed.inc();
+        metrics.latency.observe(0.003);
+
+        let text = render_registry(&registry);
+        assert!(text.contains("# TYPE rust_ingest_requests_total counter"));
+        assert!(text.contains("rust_ingest_requests_total 2\n"));
+        assert!(text.contains("rust_ingest_requests_failed_total 1\n"));
+        assert!(text.contains("rust_ingest_send_failures_total 0\n"));
+        assert!(text.contains("rust_ingest_expired_dropped_total 0\n"));
+        assert!(text.contains("rust_ingest_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("rust_ingest_request_duration_seconds_count 1\n"));
+
+        // Registering the same names twice is a configuration bug
+        assert!(RequestMetrics::register(&registry).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::request_metrics;
+use prometheus::{IntCounter, IntGauge, Registry};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResendDedupConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // How long a (device_id, ts) pair is remembered after it was first seen
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // Pairs remembered at most; the oldest goes first when full
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for ResendDedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: default_window_secs(),
+            capacity: default_capacity(),
+        }
+    }
+}
+
+fn default_window_secs() -> u64 {
+    300
+}
+
+fn default_capacity() -> usize {
+    100_000
+}
+
+type ReadingKey = (String, i64);
+
+#[derive(Default)]
+struct Seen {
+    first_seen: HashMap<ReadingKey, Instant>,
+    // Same pairs, oldest first, for expiry and eviction
+    order: VecDeque<(ReadingKey, Instant)>,
+}
+
+// Drops a reading a device sends again with the same ts, as devices on
+// flaky links do when an acknowledgement is lost. Unlike content_dedup the
+// content plays no part: a device never has two readings at one ts.
+pub struct ResendDedup {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<Seen>,
+    dropped: IntCounter,
+    // Kept equal to the pairs in `seen`
+    entries: IntGauge,
+}
+
+impl ResendDedup {
+    pub fn new(config: ResendDedupConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            capacity: config.capacity.max(1),
+            seen: Mutex::new(Seen::default()),
+            dropped: request_metrics::int_counter(
+                "rust_ingest_resend_dedup_dropped_total",
+                "Records dropped as resends of a device_id and ts already seen",
+            ),
+            entries: request_metrics::int_gauge(
+                "rust_ingest_resend_dedup_entries",
+                "(device_id, ts) pairs currently remembered",
+            ),
+        }
+    }
+
+    // Drops every pair of the device; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let before = seen.first_seen.len();
+        seen.first_seen.retain(|(device, _), _| device != device_id);
+        seen.order.retain(|((device, _), _)| device != device_id);
+        self.entries.set(seen.order.len() as i64);
+        seen.first_seen.len() != before
+    }
+
+    // Lets the pair through again, for a reading that was never delivered
+    // and that the device will retry
+    pub fn release(&self, device_id: &str, ts: i64) {
+        let mut seen = self.seen.lock().unwrap();
+        let key = (device_id.to_string(), ts);
+        if seen.first_seen.remove(&key).is_some() {
+            seen.order.retain(|(pair, _)| *pair != key);
+            self.entries.set(seen.order.len() as i64);
+        }
+    }
+
+    // True when the device sent a reading with this ts within the window
+    pub fn is_duplicate(&self, device_id: &str, ts: i64) -> bool {
+        self.is_duplicate_at(device_id, ts, Instant::now())
+    }
+
+    fn is_duplicate_at(&self, device_id: &str, ts: i64, now: Instant) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((key, first_seen)) = seen.order.front() {
+            if now.saturating_duration_since(*first_seen) < self.window {
+                break;
+            }
+            let key = key.clone();
+            seen.first_seen.remove(&key);
+            seen.order.pop_front();
+        }
+
+        let key = (device_id.to_string(), ts);
+        if seen.first_seen.contains_key(&key) {
+            self.dropped.inc();
+            self.entries.set(seen.order.len() as i64);
+            return true;
+        }
+        if seen.order.len() >= self.capacity {
+            if let Some((oldest, _)) = seen.order.pop_front() {
+                seen.first_seen.remove(&oldest);
+            }
+        }
+        seen.first_seen.insert(key.clone(), now);
+        seen.order.push_back((key, now));
+        self.entries.set(seen.order.len() as i64);
+        false
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        request_metrics::register(registry, &self.dropped)?;
+        request_metrics::register(registry, &self.entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedup(capacity: usize) -> ResendDedup {
+        ResendDedup::new(ResendDedupConfig {
+            enabled: true,
+            window_secs: 10,
+            capacity,
+        })
+    }
+
+    #[test]
+    fn test_same_device_and_ts_is_dropped_within_the_window() {
+        let dedup = dedup(100);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate_at("meter-1", 1000, now));
+        assert!(dedup.is_duplicate_at("meter-1", 1000, now + Duration::from_secs(9)));
+        // Another ts or another device is a different reading
+        assert!(!dedup.is_duplicate_at("meter-1", 1001, now));
+        assert!(!dedup.is_duplicate_at("meter-2", 1000, now));
+        // Remembered from when it was first seen, not refreshed by the resend
+        assert!(!dedup.is_duplicate_at("meter-1", 1000, now + Duration::from_secs(10)));
+
+        let metrics = request_metrics::rendered(|registry| dedup.register_metrics(registry));
+        assert!(metrics.contains("rust_ingest_resend_dedup_dropped_total 1\n"));
+    }
+
+    #[test]
+    fn test_oldest_pair_is_evicted_when_full() {
+        let dedup = dedup(2);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate_at("meter-1", 1, now));
+        assert!(!dedup.is_duplicate_at("meter-1", 2, now));
+        assert!(!dedup.is_duplicate_at("meter-1", 3, now));
+        assert!(!dedup.is_duplicate_at("meter-1", 1, now));
+        assert!(dedup.is_duplicate_at("meter-1", 3, now));
+        assert!(
+            request_metrics::rendered(|registry| dedup.register_metrics(registry))
+                .contains("rust_ingest_resend_dedup_entries 2\n")
+        );
+    }
+
+    #[test]
+    fn test_released_and_forgotten_pairs_pass_again() {
+        let dedup = dedup(100);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate_at("meter-1", 1, now));
+        assert!(!dedup.is_duplicate_at("meter-1", 2, now));
+        dedup.release("meter-1", 1);
+        assert!(!dedup.is_duplicate_at("meter-1", 1, now));
+
+        assert!(dedup.forget("meter-1"));
+        assert!(!dedup.forget("meter-1"));
+        assert!(!dedup.is_duplicate_at("meter-1", 2, now));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::proto::telemetry::Telemetry;
+use anyhow::Result;
+use axum::http::HeaderMap;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+// Header a client sets to send its request to another allowed topic
+pub const TOPIC_HEADER: &str = "x-kafka-topic";
+
+const VARIABLES: [&str; 3] = ["device_type", "region", "tenant"];
+
+// Convention-based topic names such as `telemetry.{device_type}.{region}`.
+// Variables are device_type (from the classifier), region (the record's
+// `region` tag) and tenant. Unknown variables are rejected at startup.
+pub struct TopicTemplate {
+    template: String,
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unclosed '{{' in topic template {}", template))?;
+            let name = &rest[start + 1..start + end];
+            if !VARIABLES.contains(&name) {
+                return Err(anyhow::anyhow!(
+                    "Unknown variable {{{}}} in topic template {}; expected one of {:?}",
+                    name,
+                    template,
+                    VARIABLES
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    // Build the topic name, or explain why it can't be used
+    pub fn resolve(&self, vars: &HashMap<&str, Option<&str>>) -> Result<String, String> {
+        let mut topic = self.template.clone();
+        for name in VARIABLES {
+            let placeholder = format!("{{{}}}", name);
+            if !topic.contains(&placeholder) {
+                continue;
+            }
+            match vars.get(name).copied().flatten() {
+                Some(value) => topic = topic.replace(&placeholder, value),
+                None => return Err(format!("no value for {{{}}}", name)),
+            }
+        }
+        if !is_valid_topic_name(&topic) {
+            return Err(format!("{} is not a legal Kafka topic name", topic));
+        }
+        Ok(topic)
+    }
+}
+
+// Sends matching records to `topic`. With both conditions set, a record
+// has to meet both.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopicRoute {
+    pub topic: String,
+    #[serde(default)]
+    pub device_prefix: Option<String>,
+    // Matches records carrying this metric, in `metrics` or any sample
+    #[serde(default)]
+    pub metric: Option<String>,
+}
+
+impl TopicRoute {
+    fn matches(&self, telemetry: &Telemetry) -> bool {
+        let prefix_matches = self
+            .device_prefix
+            .as_deref()
+            .is_none_or(|prefix| telemetry.device_id.starts_with(prefix));
+        let metric_matches = self.metric.as_deref().is_none_or(|metric| {
+            telemetry.metrics.contains_key(metric)
+                || telemetry
+                    .samples
+                    .iter()
+                    .any(|sample| sample.metrics.contains_key(metric))
+        });
+        prefix_matches && metric_matches
+    }
+}
+
+// Explicit routing rules, tried in order; the first match picks the topic
+pub struct TopicRoutes {
+    routes: Vec<TopicRoute>,
+}
+
+impl TopicRoutes {
+    pub fn new(routes: Vec<TopicRoute>) -> Result<Self> {
+        for route in &routes {
+            if route.device_prefix.is_none() && route.metric.is_none() {
+                return Err(anyhow::anyhow!(
+                    "Topic route to {} needs a device_prefix or a metric",
+                    route.topic
+                ));
+            }
+            if !is_valid_topic_name(&route.topic) {
+                return Err(anyhow::anyhow!(
+                    "Topic route to {} is not a legal Kafka topic name",
+                    route.topic
+                ));
+            }
+        }
+        Ok(Self { routes })
+    }
+
+    pub fn select(&self, telemetry: &Telemetry) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(telemetry))
+            .map(|route| route.topic.as_str())
+    }
+}
+
+// Topics a request may pick with the topic header, in place of the one it
+// would be routed to. Only configured ones, so the header can't create
+// topics on a cluster that auto-creates them.
+pub struct TopicOverrides {
+    allowed: HashSet<String>,
+}
+
+impl TopicOverrides {
+    pub fn new(allowed: Vec<String>) -> Result<Self> {
+        if let Some(topic) = allowed.iter().find(|topic| !is_valid_topic_name(topic)) {
+            return Err(anyhow::anyhow!(
+                "header_topics entry {} is not a legal Kafka topic name",
+                topic
+            ));
+        }
+        Ok(Self {
+            allowed: allowed.into_iter().collect(),
+        })
+    }
+
+    // The topic the request asked for, or why it can't have it
+    pub fn requested(&self, headers: &HeaderMap) -> Result<Option<String>, String> {
+        let Some(value) = headers.get(TOPIC_HEADER) else {
+            return Ok(None);
+        };
+        let topic = value.to_str().unwrap_or_default().trim();
+        if !self.allowed.contains(topic) {
+            return Err(format!(
+                "topic {:?} from {} is not allowed",
+                topic, TOPIC_HEADER
+            ));
+        }
+        Ok(Some(topic.to_string()))
+    }
+}
+
+// Kafka's rules: 1-249 chars from [a-zA-Z0-9._-], and not "." or ".."
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= 249
+        && topic != "."
+        && topic != ".."
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_resolution() {
+        let template = TopicTemplate::parse("telemetry.{device_type}.{region}").unwrap();
+        let vars = HashMap::from([
+            ("device_type", Some("thermostat")),
+            ("region", Some("eu-west")),
+            ("tenant", None),
+        ]);
+        assert_eq!(
+            template.resolve(&vars).unwrap(),
+            "telemetry.thermostat.eu-west"
+        );
+
+        // tenant isn't used by this template, so its absence doesn't matter
+        let missing_region = HashMap::from([("device_type", Some("thermostat")), ("region", None)]);
+        assert!(template.resolve(&missing_region).is_err());
+    }
+
+    #[test]
+    fn test_invalid_names_are_rejected() {
+        let template = TopicTemplate::parse("telemetry.{region}").unwrap();
+        let vars = HashMap::from([("region", Some("eu west/1"))]);
+        assert!(template
+            .resolve(&vars)
+            .unwrap_err()
+            .contains("not a legal Kafka topic name"));
+
+        assert!(TopicTemplate::parse("telemetry.{site}").is_err());
+        assert!(TopicTemplate::parse("telemetry.{region").is_err());
+    }
+
+    fn route(topic: &str, device_prefix: Option<&str>, metric: Option<&str>) -> TopicRoute {
+        TopicRoute {
+            topic: topic.to_string(),
+            device_prefix: device_prefix.map(str::to_string),
+            metric: metric.map(str::to_string),
+        }
+    }
+
+    fn telemetry(device_id: &str, metric: &str) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            metrics: HashMap::from([(metric.to_string(), 1.0)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let routes = TopicRoutes::new(vec![
+            route("telemetry.gps", None, Some("latitude")),
+            route(
+                "telemetry.fleet-temperature",
+                Some("truck-"),
+                Some("temperature"),
+            ),
+            route("telemetry.temperature", None, Some("temperature")),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            routes.select(&telemetry("truck-7", "latitude")),
+            Some("telemetry.gps")
+        );
+        assert_eq!(
+            routes.select(&telemetry("truck-7", "temperature")),
+            Some("telemetry.fleet-temperature")
+        );
+        assert_eq!(
+            routes.select(&telemetry("freezer-2", "temperature")),
+            Some("telemetry.temperature")
+        );
+        // No rule matches: the caller falls back to its default
+        assert_eq!(routes.select(&telemetry("freezer-2", "humidity")), None);
+    }
+
+    #[test]
+    fn test_invalid_routes_are_rejected() {
+        assert!(TopicRoutes::new(vec![route("telemetry.all", None, None)]).is_err());
+        assert!(TopicRoutes::new(vec![route("gps data", None, Some("latitude"))]).is_err());
+    }
+
+    #[test]
+    fn test_topic_header_is_checked_against_the_allowlist() {
+        let overrides = TopicOverrides::new(vec!["telemetry.test".to_string()]).unwrap();
+        let headers = |topic: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(TOPIC_HEADER, topic.parse().unwrap());
+            headers
+        };
+        assert_eq!(overrides.requested(&HeaderMap::new()), Ok(None));
+        assert_eq!(
+            overrides.requested(&headers(" telemetry.test ")),
+            Ok(Some("telemetry.test".to_string()))
+        );
+        assert!(overrides.requested(&headers("telemetry.other")).is_err());
+
+        // Nothing configured: every header is refused
+        let none = TopicOverrides::new(Vec::new()).unwrap();
+        assert!(none.requested(&headers("telemetry.test")).is_err());
+        assert!(TopicOverrides::new(vec!["bad topic".to_string()]).is_err());
+    }
+}
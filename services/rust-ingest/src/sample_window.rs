@@ -0,0 +1,149 @@
+use crate::{proto::telemetry::Telemetry, telemetry_handler::ValidationWarning};
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SampleWindowConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Furthest a sample's ts may be from the record's ts, either way
+    #[serde(default = "default_tolerance_secs")]
+    pub tolerance_secs: u64,
+    // Fail the record instead of flagging the sample's metrics with warnings
+    #[serde(default)]
+    pub reject: bool,
+}
+
+impl Default for SampleWindowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tolerance_secs: default_tolerance_secs(),
+            reject: false,
+        }
+    }
+}
+
+fn default_tolerance_secs() -> u64 {
+    3600
+}
+
+// Samples in one message are readings taken around the record's `ts`; one
+// hours or years away from it usually means the device filled in the wrong
+// clock. Warnings carry the sample's offset from `ts` in seconds as the
+// value, with the tolerance as the expected range. Records without samples
+// always pass.
+pub struct SampleWindow {
+    tolerance_ms: i64,
+    reject: bool,
+}
+
+impl SampleWindow {
+    pub fn new(config: &SampleWindowConfig) -> Self {
+        Self {
+            tolerance_ms: i64::try_from(config.tolerance_secs.saturating_mul(1000))
+                .unwrap_or(i64::MAX),
+            reject: config.reject,
+        }
+    }
+
+    pub fn check(&self, telemetry: &Telemetry) -> Result<Vec<ValidationWarning>> {
+        let tolerance = self.tolerance_ms as f64 / 1000.0;
+        let mut warnings = Vec::new();
+        for sample in &telemetry.samples {
+            let offset_ms = sample.ts.saturating_sub(telemetry.ts);
+            if offset_ms.unsigned_abs() <= self.tolerance_ms.unsigned_abs() {
+                continue;
+            }
+            let offset = offset_ms as f64 / 1000.0;
+            let mut metrics: Vec<&str> = sample.metrics.keys().map(String::as_str).collect();
+            metrics.sort_unstable();
+            if self.reject {
+                return Err(anyhow::anyhow!(
+                    "sample at {} ({}) is {}s from the record ts, more than the allowed {}s",
+                    sample.ts,
+                    metrics.join(", "),
+                    offset,
+                    tolerance
+                ));
+            }
+            warn!(
+                "Sample at {} for device {} ({}) is {}s from the record ts",
+                sample.ts,
+                telemetry.device_id,
+                metrics.join(", "),
+                offset
+            );
+            warnings.extend(
+                metrics
+                    .into_iter()
+                    .map(|metric| ValidationWarning::new(metric, offset, -tolerance, tolerance)),
+            );
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+
+    const TS: i64 = 1_700_000_000_000;
+
+    fn record(sample_offsets_ms: &[i64]) -> Telemetry {
+        Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: TS,
+            samples: sample_offsets_ms
+                .iter()
+                .map(|offset| Sample {
+                    ts: TS + offset,
+                    metrics: [
+                        ("temperature".to_string(), 21.0),
+                        ("humidity".to_string(), 40.0),
+                    ]
+                    .into(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn window(reject: bool) -> SampleWindow {
+        SampleWindow::new(&SampleWindowConfig {
+            enabled: true,
+            tolerance_secs: 60,
+            reject,
+        })
+    }
+
+    #[test]
+    fn test_samples_within_tolerance_pass() {
+        let window = window(true);
+        assert!(window.check(&record(&[])).unwrap().is_empty());
+        let warnings = window.check(&record(&[-60_000, 0, 60_000])).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_samples_outside_tolerance_are_flagged_or_rejected() {
+        let warnings = window(false).check(&record(&[-1_000, 3_600_000])).unwrap();
+        let mut flagged: Vec<_> = warnings.iter().map(|w| w.metric.as_str()).collect();
+        flagged.sort_unstable();
+        assert_eq!(flagged, vec!["humidity", "temperature"]);
+        assert_eq!(warnings[0].value, 3600.0);
+        assert_eq!(
+            (warnings[0].expected_min, warnings[0].expected_max),
+            (-60.0, 60.0)
+        );
+
+        let err = window(true)
+            .check(&record(&[-60_001]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("humidity, temperature"), "{}", err);
+        assert!(err.contains("-60.001s"), "{}", err);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{
+    encoding::{EncodingConfig, OutputFormat, AVRO_SCHEMA},
+    http_client::Endpoint,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::info;
+
+// First byte of a record in the Confluent wire format, before the schema id
+const MAGIC_BYTE: u8 = 0;
+
+const CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SchemaRegistryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Base URL of a Confluent-compatible registry; plain HTTP only
+    #[serde(default = "default_url")]
+    pub url: String,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for SchemaRegistryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: default_url(),
+            timeout_ms: default_timeout_ms(),
+        }
+    }
+}
+
+fn default_url() -> String {
+    "http://localhost:8081".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+impl SchemaRegistryConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled {
+            Endpoint::parse("schema_registry.url", &self.url)?;
+        }
+        Ok(())
+    }
+}
+
+// Registers the Avro schema under the subject of every topic known to get
+// Avro records (TopicNameStrategy: "<topic>-value") and keeps the id the
+// registry gives it, so encoded records carry it in front.
+pub async fn register_schema(
+    config: &SchemaRegistryConfig,
+    encoding: &mut EncodingConfig,
+    default_topic: &str,
+) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let mut topics: Vec<&str> = encoding
+        .topics
+        .iter()
+        .filter(|(_, format)| **format == OutputFormat::Avro)
+        .map(|(topic, _)| topic.as_str())
+        .collect();
+    if encoding.format_for(default_topic) == OutputFormat::Avro {
+        topics.push(default_topic);
+    }
+    topics.sort_unstable();
+    topics.dedup();
+
+    let endpoint = Endpoint::parse("schema_registry.url", &config.url)?;
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut schema_id = None;
+    for topic in topics {
+        let subject = format!("{}-value", topic);
+        let id = tokio::time::timeout(timeout, register(&endpoint, &subject))
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!("Timed out after {:?} waiting for schema registry", timeout)
+            })?
+            .with_context(|| format!("Registering the Avro schema under {}", subject))?;
+        info!("Avro schema registered under {} with id {}", subject, id);
+        // The registry hands out one id per distinct schema, whatever the subject
+        if schema_id.is_some_and(|known| known != id) {
+            return Err(anyhow::anyhow!(
+                "Schema registry gave the Avro schema id {} under {} but {} elsewhere",
+                id,
+                subject,
+                schema_id.unwrap_or_default()
+            ));
+        }
+        schema_id = Some(id);
+    }
+    encoding.schema_id = schema_id;
+    Ok(())
+}
+
+// An Avro datum in the Confluent wire format: magic byte, big-endian schema
+// id, then the datum itself
+pub fn frame(schema_id: u32, datum: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(datum.len() + 5);
+    framed.push(MAGIC_BYTE);
+    framed.extend_from_slice(&schema_id.to_be_bytes());
+    framed.extend_from_slice(datum);
+    framed
+}
+
+// Registers the schema under `subject`; returns the id the registry gave it
+async fn register(endpoint: &Endpoint, subject: &str) -> Result<u32> {
+    #[derive(Deserialize)]
+    struct Registered {
+        id: u32,
+    }
+
+    let body = serde_json::json!({ "schema": AVRO_SCHEMA }).to_string();
+    let (status, body) = endpoint
+        .post(
+            &format!("/subjects/{}/versions", subject),
+            CONTENT_TYPE,
+            CONTENT_TYPE,
+            body.as_bytes(),
+        )
+        .await?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!(
+            "Schema registry answered {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    let registered: Registered =
+        serde_json::from_slice(&body).context("Schema registry response has no schema id")?;
+    Ok(registered.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // Answers one registration with `response` and returns the request
+    async fn registry(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/registry/", listener.local_addr().unwrap());
+        let served = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read up to the end of the body the request announced
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, served)
+    }
+
+    fn avro_topics() -> EncodingConfig {
+        EncodingConfig {
+            default: OutputFormat::Protobuf,
+            topics: HashMap::from([("telemetry.avro".to_string(), OutputFormat::Avro)]),
+            schema_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schema_is_registered_under_the_topic_subject() {
+        let (url, served) = registry(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n{\"id\": 4\r\n2\r\n2}\r\n0\r\n\r\n",
+        )
+        .await;
+        let config = SchemaRegistryConfig {
+            enabled: true,
+            url,
+            timeout_ms: 5000,
+        };
+        let mut encoding = avro_topics();
+        register_schema(&config, &mut encoding, "telemetry")
+            .await
+            .unwrap();
+        assert_eq!(encoding.schema_id, Some(42));
+
+        let request = served.await.unwrap();
+        assert!(
+            request
+                .starts_with("POST /registry/subjects/telemetry.avro-value/versions HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        let body: serde_json::Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert!(body["schema"].as_str().unwrap().contains("\"Telemetry\""));
+    }
+
+    #[tokio::test]
+    async fn test_registry_errors_fail_startup() {
+        let (url, _served) = registry(
+            "HTTP/1.1 409 Conflict\r\nContent-Length: 52\r\n\r\n{\"error_code\":409,\"message\":\"Schema is incompatible\"}",
+        )
+        .await;
+        let config = SchemaRegistryConfig {
+            enabled: true,
+            url,
+            timeout_ms: 5000,
+        };
+        let err = register_schema(&config, &mut avro_topics(), "telemetry")
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("409"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_registered_without_avro_topics() {
+        // Nothing listens here; enabled but with no Avro topic it isn't called
+        let config = SchemaRegistryConfig {
+            enabled: true,
+            url: "http://127.0.0.1:1".to_string(),
+            timeout_ms: 100,
+        };
+        let mut encoding = EncodingConfig::default();
+        register_schema(&config, &mut encoding, "telemetry")
+            .await
+            .unwrap();
+        assert_eq!(encoding.schema_id, None);
+    }
+
+    #[test]
+    fn test_records_are_framed_with_the_schema_id() {
+        assert_eq!(frame(258, &[0xaa]), vec![0, 0, 0, 1, 2, 0xaa]);
+    }
+}
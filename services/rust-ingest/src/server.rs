@@ -1,33 +1,189 @@
-use crate::{telemetry_handler::handle_telemetry, Config};
+use crate::{
+    ack::{AckMode, AckModeCounters},
+    api_keys::{self, ApiKeyRegistry, ApiKeyStats},
+    band_changes::BandTracker,
+    baseline::BaselineTracker,
+    batch::{self, MemoryBudget},
+    broker_health::{self, BrokerHealth, BrokerMonitor},
+    cardinality::CardinalityGuard,
+    circuit_breaker::{CircuitBreakerSink, CircuitOpen, TopicBreakers},
+    clock_skew,
+    coalescing::CoalescingSink,
+    concurrency_limit::ConcurrencyLimit,
+    config::Config,
+    connections::{spawn_reaper, ConnectionTracker},
+    content_dedup::ContentDedup,
+    content_encoding, cors,
+    csv_ingest::{self, CsvConfig},
+    dead_letter::{DeadLetterQueue, StoredDeadLetter},
+    debug_messages::{DebugMessage, LastMessages},
+    delayed_delivery::{self, DelayQueue, DelayRejection},
+    device_attributes::{self, DeviceAttributes},
+    device_metrics::DeviceCounts,
+    device_rate_limit::DeviceRateLimiter,
+    device_types::DeviceClassifier,
+    dlq_replay::{DeadLetterSource, DlqReplayer, KafkaDeadLetterSource},
+    duplicate_backoff::DuplicateBackoff,
+    exposition,
+    health::{Backlog, HealthConfig, HealthStatus},
+    heartbeat::{self, HeartbeatTracker},
+    histograms::PipelineHistograms,
+    imputation::Imputer,
+    ingest_sequence::IngestSequencer,
+    key_pseudonyms::{KeyPseudonymizer, PseudonymousKeySink},
+    load_shedding::{FreshnessWeights, LoadSheddingSampler, Shedding},
+    logging::LogSampler,
+    maintenance::{self, MaintenanceSchedule},
+    metric_precision::MetricPrecision,
+    metric_renames::MetricRenamer,
+    metric_values::{normalize_metrics, LargeIntegerPolicy, MetricCoercion, MetricValue},
+    ndjson_ingest,
+    openapi::{self, ApiFeatures},
+    ordering::KeyOrderedSink,
+    pipeline_retry::{PipelineRetry, TransientError},
+    priority::{Priority, PriorityLanes, PrioritySink},
+    proto::telemetry::{Sample, Telemetry},
+    provisioning::DeviceProvisioner,
+    quality::QualityStream,
+    rate_of_change::RateOfChangeChecker,
+    receipts::{Receipt, ReceiptStore, ReceiptTicket},
+    request_id::{self, RequestId},
+    request_metrics::{self, RequestMetrics},
+    request_signing::{self, SignatureVerifier},
+    resend_dedup::ResendDedup,
+    routing::{TopicOverrides, TopicRoutes, TopicTemplate},
+    sample_window::SampleWindow,
+    shutdown::{self, Drain, InFlightRequest, InFlightRequests, ShutdownConfig},
+    sink::TelemetrySink,
+    size_budget::{MessageLimit, OverBudget, SizeBudgets},
+    spillover::{self, BufferFull, DeliveryMode, SpilloverSink},
+    telemetry_body::TelemetryBody,
+    telemetry_handler::{
+        dry_run, handle_telemetry, metric_unit, Delivery, DryRun, Enrichment, Expired,
+        HandlerContext, MetricRules, PreparedTelemetry, TelemetryError, ValidationWarning,
+        RESEND_DEDUP,
+    },
+    tenancy::{TenantRegistry, TenantRejection, TenantUsage},
+    time_grid::GridAligner,
+    timestamps,
+    trace_sampling::{self, TraceDecision, TraceSampler},
+    ts_window::TimestampWindow,
+    ttl::TtlConfig,
+    unit_conversion::{self, UnitConverter},
+    validation_profiles::ValidationProfiles,
+    websocket::{self, WebSocketConfig},
+    worker_pool::ValidationPool,
+};
 use anyhow::Result;
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::Json,
+    extract::{DefaultBodyLimit, Extension, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
-use rdkafka::producer::FutureProducer;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder as ConnectionBuilder,
+};
+use prometheus::Registry;
+use prost::Message;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
-use tokio::net::TcpListener;
-use tower::ServiceBuilder;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use tracing::{info, warn};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet};
+use tower::{ServiceBuilder, ServiceExt};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 
 #[derive(Debug, Deserialize)]
 pub struct TelemetryRequest {
     pub device_id: String,
+    // Unix millis; devices may also send seconds or an RFC 3339 string
+    #[serde(default, deserialize_with = "timestamps::optional_millis")]
     pub ts: Option<i64>,
-    pub metrics: HashMap<String, f64>,
+    #[serde(default)]
+    pub metrics: HashMap<String, MetricValue>,
+    // Further readings carried in the same message as a short time series
+    #[serde(default)]
+    pub samples: Vec<SampleRequest>,
     pub raw: Option<Vec<u8>>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    // Drop the record instead of sending it once this many ms have passed
+    pub ttl_ms: Option<u64>,
+    // Unix millis; a future time holds the record back until then
+    pub deliver_at: Option<i64>,
+}
+
+impl TelemetryRequest {
+    // Every metric in the record, its own or a sample's, once each
+    fn metric_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .metrics
+            .keys()
+            .chain(self.samples.iter().flat_map(|sample| sample.metrics.keys()))
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    // Samples left without any metrics go too
+    fn remove_metrics(&mut self, names: &[String]) {
+        for name in names {
+            self.metrics.remove(name);
+        }
+        for sample in &mut self.samples {
+            sample.metrics.retain(|name, _| !names.contains(name));
+        }
+        self.samples.retain(|sample| !sample.metrics.is_empty());
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SampleRequest {
+    #[serde(deserialize_with = "timestamps::millis")]
+    pub ts: i64,
+    pub metrics: HashMap<String, MetricValue>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
-    status: String,
+    status: HealthStatus,
+    // Thresholds exceeded while degraded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reasons: Vec<String>,
     timestamp: i64,
     version: String,
+    // Which build is running, as build.rs recorded it
+    git_commit: String,
+    // Unix seconds
+    build_timestamp: i64,
+    rustc_version: String,
+    node_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadyResponse {
+    ready: bool,
+    // Why not, while not ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    // As of the last background probe, when probing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brokers: Option<BrokerHealth>,
+    timestamp: i64,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,111 +191,3681 @@ pub struct TelemetryResponse {
     success: bool,
     message: String,
     device_id: String,
+    // Id to look the outcome up under at /receipts/{request_id}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interpreted: Option<Interpretation>,
+    // Set when the device already sent this reading, which wasn't sent again
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    duplicate: bool,
+    // Where the record was written, when the sink reported it before the
+    // response went out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partition: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<i64>,
+}
+
+// How the pipeline interpreted a record, echoed back to integrators so they
+// can check their payloads during onboarding
+#[derive(Debug, Serialize)]
+pub struct Interpretation {
+    metrics: HashMap<String, f64>,
+    units: HashMap<String, &'static str>,
+    transforms: Vec<&'static str>,
+}
+
+impl From<PreparedTelemetry> for Interpretation {
+    fn from(prepared: PreparedTelemetry) -> Self {
+        let units = prepared
+            .telemetry
+            .metrics
+            .keys()
+            .filter_map(|metric| metric_unit(metric).map(|unit| (metric.clone(), unit)))
+            .collect();
+        Self {
+            metrics: prepared.telemetry.metrics,
+            units,
+            transforms: prepared.transforms,
+        }
+    }
+}
+
+// Result of POST /telemetry/validate
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    valid: bool,
+    device_id: String,
+    // Where the record would have been published
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    errors: Vec<String>,
+    warnings: Vec<ValidationWarning>,
 }
 
+// Request header asking for the interpreted echo when it isn't on by default
+pub(crate) const ECHO_HEADER: &str = "x-echo-interpretation";
+
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     error: String,
     details: Option<String>,
 }
 
-#[derive(Clone)]
+// Error returned by handlers, rendered as an ErrorResponse body
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    error: String,
+    details: Option<String>,
+    retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+            details: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.error
+    }
+
+    pub fn details(&self) -> Option<&str> {
+        self.details.as_deref()
+    }
+
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(ErrorResponse {
+            error: self.error,
+            details: self.details,
+        });
+        match self.retry_after {
+            Some(retry_after) => {
+                // Retry-After is whole seconds; round up so clients never retry too early
+                let secs = retry_after
+                    .as_secs()
+                    .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+                (self.status, [(header::RETRY_AFTER, secs.to_string())], body).into_response()
+            }
+            None => (self.status, body).into_response(),
+        }
+    }
+}
+
 pub struct AppState {
-    producer: FutureProducer,
-    topic: String,
+    pub(crate) sink: Arc<dyn TelemetrySink>,
+    pub(crate) topic: String,
+    pub(crate) topic_routes: TopicRoutes,
+    pub(crate) topic_template: Option<TopicTemplate>,
+    pub(crate) topic_overrides: TopicOverrides,
+    pub(crate) echo_interpretation: bool,
+    pub(crate) detect_body_format: bool,
+    pub(crate) max_samples_per_message: usize,
+    pub(crate) boolean_metrics: HashSet<String>,
+    pub(crate) metric_coercion: MetricCoercion,
+    pub(crate) large_integers: LargeIntegerPolicy,
+    pub(crate) ts_window: Option<TimestampWindow>,
+    pub(crate) device_limiter: Option<DeviceRateLimiter>,
+    pub(crate) trace_sampler: Arc<TraceSampler>,
+    pub(crate) default_ack_mode: AckMode,
+    pub(crate) delivery_mode: DeliveryMode,
+    pub(crate) ack_modes: AckModeCounters,
+    pub(crate) admin_token: Option<String>,
+    // Recently published records, when debug endpoints are enabled
+    pub(crate) last_messages: Option<LastMessages>,
+    pub(crate) tenants: TenantRegistry,
+    pub(crate) api_keys: Arc<ApiKeyRegistry>,
+    pub(crate) ttl: TtlConfig,
+    pub(crate) health: HealthConfig,
+    pub(crate) broker_monitor: Option<Arc<BrokerMonitor>>,
+    // Serves POST /admin/replay when dlq_replay is enabled
+    pub(crate) dlq_replayer: Option<DlqReplayer>,
+    pub(crate) connections: Arc<ConnectionTracker>,
+    pub(crate) batch_budget: MemoryBudget,
+    // Also the longest line a streamed upload may have
+    pub(crate) max_body_bytes: usize,
+    // Bulk uploads decompress themselves, within their budget reservation
+    pub(crate) max_decompressed_bytes: usize,
+    // Records currently between receipt and publish
+    pub(crate) in_flight: AtomicUsize,
+    pub(crate) requests_in_flight: InFlightRequests,
+    pub(crate) load_shedder: Option<LoadSheddingSampler>,
+    pub(crate) concurrency_limit: Option<ConcurrencyLimit>,
+    pub(crate) breakers: Option<Arc<TopicBreakers>>,
+    pub(crate) device_counts: Option<Arc<DeviceCounts>>,
+    pub(crate) priority_lanes: Option<Arc<PriorityLanes>>,
+    pub(crate) spillover: Option<Arc<SpilloverSink>>,
+    pub(crate) receipts: Option<Arc<ReceiptStore>>,
+    pub(crate) websocket: WebSocketConfig,
+    // Also checked per WebSocket message, since a stream can outlast the
+    // start of a window
+    pub(crate) maintenance: Option<Arc<MaintenanceSchedule>>,
+    // Which ingestion node this is, as configured or from the hostname
+    pub(crate) node_id: String,
+    pub(crate) csv: CsvConfig,
+    pub(crate) registry: Registry,
+    pub(crate) handler: Arc<HandlerContext>,
+}
+
+pub async fn run_server(cfg: Config, sink: Arc<dyn TelemetrySink>) -> Result<()> {
+    let listeners = bind_all(&cfg.listen_addr.addrs()).await?;
+    let shutdown_config = cfg.shutdown.clone();
+    let server = build_server(cfg, sink)?;
+    server
+        .serve(listeners, shutdown::signal(), &shutdown_config)
+        .await
+}
+
+// Binds every address or none: a failure on any one of them fails startup,
+// naming the address, rather than leaving the server up on the others
+async fn bind_all(addrs: &[&str]) -> Result<Vec<TcpListener>> {
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("listen_addr names no address to listen on"));
+    }
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", addr, e))?;
+        info!("Rust ingestion server listening on {}", addr);
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+// Everything the server runs, built from config but not yet bound to a
+// port: `app` can be served on any listener, or driven directly in tests
+pub struct Server {
+    pub(crate) app: Router,
+    state: Arc<AppState>,
+    connections: Arc<ConnectionTracker>,
+}
+
+impl Server {
+    // Serves every listener, each from its own task, until `shutdown`
+    // resolves, then drains what is left. An accept loop failing stops the
+    // whole server.
+    pub async fn serve(
+        self,
+        listeners: Vec<TcpListener>,
+        shutdown: impl std::future::Future<Output = ()>,
+        config: &ShutdownConfig,
+    ) -> Result<()> {
+        let (stop, stopped) = watch::channel(());
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            let mut stopped = stopped.clone();
+            accept_loops.spawn(serve_connections(
+                listener,
+                self.app.clone(),
+                Arc::clone(&self.connections),
+                async move {
+                    let _ = stopped.changed().await;
+                },
+            ));
+        }
+        let failed = tokio::select! {
+            _ = shutdown => None,
+            Some(result) = accept_loops.join_next() => Some(result),
+        };
+        drop(stop);
+        while let Some(result) = accept_loops.join_next().await {
+            result??;
+        }
+        if let Some(result) = failed {
+            result??;
+        }
+
+        let report = Drain {
+            connections: &self.connections,
+            in_flight: &self.state.in_flight,
+            requests: &self.state.requests_in_flight,
+            sink: self.state.sink.as_ref(),
+            delay_queue: self.state.handler.delay_queue.as_ref(),
+        }
+        .run(config.drain_timeout())
+        .await;
+        report.emit(config)
+    }
+}
+
+// Starts the background tasks the pipeline needs, so call it from within
+// the runtime
+pub fn build_server(cfg: Config, sink: Arc<dyn TelemetrySink>) -> Result<Server> {
+    let dead_letters = cfg
+        .dlq_replay
+        .enabled
+        .then(|| -> Result<Arc<dyn DeadLetterSource>> {
+            let topic = cfg.dead_letter_topic.as_deref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "dlq_replay is enabled, but there is no dead_letter_topic to replay"
+                )
+            })?;
+            Ok(Arc::new(KafkaDeadLetterSource::new(
+                &cfg.kafka_brokers,
+                topic,
+                &cfg.dlq_replay,
+            )?))
+        })
+        .transpose()?;
+    build_server_with_dead_letters(cfg, sink, dead_letters)
 }
 
-pub async fn run_server(cfg: Config, producer: FutureProducer) -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+// build_server, reading records for /admin/replay from `dead_letters`
+// rather than the dead-letter topic
+pub(crate) fn build_server_with_dead_letters(
+    cfg: Config,
+    sink: Arc<dyn TelemetrySink>,
+    dead_letters: Option<Arc<dyn DeadLetterSource>>,
+) -> Result<Server> {
+    // Rendered once, from the config as it is before the parts get moved out
+    let openapi_document = cfg
+        .openapi
+        .enabled
+        .then(|| openapi::document(&ApiFeatures::from_config(&cfg)).to_string());
+
+    let node_id = cfg.node_id();
+    info!("Ingestion node id: {}", node_id);
+    let connections = Arc::new(ConnectionTracker::default());
+    if cfg.connection_reaper.enabled {
+        spawn_reaper(Arc::clone(&connections), &cfg.connection_reaper);
+    }
+    let requests_in_flight = InFlightRequests::default();
+    if cfg.shutdown.in_flight_log_interval_secs > 0 {
+        requests_in_flight.spawn_logger(Duration::from_secs(
+            cfg.shutdown.in_flight_log_interval_secs,
+        ));
+    }
+
+    let freshness = FreshnessWeights::new(&cfg.freshness);
+    let sink: Arc<dyn TelemetrySink> = if cfg.partition_keys.enabled {
+        let keys = KeyPseudonymizer::new(&cfg.partition_keys.secret)?;
+        Arc::new(PseudonymousKeySink::new(sink, keys))
+    } else {
+        sink
+    };
+    let breakers = cfg
+        .circuit_breaker
+        .enabled
+        .then(|| Arc::new(TopicBreakers::new(cfg.circuit_breaker)));
+    let sink: Arc<dyn TelemetrySink> = match &breakers {
+        Some(breakers) => Arc::new(CircuitBreakerSink::new(sink, Arc::clone(breakers))),
+        None => sink,
+    };
+    let priority_lanes = cfg
+        .priority
+        .enabled
+        .then(|| Arc::new(PriorityLanes::new(cfg.priority)));
+    let sink: Arc<dyn TelemetrySink> = match &priority_lanes {
+        Some(lanes) => Arc::new(PrioritySink::new(sink, Arc::clone(lanes))),
+        None => sink,
+    };
+    let sink: Arc<dyn TelemetrySink> = if cfg.ordering.enabled {
+        Arc::new(KeyOrderedSink::new(sink, &cfg.ordering))
+    } else {
+        sink
+    };
+    // Outside the ordering lock, so a waiting record doesn't keep a newer
+    // one for the device from reaching the queue and replacing it
+    let sink: Arc<dyn TelemetrySink> = if cfg.coalescing.enabled {
+        Arc::new(CoalescingSink::new(
+            sink,
+            &cfg.coalescing,
+            freshness.clone(),
+        ))
+    } else {
+        sink
+    };
+    // Outermost, so it holds whatever the rest of the chain fails to send
+    let spillover = (cfg.spillover.enabled || cfg.delivery_mode == DeliveryMode::AsyncBuffered)
+        .then(|| Arc::new(SpilloverSink::new(sink.clone(), &cfg.spillover)));
+    let sink: Arc<dyn TelemetrySink> = match &spillover {
+        Some(spillover) => spillover.clone(),
+        None => sink,
+    };
+
+    let trace_sampler = Arc::new(TraceSampler::new(cfg.trace_sampling));
+    let registry = Registry::new();
+    let request_metrics = RequestMetrics::register(&registry)?;
+
+    let maintenance = cfg
+        .maintenance
+        .enabled
+        .then(|| MaintenanceSchedule::new(&cfg.maintenance).map(Arc::new))
+        .transpose()?;
 
+    let api_keys = Arc::new(ApiKeyRegistry::new(cfg.api_keys));
     let state = AppState {
-        producer,
+        sink,
         topic: cfg.kafka_topic,
+        topic_routes: TopicRoutes::new(cfg.topic_routes)?,
+        topic_overrides: TopicOverrides::new(cfg.header_topics)?,
+        topic_template: cfg
+            .topic_template
+            .as_deref()
+            .map(TopicTemplate::parse)
+            .transpose()?,
+        echo_interpretation: cfg.echo_interpretation,
+        detect_body_format: cfg.detect_body_format,
+        max_samples_per_message: cfg.max_samples_per_message,
+        boolean_metrics: cfg.boolean_metrics.into_iter().collect(),
+        metric_coercion: cfg.metric_coercion,
+        large_integers: cfg.large_integers,
+        ts_window: cfg
+            .ts_window
+            .enabled
+            .then(|| TimestampWindow::new(&cfg.ts_window)),
+        device_limiter: cfg
+            .device_rate_limit
+            .enabled
+            .then(|| DeviceRateLimiter::new(cfg.device_rate_limit)),
+        trace_sampler: Arc::clone(&trace_sampler),
+        default_ack_mode: cfg.default_ack_mode,
+        delivery_mode: cfg.delivery_mode,
+        ack_modes: AckModeCounters::default(),
+        admin_token: cfg.admin_token,
+        last_messages: cfg
+            .enable_debug_endpoints
+            .then(|| LastMessages::new(cfg.debug_last_messages)),
+        tenants: TenantRegistry::new(cfg.tenancy),
+        api_keys: Arc::clone(&api_keys),
+        ttl: cfg.ttl,
+        health: cfg.health,
+        broker_monitor: cfg
+            .broker_probe
+            .enabled
+            .then(|| Arc::new(BrokerMonitor::new(&cfg.broker_probe))),
+        dlq_replayer: dead_letters.map(|source| DlqReplayer::new(source, &cfg.dlq_replay)),
+        connections: Arc::clone(&connections),
+        batch_budget: MemoryBudget::new(cfg.batch_memory_budget_bytes),
+        max_body_bytes: cfg.max_body_bytes,
+        max_decompressed_bytes: cfg.content_encoding.max_decompressed_bytes,
+        in_flight: AtomicUsize::new(0),
+        requests_in_flight,
+        load_shedder: cfg
+            .load_shedding
+            .enabled
+            .then(|| LoadSheddingSampler::new(cfg.load_shedding, freshness.clone())),
+        concurrency_limit: cfg
+            .concurrency_limit
+            .enabled
+            .then(|| ConcurrencyLimit::new(&cfg.concurrency_limit)),
+        breakers,
+        device_counts: cfg
+            .device_metrics
+            .enabled
+            .then(|| Arc::new(DeviceCounts::new(&cfg.device_metrics))),
+        priority_lanes,
+        spillover: spillover.clone(),
+        receipts: cfg
+            .receipts
+            .enabled
+            .then(|| Arc::new(ReceiptStore::new(&cfg.receipts))),
+        // A WebSocket message is held to the same cap as a request body
+        websocket: WebSocketConfig {
+            max_message_bytes: cfg.websocket.max_message_bytes.min(cfg.max_body_bytes),
+            ..cfg.websocket.clone()
+        },
+        maintenance: maintenance.clone(),
+        csv: cfg.csv.clone(),
+        node_id: node_id.clone(),
+        registry,
+        handler: Arc::new(HandlerContext {
+            metric_key_case: cfg.metric_key_case,
+            metric_renamer: (!cfg.metric_renames.is_empty())
+                .then(|| MetricRenamer::new(&cfg.metric_renames))
+                .transpose()?,
+            unit_converter: cfg
+                .unit_conversion
+                .enabled
+                .then(|| UnitConverter::new(&cfg.unit_conversion))
+                .transpose()?,
+            baselines: cfg
+                .adaptive_validation
+                .enabled
+                .then(|| BaselineTracker::new(cfg.adaptive_validation)),
+            validation_pool: cfg
+                .validation_pool
+                .enabled
+                .then(|| ValidationPool::new(&cfg.validation_pool)),
+            classifier: DeviceClassifier::new(cfg.device_types),
+            provisioner: cfg
+                .auto_provision
+                .enabled
+                .then(|| DeviceProvisioner::new(&cfg.auto_provision)),
+            cardinality_guard: cfg
+                .cardinality_guard
+                .enabled
+                .then(|| CardinalityGuard::new(cfg.cardinality_guard.clone())),
+            quality_stream: cfg
+                .quality_topic
+                .map(|topic| QualityStream::new(topic, cfg.quality_stream)),
+            dead_letters: cfg.dead_letter_topic.map(DeadLetterQueue::new),
+            imputer: cfg.imputation.enabled.then(|| Imputer::new(cfg.imputation)),
+            encoding: cfg.encoding,
+            resend_dedup: cfg
+                .resend_dedup
+                .enabled
+                .then(|| ResendDedup::new(cfg.resend_dedup)),
+            content_dedup: cfg
+                .content_dedup
+                .enabled
+                .then(|| ContentDedup::new(cfg.content_dedup)),
+            duplicate_backoff: cfg
+                .duplicate_backoff
+                .enabled
+                .then(|| DuplicateBackoff::new(cfg.duplicate_backoff)),
+            heartbeats: cfg
+                .heartbeat
+                .enabled
+                .then(|| HeartbeatTracker::new(&cfg.heartbeat)),
+            time_grid: cfg
+                .time_grid
+                .enabled
+                .then(|| GridAligner::new(cfg.time_grid)),
+            size_budgets: cfg
+                .size_budgets
+                .enabled
+                .then(|| SizeBudgets::new(cfg.size_budgets)),
+            histograms: PipelineHistograms::new(&cfg.histograms),
+            request_metrics,
+            delay_queue: cfg
+                .delayed_delivery
+                .enabled
+                .then(|| DelayQueue::new(&cfg.delayed_delivery)),
+            band_changes: cfg
+                .band_changes
+                .enabled
+                .then(|| BandTracker::new(cfg.band_changes)),
+            validation_profiles: cfg
+                .validation_profiles
+                .enabled
+                .then(|| ValidationProfiles::new(cfg.validation_profiles))
+                .transpose()?,
+            rate_of_change: cfg
+                .rate_of_change
+                .enabled
+                .then(|| RateOfChangeChecker::new(cfg.rate_of_change)),
+            sample_window: cfg
+                .sample_window
+                .enabled
+                .then(|| SampleWindow::new(&cfg.sample_window)),
+            metric_rules: MetricRules::new(&cfg.metric_rules)?,
+            metric_precision: (!cfg.metric_precision.is_empty())
+                .then(|| MetricPrecision::new(&cfg.metric_precision))
+                .transpose()?,
+            allowed_metrics: cfg.allowed_metrics.into_iter().collect(),
+            max_metrics_per_message: cfg.max_metrics_per_message,
+            tag_limits: cfg.tag_limits.clone(),
+            max_message_bytes: cfg.max_message_bytes,
+            validation_mode: cfg.validation_mode,
+            device_attributes: cfg
+                .device_attributes
+                .enabled
+                .then(|| DeviceAttributes::load(cfg.device_attributes))
+                .transpose()?,
+            ingest_sequence: cfg
+                .ingest_sequence
+                .enabled
+                .then(|| IngestSequencer::new(&cfg.ingest_sequence, &node_id)),
+            pipeline_retry: cfg
+                .pipeline_retry
+                .enabled
+                .then(|| PipelineRetry::new(cfg.pipeline_retry)),
+            enrichment: cfg.enrichment.enabled.then_some(Enrichment {
+                legacy_raw: cfg.enrichment.legacy_raw,
+            }),
+            log_sampler: LogSampler::new(cfg.log_sample_rate),
+            node_id,
+        }),
     };
 
-    let app = Router::new()
-        .route("/health", get(health_check))
+    register_metrics(&state)?;
+    heartbeat::spawn_emitter(Arc::clone(&state.handler), Arc::clone(&state.sink));
+    delayed_delivery::spawn_releaser(Arc::clone(&state.handler), Arc::clone(&state.sink));
+    device_attributes::spawn_reloader(Arc::clone(&state.handler));
+    if let Some(spillover) = spillover {
+        spillover::spawn_drainer(spillover, &cfg.spillover);
+    }
+    if let Some(monitor) = &state.broker_monitor {
+        broker_health::spawn_prober(
+            Arc::clone(monitor),
+            Arc::clone(&state.sink),
+            &cfg.broker_probe,
+        );
+    }
+
+    let decompress = middleware::from_fn_with_state(
+        cfg.content_encoding.clone(),
+        content_encoding::decompress_request,
+    );
+    // Bulk uploads are added after decompression, which they do themselves
+    // once their batch budget is reserved
+    let mut ingest_routes = Router::new()
         .route("/telemetry", post(ingest_telemetry))
+        .route("/telemetry/validate", post(validate_telemetry))
+        .route_layer(decompress.clone())
+        .route("/telemetry/batch", post(batch::ingest_batch));
+    if cfg.csv.enabled {
+        ingest_routes = ingest_routes.route("/telemetry/csv", post(csv_ingest::ingest_csv));
+    }
+    // Outside decompression, so the signature covers the body as sent
+    if cfg.request_signing.enabled {
+        let verifier = SignatureVerifier::new(&cfg.request_signing, cfg.max_body_bytes)?;
+        ingest_routes = ingest_routes.route_layer(middleware::from_fn_with_state(
+            Arc::new(verifier),
+            request_signing::verify_signature,
+        ));
+    }
+    // Added after signing, which would have to buffer the whole stream
+    let mut ingest_routes = ingest_routes
+        .route(
+            "/telemetry/stream",
+            post(ndjson_ingest::ingest_stream).route_layer(decompress),
+        )
+        .route_layer(middleware::from_fn(request_id::assign_request_id))
+        .layer(DefaultBodyLimit::max(cfg.max_body_bytes));
+    if cfg.clock_skew.enabled {
+        ingest_routes = ingest_routes.route_layer(middleware::from_fn_with_state(
+            cfg.clock_skew,
+            clock_skew::reject_skewed,
+        ));
+    }
+    if let Some(schedule) = &maintenance {
+        maintenance::spawn_reloader(Arc::clone(schedule));
+        ingest_routes = ingest_routes.route_layer(middleware::from_fn_with_state(
+            Arc::clone(schedule),
+            maintenance::reject_during_maintenance,
+        ));
+    }
+
+    let mut doc_routes = Router::new();
+    if let Some(document) = openapi_document {
+        doc_routes = doc_routes.route(
+            "/openapi.json",
+            get(move || async move { ([(header::CONTENT_TYPE, "application/json")], document) }),
+        );
+    }
+
+    let mut client_routes = Router::new()
+        .merge(ingest_routes)
+        .merge(doc_routes)
         .route("/metrics", get(metrics_handler))
+        .route("/stats", get(key_stats));
+    // Not under the ingest layers: the clock-skew header can't be sent per
+    // message, and browsers can't set it on the handshake
+    if cfg.websocket.enabled {
+        let mut handshake = get(websocket::ingest_websocket)
+            .layer(middleware::from_fn(request_id::assign_request_id));
+        if let Some(schedule) = &maintenance {
+            handshake = handshake.layer(middleware::from_fn_with_state(
+                Arc::clone(schedule),
+                maintenance::reject_during_maintenance,
+            ));
+        }
+        client_routes = client_routes.route("/telemetry/ws", handshake);
+    }
+    if state.receipts.is_some() {
+        client_routes = client_routes.route("/receipts/:request_id", get(get_receipt));
+    }
+    if api_keys.enabled() && cfg.require_api_key {
+        client_routes = client_routes.route_layer(middleware::from_fn_with_state(
+            Arc::clone(&api_keys),
+            api_keys::require_api_key,
+        ));
+    }
+
+    let mut admin_routes = Router::new()
+        .route("/admin/tenants/usage", get(all_tenant_usage))
+        .route("/admin/tenants/:tenant/usage", get(tenant_usage))
+        .route("/admin/decommission/:device_id", post(decommission_device));
+    if state.last_messages.is_some() {
+        admin_routes = admin_routes.route("/debug/last-messages", get(last_messages));
+    }
+    if state.dlq_replayer.is_some() {
+        admin_routes = admin_routes.route("/admin/replay", post(replay_dead_letters));
+    }
+
+    let state = Arc::new(state);
+    // Admin endpoints check the admin token themselves
+    let mut app = Router::new()
+        .route("/health", get(health_check))
+        .route("/ready", get(readiness_check))
+        .merge(client_routes)
+        .merge(admin_routes);
+    if let Some(cors) = cors::cors_layer(&cfg.cors_allowed_origins, cfg.cors_permissive)? {
+        app = app.layer(cors);
+    }
+    let app = app
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::permissive()),
+                .layer(middleware::from_fn_with_state(
+                    trace_sampler,
+                    trace_sampling::sample_request,
+                ))
+                .layer(TraceLayer::new_for_http().make_span_with(trace_sampling::request_span)),
         )
-        .with_state(Arc::new(state));
+        .with_state(Arc::clone(&state));
+
+    Ok(Server {
+        app,
+        state,
+        connections,
+    })
+}
 
-    let listener = TcpListener::bind(&cfg.listen_addr).await?;
-    info!("Rust ingestion server listening on {}", cfg.listen_addr);
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
 
-    axum::serve(listener, app).await?;
-    Ok(())
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionRefused
+    )
 }
 
-async fn health_check() -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "healthy".to_string(),
-        timestamp: chrono::Utc::now().timestamp(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
-    })
+// Accept loop serving HTTP/1 and HTTP/2 connections until `shutdown`
+// resolves. Each connection is registered with the tracker so the idle
+// reaper and shutdown can close it gracefully.
+async fn serve_connections(
+    listener: TcpListener,
+    app: Router,
+    connections: Arc<ConnectionTracker>,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            // The client gave up before we got to it; nothing to wait out
+            Err(e) if is_connection_error(&e) => {
+                debug!("Failed to accept a connection: {}", e);
+                continue;
+            }
+            // Typically out of file descriptors (EMFILE, ENFILE); retrying
+            // straight away would spin until some connections close
+            Err(e) => {
+                warn!("Failed to accept a connection, pausing accepts: {}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(ACCEPT_ERROR_PAUSE) => continue,
+                    _ = &mut shutdown => return Ok(()),
+                }
+            }
+        };
+        let app = app.clone();
+        let handle = connections.register();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(|request: Request<Incoming>| {
+                let guard = handle.start_request();
+                let response = app.clone().oneshot(request);
+                async move {
+                    let response = response.await;
+                    drop(guard);
+                    response
+                }
+            });
+            let builder = ConnectionBuilder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = handle.closed() => {
+                    info!("Closing connection from {}", remote_addr);
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                warn!("Connection from {} ended with error: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+async fn health_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let health = state.health.assess(Backlog {
+        in_flight: state.in_flight.load(Ordering::Relaxed),
+        sink_pending: state.sink.pending(),
+    });
+    if health.status == HealthStatus::Degraded {
+        warn!("Reporting degraded health: {}", health.reasons.join(", "));
+    }
+    (
+        state.health.status_code(&health),
+        Json(HealthResponse {
+            status: health.status,
+            reasons: health.reasons,
+            timestamp: chrono::Utc::now().timestamp(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("BUILD_GIT_COMMIT").to_string(),
+            build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+            rustc_version: env!("BUILD_RUSTC_VERSION").to_string(),
+            node_id: state.node_id.clone(),
+        }),
+    )
+}
+
+// Readiness, unlike /health's liveness: whether the sinks can reach their
+// backends. A broker outage takes the node out of rotation without getting
+// it restarted. With the background probe on, the answer is its last
+// result; degraded brokers still count as ready.
+async fn readiness_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadyResponse>) {
+    if let Some(monitor) = &state.broker_monitor {
+        let brokers = monitor.health();
+        let reason = (brokers == BrokerHealth::Down).then(|| {
+            monitor
+                .last_error()
+                .unwrap_or_else(|| "brokers down".to_string())
+        });
+        let status = match reason {
+            Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+            None => StatusCode::OK,
+        };
+        return (
+            status,
+            Json(ReadyResponse {
+                ready: reason.is_none(),
+                reason,
+                brokers: Some(brokers),
+                timestamp: chrono::Utc::now().timestamp(),
+            }),
+        );
+    }
+
+    let (status, reason) = match state.sink.check_ready(state.health.ready_timeout()).await {
+        Ok(()) => (StatusCode::OK, None),
+        Err(e) => {
+            warn!("Reporting not ready: {:#}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, Some(format!("{:#}", e)))
+        }
+    };
+    (
+        status,
+        Json(ReadyResponse {
+            ready: reason.is_none(),
+            reason,
+            brokers: None,
+            timestamp: chrono::Utc::now().timestamp(),
+        }),
+    )
 }
 
 async fn ingest_telemetry(
     State(state): State<Arc<AppState>>,
-    Json(payload): Json<TelemetryRequest>,
-) -> Result<Json<TelemetryResponse>, (StatusCode, Json<ErrorResponse>)> {
-    if payload.device_id.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "device_id is required".to_string(),
-                details: None,
+    Extension(trace): Extension<TraceDecision>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+    TelemetryBody(payload): TelemetryBody,
+) -> Result<(StatusCode, Json<TelemetryResponse>), ApiError> {
+    let in_flight = state.requests_in_flight.enter();
+    let metrics = &state.handler.request_metrics;
+    metrics.requests.inc();
+    let timer = metrics.latency.start_timer();
+    let result = accept_telemetry(&state, trace, request_id, &headers, payload, in_flight).await;
+    timer.observe_duration();
+    if result.is_err() {
+        metrics.failed.inc();
+    }
+    result
+}
+
+// Dry run of POST /telemetry for developing device payloads: the same
+// checks, with every failure reported rather than the first, and nothing
+// published. Rate limits, quotas and request metrics are left alone.
+async fn validate_telemetry(
+    State(state): State<Arc<AppState>>,
+    TelemetryBody(payload): TelemetryBody,
+) -> (StatusCode, Json<ValidationReport>) {
+    let device_id = payload.device_id.clone();
+    let received_at = chrono::Utc::now().timestamp_millis();
+    let (topic, report) = match to_telemetry(&state, payload, received_at) {
+        Ok(telemetry) => (
+            Some(route_topic(&state, &telemetry).into_owned()),
+            dry_run(telemetry, &state.handler),
+        ),
+        Err(e) => {
+            let error = match e.details() {
+                Some(details) => format!("{}: {}", e.message(), details),
+                None => e.message().to_string(),
+            };
+            (
+                None,
+                DryRun {
+                    errors: vec![error],
+                    ..Default::default()
+                },
+            )
+        }
+    };
+    let valid = report.errors.is_empty();
+    let status = if valid {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    (
+        status,
+        Json(ValidationReport {
+            valid,
+            device_id,
+            topic,
+            errors: report.errors,
+            warnings: report.warnings,
+        }),
+    )
+}
+
+async fn accept_telemetry(
+    state: &Arc<AppState>,
+    trace: TraceDecision,
+    request_id: RequestId,
+    headers: &HeaderMap,
+    payload: TelemetryRequest,
+    in_flight: InFlightRequest,
+) -> Result<(StatusCode, Json<TelemetryResponse>), ApiError> {
+    let device_id = payload.device_id.clone();
+    let ack = AckMode::from_headers(headers, state.default_ack_mode)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let request = RequestContext {
+        api_key: state.api_keys.identify(headers),
+        trace,
+        ack,
+        priority: Priority::from_headers(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        topic: state
+            .topic_overrides
+            .requested(headers)
+            .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?,
+        units: unit_conversion::declared_units(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        request_id,
+    };
+    state.ack_modes.record(ack);
+
+    // Held until the record is processed, in the background too for ack none
+    let permit = match &state.concurrency_limit {
+        Some(limit) => Some(limit.acquire().await.ok_or_else(|| {
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many requests in progress, retry later",
+            )
+            .with_retry_after(Duration::from_secs(1))
+        })?),
+        None => None,
+    };
+
+    // A 202 doesn't say whether the record made it, so keep a receipt the
+    // client can check later
+    let receipt = match &state.receipts {
+        Some(receipts) if ack.success_status() == StatusCode::ACCEPTED => {
+            Some(receipts.open(&request.request_id.0, Instant::now()))
+        }
+        _ => None,
+    };
+    let request_id = receipt.as_ref().map(|receipt| receipt.id().to_string());
+
+    // Fire-and-forget: answer now, process in the background
+    if ack == AckMode::None {
+        let state = Arc::clone(state);
+        let device = device_id.clone();
+        tokio::spawn(async move {
+            let outcome = process_request(&state, payload, &request, receipt.clone()).await;
+            drop(permit);
+            settle_receipt(receipt.as_ref(), &outcome);
+            // Counted until here, so a drain waits for it like any other
+            drop(in_flight);
+            if let Err(e) = outcome {
+                debug!(
+                    "Unacknowledged telemetry for device {} failed: {}",
+                    device,
+                    e.message()
+                );
+            }
+        });
+        return Ok((
+            ack.success_status(),
+            Json(TelemetryResponse {
+                success: true,
+                message: "Telemetry accepted".to_string(),
+                device_id,
+                request_id,
+                interpreted: None,
+                duplicate: false,
+                partition: None,
+                offset: None,
             }),
         ));
     }
 
-    if payload.metrics.is_empty() {
-        return Err((
+    let echo = state.echo_interpretation
+        || headers
+            .get(ECHO_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.eq_ignore_ascii_case("true") || value == "1");
+    // The slot, the in-flight counts and the receipt go with the record, so
+    // they are released once it has been dealt with, not when the client
+    // hangs up
+    let outcome = {
+        let state = Arc::clone(state);
+        run_detached(async move {
+            let outcome = process_request(&state, payload, &request, receipt.clone()).await;
+            drop(permit);
+            settle_receipt(receipt.as_ref(), &outcome);
+            drop(in_flight);
+            outcome
+        })
+        .await?
+    };
+    let duplicate = matches!(
+        &outcome,
+        RequestOutcome::Published(prepared) if prepared.dropped_by == Some(RESEND_DEDUP)
+    );
+    let position = match &outcome {
+        RequestOutcome::Published(prepared) => prepared.position,
+        RequestOutcome::Shed => None,
+    };
+    let (status, message, interpreted) = match outcome {
+        // Already delivered the first time; nothing is left pending
+        RequestOutcome::Published(prepared) if duplicate => (
+            StatusCode::OK,
+            "Duplicate telemetry ignored",
+            echo.then(|| Interpretation::from(*prepared)),
+        ),
+        // Held, not delivered, whatever the ack mode
+        RequestOutcome::Published(prepared) if prepared.scheduled_for.is_some() => (
+            StatusCode::ACCEPTED,
+            "Telemetry scheduled for delivery",
+            echo.then(|| Interpretation::from(*prepared)),
+        ),
+        // Possibly only in the spillover buffer so far
+        RequestOutcome::Published(prepared)
+            if state.delivery_mode == DeliveryMode::AsyncBuffered =>
+        {
+            (
+                StatusCode::ACCEPTED,
+                "Telemetry accepted for delivery",
+                echo.then(|| Interpretation::from(*prepared)),
+            )
+        }
+        RequestOutcome::Published(prepared) => (
+            ack.success_status(),
+            "Telemetry received successfully",
+            echo.then(|| Interpretation::from(*prepared)),
+        ),
+        RequestOutcome::Shed => (
+            ack.success_status(),
+            "Telemetry dropped by load shedding",
+            None,
+        ),
+    };
+
+    Ok((
+        status,
+        Json(TelemetryResponse {
+            success: true,
+            message: message.to_string(),
+            device_id,
+            request_id,
+            interpreted,
+            duplicate,
+            partition: position.map(|position| position.partition),
+            offset: position.and_then(|position| position.offset),
+        }),
+    ))
+}
+
+// Records the outcomes no sink sees: a failure anywhere in the pipeline, or
+// a record that was never sent. Delivery itself is recorded by the sink.
+// A record held for later delivery stays pending.
+fn settle_receipt(receipt: Option<&ReceiptTicket>, outcome: &Result<RequestOutcome, ApiError>) {
+    let Some(receipt) = receipt else {
+        return;
+    };
+    match outcome {
+        Err(e) => receipt.failed(match e.details() {
+            Some(details) => format!("{}: {}", e.message(), details),
+            None => e.message().to_string(),
+        }),
+        Ok(RequestOutcome::Shed) => receipt.failed("dropped by load shedding"),
+        Ok(RequestOutcome::Published(prepared)) => {
+            if let Some(reason) = prepared.dropped_by {
+                receipt.failed(format!("not sent: dropped by {}", reason));
+            }
+        }
+    }
+}
+
+async fn get_receipt(
+    State(state): State<Arc<AppState>>,
+    Path(request_id): Path<String>,
+) -> Result<Json<Receipt>, ApiError> {
+    state
+        .receipts
+        .as_ref()
+        .and_then(|receipts| receipts.get(&request_id, Instant::now()))
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("no receipt for request {}", request_id),
+            )
+        })
+}
+
+pub(crate) enum RequestOutcome {
+    Published(Box<PreparedTelemetry>),
+    // Sampled out by the load shedder; not an error for the client
+    Shed,
+}
+
+// Runs a record's processing on a task of its own and waits for it. axum
+// drops the handler of a client that disconnects; the task carries on, so
+// the record is never cut off halfway through (a send started but not seen
+// through, a dedup entry taken for a record never sent). Whatever the task
+// owns is released when the record is done.
+pub(crate) async fn run_detached<T: Send + 'static>(
+    task: impl std::future::Future<Output = T> + Send + 'static,
+) -> T {
+    match tokio::spawn(task.instrument(Span::current())).await {
+        Ok(output) => output,
+        // Never aborted, so a failed join is a panic to pass on
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+// Counts a record as in flight for as long as it is held
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    // Returns the guard and the depth including this record
+    fn enter(counter: &'a AtomicUsize) -> (Self, usize) {
+        let depth = counter.fetch_add(1, Ordering::Relaxed) + 1;
+        (Self(counter), depth)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// What every record of a request is processed with, worked out once from
+// the request's headers
+#[derive(Clone)]
+pub(crate) struct RequestContext {
+    // The API key the caller presented, which the outcome is accounted to
+    pub(crate) api_key: Option<usize>,
+    pub(crate) trace: TraceDecision,
+    pub(crate) ack: AckMode,
+    // The priority the caller asked for, if any
+    pub(crate) priority: Option<Priority>,
+    // The topic the caller picked by header, in place of the routed one
+    pub(crate) topic: Option<String>,
+    // Units the caller declared its metrics in, by metric
+    pub(crate) units: HashMap<String, String>,
+    pub(crate) request_id: RequestId,
+}
+
+// Validate, admit and publish a single telemetry request. Shared by the
+// single-record, batch and WebSocket endpoints.
+pub(crate) async fn process_request(
+    state: &AppState,
+    mut payload: TelemetryRequest,
+    request: &RequestContext,
+    receipt: Option<ReceiptTicket>,
+) -> Result<RequestOutcome, ApiError> {
+    let RequestContext {
+        api_key,
+        trace,
+        priority: requested,
+        ref request_id,
+        ..
+    } = *request;
+    if let Some(counts) = &state.device_counts {
+        counts.record(&payload.device_id);
+    }
+    let priority = match &state.priority_lanes {
+        Some(lanes) => {
+            let device_type = state
+                .handler
+                .classifier
+                .classify(&payload.device_id, payload.metrics.keys());
+            lanes.priority_of(requested, device_type.as_deref())
+        }
+        None => Priority::Normal,
+    };
+
+    let (_in_flight, depth) = InFlight::enter(&state.in_flight);
+    if let Some(sampler) = &state.load_shedder {
+        let metrics = payload.metric_names();
+        match sampler.shed(&payload.device_id, &metrics, depth, priority) {
+            Shedding::Keep => {}
+            Shedding::Metrics(shed) => {
+                debug!(
+                    "Shed {} from telemetry for device {} at depth {}",
+                    shed.join(", "),
+                    payload.device_id,
+                    depth
+                );
+                payload.remove_metrics(&shed);
+            }
+            Shedding::Record => {
+                debug!(
+                    "Shed telemetry for device {} at depth {}",
+                    payload.device_id, depth
+                );
+                return Ok(RequestOutcome::Shed);
+            }
+        }
+    }
+
+    let span = if state.trace_sampler.traces_device(trace, &payload.device_id) {
+        info_span!(
+            "telemetry",
+            device_id = %payload.device_id,
+            request_id = %request_id.0,
+            // Filled in by publish_request and handle_telemetry
+            topic = tracing::field::Empty,
+            send_ms = tracing::field::Empty,
+        )
+    } else {
+        Span::none()
+    };
+    let result = publish_request(state, payload, request, priority, receipt)
+        .instrument(span)
+        .await;
+    if let Some(key) = api_key {
+        match &result {
+            Ok(prepared) => state.api_keys.record_accepted(key, prepared.warnings.len()),
+            Err(_) => state.api_keys.record_rejected(key),
+        }
+    }
+    result.map(|prepared| RequestOutcome::Published(Box::new(prepared)))
+}
+
+async fn publish_request(
+    state: &AppState,
+    payload: TelemetryRequest,
+    request: &RequestContext,
+    priority: Priority,
+    receipt: Option<ReceiptTicket>,
+) -> Result<PreparedTelemetry, ApiError> {
+    if payload.device_id.is_empty() {
+        return Err(ApiError::new(
             StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "metrics cannot be empty".to_string(),
-                details: None,
-            }),
+            "device_id is required",
         ));
     }
 
-    // Convert HTTP request to telemetry and process
-    let telemetry_data = crate::proto::telemetry::Telemetry {
-        device_id: payload.device_id.clone(),
-        ts: payload
-            .ts
-            .unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
-        metrics: payload.metrics,
-        raw: payload.raw.unwrap_or_default(),
+    if let Some(limiter) = &state.device_limiter {
+        if let Err(retry_after) = limiter.check(&payload.device_id, Instant::now()) {
+            // Per request, so kept out of the default log level during a flood
+            debug!("Rate limited device {}", payload.device_id);
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("rate limit exceeded for device {}", payload.device_id),
+            )
+            .with_retry_after(retry_after));
+        }
+    }
+
+    let received_at = chrono::Utc::now().timestamp_millis();
+    let (ttl_ms, deliver_at) = (payload.ttl_ms, payload.deliver_at);
+    let telemetry_data = to_telemetry(state, payload, received_at)?;
+
+    let topic = match &request.topic {
+        Some(topic) => Cow::Borrowed(topic.as_str()),
+        None => route_topic(state, &telemetry_data),
     };
+    Span::current().record("topic", topic.as_ref());
+    // A scheduled record's TTL runs from its delivery time
+    let deliver_at = deliver_at.filter(|at| *at > received_at);
+    if let Some(deliver_at) = deliver_at {
+        let Some(queue) = &state.handler.delay_queue else {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "deliver_at is not supported: delayed delivery is disabled",
+            ));
+        };
+        queue
+            .check_delay(deliver_at)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+    let expires_at = state
+        .ttl
+        .expires_at(ttl_ms, &topic, deliver_at.unwrap_or(received_at));
 
-    match handle_telemetry(telemetry_data, &state.producer, &state.topic).await {
-        Ok(_) => Ok(Json(TelemetryResponse {
-            success: true,
-            message: "Telemetry received successfully".to_string(),
-            device_id: payload.device_id,
-        })),
+    if state.tenants.enabled() {
+        if let Some(tenant) = state.tenants.resolve_tenant(&telemetry_data.device_id) {
+            let bytes = telemetry_data.encoded_len() as u64;
+            if let Err(rejection) = state.tenants.admit(tenant, bytes) {
+                warn!("Rejected telemetry for tenant {}: {:?}", tenant, rejection);
+                return Err(tenant_rejection_error(tenant, rejection));
+            }
+        }
+    }
+
+    match handle_telemetry(
+        telemetry_data,
+        state.sink.as_ref(),
+        &topic,
+        &state.handler,
+        Delivery {
+            expires_at,
+            deliver_at,
+            ack: request.ack,
+            priority,
+            request_id: Some(request.request_id.0.clone()),
+            receipt,
+            units: request.units.clone(),
+        },
+    )
+    .await
+    {
+        Ok(prepared) => {
+            let published = prepared.dropped_by.is_none() && prepared.scheduled_for.is_none();
+            if let (Some(last), true) = (&state.last_messages, published) {
+                last.record(&topic, &request.request_id.0, &prepared.telemetry);
+            }
+            Ok(prepared)
+        }
+        Err(e) if e.is::<CircuitOpen>() => {
+            let open = e.downcast::<CircuitOpen>().unwrap();
+            debug!("Short-circuited telemetry: {}", open);
+            Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("topic {} is temporarily unavailable", open.topic),
+            )
+            .with_retry_after(open.retry_after))
+        }
+        Err(e) if e.is::<BufferFull>() => {
+            warn!("Rejected telemetry: {}", e);
+            Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "telemetry could not be sent, retry later",
+            )
+            .with_details(e.to_string())
+            .with_retry_after(Duration::from_secs(1)))
+        }
+        Err(e) if e.is::<DelayRejection>() => {
+            let rejection = e.downcast::<DelayRejection>().unwrap();
+            let status = match rejection {
+                DelayRejection::TooFarAhead { .. } => StatusCode::BAD_REQUEST,
+                DelayRejection::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Err(ApiError::new(status, rejection.to_string()))
+        }
+        // Nothing was sent, so the client can safely send it again
+        Err(e) if e.is::<TransientError>() => {
+            warn!("Failed to process telemetry: {}", e);
+            Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "telemetry could not be sent, retry later",
+            )
+            .with_details(e.to_string())
+            .with_retry_after(Duration::from_secs(1)))
+        }
+        Err(e) if e.is::<TelemetryError>() => match e.downcast::<TelemetryError>().unwrap() {
+            // The client's data, so resending it as is won't help
+            TelemetryError::Validation(e) => {
+                debug!("Rejected invalid telemetry: {}", e);
+                Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "telemetry failed validation",
+                )
+                .with_details(e.to_string()))
+            }
+            TelemetryError::Kafka(e) => {
+                warn!("Failed to send telemetry: {:?}", e);
+                Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "telemetry could not be sent, retry later",
+                )
+                .with_details(e.to_string())
+                .with_retry_after(Duration::from_secs(1)))
+            }
+        },
+        // Too late to be of use to anyone, so a retry won't help either
+        Err(e) if e.is::<Expired>() => Err(ApiError::new(
+            StatusCode::GONE,
+            "telemetry expired before it could be sent",
+        )
+        .with_details(e.to_string())),
+        Err(e) if e.is::<OverBudget>() => {
+            let over = e.downcast::<OverBudget>().unwrap();
+            debug!("Rejected oversized telemetry: {}", over);
+            Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                over.to_string(),
+            ))
+        }
+        Err(e) if e.is::<MessageLimit>() => {
+            let over = e.downcast::<MessageLimit>().unwrap();
+            debug!("Rejected oversized telemetry: {}", over);
+            Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                over.to_string(),
+            ))
+        }
         Err(e) => {
             warn!("Failed to process telemetry: {:?}", e);
-            Err((
+            Err(ApiError::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to process telemetry".to_string(),
-                    details: Some(e.to_string()),
-                }),
-            ))
+                "Failed to process telemetry",
+            )
+            .with_details(e.to_string()))
+        }
+    }
+}
+
+// The request checks and conversion shared by publishing and the dry run:
+// metric values normalized, sample count and timestamps checked
+fn to_telemetry(
+    state: &AppState,
+    payload: TelemetryRequest,
+    received_at: i64,
+) -> Result<Telemetry, ApiError> {
+    if payload.metrics.is_empty() && payload.samples.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "metrics cannot be empty",
+        ));
+    }
+
+    if payload.samples.len() > state.max_samples_per_message {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "at most {} samples per message",
+                state.max_samples_per_message
+            ),
+        ));
+    }
+
+    let invalid =
+        |e| ApiError::new(StatusCode::BAD_REQUEST, "invalid metric value").with_details(e);
+    let normalized = normalize_metrics(
+        payload.metrics,
+        &state.boolean_metrics,
+        state.metric_coercion,
+        state.large_integers,
+    )
+    .map_err(invalid)?;
+    // Samples take plain numbers only, as before
+    let mut samples = payload
+        .samples
+        .into_iter()
+        .map(|sample| {
+            let metrics = normalize_metrics(
+                sample.metrics,
+                &HashSet::new(),
+                MetricCoercion::Strict,
+                state.large_integers,
+            )?
+            .metrics;
+            Ok(Sample {
+                ts: sample.ts,
+                metrics,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(invalid)?;
+    let metadata = if normalized.coerced.is_empty() {
+        None
+    } else {
+        warn!(
+            "Coerced non-numeric values for device {}: {}",
+            payload.device_id,
+            normalized.coerced.join(", ")
+        );
+        Some(coerced_metadata(&normalized.coerced))
+    };
+
+    let mut ts = payload.ts.unwrap_or(received_at);
+    if let Some(window) = &state.ts_window {
+        let implausible =
+            |e| ApiError::new(StatusCode::BAD_REQUEST, "implausible timestamp").with_details(e);
+        ts = window
+            .apply(&payload.device_id, ts, received_at)
+            .map_err(implausible)?;
+        for sample in &mut samples {
+            sample.ts = window
+                .apply(&payload.device_id, sample.ts, received_at)
+                .map_err(|e| implausible(format!("sample {}", e)))?;
+        }
+    }
+
+    Ok(Telemetry {
+        device_id: payload.device_id,
+        ts,
+        metrics: normalized.metrics,
+        raw: payload.raw.unwrap_or_default(),
+        tags: payload.tags,
+        metadata,
+        samples,
+        flag_metrics: normalized.flags,
+        text_metrics: normalized.text,
+    })
+}
+
+// Flags the record so consumers can tell which values arrived as strings
+fn coerced_metadata(coerced: &[String]) -> prost_types::Struct {
+    use prost_types::{value::Kind, ListValue, Struct, Value};
+    let names = coerced
+        .iter()
+        .map(|name| Value {
+            kind: Some(Kind::StringValue(name.clone())),
+        })
+        .collect();
+    Struct {
+        fields: BTreeMap::from([(
+            "coerced_metrics".to_string(),
+            Value {
+                kind: Some(Kind::ListValue(ListValue { values: names })),
+            },
+        )]),
+    }
+}
+
+// Destination topic for a record: the configured template when it resolves
+// to a legal name, otherwise the default topic
+fn route_topic<'a>(state: &'a AppState, telemetry: &Telemetry) -> Cow<'a, str> {
+    if let Some(topic) = state.topic_routes.select(telemetry) {
+        debug!(
+            "Routing device {} to topic {} by rule",
+            telemetry.device_id, topic
+        );
+        return Cow::Borrowed(topic);
+    }
+    let Some(template) = &state.topic_template else {
+        debug!(
+            "Routing device {} to default topic {}",
+            telemetry.device_id, state.topic
+        );
+        return Cow::Borrowed(&state.topic);
+    };
+    let device_type = state
+        .handler
+        .classifier
+        .classify(&telemetry.device_id, telemetry.metrics.keys());
+    let vars = HashMap::from([
+        ("device_type", device_type.as_deref()),
+        ("region", telemetry.tags.get("region").map(String::as_str)),
+        ("tenant", state.tenants.resolve_tenant(&telemetry.device_id)),
+    ]);
+    match template.resolve(&vars) {
+        Ok(topic) => {
+            debug!("Routing device {} to topic {}", telemetry.device_id, topic);
+            Cow::Owned(topic)
+        }
+        Err(reason) => {
+            warn!(
+                "Topic template unusable for device {} ({}); using default topic {}",
+                telemetry.device_id, reason, state.topic
+            );
+            Cow::Borrowed(&state.topic)
         }
     }
 }
 
-async fn metrics_handler() -> &'static str {
-    // Basic prometheus metrics endpoint
-    // In a real implementation, you'd use the prometheus crate properly
-    "# HELP rust_ingest_requests_total Total number of telemetry requests\n# TYPE rust_ingest_requests_total counter\nrust_ingest_requests_total 0\n"
+fn tenant_rejection_error(tenant: &str, rejection: TenantRejection) -> ApiError {
+    match rejection {
+        TenantRejection::RateLimited { retry_after } => ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("rate limit exceeded for tenant {}", tenant),
+        )
+        .with_retry_after(retry_after),
+        TenantRejection::QuotaExceeded { quota, resets_in } => ApiError::new(
+            StatusCode::PAYMENT_REQUIRED,
+            format!("monthly {} quota exceeded for tenant {}", quota, tenant),
+        )
+        .with_retry_after(resets_in),
+    }
+}
+
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin endpoints are disabled",
+        ));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid admin token",
+        ))
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn last_messages(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DebugMessage>>, ApiError> {
+    authorize_admin(&state, &headers)?;
+    let messages = state
+        .last_messages
+        .as_ref()
+        .map(LastMessages::snapshot)
+        .unwrap_or_default();
+    Ok(Json(messages))
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayParams {
+    // Fewer records than dlq_replay.max_records
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayResponse {
+    // Records read from the dead-letter topic
+    read: usize,
+    // Published this time
+    replayed: usize,
+    // Accepted but not published, e.g. already delivered by an earlier replay
+    dropped: usize,
+    // Rejected again, and dead-lettered again with the new reason
+    failed: usize,
+    // Not a dead letter this service can read; left behind
+    unreadable: usize,
+    // Read, but not replayed because a send failed; the next replay reads
+    // them again, starting with the one that failed
+    pending: usize,
+    failures: Vec<ReplayFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayFailure {
+    partition: i32,
+    offset: i64,
+    device_id: String,
+    error: String,
+}
+
+// Re-runs up to `limit` records from the dead-letter topic through the
+// pipeline, e.g. after widening a validation range that rejected them.
+// Offsets are committed for the records dealt with, so the next call picks
+// up after them; a send failure ends the replay, and everything from the
+// failed record on is read again next time. Each record goes out under a
+// request id derived from its dead-letter offset and keeps its original
+// timestamp, so resend dedup can drop a record a failed commit lets through
+// twice.
+async fn replay_dead_letters(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<ReplayParams>,
+) -> Result<Json<ReplayResponse>, ApiError> {
+    authorize_admin(&state, &headers)?;
+    // Runs to the commit even if the caller hangs up, so records it has sent
+    // aren't left uncommitted behind the consumer's position
+    run_detached(async move { replay(&state, params.limit).await })
+        .await
+        .map(Json)
+}
+
+async fn replay(state: &AppState, limit: Option<usize>) -> Result<ReplayResponse, ApiError> {
+    let Some(replayer) = &state.dlq_replayer else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "replay is disabled"));
+    };
+    let Some(source) = replayer.try_begin() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "a replay is already running",
+        ));
+    };
+    let messages = match source.fetch(replayer.limit(limit), replayer.idle()).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            rewind_dead_letters(source.as_ref()).await;
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read the dead-letter topic",
+            )
+            .with_details(e.to_string()));
+        }
+    };
+
+    let mut response = ReplayResponse {
+        read: messages.len(),
+        ..Default::default()
+    };
+    // Records before this one have been dealt with and can be committed
+    let mut settled = messages.len();
+    for (i, message) in messages.iter().enumerate() {
+        let stored = match StoredDeadLetter::parse(&message.payload) {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!(
+                    "Skipped unreadable dead letter at {}/{}: {}",
+                    message.partition, message.offset, e
+                );
+                response.unreadable += 1;
+                continue;
+            }
+        };
+        debug!(
+            "Replaying dead letter at {}/{}, rejected at {}: {}",
+            message.partition, message.offset, stored.rejected_at, stored.reason
+        );
+        let telemetry = stored.into_telemetry();
+        let device_id = telemetry.device_id.clone();
+        let topic = route_topic(state, &telemetry).into_owned();
+        // Units declared in X-Units aren't kept with the dead letter; per
+        // device units still apply
+        let outcome = handle_telemetry(
+            telemetry,
+            state.sink.as_ref(),
+            &topic,
+            &state.handler,
+            Delivery {
+                ack: state.default_ack_mode,
+                request_id: Some(message.request_id()),
+                ..Default::default()
+            },
+        )
+        .await;
+        let error = match outcome {
+            Ok(prepared) if prepared.dropped_by.is_none() => {
+                response.replayed += 1;
+                continue;
+            }
+            Ok(_) => {
+                response.dropped += 1;
+                continue;
+            }
+            Err(e) => e,
+        };
+        response.failures.push(ReplayFailure {
+            partition: message.partition,
+            offset: message.offset,
+            device_id,
+            error: format!("{:#}", error),
+        });
+        // A record rejected again has just been dead-lettered again, so it is
+        // dealt with; one that wasn't sent must stay to be replayed
+        match error.downcast_ref::<TelemetryError>() {
+            Some(TelemetryError::Validation(_)) => response.failed += 1,
+            _ => {
+                settled = i;
+                break;
+            }
+        }
+    }
+
+    let (replayed, pending) = messages.split_at(settled);
+    response.pending = pending.len();
+    let committed = match replayed {
+        [] => Ok(()),
+        replayed => source.commit(replayed).await,
+    };
+    if committed.is_err() || !pending.is_empty() {
+        rewind_dead_letters(source.as_ref()).await;
+    }
+    committed.map_err(|e| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Replayed, but failed to commit the dead-letter offsets",
+        )
+        .with_details(format!("the same records may be replayed again: {}", e))
+    })?;
+    info!(
+        "Replayed dead letters: {} read, {} replayed, {} dropped, {} failed, {} unreadable, {} pending",
+        response.read,
+        response.replayed,
+        response.dropped,
+        response.failed,
+        response.unreadable,
+        response.pending
+    );
+    Ok(response)
+}
+
+// Without this, records read but not committed would be skipped until the
+// consumer next rejoins its group
+async fn rewind_dead_letters(source: &dyn DeadLetterSource) {
+    if let Err(e) = source.rewind().await {
+        warn!(
+            "Failed to rewind the dead-letter consumer; uncommitted records are skipped until restart: {:#}",
+            e
+        );
+    }
+}
+
+async fn all_tenant_usage(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TenantUsage>>, ApiError> {
+    authorize_admin(&state, &headers)?;
+    Ok(Json(state.tenants.all_usage()))
+}
+
+async fn tenant_usage(
+    State(state): State<Arc<AppState>>,
+    Path(tenant): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<TenantUsage>, ApiError> {
+    authorize_admin(&state, &headers)?;
+    state
+        .tenants
+        .usage(&tenant)
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("unknown tenant {}", tenant)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct DecommissionResponse {
+    device_id: String,
+    topic: String,
+    // In-memory stores that held state for the device
+    stores_cleared: usize,
+}
+
+// Tombstones a retired device on the default topic, so compacted-topic
+// consumers drop its state, and forgets it in the pipeline
+async fn decommission_device(
+    State(state): State<Arc<AppState>>,
+    Path(device_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<DecommissionResponse>, ApiError> {
+    authorize_admin(&state, &headers)?;
+    // Cleared first, so nothing held back for the device (delayed delivery,
+    // spillover, coalescing) goes out after the tombstone and brings its
+    // state back
+    let stores_cleared = forget_device(&state, &device_id);
+    if let Err(e) = state.sink.publish_tombstone(&state.topic, &device_id).await {
+        warn!("Failed to tombstone device {}: {:?}", device_id, e);
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to send tombstone",
+        )
+        .with_details(e.to_string()));
+    }
+    info!(
+        "Decommissioned device {}: tombstoned on {}, cleared {} stores",
+        device_id, state.topic, stores_cleared
+    );
+    Ok(Json(DecommissionResponse {
+        device_id,
+        topic: state.topic.clone(),
+        stores_cleared,
+    }))
+}
+
+// Drops everything held in memory for the device; returns how many stores
+// had something
+fn forget_device(state: &AppState, device_id: &str) -> usize {
+    let forgotten = [
+        state
+            .device_limiter
+            .as_ref()
+            .map(|limiter| limiter.forget(device_id)),
+        state
+            .device_counts
+            .as_ref()
+            .map(|counts| counts.forget(device_id)),
+        state
+            .last_messages
+            .as_ref()
+            .map(|messages| messages.forget(device_id)),
+    ];
+    forgotten.into_iter().flatten().filter(|had| *had).count()
+        + state.handler.forget_device(device_id)
+        + state.sink.forget_device(device_id)
+}
+
+// Usage of the calling API key. The key is taken from the request itself,
+// so a caller can never see another key's stats.
+async fn key_stats(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ApiKeyStats>, ApiError> {
+    if !state.api_keys.enabled() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "API keys are not configured",
+        ));
+    }
+    state
+        .api_keys
+        .identify(&headers)
+        .and_then(|key| state.api_keys.stats(key))
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "invalid API key"))
+}
+
+async fn metrics_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let text = request_metrics::render_registry(&state.registry);
+    let histograms = &state.handler.histograms;
+    // Native histograms only exist in the protobuf format
+    if histograms.native && exposition::accepts_protobuf(&headers) {
+        let mut families = exposition::families_from_text(&text);
+        families.extend(histograms.families());
+        return (
+            [(header::CONTENT_TYPE, exposition::PROTOBUF_CONTENT_TYPE)],
+            exposition::encode_delimited(&families),
+        )
+            .into_response();
+    }
+    (text + &histograms.render_text()).into_response()
+}
+
+// Adds every component's metrics to the registry behind /metrics
+fn register_metrics(state: &AppState) -> prometheus::Result<()> {
+    let registry = &state.registry;
+    state.connections.register_metrics(registry)?;
+    state.ack_modes.register_metrics(registry)?;
+    if let Some(counts) = &state.device_counts {
+        counts.register_metrics(registry)?;
+    }
+    if let Some(limit) = &state.concurrency_limit {
+        limit.register_metrics(registry)?;
+    }
+    if let Some(shedder) = &state.load_shedder {
+        shedder.register_metrics(registry)?;
+    }
+    if let Some(lanes) = &state.priority_lanes {
+        lanes.register_metrics(registry)?;
+    }
+    if let Some(breakers) = &state.breakers {
+        breakers.register_metrics(registry)?;
+    }
+    if let Some(spillover) = &state.spillover {
+        spillover.register_metrics(registry)?;
+    }
+    state.handler.register_metrics(registry)?;
+    // The chain's wrappers pass this on, down to the sinks that have metrics
+    state.sink.register_metrics(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config,
+        dlq_replay::DeadLetterMessage,
+        request_id::REQUEST_ID_HEADER,
+        routing::TOPIC_HEADER,
+        sink::{Position, SinkRecord},
+    };
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{
+        io::Write,
+        sync::{atomic::AtomicBool, Mutex},
+    };
+
+    // Stands in for Kafka: keeps what would have been sent, or fails every
+    // send and readiness check while `failing`
+    #[derive(Default)]
+    struct MockProducer {
+        sent: Mutex<Vec<(String, String, Vec<u8>)>>,
+        request_ids: Mutex<Vec<Option<String>>>,
+        tombstones: Mutex<Vec<String>>,
+        failing: AtomicBool,
+    }
+
+    impl MockProducer {
+        fn keys(&self) -> Vec<String> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter().map(|(_, key, _)| key.clone()).collect()
+        }
+    }
+
+    #[async_trait]
+    impl TelemetrySink for MockProducer {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("broker unavailable"));
+            }
+            let mut sent = self.sent.lock().unwrap();
+            if let Some(slot) = record.position {
+                let _ = slot.set(Position {
+                    partition: 0,
+                    offset: Some(sent.len() as i64),
+                });
+            }
+            sent.push((
+                record.topic.to_string(),
+                record.key.to_string(),
+                record.payload.to_vec(),
+            ));
+            self.request_ids
+                .lock()
+                .unwrap()
+                .push(record.request_id.map(str::to_string));
+            Ok(())
+        }
+
+        async fn publish_tombstone(&self, _topic: &str, key: &str) -> Result<()> {
+            self.tombstones.lock().unwrap().push(key.to_string());
+            Ok(())
+        }
+
+        async fn check_ready(&self, _timeout: Duration) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("no brokers"));
+            }
+            Ok(())
+        }
+    }
+
+    // The minimal config plus `extra` keys
+    fn build_with(extra: &str, producer: Arc<MockProducer>) -> Server {
+        let cfg = config::from_toml(&format!(
+            r#"
+            listen_addr = "127.0.0.1:0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            {}
+            "#,
+            extra
+        ))
+        .unwrap();
+        build_server(cfg, producer as Arc<dyn TelemetrySink>).unwrap()
+    }
+
+    fn build(producer: Arc<MockProducer>) -> Server {
+        build_with("", producer)
+    }
+
+    fn server_with(producer: MockProducer) -> (Router, Arc<MockProducer>) {
+        let producer = Arc::new(producer);
+        (build(Arc::clone(&producer)).app, producer)
+    }
+
+    fn server() -> (Router, Arc<MockProducer>) {
+        server_with(MockProducer::default())
+    }
+
+    async fn send(app: &Router, request: Request) -> (StatusCode, serde_json::Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        // Extractor rejections come back as plain text
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    async fn post(app: &Router, body: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::post("/telemetry")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send(app, request).await
+    }
+
+    #[tokio::test]
+    async fn test_telemetry_is_published() {
+        let (app, producer) = server();
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["device_id"], "sensor-1");
+
+        let health = Request::get("/health").body(Body::empty()).unwrap();
+        let (status, health) = send(&app, health).await;
+        assert_eq!(status, StatusCode::OK);
+        for field in ["version", "git_commit", "rustc_version"] {
+            assert!(
+                !health[field].as_str().unwrap().is_empty(),
+                "{}: {}",
+                field,
+                health
+            );
+        }
+        assert!(health["build_timestamp"].as_i64().unwrap() > 0);
+
+        let sent = producer.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (topic, key, payload) = &sent[0];
+        assert_eq!((topic.as_str(), key.as_str()), ("telemetry", "sensor-1"));
+        let telemetry = Telemetry::decode(&payload[..]).unwrap();
+        assert_eq!(telemetry.ts, 1_700_000_000_000);
+        assert_eq!(telemetry.metrics["temperature"], 21.5);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_rounded_to_their_precision() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"
+            [metric_precision]
+            temperature = 1
+            pressure = 0
+            "#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.46, "pressure": 1013.5, "humidity": 45.123}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let sent = producer.sent.lock().unwrap();
+        let telemetry = Telemetry::decode(&sent[0].2[..]).unwrap();
+        assert_eq!(telemetry.metrics["temperature"], 21.5);
+        assert_eq!(telemetry.metrics["pressure"], 1014.0);
+        assert_eq!(telemetry.metrics["humidity"], 45.123);
+    }
+
+    #[tokio::test]
+    async fn test_typed_coercion_keeps_flags_and_text() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(r#"metric_coercion = "typed""#, Arc::clone(&producer)).app;
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5, "door_open": false, "status": "idle"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let payload = producer.sent.lock().unwrap()[0].2.clone();
+        let telemetry = Telemetry::decode(&payload[..]).unwrap();
+        assert_eq!(telemetry.metrics.len(), 1);
+        assert!(!telemetry.flag_metrics["door_open"]);
+        assert_eq!(telemetry.text_metrics["status"], "idle");
+
+        // Still rejected by default
+        let (app, _) = server();
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5, "status": "idle"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_telemetry_is_rejected() {
+        let (app, producer) = server();
+        let (status, body) = post(&app, r#"{"device_id": "sensor-1", "metrics": {}}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "metrics cannot be empty");
+
+        let (status, body) = post(&app, r#"{"device_id": "", "metrics": {"t": 1.0}}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "device_id is required");
+
+        // Out of range: the data is at fault, not the server
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"battery_level": 150}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "telemetry failed validation");
+        assert!(
+            body["details"].as_str().unwrap().contains("battery_level"),
+            "{}",
+            body
+        );
+
+        let (status, _) = post(&app, r#"{"metrics": {"temperature": 21.5}}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _) = post(&app, "{not json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(producer.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_telemetry_is_gone() {
+        let (app, producer) = server();
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "ttl_ms": 0, "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["error"], "telemetry expired before it could be sent");
+        assert!(producer.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_and_gzip_bodies_are_accepted() {
+        let (app, producer) = server();
+        let protobuf = Telemetry {
+            device_id: "sensor-1".to_string(),
+            metrics: HashMap::from([("temperature".to_string(), 21.5)]),
+            ..Default::default()
+        };
+        let request = Request::post("/telemetry")
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
+            .body(Body::from(protobuf.encode_to_vec()))
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::OK);
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(br#"{"device_id": "sensor-2", "metrics": {"temperature": 22.0}}"#)
+            .unwrap();
+        let request = Request::post("/telemetry")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(gzip.finish().unwrap()))
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::OK);
+
+        assert_eq!(producer.keys(), vec!["sensor-1", "sensor-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_each_record() {
+        let (app, producer) = server();
+        let request = Request::post("/telemetry/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"[
+                    {"device_id": "sensor-1", "metrics": {"temperature": 21.5}},
+                    {"device_id": "sensor-2", "metrics": {}},
+                    {"metrics": {"temperature": 21.5}}
+                ]"#,
+            ))
+            .unwrap();
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (body["succeeded"].clone(), body["failed"].clone()),
+            (1.into(), 2.into())
+        );
+        let statuses: Vec<_> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].as_u64().unwrap())
+            .collect();
+        assert_eq!(statuses, vec![200, 400, 400]);
+        assert_eq!(producer.keys(), vec!["sensor-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_budget_is_reserved_before_the_body_is_read() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with("batch_memory_budget_bytes = 1000", Arc::clone(&producer)).app;
+        let batch = |device_id: &str, len: usize| {
+            let records = format!(
+                r#"[{{"device_id": "{}", "metrics": {{"temperature": 21.5}}}}"#,
+                device_id
+            );
+            format!("{}{}]", records, " ".repeat(len - records.len() - 1))
+        };
+
+        // An upload that has declared 800 bytes, of which only some have come
+        let slow = batch("sensor-1", 800);
+        let (chunks, body) =
+            tokio::sync::mpsc::channel::<Result<String, std::convert::Infallible>>(1);
+        let request = Request::post("/telemetry/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, slow.len())
+            .body(Body::from_stream(
+                tokio_stream::wrappers::ReceiverStream::new(body),
+            ))
+            .unwrap();
+        let response = {
+            let app = app.clone();
+            tokio::spawn(async move { send(&app, request).await })
+        };
+        chunks.send(Ok(slow[..100].to_string())).await.unwrap();
+        // Once the first chunk has been taken, the body is being read
+        let rest = chunks.reserve().await.unwrap();
+
+        let quick = |body: String| {
+            Request::post("/telemetry/batch")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let (status, _) = send(&app, quick(batch("sensor-2", 300))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        rest.send(Ok(slow[100..].to_string()));
+        drop(chunks);
+        let (status, _) = response.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, quick(batch("sensor-2", 300))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(producer.keys(), vec!["sensor-1", "sensor-2"]);
+
+        // Declared larger than the whole budget: refused without reading it
+        let (status, _) = send(&app, quick(batch("sensor-3", 1200))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_batch_is_inflated_within_the_budget() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with("batch_memory_budget_bytes = 1000", Arc::clone(&producer)).app;
+        let gzip = |body: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let request = |body: Body| {
+            Request::post("/telemetry/batch")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(body)
+                .unwrap()
+        };
+
+        let records = br#"[{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}]"#;
+        let (status, _) = send(&app, request(Body::from(gzip(records)))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(producer.keys(), vec!["sensor-1"]);
+
+        // Inflates past what the budget could hold
+        let bomb = gzip(&vec![b' '; 64 * 1024]);
+        assert!(bomb.len() < 1000);
+        let (status, _) = send(&app, request(Body::from(bomb))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // The budget is taken before any of the body is decoded: while one
+        // compressed upload is still coming in, another is refused outright
+        let (chunks, body) =
+            tokio::sync::mpsc::channel::<Result<Vec<u8>, std::convert::Infallible>>(1);
+        let slow = {
+            let app = app.clone();
+            let body = Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(body));
+            tokio::spawn(async move { send(&app, request(body)).await })
+        };
+        let compressed = gzip(records);
+        chunks.send(Ok(compressed[..10].to_vec())).await.unwrap();
+        let rest = chunks.reserve().await.unwrap();
+        let (status, _) = send(&app, request(Body::from(gzip(records)))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        rest.send(Ok(compressed[10..].to_vec()));
+        drop(chunks);
+        assert_eq!(slow.await.unwrap().0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_stream_publishes_lines_as_they_arrive() {
+        let (app, producer) = server();
+        let (chunks, body) =
+            tokio::sync::mpsc::channel::<Result<&'static str, std::convert::Infallible>>(4);
+        let request = Request::post("/telemetry/stream")
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from_stream(
+                tokio_stream::wrappers::ReceiverStream::new(body),
+            ))
+            .unwrap();
+        let response = tokio::spawn(async move { send(&app, request).await });
+
+        chunks
+            .send(Ok(
+                r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}
+{"device_id": "sens"#,
+            ))
+            .await
+            .unwrap();
+        // Published while the rest of the upload is still to come
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while producer.keys().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+
+        chunks
+            .send(Ok(r#"or-2", "metrics": {"temperature": 22.0}}
+not json
+
+{"device_id": "sensor-3", "metrics": {"temperature": 23.5}}"#))
+            .await
+            .unwrap();
+        drop(chunks);
+        let (status, body) = response.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            (&body["total"], &body["succeeded"], &body["failed"]),
+            (&4.into(), &3.into(), &1.into())
+        );
+        assert_eq!(body["failures"][0]["line"], 3);
+        assert_eq!(body["failures"][0]["index"], 2);
+        assert_eq!(producer.keys(), vec!["sensor-1", "sensor-2", "sensor-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_producer_failure_fails_the_request() {
+        let (app, _) = server_with(MockProducer {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            body["details"]
+                .as_str()
+                .unwrap()
+                .contains("broker unavailable"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spillover_holds_records_until_full() {
+        let server = build_with(
+            r#"
+            [spillover]
+            enabled = true
+            capacity = 1
+            drain_interval_ms = 3600000
+            "#,
+            Arc::new(MockProducer {
+                failing: AtomicBool::new(true),
+                ..Default::default()
+            }),
+        );
+        let body = r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#;
+        let (status, _) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            body["details"].as_str().unwrap().contains("buffer full"),
+            "{}",
+            body
+        );
+        assert!(request_metrics::render_registry(&server.state.registry)
+            .contains("rust_ingest_spillover_records 1"));
+    }
+
+    #[tokio::test]
+    async fn test_decommission_drops_records_held_for_the_device() {
+        let producer = Arc::new(MockProducer {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let server = build_with(
+            r#"
+            admin_token = "secret"
+            [delayed_delivery]
+            enabled = true
+            tick_ms = 10
+            [spillover]
+            enabled = true
+            drain_interval_ms = 10
+            [device_metrics]
+            enabled = true
+            "#,
+            producer.clone(),
+        );
+        // One record held by the spillover while the broker is down...
+        let (status, _) = post(
+            &server.app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.state.spillover.as_ref().unwrap().depth(), 1);
+        // ...and one held for delivery later
+        let deliver_at = chrono::Utc::now().timestamp_millis() + 200;
+        let (status, _) = post(
+            &server.app,
+            &format!(
+                r#"{{"device_id": "sensor-1", "deliver_at": {}, "metrics": {{"temperature": 21.6}}}}"#,
+                deliver_at
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let delay_queue = server.state.handler.delay_queue.as_ref().unwrap();
+        assert_eq!(delay_queue.held(), 1);
+
+        let request = Request::post("/admin/decommission/sensor-1")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&server.app, request).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        // Spillover, delay queue and device counts
+        assert_eq!(body["stores_cleared"], 3);
+        assert_eq!(*producer.tombstones.lock().unwrap(), vec!["sensor-1"]);
+        assert_eq!(server.state.spillover.as_ref().unwrap().depth(), 0);
+        assert_eq!(delay_queue.held(), 0);
+        assert!(!request_metrics::render_registry(&server.state.registry)
+            .contains("device_id=\"sensor-1\""));
+
+        // Neither record reaches the sink once it is back and the delivery
+        // time has passed
+        producer.failing.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(producer.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_delivery_reports_sink_failures() {
+        let producer = Arc::new(MockProducer {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let server = build_with(r#"delivery_mode = "sync""#, producer.clone());
+        let body = r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#;
+        let (status, _) = post(&server.app, body).await;
+        assert!(status.is_server_error(), "{}", status);
+        assert!(server.state.spillover.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_buffered_delivery_holds_records_and_answers_202() {
+        let producer = Arc::new(MockProducer {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let server = build_with(
+            r#"
+            delivery_mode = "async_buffered"
+            [spillover]
+            drain_interval_ms = 10
+            "#,
+            producer.clone(),
+        );
+        let body = r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#;
+        let (status, body) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["success"], true);
+        assert!(producer.sent.lock().unwrap().is_empty());
+        assert_eq!(server.state.spillover.as_ref().unwrap().depth(), 1);
+
+        // The drainer sends it once the broker is back
+        producer.failing.store(false, Ordering::SeqCst);
+        for _ in 0..100 {
+            if !producer.sent.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(producer.keys(), vec!["sensor-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_signed_requests_are_verified_before_decompression() {
+        let producer = Arc::new(MockProducer::default());
+        let server = build_with(
+            r#"
+            [request_signing]
+            enabled = true
+            secret = "s3cret"
+            "#,
+            producer.clone(),
+        );
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(br#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#)
+            .unwrap();
+        let body = encoder.finish().unwrap();
+        let signature: String = crate::hmac::HmacSha256::new(b"s3cret")
+            .mac(&body)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let request = |signature: &str| {
+            Request::post("/telemetry")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .header("x-signature", format!("sha256={}", signature))
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+
+        let (status, _) = send(&server.app, request(&signature)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = send(&server.app, request(&"0".repeat(64))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "signature does not match the body");
+
+        let (status, _) = post(
+            &server.app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(producer.keys(), vec!["sensor-1"]);
+
+        // Streamed uploads aren't buffered to be checked
+        let request = Request::post("/telemetry/stream")
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(
+                "{\"device_id\": \"sensor-2\", \"metrics\": {\"temperature\": 21.5}}\n",
+            ))
+            .unwrap();
+        assert_eq!(send(&server.app, request).await.0, StatusCode::OK);
+        assert_eq!(producer.keys(), vec!["sensor-1", "sensor-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_only_allowed_origins_get_cors_headers() {
+        let server = build_with(
+            r#"cors_allowed_origins = ["https://app.example.com"]"#,
+            Arc::new(MockProducer::default()),
+        );
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method("OPTIONS")
+                .uri("/telemetry")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = server
+            .app
+            .clone()
+            .oneshot(preflight("https://app.example.com"))
+            .await
+            .unwrap();
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+
+        let response = server
+            .app
+            .clone()
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // A simple request from an unlisted origin is still served, but the
+        // browser won't let the page read the response
+        let request = Request::post("/telemetry")
+            .header(header::ORIGIN, "https://evil.example.com")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+            ))
+            .unwrap();
+        let response = server.app.clone().oneshot(request).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_cors_is_off_unless_configured() {
+        let (app, _) = server();
+        let request = Request::get("/health")
+            .header(header::ORIGIN, "https://app.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let server = build_with("cors_permissive = true", Arc::new(MockProducer::default()));
+        let request = Request::get("/health")
+            .header(header::ORIGIN, "https://app.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = server.app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn test_ready_reports_the_last_broker_probe() {
+        let producer = Arc::new(MockProducer {
+            failing: AtomicBool::new(true),
+            ..Default::default()
+        });
+        let server = build_with(
+            r#"
+            [broker_probe]
+            enabled = true
+            interval_ms = 10
+            failures_before_down = 1
+            "#,
+            producer.clone(),
+        );
+        let ready = |app: Router| async move {
+            send(&app, Request::get("/ready").body(Body::empty()).unwrap()).await
+        };
+        let monitor = server.state.broker_monitor.clone().unwrap();
+        // The first probe replaces the "not probed yet" placeholder
+        let probed = |health: BrokerHealth| {
+            let monitor = Arc::clone(&monitor);
+            async move {
+                for _ in 0..100 {
+                    let error = monitor.last_error();
+                    if monitor.health() == health
+                        && error.is_none_or(|error| !error.contains("not probed"))
+                    {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("brokers never {}", health);
+            }
+        };
+
+        probed(BrokerHealth::Down).await;
+        let (status, body) = ready(server.app.clone()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["brokers"], "down");
+        assert!(
+            body["reason"].as_str().unwrap().contains("no brokers"),
+            "{}",
+            body
+        );
+
+        producer.failing.store(false, Ordering::SeqCst);
+        probed(BrokerHealth::Healthy).await;
+        let (status, body) = ready(server.app.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["ready"], true);
+        assert_eq!(body["brokers"], "healthy");
+    }
+
+    #[tokio::test]
+    async fn test_response_says_where_the_record_was_written() {
+        let (app, _producer) = server();
+        let body =
+            r#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#;
+        let (status, first) = post(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["partition"], 0);
+        assert_eq!(first["offset"], 0);
+
+        let (_, second) = post(&app, body).await;
+        assert_eq!(second["offset"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_last_messages_need_the_flag_and_the_admin_token() {
+        let get_last = |token: Option<&str>| {
+            let mut request = Request::get("/debug/last-messages");
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let (app, _) = server();
+        assert_eq!(
+            send(&app, get_last(Some("secret"))).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let app = build_with(
+            r#"
+            admin_token = "secret"
+            enable_debug_endpoints = true
+            debug_last_messages = 2
+            "#,
+            Arc::default(),
+        )
+        .app;
+        for device in ["sensor-1", "sensor-2", "sensor-3"] {
+            let body = format!(
+                r#"{{"device_id": "{}", "metrics": {{"temperature": 21.5}}}}"#,
+                device
+            );
+            assert_eq!(post(&app, &body).await.0, StatusCode::OK);
+        }
+        assert_eq!(send(&app, get_last(None)).await.0, StatusCode::UNAUTHORIZED);
+        let (status, body) = send(&app, get_last(Some("secret"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let devices: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["telemetry"]["device_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(devices, vec!["sensor-3", "sensor-2"]);
+        assert_eq!(body[0]["topic"], "telemetry");
+    }
+
+    // A dead-letter partition: records handed out in order from a position
+    // that a rewind moves back to the last commit
+    #[derive(Default)]
+    struct DeadLetterFixture {
+        records: Mutex<Vec<DeadLetterMessage>>,
+        position: AtomicUsize,
+        committed: AtomicUsize,
+        commits: AtomicUsize,
+        rewinds: AtomicUsize,
+    }
+
+    impl DeadLetterFixture {
+        fn uncommitted(&self) -> usize {
+            self.records.lock().unwrap().len() - self.committed.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl DeadLetterSource for DeadLetterFixture {
+        async fn fetch(&self, max: usize, _idle: Duration) -> Result<Vec<DeadLetterMessage>> {
+            let records = self.records.lock().unwrap();
+            let from = self.position.load(Ordering::SeqCst);
+            let to = records.len().min(from + max);
+            self.position.store(to, Ordering::SeqCst);
+            Ok(records[from..to].to_vec())
+        }
+
+        async fn commit(&self, replayed: &[DeadLetterMessage]) -> Result<()> {
+            let last = replayed.last().unwrap().offset;
+            let records = self.records.lock().unwrap();
+            let next = records.iter().position(|r| r.offset == last).unwrap() + 1;
+            self.committed.store(next, Ordering::SeqCst);
+            self.commits.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn rewind(&self) -> Result<()> {
+            self.position
+                .store(self.committed.load(Ordering::SeqCst), Ordering::SeqCst);
+            self.rewinds.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn replay_request(token: Option<&str>, uri: &str) -> Request<Body> {
+        let mut request = Request::post(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn dead_letter(offset: i64, battery_level: f64) -> DeadLetterMessage {
+        let payload = serde_json::json!({
+            "device_id": "sensor-1",
+            "reason": "battery_level out of range",
+            "rejected_at": 1_700_000_000_500_i64,
+            "record": {
+                "device_id": "sensor-1",
+                "ts": 1_700_000_000_000_i64 + offset,
+                "metrics": {"battery_level": battery_level},
+                "raw": "",
+                "tags": {},
+            },
+        });
+        DeadLetterMessage {
+            partition: 0,
+            offset,
+            payload: serde_json::to_vec(&payload).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_are_replayed_through_the_pipeline() {
+        // battery_level up to 200 is now allowed; 250 is still rejected
+        let cfg = config::from_toml(
+            r#"
+            listen_addr = "127.0.0.1:0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            admin_token = "secret"
+            dead_letter_topic = "telemetry.dead"
+
+            [dlq_replay]
+            enabled = true
+            max_records = 3
+
+            [metric_rules.battery_level]
+            min = 0.0
+            max = 200.0
+            reject_out_of_range = true
+            "#,
+        )
+        .unwrap();
+        let producer = Arc::new(MockProducer::default());
+        let fixture = Arc::new(DeadLetterFixture::default());
+        fixture.records.lock().unwrap().extend([
+            dead_letter(10, 150.0),
+            dead_letter(11, 250.0),
+            DeadLetterMessage {
+                partition: 0,
+                offset: 12,
+                payload: b"not a dead letter".to_vec(),
+            },
+            dead_letter(13, 120.0),
+        ]);
+        let app = build_server_with_dead_letters(
+            cfg,
+            Arc::clone(&producer) as Arc<dyn TelemetrySink>,
+            Some(Arc::clone(&fixture) as Arc<dyn DeadLetterSource>),
+        )
+        .unwrap()
+        .app;
+        let replay = replay_request;
+
+        let (status, _) = send(&app, replay(None, "/admin/replay")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(fixture.uncommitted(), 4);
+
+        // At most max_records per call, however many are asked for
+        let (status, body) = send(&app, replay(Some("secret"), "/admin/replay?limit=10")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["read"], 3);
+        assert_eq!(body["replayed"], 1);
+        assert_eq!(body["failed"], 1);
+        assert_eq!(body["unreadable"], 1);
+        assert_eq!(body["failures"][0]["offset"], 11);
+        assert_eq!(body["failures"][0]["device_id"], "sensor-1");
+        assert_eq!(fixture.commits.load(Ordering::SeqCst), 1);
+
+        // Published under a request id derived from the dead letter, with its
+        // original timestamp
+        {
+            let sent = producer.sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].0, "telemetry");
+            let replayed = Telemetry::decode(sent[0].2.as_slice()).unwrap();
+            assert_eq!(replayed.metrics["battery_level"], 150.0);
+            assert_eq!(replayed.ts, 1_700_000_000_010);
+        }
+        assert_eq!(
+            producer.request_ids.lock().unwrap()[0].as_deref(),
+            Some("dlq-replay-0-10")
+        );
+
+        let (_, body) = send(&app, replay(Some("secret"), "/admin/replay")).await;
+        assert_eq!(body["read"], 1);
+        assert_eq!(body["replayed"], 1);
+
+        // Nothing left: nothing to commit either
+        let (status, body) = send(&app, replay(Some("secret"), "/admin/replay")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["read"], 0);
+        assert_eq!(fixture.commits.load(Ordering::SeqCst), 2);
+        assert_eq!(fixture.uncommitted(), 0);
+        assert_eq!(fixture.rewinds.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_stops_at_a_failed_send() {
+        let cfg = config::from_toml(
+            r#"
+            listen_addr = "127.0.0.1:0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+            admin_token = "secret"
+            dead_letter_topic = "telemetry.dead"
+
+            [dlq_replay]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+        let producer = Arc::new(MockProducer::default());
+        let fixture = Arc::new(DeadLetterFixture::default());
+        fixture.records.lock().unwrap().extend([
+            dead_letter(10, 50.0),
+            dead_letter(11, 60.0),
+            dead_letter(12, 70.0),
+        ]);
+        let app = build_server_with_dead_letters(
+            cfg,
+            Arc::clone(&producer) as Arc<dyn TelemetrySink>,
+            Some(Arc::clone(&fixture) as Arc<dyn DeadLetterSource>),
+        )
+        .unwrap()
+        .app;
+
+        // Nothing is committed past the record that wasn't sent, and the
+        // consumer goes back to read it again
+        producer.failing.store(true, Ordering::SeqCst);
+        let (status, body) = send(&app, replay_request(Some("secret"), "/admin/replay")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["read"], 3);
+        assert_eq!(body["replayed"], 0);
+        assert_eq!(body["failed"], 0);
+        assert_eq!(body["pending"], 3);
+        assert_eq!(body["failures"][0]["offset"], 10);
+        assert_eq!(fixture.commits.load(Ordering::SeqCst), 0);
+        assert_eq!(fixture.rewinds.load(Ordering::SeqCst), 1);
+        assert_eq!(fixture.uncommitted(), 3);
+
+        producer.failing.store(false, Ordering::SeqCst);
+        let (_, body) = send(&app, replay_request(Some("secret"), "/admin/replay")).await;
+        assert_eq!(body["read"], 3);
+        assert_eq!(body["replayed"], 3);
+        assert_eq!(body["pending"], 0);
+        assert_eq!(fixture.uncommitted(), 0);
+        assert_eq!(
+            *producer.request_ids.lock().unwrap(),
+            ["dlq-replay-0-10", "dlq-replay-0-11", "dlq-replay-0-12"]
+                .map(|id| Some(id.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_declared_units_are_converted_before_publish() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"
+            [unit_conversion]
+            enabled = true
+            rules = [{ metric = "temperature", from = "fahrenheit", to = "celsius" }]
+            "#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let units =
+            |unit: &str| {
+                Request::post("/telemetry")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(unit_conversion::UNITS_HEADER, format!("temperature={}", unit))
+                .body(Body::from(
+                    r#"{"device_id": "sensor-1", "metrics": {"temperature": 212, "humidity": 40}}"#,
+                ))
+                .unwrap()
+            };
+        assert_eq!(send(&app, units("fahrenheit")).await.0, StatusCode::OK);
+        let (status, body) = send(&app, units("rankine")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(
+            body["details"].as_str().unwrap().contains("rankine"),
+            "{}",
+            body
+        );
+
+        let sent = producer.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let telemetry = Telemetry::decode(sent[0].2.as_slice()).unwrap();
+        assert!((telemetry.metrics["temperature"] - 100.0).abs() < 1e-9);
+        assert_eq!(telemetry.metrics["humidity"], 40.0);
+        assert_eq!(
+            unit_conversion::original_units(&telemetry),
+            Some("temperature=fahrenheit")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resent_reading_is_not_republished() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"
+            [resend_dedup]
+            enabled = true
+            window_secs = 60
+            capacity = 100
+            "#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let body =
+            r#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#;
+        let (status, first) = post(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(first.get("duplicate").is_none(), "{}", first);
+
+        let (status, resend) = post(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resend["duplicate"], true);
+        assert_eq!(producer.sent.lock().unwrap().len(), 1);
+
+        // A new reading from the device still goes out
+        let next =
+            r#"{"device_id": "sensor-1", "ts": 1700000001000, "metrics": {"temperature": 21.5}}"#;
+        let (_, response) = post(&app, next).await;
+        assert!(response.get("duplicate").is_none());
+        assert_eq!(producer.sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_csv_rows_are_published_and_bad_ones_reported() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"
+            [csv]
+            enabled = true
+            columns = ["device_id", "ts", "temperature", "humidity"]
+            "#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let upload = |content_type: &str, body: &str| {
+            Request::post("/telemetry/csv")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (status, body) = send(
+            &app,
+            upload(
+                "text/csv",
+                "sensor-1,1700000000000,21.5,40\nsensor-2,soon,21.5,40\nsensor-3,1700000000000,22.0,\n",
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["failed"], 1);
+        assert_eq!(body["results"][1]["line"], 2);
+        assert_eq!(body["results"][1]["status"], 400);
+        assert_eq!(producer.keys(), vec!["sensor-1", "sensor-3"]);
+
+        // The client says the first line names the columns
+        let (status, body) = send(
+            &app,
+            upload(
+                "text/csv; header=present",
+                "humidity,device_id\n45,sensor-4\n",
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["succeeded"], 1, "{}", body);
+        assert_eq!(body["results"][0]["line"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_show_the_busiest_devices() {
+        let server = build_with(
+            r#"
+            [device_metrics]
+            enabled = true
+            top_n = 1
+            "#,
+            Arc::new(MockProducer::default()),
+        );
+        for device in ["pump-1", "pump-1", "pump-2", "pump-3"] {
+            let body = format!(
+                r#"{{"device_id": "{}", "metrics": {{"flow": 1.0}}}}"#,
+                device
+            );
+            let (status, _) = post(&server.app, &body).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let metrics = request_metrics::render_registry(&server.state.registry);
+        assert!(metrics.contains("rust_ingest_device_records{device_id=\"pump-1\"} 2\n"));
+        assert!(metrics.contains("rust_ingest_device_records{device_id=\"other\"} 2\n"));
+        assert!(!metrics.contains("pump-2"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_outside_the_allowlist_are_rejected() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"allowed_metrics = ["temperature", "humidity"]"#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5, "humidity": 40}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temprature": 21.5, "humidity": 40}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["details"], "Metrics not in the allowlist: temprature");
+        assert_eq!(producer.keys().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_in_seconds_or_rfc3339_are_normalized() {
+        let (app, producer) = server();
+        for ts in ["1700000000", "1700000000000", r#""2023-11-14T22:13:20Z""#] {
+            let body = format!(
+                r#"{{"device_id": "sensor-1", "ts": {}, "metrics": {{"temperature": 21.5}}}}"#,
+                ts
+            );
+            let (status, response) = post(&app, &body).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", ts, response);
+        }
+        for (_, _, payload) in producer.sent.lock().unwrap().iter() {
+            let telemetry = Telemetry::decode(payload.as_slice()).unwrap();
+            assert_eq!(telemetry.ts, 1700000000000);
+        }
+
+        let (status, response) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "ts": "last tuesday", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response["error"], "invalid timestamp");
+        assert!(
+            response["details"]
+                .as_str()
+                .unwrap()
+                .contains(timestamps::EXPECTED),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_handshake_is_refused_during_maintenance() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"
+            [websocket]
+            enabled = true
+
+            [maintenance]
+            enabled = true
+            windows = [{ start = "2000-01-01T00:00:00Z", end = "2999-01-01T00:00:00Z" }]
+            "#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let handshake = Request::get("/telemetry/ws")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&app, handshake).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body["error"],
+            "ingestion is paused for scheduled maintenance"
+        );
+    }
+
+    // Holds every publish until released, saying when one has started
+    #[derive(Default)]
+    struct GatedSink {
+        started: tokio::sync::Notify,
+        release: tokio::sync::Notify,
+        published: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for GatedSink {
+        fn name(&self) -> &'static str {
+            "gated"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            self.started.notify_one();
+            self.release.notified().await;
+            self.published.lock().unwrap().push(record.key.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_outlives_a_disconnected_client() {
+        let cfg = config::from_toml(
+            r#"
+            listen_addr = "127.0.0.1:0"
+            kafka_brokers = "localhost:9092"
+            kafka_topic = "telemetry"
+
+            [concurrency_limit]
+            enabled = true
+            max_concurrent = 1
+            acquire_timeout_ms = 10
+            "#,
+        )
+        .unwrap();
+        let sink = Arc::new(GatedSink::default());
+        let server = build_server(cfg, sink.clone()).unwrap();
+        let body = r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#;
+
+        // A client disconnecting mid-send: the server drops its handler
+        let client = {
+            let app = server.app.clone();
+            tokio::spawn(async move { post(&app, body).await })
+        };
+        sink.started.notified().await;
+        client.abort();
+        assert!(matches!(client.await, Err(e) if e.is_cancelled()));
+
+        // The record still holds its slot and counts as in flight
+        assert_eq!(server.state.in_flight.load(Ordering::SeqCst), 1);
+        assert_eq!(server.state.requests_in_flight.current(), 1);
+        let (status, _) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // and is sent to the end, then lets go of them
+        sink.release.notify_one();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.state.in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the record was abandoned with its client");
+        assert_eq!(*sink.published.lock().unwrap(), vec!["sensor-1"]);
+        assert_eq!(server.state.requests_in_flight.current(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_record_counts_as_in_flight_until_sent() {
+        let sink = Arc::new(GatedSink::default());
+        let server = build_server(
+            config::from_toml(
+                r#"
+                listen_addr = "127.0.0.1:0"
+                kafka_brokers = "localhost:9092"
+                kafka_topic = "telemetry"
+                "#,
+            )
+            .unwrap(),
+            sink.clone(),
+        )
+        .unwrap();
+        let request = Request::post("/telemetry")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(crate::ack::ACK_HEADER, "none")
+            .body(Body::from(
+                r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+            ))
+            .unwrap();
+        let (status, _) = send(&server.app, request).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // Answered, but still being sent in the background
+        sink.started.notified().await;
+        assert_eq!(server.state.requests_in_flight.current(), 1);
+
+        sink.release.notify_one();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.state.requests_in_flight.current() > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the record was still counted after it was sent");
+        assert_eq!(*sink.published.lock().unwrap(), vec!["sensor-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_requests_are_released_on_error() {
+        let server = build_with("", Arc::new(MockProducer::default()));
+        let (status, _) = post(
+            &server.app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post(&server.app, r#"{"device_id": "", "metrics": {"t": 1}}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(server.state.requests_in_flight.current(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_requests_past_the_concurrency_limit_get_503() {
+        let producer = Arc::new(MockProducer::default());
+        let server = build_with(
+            r#"
+            [concurrency_limit]
+            enabled = true
+            max_concurrent = 1
+            acquire_timeout_ms = 20
+            "#,
+            Arc::clone(&producer),
+        );
+        let body =
+            r#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#;
+        let limit = server.state.concurrency_limit.as_ref().unwrap();
+
+        // Another request holds the only slot
+        let held = limit.acquire().await.unwrap();
+        let (status, response) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response["error"],
+            "too many requests in progress, retry later"
+        );
+        assert!(request_metrics::render_registry(&server.state.registry)
+            .contains("rust_ingest_concurrency_rejected_total 1\n"));
+
+        drop(held);
+        let (status, _) = post(&server.app, body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(producer.keys().len(), 1);
+        assert!(request_metrics::render_registry(&server.state.registry)
+            .contains("rust_ingest_concurrency_in_use 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_topic_header_picks_an_allowed_topic() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            r#"header_topics = ["telemetry.tenant-test"]"#,
+            Arc::clone(&producer),
+        )
+        .app;
+        let body =
+            r#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#;
+        let with_topic = |topic: &str| {
+            Request::post("/telemetry")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(TOPIC_HEADER, topic)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let (status, _) = send(&app, with_topic("telemetry.tenant-test")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, response) = send(&app, with_topic("telemetry.anything")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(
+            response["error"]
+                .as_str()
+                .unwrap()
+                .contains("telemetry.anything"),
+            "{}",
+            response
+        );
+        let (status, _) = post(&app, body).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let sent = producer.sent.lock().unwrap();
+        let topics: Vec<&str> = sent.iter().map(|(topic, _, _)| topic.as_str()).collect();
+        assert_eq!(topics, vec!["telemetry.tenant-test", "telemetry"]);
+    }
+
+    #[tokio::test]
+    async fn test_tags_are_published_within_their_limits() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with("[tag_limits]\nmax_value_bytes = 8", Arc::clone(&producer)).app;
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}, "tags": {"firmware": "2.4.1", "region": "eu-west"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let telemetry = Telemetry::decode(&producer.sent.lock().unwrap()[0].2[..]).unwrap();
+        assert_eq!(telemetry.tags["firmware"], "2.4.1");
+        assert_eq!(telemetry.tags["region"], "eu-west");
+
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}, "tags": {"region": "eu-west-1a"}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body["error"],
+            "tag region value of 10 bytes is longer than the 8 byte limit"
+        );
+        assert_eq!(producer.sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_messages_are_rejected_before_the_sink() {
+        let producer = Arc::new(MockProducer::default());
+        let app = build_with(
+            "max_metrics_per_message = 2\nmax_message_bytes = 64",
+            Arc::clone(&producer),
+        )
+        .app;
+        let (status, body) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"a": 1, "b": 2, "c": 3}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(
+            body["error"].as_str().unwrap().contains("3 metric values"),
+            "{}",
+            body
+        );
+
+        let long_id = "d".repeat(100);
+        let (status, body) = post(
+            &app,
+            &format!(r#"{{"device_id": "{}", "metrics": {{"a": 1}}}}"#, long_id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .contains("64 byte message limit"),
+            "{}",
+            body
+        );
+        assert!(producer.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_without_publishing() {
+        let (app, producer) = server();
+        let validate = |body: &str| {
+            Request::post("/telemetry/validate")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let (status, report) = send(
+            &app,
+            validate(r#"{"device_id": "sensor-1", "metrics": {"humidity": 140}}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["valid"], true);
+        assert_eq!(report["topic"], "telemetry");
+        assert_eq!(report["warnings"][0]["metric"], "humidity");
+
+        // Every failure is reported, not only the first
+        let (status, report) = send(
+            &app,
+            validate(
+                r#"{"device_id": "sensor-1", "metrics": {"battery_level": 140},
+                    "samples": [{"ts": 1700000000000, "metrics": {"battery_level": -5}}]}"#,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["errors"].as_array().unwrap().len(), 2, "{}", report);
+
+        let (status, report) = send(&app, validate(r#"{"device_id": "sensor-1"}"#)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(report["errors"][0], "metrics cannot be empty");
+
+        assert!(producer.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_and_passed_to_the_sink() {
+        let (app, producer) = server();
+        let request = |request_id: Option<&str>| {
+            let mut request = Request::post("/telemetry")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+                ))
+                .unwrap();
+            if let Some(id) = request_id {
+                request
+                    .headers_mut()
+                    .insert(REQUEST_ID_HEADER, id.parse().unwrap());
+            }
+            request
+        };
+        let echoed = |response: &Response| {
+            response.headers()[REQUEST_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("trace-42")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(echoed(&response), "trace-42");
+
+        // Made up when the client sent none
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        let generated = echoed(&response);
+        assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{}", generated);
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("two words")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            *producer.request_ids.lock().unwrap(),
+            vec![Some("trace-42".to_string()), Some(generated)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_bodies_are_rejected() {
+        let app = build_with("max_body_bytes = 256", Arc::default()).app;
+        let padding = "x".repeat(256);
+        let (status, _) = post(
+            &app,
+            &format!(
+                r#"{{"device_id": "sensor-1", "metrics": {{"temperature": 21.5}}, "tags": {{"note": "{}"}}}}"#,
+                padding
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let batch = Request::post("/telemetry/batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                r#"{{"records": [{{"device_id": "{}", "metrics": {{"temperature": 21.5}}}}]}}"#,
+                padding
+            )))
+            .unwrap();
+        assert_eq!(send(&app, batch).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Under the limit still goes through
+        let (status, _) = post(
+            &app,
+            r#"{"device_id": "sensor-1", "metrics": {"temperature": 21.5}}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    async fn get_health(addr: std::net::SocketAddr) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_every_listen_address_is_served() {
+        let listeners = bind_all(&["127.0.0.1:0", "127.0.0.1:0"]).await.unwrap();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(async move {
+            let stopped = async {
+                let _ = stopped.await;
+            };
+            build(Arc::default())
+                .serve(listeners, stopped, &ShutdownConfig::default())
+                .await
+        });
+
+        for addr in addrs {
+            let response = get_health(addr).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        }
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure_names_the_address() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let err = bind_all(&["127.0.0.1:0", &addr]).await.unwrap_err();
+        assert!(err.to_string().contains(&addr), "{}", err);
+
+        assert!(bind_all(&[]).await.is_err());
+    }
 }
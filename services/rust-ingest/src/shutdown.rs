@@ -0,0 +1,304 @@
+use crate::{connections::ConnectionTracker, delayed_delivery::DelayQueue, sink::TelemetrySink};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShutdownConfig {
+    // Also write the shutdown report here as JSON, for deploy tooling
+    #[serde(default)]
+    pub report_path: Option<String>,
+    // How long open requests get to finish once shutdown starts, before the
+    // final flush. Keep it under the orchestrator's kill grace period.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    // How often the number of POST /telemetry requests in progress is
+    // logged, so the last line before a crash says what was lost; 0 to only
+    // log it at shutdown
+    #[serde(default = "default_in_flight_log_interval_secs")]
+    pub in_flight_log_interval_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            report_path: None,
+            drain_timeout_secs: default_drain_timeout_secs(),
+            in_flight_log_interval_secs: default_in_flight_log_interval_secs(),
+        }
+    }
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_in_flight_log_interval_secs() -> u64 {
+    60
+}
+
+impl ShutdownConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+// POST /telemetry requests being processed right now
+#[derive(Default)]
+pub struct InFlightRequests(Arc<AtomicUsize>);
+
+impl InFlightRequests {
+    // Counts the request until the guard is dropped, whether it succeeded,
+    // failed or was cancelled
+    pub fn enter(&self) -> InFlightRequest {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        InFlightRequest(Arc::clone(&self.0))
+    }
+
+    pub fn current(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn spawn_logger(&self, interval: Duration) {
+        let counter = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                info!(
+                    requests_in_flight = counter.load(Ordering::Relaxed),
+                    "Telemetry requests in flight"
+                );
+            }
+        });
+    }
+}
+
+pub struct InFlightRequest(Arc<AtomicUsize>);
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// What happened during shutdown, so a deploy can tell whether it lost data
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ShutdownReport {
+    // POST /telemetry requests in progress when shutdown started
+    pub requests_in_flight: usize,
+    // Requests in flight when shutdown started that completed
+    pub requests_drained: usize,
+    // Records still buffered in the sink that the final flush wrote out
+    pub records_flushed: usize,
+    // Nothing spills to disk yet, so this stays 0 until something does
+    pub records_spilled: usize,
+    // Records buffered in the sink or held for delayed delivery that were
+    // never written
+    pub records_lost: usize,
+    // Connections still open when the drain timed out
+    pub connections_force_closed: usize,
+    pub duration_ms: u64,
+}
+
+impl ShutdownReport {
+    // Final log line, plus the JSON file when configured
+    pub fn emit(&self, config: &ShutdownConfig) -> Result<()> {
+        info!(
+            requests_in_flight = self.requests_in_flight,
+            requests_drained = self.requests_drained,
+            records_flushed = self.records_flushed,
+            records_spilled = self.records_spilled,
+            records_lost = self.records_lost,
+            connections_force_closed = self.connections_force_closed,
+            duration_ms = self.duration_ms,
+            "Shutdown report"
+        );
+        if let Some(path) = &config.report_path {
+            std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        }
+        Ok(())
+    }
+}
+
+// Resolves on SIGINT, or SIGTERM where there is one
+pub async fn signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+// What a shutdown waits on and flushes once the listener has stopped accepting
+pub struct Drain<'a> {
+    pub connections: &'a ConnectionTracker,
+    // Records between receipt and publish, including unacknowledged ones
+    // whose connection has already gone
+    pub in_flight: &'a AtomicUsize,
+    pub requests: &'a InFlightRequests,
+    pub sink: &'a dyn TelemetrySink,
+    pub delay_queue: Option<&'a DelayQueue>,
+}
+
+impl Drain<'_> {
+    // Asks every connection to close after its current request, waits up to
+    // `timeout` for them and for records still in the pipeline, then flushes
+    // the sink
+    pub async fn run(&self, timeout: Duration) -> ShutdownReport {
+        let started = Instant::now();
+        let requests_in_flight = self.requests.current();
+        info!(requests_in_flight, "Shutting down; draining open requests");
+        let requests_at_start = self.connections.in_flight_requests();
+        self.connections.close_all();
+
+        let deadline = started + timeout;
+        while (self.connections.open() > 0 || self.in_flight.load(Ordering::Relaxed) > 0)
+            && Instant::now() < deadline
+        {
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+        let requests_drained =
+            requests_at_start.saturating_sub(self.connections.in_flight_requests());
+        let connections_force_closed = self.connections.open();
+
+        let buffered = self.sink.pending();
+        if let Err(e) = self.sink.flush().await {
+            warn!("Final flush of {} sink failed: {:?}", self.sink.name(), e);
+        }
+        let unflushed = self.sink.pending();
+
+        ShutdownReport {
+            requests_in_flight,
+            requests_drained,
+            records_flushed: buffered.saturating_sub(unflushed),
+            records_spilled: 0,
+            records_lost: unflushed + self.delay_queue.map(DelayQueue::held).unwrap_or(0),
+            connections_force_closed,
+            duration_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::SinkRecord;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    // Buffers records until flushed; a flush writes out at most `flushable`
+    struct BufferingSink {
+        buffered: Mutex<usize>,
+        flushable: usize,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for BufferingSink {
+        fn name(&self) -> &'static str {
+            "buffering"
+        }
+
+        async fn publish(&self, _record: SinkRecord<'_>) -> Result<()> {
+            *self.buffered.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn pending(&self) -> usize {
+            *self.buffered.lock().unwrap()
+        }
+
+        async fn flush(&self) -> Result<()> {
+            let mut buffered = self.buffered.lock().unwrap();
+            *buffered -= (*buffered).min(self.flushable);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report_covers_a_simulated_shutdown() {
+        let connections = Arc::new(ConnectionTracker::default());
+        let in_flight = AtomicUsize::new(0);
+        let requests = InFlightRequests::default();
+        let sink = BufferingSink {
+            buffered: Mutex::new(5),
+            flushable: 3,
+        };
+
+        // One connection finishes its request once asked to close, the
+        // other never closes
+        let finishing = connections.register();
+        let request = finishing.start_request();
+        let stuck = connections.register();
+        let _stuck_request = stuck.start_request();
+        let _counted = requests.enter();
+        let closer = tokio::spawn(async move {
+            finishing.closed().await;
+            drop(request);
+            drop(finishing);
+        });
+
+        let report = Drain {
+            connections: &connections,
+            in_flight: &in_flight,
+            requests: &requests,
+            sink: &sink,
+            delay_queue: None,
+        }
+        .run(Duration::from_millis(200))
+        .await;
+        closer.await.unwrap();
+
+        assert_eq!(report.requests_in_flight, 1);
+        assert_eq!(report.requests_drained, 1);
+        assert_eq!(report.connections_force_closed, 1);
+        assert_eq!(report.records_flushed, 3);
+        assert_eq!(report.records_spilled, 0);
+        assert_eq!(report.records_lost, 2);
+        assert!(report.duration_ms >= 200);
+
+        let path =
+            std::env::temp_dir().join(format!("shutdown-report-{}.json", std::process::id()));
+        report
+            .emit(&ShutdownConfig {
+                report_path: Some(path.to_string_lossy().into_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written["records_flushed"], 3);
+        assert_eq!(written["connections_force_closed"], 1);
+    }
+}
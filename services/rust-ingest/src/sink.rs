@@ -0,0 +1,210 @@
+use crate::{
+    ack::AckMode, config::Config, kafka, line_sink::LineSink, mqtt_sink::MqttSink,
+    parquet_sink::ParquetSink, priority::Priority, proto::telemetry::Telemetry,
+    receipts::ReceiptTicket, redis_sink::RedisStreamSink,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::Registry;
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tracing::info;
+
+// A single record handed to a sink. Byte-oriented sinks (Kafka) use the
+// encoded payload, columnar sinks work from the decoded telemetry.
+#[derive(Clone, Copy)]
+pub struct SinkRecord<'a> {
+    pub topic: &'a str,
+    pub key: &'a str,
+    pub payload: &'a [u8],
+    pub telemetry: &'a Telemetry,
+    // TTL deadline in unix millis, for sinks that can pass it on to consumers
+    pub expires_at: Option<i64>,
+    // Sinks without a notion of acknowledgment levels ignore this
+    pub ack: AckMode,
+    // Lane the priority dispatcher sends the record through
+    pub priority: Priority,
+    // Where to record the outcome, for sinks that learn the delivered
+    // position or only learn the outcome later
+    pub receipt: Option<&'a ReceiptTicket>,
+    // Correlation id of the request the record came in with
+    pub request_id: Option<&'a str>,
+    // Where to report the written position back to the caller, for sinks
+    // that learn it by the time publish returns
+    pub position: Option<&'a OnceLock<Position>>,
+}
+
+// Where a sink wrote a record: the Kafka partition, and the offset when the
+// broker reported one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub partition: i32,
+    pub offset: Option<i64>,
+}
+
+#[async_trait]
+pub trait TelemetrySink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()>;
+
+    // Publish a side-stream message (e.g. data-quality events) that is not a
+    // telemetry record. Sinks that only store telemetry ignore these.
+    async fn publish_raw(&self, _topic: &str, _key: &str, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    // A null-value record telling compacted-topic consumers to drop `key`.
+    // Sinks without compaction ignore these.
+    async fn publish_tombstone(&self, _topic: &str, _key: &str) -> Result<()> {
+        Ok(())
+    }
+
+    // Push out anything buffered; called on a timer and before exit
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Records accepted but not yet written out, for the shutdown report
+    fn pending(&self) -> usize {
+        0
+    }
+
+    // Adds the sink's own metrics to the registry behind /metrics
+    fn register_metrics(&self, _registry: &Registry) -> prometheus::Result<()> {
+        Ok(())
+    }
+
+    // Drops records held back for a device that is being decommissioned,
+    // and whatever else the sink keeps for it; returns how many stores held
+    // something
+    fn forget_device(&self, _device_id: &str) -> usize {
+        0
+    }
+
+    // Whether the backend can be reached right now, for the readiness
+    // probe. Sinks that don't check are taken to be ready.
+    async fn check_ready(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Publishes every record to all inner sinks, failing if any of them fails
+pub struct FanoutSink {
+    sinks: Vec<Arc<dyn TelemetrySink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Arc<dyn TelemetrySink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for FanoutSink {
+    fn name(&self) -> &'static str {
+        "fanout"
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        for sink in &self.sinks {
+            sink.publish(SinkRecord {
+                topic: record.topic,
+                key: record.key,
+                payload: record.payload,
+                telemetry: record.telemetry,
+                expires_at: record.expires_at,
+                ack: record.ack,
+                priority: record.priority,
+                receipt: record.receipt,
+                request_id: record.request_id,
+                position: record.position,
+            })
+            .await
+            .map_err(|e| e.context(format!("{} sink failed", sink.name())))?;
+        }
+        Ok(())
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        for sink in &self.sinks {
+            sink.publish_raw(topic, key, payload)
+                .await
+                .map_err(|e| e.context(format!("{} sink failed", sink.name())))?;
+        }
+        Ok(())
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        for sink in &self.sinks {
+            sink.publish_tombstone(topic, key)
+                .await
+                .map_err(|e| e.context(format!("{} sink failed", sink.name())))?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for sink in &self.sinks {
+            sink.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn pending(&self) -> usize {
+        self.sinks.iter().map(|sink| sink.pending()).sum()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.sinks
+            .iter()
+            .map(|sink| sink.forget_device(device_id))
+            .sum()
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        for sink in &self.sinks {
+            sink.register_metrics(registry)?;
+        }
+        Ok(())
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        for sink in &self.sinks {
+            sink.check_ready(timeout)
+                .await
+                .map_err(|e| e.context(format!("{} sink is not ready", sink.name())))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn build_sink(cfg: &Config) -> Result<Arc<dyn TelemetrySink>> {
+    let mut sinks: Vec<Arc<dyn TelemetrySink>> = Vec::new();
+    for name in &cfg.sinks {
+        let sink: Arc<dyn TelemetrySink> = match name.as_str() {
+            "kafka" => Arc::new(kafka::KafkaSink::new(
+                &cfg.kafka_brokers,
+                &cfg.kafka_producer,
+                &cfg.encoding,
+                &cfg.node_id(),
+            )?),
+            "parquet" => ParquetSink::start(cfg.parquet.clone())?,
+            "redis" => Arc::new(RedisStreamSink::new(cfg.redis.clone())?),
+            "mqtt" => Arc::new(MqttSink::new(cfg.mqtt.clone(), cfg.device_types.clone())?),
+            "stdout" => Arc::new(LineSink::stdout()),
+            "file" => Arc::new(LineSink::file(&cfg.file_sink)?),
+            other => return Err(anyhow::anyhow!("Unknown sink '{}' in sinks", other)),
+        };
+        info!("Enabled {} sink", sink.name());
+        sinks.push(sink);
+    }
+
+    match sinks.len() {
+        0 => Err(anyhow::anyhow!("At least one sink must be configured")),
+        1 => Ok(sinks.remove(0)),
+        _ => Ok(Arc::new(FanoutSink::new(sinks))),
+    }
+}
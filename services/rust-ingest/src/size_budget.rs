@@ -0,0 +1,381 @@
+use crate::proto::telemetry::Telemetry;
+use prost::Message;
+use serde::Deserialize;
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SizeBudgetConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Budget for devices whose type has no entry in `device_types`
+    #[serde(default = "default_max_bytes")]
+    pub default_max_bytes: usize,
+    // device type -> largest record it may send, in encoded bytes
+    #[serde(default)]
+    pub device_types: HashMap<String, usize>,
+}
+
+impl Default for SizeBudgetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_max_bytes: default_max_bytes(),
+            device_types: HashMap::new(),
+        }
+    }
+}
+
+fn default_max_bytes() -> usize {
+    64 * 1024
+}
+
+// Bounds on the tags a client may attach to a record, so free-form
+// metadata can't be used to smuggle bulk data through or blow up the
+// cardinality of downstream indexes
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagLimitsConfig {
+    #[serde(default = "default_max_tags")]
+    pub max_tags: usize,
+    #[serde(default = "default_max_key_bytes")]
+    pub max_key_bytes: usize,
+    #[serde(default = "default_max_value_bytes")]
+    pub max_value_bytes: usize,
+}
+
+impl Default for TagLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_tags: default_max_tags(),
+            max_key_bytes: default_max_key_bytes(),
+            max_value_bytes: default_max_value_bytes(),
+        }
+    }
+}
+
+fn default_max_tags() -> usize {
+    32
+}
+
+fn default_max_key_bytes() -> usize {
+    64
+}
+
+fn default_max_value_bytes() -> usize {
+    256
+}
+
+// Returned by the handler for a record larger than its device type allows
+#[derive(Debug)]
+pub struct OverBudget {
+    pub size: usize,
+    pub limit: usize,
+    // None when the global default applied
+    pub device_type: Option<String>,
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.device_type {
+            Some(device_type) => write!(
+                f,
+                "record of {} bytes exceeds the {} byte budget for device type {}",
+                self.size, self.limit, device_type
+            ),
+            None => write!(
+                f,
+                "record of {} bytes exceeds the default {} byte budget",
+                self.size, self.limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OverBudget {}
+
+// Returned by the handler for a record past one of the limits every device
+// is held to, whatever its size budget
+#[derive(Debug)]
+pub enum MessageLimit {
+    // More metric values than max_metrics_per_message
+    Metrics {
+        count: usize,
+        limit: usize,
+    },
+    // Encoded for the sink, larger than max_message_bytes
+    Encoded {
+        size: usize,
+        limit: usize,
+    },
+    // More tags than tag_limits.max_tags
+    Tags {
+        count: usize,
+        limit: usize,
+    },
+    // A tag key longer than tag_limits.max_key_bytes; the key itself is left
+    // out of the message, it could be anything up to the body limit
+    TagKey {
+        size: usize,
+        limit: usize,
+    },
+    // A tag value longer than tag_limits.max_value_bytes
+    TagValue {
+        key: String,
+        size: usize,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for MessageLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Metrics { count, limit } => write!(
+                f,
+                "record has {} metric values, more than the {} allowed per message",
+                count, limit
+            ),
+            Self::Encoded { size, limit } => write!(
+                f,
+                "record encodes to {} bytes, more than the {} byte message limit",
+                size, limit
+            ),
+            Self::Tags { count, limit } => write!(
+                f,
+                "record has {} tags, more than the {} allowed",
+                count, limit
+            ),
+            Self::TagKey { size, limit } => write!(
+                f,
+                "tag key of {} bytes is longer than the {} byte limit",
+                size, limit
+            ),
+            Self::TagValue { key, size, limit } => write!(
+                f,
+                "tag {} value of {} bytes is longer than the {} byte limit",
+                key, size, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MessageLimit {}
+
+// Values of every kind, samples included, as each ends up in the message
+pub fn metric_count(telemetry: &Telemetry) -> usize {
+    telemetry.metrics.len()
+        + telemetry.flag_metrics.len()
+        + telemetry.text_metrics.len()
+        + telemetry
+            .samples
+            .iter()
+            .map(|sample| sample.metrics.len())
+            .sum::<usize>()
+}
+
+pub fn check_metric_count(telemetry: &Telemetry, limit: Option<usize>) -> Result<(), MessageLimit> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let count = metric_count(telemetry);
+    if count > limit {
+        return Err(MessageLimit::Metrics { count, limit });
+    }
+    Ok(())
+}
+
+pub fn check_tags(telemetry: &Telemetry, limits: &TagLimitsConfig) -> Result<(), MessageLimit> {
+    let count = telemetry.tags.len();
+    if count > limits.max_tags {
+        return Err(MessageLimit::Tags {
+            count,
+            limit: limits.max_tags,
+        });
+    }
+    // Sorted, so a record breaking several limits always reports the same one
+    let mut tags: Vec<_> = telemetry.tags.iter().collect();
+    tags.sort_unstable();
+    for (key, value) in tags {
+        if key.len() > limits.max_key_bytes {
+            return Err(MessageLimit::TagKey {
+                size: key.len(),
+                limit: limits.max_key_bytes,
+            });
+        }
+        if value.len() > limits.max_value_bytes {
+            return Err(MessageLimit::TagValue {
+                key: key.clone(),
+                size: value.len(),
+                limit: limits.max_value_bytes,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Checked on the encoded payload, so the sink isn't handed a message the
+// broker would refuse with an opaque error
+pub fn check_encoded_size(payload: &[u8], limit: usize) -> Result<(), MessageLimit> {
+    if payload.len() > limit {
+        return Err(MessageLimit::Encoded {
+            size: payload.len(),
+            limit,
+        });
+    }
+    Ok(())
+}
+
+// Per-device-type ceilings on record size, measured as the protobuf
+// encoding so raw payloads and sample series count towards it
+pub struct SizeBudgets {
+    config: SizeBudgetConfig,
+}
+
+impl SizeBudgets {
+    pub fn new(config: SizeBudgetConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn check(
+        &self,
+        telemetry: &Telemetry,
+        device_type: Option<&str>,
+    ) -> Result<(), OverBudget> {
+        let typed = device_type.and_then(|t| {
+            self.config
+                .device_types
+                .get(t)
+                .map(|limit| (t.to_string(), *limit))
+        });
+        let (device_type, limit) = match typed {
+            Some((device_type, limit)) => (Some(device_type), limit),
+            None => (None, self.config.default_max_bytes),
+        };
+        let size = telemetry.encoded_len();
+        if size > limit {
+            return Err(OverBudget {
+                size,
+                limit,
+                device_type,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budgets() -> SizeBudgets {
+        SizeBudgets::new(SizeBudgetConfig {
+            enabled: true,
+            default_max_bytes: 256,
+            device_types: HashMap::from([
+                ("vibration".to_string(), 8192),
+                ("thermostat".to_string(), 64),
+            ]),
+        })
+    }
+
+    fn record(raw_bytes: usize) -> Telemetry {
+        Telemetry {
+            device_id: "dev-1".to_string(),
+            raw: vec![0; raw_bytes],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_limit_follows_device_type() {
+        let budgets = budgets();
+        let waveform = record(4096);
+        assert!(budgets.check(&waveform, Some("vibration")).is_ok());
+
+        let error = budgets.check(&waveform, Some("thermostat")).unwrap_err();
+        assert_eq!(error.limit, 64);
+        assert!(error.to_string().contains("device type thermostat"));
+
+        assert!(budgets.check(&record(32), Some("thermostat")).is_ok());
+        assert!(budgets.check(&record(100), Some("thermostat")).is_err());
+    }
+
+    #[test]
+    fn test_unmapped_types_use_default() {
+        let budgets = budgets();
+        assert!(budgets.check(&record(200), Some("gps")).is_ok());
+        assert!(budgets.check(&record(200), None).is_ok());
+
+        let error = budgets.check(&record(4096), Some("gps")).unwrap_err();
+        assert_eq!(error.limit, 256);
+        assert_eq!(error.device_type, None);
+        assert!(error.to_string().contains("default 256 byte budget"));
+    }
+
+    #[test]
+    fn test_metric_count_includes_every_kind_of_value() {
+        let telemetry = Telemetry {
+            metrics: HashMap::from([("temperature".to_string(), 21.5)]),
+            flag_metrics: HashMap::from([("door_open".to_string(), true)]),
+            text_metrics: HashMap::from([("mode".to_string(), "eco".to_string())]),
+            samples: vec![crate::proto::telemetry::Sample {
+                ts: 1700000000000,
+                metrics: HashMap::from([
+                    ("temperature".to_string(), 21.0),
+                    ("humidity".to_string(), 40.0),
+                ]),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(metric_count(&telemetry), 5);
+        assert!(check_metric_count(&telemetry, None).is_ok());
+        assert!(check_metric_count(&telemetry, Some(5)).is_ok());
+        let error = check_metric_count(&telemetry, Some(4)).unwrap_err();
+        assert!(error.to_string().contains("5 metric values"), "{}", error);
+    }
+
+    #[test]
+    fn test_encoded_size_limit() {
+        assert!(check_encoded_size(&[0; 100], 100).is_ok());
+        let error = check_encoded_size(&[0; 101], 100).unwrap_err();
+        assert!(error.to_string().contains("101 bytes"), "{}", error);
+    }
+
+    #[test]
+    fn test_tag_limits() {
+        let limits = TagLimitsConfig {
+            max_tags: 2,
+            max_key_bytes: 8,
+            max_value_bytes: 16,
+        };
+        let tagged = |tags: &[(&str, &str)]| Telemetry {
+            tags: tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(check_tags(
+            &tagged(&[("firmware", "2.4.1"), ("region", "eu-west")]),
+            &limits
+        )
+        .is_ok());
+
+        let error =
+            check_tags(&tagged(&[("a", "1"), ("b", "2"), ("c", "3")]), &limits).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "record has 3 tags, more than the 2 allowed"
+        );
+
+        let error = check_tags(&tagged(&[("firmware_version", "2.4.1")]), &limits).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tag key of 16 bytes is longer than the 8 byte limit"
+        );
+
+        let error = check_tags(&tagged(&[("note", &"x".repeat(17))]), &limits).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tag note value of 17 bytes is longer than the 16 byte limit"
+        );
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{
+    ack::AckMode,
+    priority::Priority,
+    proto::telemetry::Telemetry,
+    receipts::ReceiptTicket,
+    request_metrics,
+    sink::{SinkRecord, TelemetrySink},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::{IntCounter, IntGauge, Registry};
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpilloverConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Records held while the sink is down; further requests get a 503
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    // How often the drainer checks whether the sink is back
+    #[serde(default = "default_drain_interval_ms")]
+    pub drain_interval_ms: u64,
+    #[serde(default = "default_ready_timeout_ms")]
+    pub ready_timeout_ms: u64,
+}
+
+impl Default for SpilloverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: default_capacity(),
+            drain_interval_ms: default_drain_interval_ms(),
+            ready_timeout_ms: default_ready_timeout_ms(),
+        }
+    }
+}
+
+fn default_capacity() -> usize {
+    10_000
+}
+
+fn default_drain_interval_ms() -> u64 {
+    1000
+}
+
+fn default_ready_timeout_ms() -> u64 {
+    2000
+}
+
+// What a request is told when the sink fails to take its record:
+// - sync: the failure, as a 5xx, so the client can retry
+// - async_buffered: 202; the record waits in the spillover buffer and the
+//   drainer delivers it once the sink recovers. Every accepted record is
+//   answered with 202 in this mode, since it may be queued behind held ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    #[default]
+    Sync,
+    AsyncBuffered,
+}
+
+// Returned by the sink when a record can't be sent and there is no room
+// left to hold it
+#[derive(Debug)]
+pub struct BufferFull {
+    pub capacity: usize,
+}
+
+impl fmt::Display for BufferFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sink unavailable and spillover buffer full ({} records)",
+            self.capacity
+        )
+    }
+}
+
+impl std::error::Error for BufferFull {}
+
+// An owned copy of a record the sink failed to take
+struct Spilled {
+    topic: String,
+    key: String,
+    payload: Vec<u8>,
+    telemetry: Telemetry,
+    expires_at: Option<i64>,
+    ack: AckMode,
+    priority: Priority,
+    receipt: Option<ReceiptTicket>,
+    request_id: Option<String>,
+}
+
+impl Spilled {
+    fn new(record: &SinkRecord<'_>) -> Self {
+        Self {
+            topic: record.topic.to_string(),
+            key: record.key.to_string(),
+            payload: record.payload.to_vec(),
+            telemetry: record.telemetry.clone(),
+            expires_at: record.expires_at,
+            ack: record.ack,
+            priority: record.priority,
+            receipt: record.receipt.cloned(),
+            request_id: record.request_id.map(str::to_string),
+        }
+    }
+
+    fn record(&self) -> SinkRecord<'_> {
+        SinkRecord {
+            topic: &self.topic,
+            key: &self.key,
+            payload: &self.payload,
+            telemetry: &self.telemetry,
+            expires_at: self.expires_at,
+            ack: self.ack,
+            priority: self.priority,
+            receipt: self.receipt.as_ref(),
+            request_id: self.request_id.as_deref(),
+            position: None,
+        }
+    }
+
+    fn fail(&self, reason: &str) {
+        if let Some(receipt) = &self.receipt {
+            receipt.failed(reason);
+        }
+    }
+}
+
+// Wraps the configured sink so an outage doesn't lose data: a record the
+// sink fails to take is held in memory and the request still succeeds. While
+// anything is held, new records queue behind it, so a device's records go
+// out in the order they came in once the drainer finds the sink ready again.
+// Held records are lost if the process exits first.
+pub struct SpilloverSink {
+    inner: Arc<dyn TelemetrySink>,
+    capacity: usize,
+    ready_timeout: Duration,
+    held: Mutex<VecDeque<Spilled>>,
+    spilled: IntCounter,
+    drained: IntCounter,
+    dropped: IntCounter,
+}
+
+impl SpilloverSink {
+    pub fn new(inner: Arc<dyn TelemetrySink>, config: &SpilloverConfig) -> Self {
+        Self {
+            inner,
+            capacity: config.capacity,
+            ready_timeout: Duration::from_millis(config.ready_timeout_ms),
+            held: Mutex::new(VecDeque::new()),
+            spilled: request_metrics::int_counter(
+                "rust_ingest_spillover_spilled_total",
+                "Records held because the sink failed to take them",
+            ),
+            drained: request_metrics::int_counter(
+                "rust_ingest_spillover_drained_total",
+                "Held records sent once the sink recovered",
+            ),
+            dropped: request_metrics::int_counter(
+                "rust_ingest_spillover_dropped_total",
+                "Held records dropped (TTL elapsed or rejected by the sink)",
+            ),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    // Drops the device's held records, failing their receipts; returns
+    // whether there was any
+    fn forget(&self, device_id: &str) -> bool {
+        let removed: VecDeque<Spilled> = {
+            let mut held = self.held.lock().unwrap();
+            let (removed, kept) = held
+                .drain(..)
+                .partition(|spilled| spilled.telemetry.device_id == device_id);
+            *held = kept;
+            removed
+        };
+        for spilled in &removed {
+            spilled.fail("device decommissioned before the sink recovered");
+        }
+        !removed.is_empty()
+    }
+
+    fn hold(&self, record: &SinkRecord<'_>) -> Result<(), BufferFull> {
+        let mut held = self.held.lock().unwrap();
+        if held.len() >= self.capacity {
+            return Err(BufferFull {
+                capacity: self.capacity,
+            });
+        }
+        // The outcome is only known once the record is drained
+        if let Some(receipt) = record.receipt {
+            receipt.report_later();
+        }
+        held.push_back(Spilled::new(record));
+        self.spilled.inc();
+        Ok(())
+    }
+
+    // Sends held records, oldest first, once the sink answers its readiness
+    // check. A send failing while the sink is still ready is the record's
+    // fault, not the outage's, so that record is dropped rather than left to
+    // hold up the rest; otherwise it goes back in front for the next round.
+    async fn drain(&self) {
+        if self.depth() == 0 {
+            return;
+        }
+        if let Err(e) = self.inner.check_ready(self.ready_timeout).await {
+            debug!(
+                "Sink still unavailable, holding {} records: {}",
+                self.depth(),
+                e
+            );
+            return;
+        }
+        let mut drained = 0;
+        loop {
+            let Some(spilled) = self.held.lock().unwrap().pop_front() else {
+                break;
+            };
+            let now = chrono::Utc::now().timestamp_millis();
+            if spilled
+                .expires_at
+                .is_some_and(|expires_at| now >= expires_at)
+            {
+                self.dropped.inc();
+                spilled.fail("TTL elapsed while held for the sink to recover");
+                continue;
+            }
+            let Err(e) = self.inner.publish(spilled.record()).await else {
+                if let Some(receipt) = &spilled.receipt {
+                    receipt.sent();
+                }
+                drained += 1;
+                continue;
+            };
+            if self.inner.check_ready(self.ready_timeout).await.is_err() {
+                self.held.lock().unwrap().push_front(spilled);
+                break;
+            }
+            warn!(
+                "Dropped held telemetry for device {}: {}",
+                spilled.telemetry.device_id, e
+            );
+            self.dropped.inc();
+            spilled.fail(&e.to_string());
+        }
+        if drained > 0 {
+            self.drained.inc_by(drained);
+            info!("Sent {} held records, {} still held", drained, self.depth());
+        }
+    }
+
+    pub fn register_metrics(self: &Arc<Self>, registry: &Registry) -> prometheus::Result<()> {
+        let sink = Arc::clone(self);
+        request_metrics::register_live(
+            registry,
+            request_metrics::int_gauge(
+                "rust_ingest_spillover_records",
+                "Records held while the sink is unavailable",
+            ),
+            move |records: &IntGauge| records.set(sink.depth() as i64),
+        )?;
+        request_metrics::register(registry, &self.spilled)?;
+        request_metrics::register(registry, &self.drained)?;
+        request_metrics::register(registry, &self.dropped)
+    }
+}
+
+#[async_trait]
+impl TelemetrySink for SpilloverSink {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+        if self.depth() > 0 {
+            return Ok(self.hold(&record)?);
+        }
+        match self.inner.publish(record).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!(
+                    "Holding telemetry for device {} until the sink recovers: {}",
+                    record.telemetry.device_id, e
+                );
+                Ok(self.hold(&record)?)
+            }
+        }
+    }
+
+    async fn publish_raw(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        self.inner.publish_raw(topic, key, payload).await
+    }
+
+    async fn publish_tombstone(&self, topic: &str, key: &str) -> Result<()> {
+        self.inner.publish_tombstone(topic, key).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    fn pending(&self) -> usize {
+        self.inner.pending() + self.depth()
+    }
+
+    fn forget_device(&self, device_id: &str) -> usize {
+        self.inner.forget_device(device_id) + usize::from(self.forget(device_id))
+    }
+
+    fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.inner.register_metrics(registry)
+    }
+
+    async fn check_ready(&self, timeout: Duration) -> Result<()> {
+        self.inner.check_ready(timeout).await
+    }
+}
+
+pub fn spawn_drainer(sink: Arc<SpilloverSink>, config: &SpilloverConfig) {
+    let interval = Duration::from_millis(config.drain_interval_ms.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            sink.drain().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Fails every send while `down`; rejects devices named "poison" outright
+    #[derive(Default)]
+    struct FlakySink {
+        down: AtomicBool,
+        sent: Mutex<Vec<i64>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for FlakySink {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("all brokers down"));
+            }
+            if record.telemetry.device_id == "poison" {
+                return Err(anyhow::anyhow!("message too large"));
+            }
+            self.sent.lock().unwrap().push(record.telemetry.ts);
+            Ok(())
+        }
+
+        async fn check_ready(&self, _timeout: Duration) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("no brokers"));
+            }
+            Ok(())
+        }
+    }
+
+    fn spillover(capacity: usize) -> (Arc<SpilloverSink>, Arc<FlakySink>) {
+        let inner = Arc::new(FlakySink::default());
+        let sink = Arc::new(SpilloverSink::new(
+            Arc::clone(&inner) as Arc<dyn TelemetrySink>,
+            &SpilloverConfig {
+                enabled: true,
+                capacity,
+                ..Default::default()
+            },
+        ));
+        (sink, inner)
+    }
+
+    fn rendered(sink: &Arc<SpilloverSink>) -> String {
+        request_metrics::rendered(|registry| sink.register_metrics(registry))
+    }
+
+    async fn send(sink: &SpilloverSink, device_id: &str, ts: i64) -> Result<()> {
+        let telemetry = Telemetry {
+            device_id: device_id.to_string(),
+            ts,
+            ..Default::default()
+        };
+        sink.publish(SinkRecord {
+            topic: "telemetry",
+            key: device_id,
+            payload: &[],
+            telemetry: &telemetry,
+            expires_at: None,
+            ack: AckMode::All,
+            priority: Priority::Normal,
+            receipt: None,
+            request_id: None,
+            position: None,
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_records_are_held_during_an_outage_and_drained_in_order() {
+        let (sink, inner) = spillover(3);
+        send(&sink, "sensor-1", 1).await.unwrap();
+        inner.down.store(true, Ordering::SeqCst);
+        send(&sink, "sensor-1", 2).await.unwrap();
+        send(&sink, "sensor-1", 3).await.unwrap();
+        send(&sink, "sensor-1", 4).await.unwrap();
+        assert_eq!(sink.depth(), 3);
+        assert!(rendered(&sink).contains("rust_ingest_spillover_records 3"));
+
+        // Full: the next record is refused instead of growing the buffer
+        let err = send(&sink, "sensor-1", 5).await.unwrap_err();
+        assert!(err.is::<BufferFull>());
+
+        // Still down: nothing moves
+        sink.drain().await;
+        assert_eq!(sink.depth(), 3);
+
+        inner.down.store(false, Ordering::SeqCst);
+        sink.drain().await;
+        assert_eq!(sink.depth(), 0);
+        send(&sink, "sensor-1", 5).await.unwrap();
+        assert_eq!(*inner.sent.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        assert!(rendered(&sink).contains("rust_ingest_spillover_drained_total 3"));
+    }
+
+    #[tokio::test]
+    async fn test_record_rejected_by_a_ready_sink_is_dropped() {
+        let (sink, inner) = spillover(10);
+        inner.down.store(true, Ordering::SeqCst);
+        send(&sink, "sensor-1", 1).await.unwrap();
+        send(&sink, "poison", 2).await.unwrap();
+        send(&sink, "sensor-1", 3).await.unwrap();
+
+        inner.down.store(false, Ordering::SeqCst);
+        sink.drain().await;
+        assert_eq!(sink.depth(), 0);
+        assert_eq!(*inner.sent.lock().unwrap(), vec![1, 3]);
+        assert!(rendered(&sink).contains("rust_ingest_spillover_dropped_total 1"));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{
+    metric_values::MetricValue,
+    proto::telemetry::Telemetry,
+    server::{ApiError, AppState, SampleRequest, TelemetryRequest},
+    timestamps,
+};
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{rejection::JsonRejection, FromRef, FromRequest, Request},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use prost::Message;
+use std::sync::Arc;
+
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// Tags of the fields of `Telemetry`, one of which starts any encoded message
+const PROTOBUF_FIELD_TAGS: [u8; 7] = [0x0a, 0x10, 0x1a, 0x22, 0x2a, 0x32, 0x3a];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyFormat {
+    Json,
+    Protobuf,
+    MessagePack,
+    LineProtocol,
+}
+
+impl BodyFormat {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Json => "JSON",
+            Self::Protobuf => "protobuf",
+            Self::MessagePack => "MessagePack",
+            Self::LineProtocol => "InfluxDB line protocol",
+        }
+    }
+
+    // Whether the body starts the way this format would
+    fn matches(self, body: &[u8]) -> bool {
+        match self {
+            Self::Json => matches!(
+                body.iter().find(|b| !b.is_ascii_whitespace()),
+                Some(b'{' | b'[')
+            ),
+            Self::Protobuf => body
+                .first()
+                .is_some_and(|b| PROTOBUF_FIELD_TAGS.contains(b)),
+            // A map, as any record is
+            Self::MessagePack => matches!(body.first(), Some(0x80..=0x8f | 0xde | 0xdf)),
+            // `measurement,tag=value field=value` or `measurement field=value`
+            Self::LineProtocol => {
+                let line = body.split(|b| *b == b'\n').next().unwrap_or_default();
+                let name_end = line
+                    .iter()
+                    .position(|b| matches!(b, b',' | b' '))
+                    .unwrap_or(line.len());
+                name_end < line.len()
+                    && line[0].is_ascii_alphabetic()
+                    && line[..name_end]
+                        .iter()
+                        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+                    && line[name_end..].contains(&b'=')
+            }
+        }
+    }
+}
+
+const SNIFFED_FORMATS: [BodyFormat; 4] = [
+    BodyFormat::Json,
+    BodyFormat::Protobuf,
+    BodyFormat::MessagePack,
+    BodyFormat::LineProtocol,
+];
+
+// Whether bodies without a usable Content-Type are sniffed, taken from the
+// server's state so the extractor can be used on its own in tests
+#[derive(Debug, Clone, Copy)]
+pub struct BodyFormatDetection(pub bool);
+
+impl FromRef<Arc<AppState>> for BodyFormatDetection {
+    fn from_ref(state: &Arc<AppState>) -> Self {
+        Self(state.detect_body_format)
+    }
+}
+
+fn media_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|media| media.trim().to_ascii_lowercase())
+}
+
+fn declared_format(media: &str) -> Option<BodyFormat> {
+    match media {
+        PROTOBUF_CONTENT_TYPE => Some(BodyFormat::Protobuf),
+        MSGPACK_CONTENT_TYPE | "application/x-msgpack" | "application/vnd.msgpack" => {
+            Some(BodyFormat::MessagePack)
+        }
+        _ => None,
+    }
+}
+
+// A ts in none of the accepted forms is the client's mistake like malformed
+// JSON, so it gets a 400 saying what would have been taken rather than the
+// 422 for a body of the wrong shape
+fn invalid_timestamp(rejection: &JsonRejection) -> Option<ApiError> {
+    let details = rejection.body_text();
+    details
+        .contains(timestamps::EXPECTED)
+        .then(|| ApiError::new(StatusCode::BAD_REQUEST, "invalid timestamp").with_details(details))
+}
+
+fn decode(format: BodyFormat, body: &Bytes) -> Result<TelemetryRequest, ApiError> {
+    match format {
+        // Same status and wording as when it comes through `Json`
+        BodyFormat::Json => Json::from_bytes(body)
+            .map(|Json(payload)| payload)
+            .map_err(|e| {
+                invalid_timestamp(&e).unwrap_or_else(|| {
+                    ApiError::new(e.status(), "invalid JSON body").with_details(e.body_text())
+                })
+            }),
+        BodyFormat::Protobuf => Telemetry::decode(body.clone())
+            .map(Into::into)
+            .map_err(|e| {
+                ApiError::new(StatusCode::BAD_REQUEST, "invalid protobuf body")
+                    .with_details(e.to_string())
+            }),
+        BodyFormat::MessagePack => rmp_serde::from_slice(body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid MessagePack body")
+                .with_details(e.to_string())
+        }),
+        // Recognised so the client is told why, but there's no decoder for it
+        BodyFormat::LineProtocol => Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "InfluxDB line protocol is not accepted",
+        )
+        .with_details("send JSON, protobuf or MessagePack")),
+    }
+}
+
+// Picks the format from the first bytes. A body that could be more than one
+// (JSON after a leading newline also looks like protobuf) goes to whichever
+// of them decodes it; if none or several do, the client has to say.
+fn sniff(body: &Bytes) -> Result<TelemetryRequest, ApiError> {
+    let candidates: Vec<BodyFormat> = SNIFFED_FORMATS
+        .into_iter()
+        .filter(|format| format.matches(body))
+        .collect();
+    if let [format] = candidates[..] {
+        return decode(format, body);
+    }
+
+    let mut decoded: Vec<TelemetryRequest> = candidates
+        .iter()
+        .filter_map(|format| decode(*format, body).ok())
+        .collect();
+    if decoded.len() == 1 {
+        return Ok(decoded.remove(0));
+    }
+    let details = if candidates.is_empty() {
+        "not JSON, protobuf or MessagePack; set Content-Type".to_string()
+    } else {
+        let names: Vec<&str> = candidates.iter().map(|format| format.name()).collect();
+        format!("could be {}; set Content-Type", names.join(" or "))
+    };
+    Err(ApiError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "could not tell the body format",
+    )
+    .with_details(details))
+}
+
+// A telemetry request body: an encoded `Telemetry` message when sent as
+// application/x-protobuf, MessagePack when sent as application/msgpack, JSON
+// otherwise, with the same rejections as `Json`. With detection on, a body
+// with no Content-Type or application/octet-stream is sniffed instead; a
+// declared type is always taken at its word.
+pub struct TelemetryBody(pub TelemetryRequest);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for TelemetryBody
+where
+    BodyFormatDetection: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let BodyFormatDetection(detect) = BodyFormatDetection::from_ref(state);
+        let media = media_type(req.headers());
+        let declared = media.as_deref().and_then(declared_format);
+        let undeclared = media
+            .as_deref()
+            .is_none_or(|media| media == "application/octet-stream");
+        if declared.is_none() && !(detect && undeclared) {
+            let Json(payload) = Json::from_request(req, state).await.map_err(|e| {
+                invalid_timestamp(&e)
+                    .map(IntoResponse::into_response)
+                    .unwrap_or_else(|| e.into_response())
+            })?;
+            return Ok(Self(payload));
+        }
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        match declared {
+            Some(format) => decode(format, &body),
+            None => sniff(&body),
+        }
+        .map(Self)
+        .map_err(IntoResponse::into_response)
+    }
+}
+
+// Protobuf has no "absent" for scalars, so a zero ts or empty raw means the
+// client didn't set one. The message has no ttl_ms or deliver_at, and its
+// metadata is for the server to fill in, so anything sent there is ignored.
+impl From<Telemetry> for TelemetryRequest {
+    fn from(telemetry: Telemetry) -> Self {
+        let numbers = |metrics: std::collections::HashMap<String, f64>| {
+            metrics
+                .into_iter()
+                .map(|(name, value)| (name, MetricValue::Number(value)))
+                .collect()
+        };
+        Self {
+            device_id: telemetry.device_id,
+            ts: (telemetry.ts != 0).then_some(telemetry.ts),
+            metrics: numbers(telemetry.metrics),
+            samples: telemetry
+                .samples
+                .into_iter()
+                .map(|sample| SampleRequest {
+                    ts: sample.ts,
+                    metrics: numbers(sample.metrics),
+                })
+                .collect(),
+            raw: (!telemetry.raw.is_empty()).then_some(telemetry.raw),
+            tags: telemetry.tags,
+            ttl_ms: None,
+            deliver_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+    use axum::body::Body;
+
+    async fn extract_with(
+        content_type: Option<&str>,
+        detect: bool,
+        body: Vec<u8>,
+    ) -> Result<TelemetryRequest, StatusCode> {
+        let mut request = Request::builder().method("POST").uri("/telemetry");
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        TelemetryBody::from_request(request, &BodyFormatDetection(detect))
+            .await
+            .map(|TelemetryBody(payload)| payload)
+            .map_err(|response| response.status())
+    }
+
+    async fn extract(content_type: &str, body: Vec<u8>) -> Result<TelemetryRequest, StatusCode> {
+        extract_with(Some(content_type), false, body).await
+    }
+
+    async fn sniffed(body: impl Into<Vec<u8>>) -> Result<TelemetryRequest, StatusCode> {
+        extract_with(None, true, body.into()).await
+    }
+
+    fn protobuf_record() -> Vec<u8> {
+        Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: 1_700_000_000_000,
+            metrics: [("temperature".to_string(), 21.5)].into(),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_body_is_decoded() {
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            ts: 1_700_000_000_000,
+            metrics: [("temperature".to_string(), 21.5)].into(),
+            samples: vec![Sample {
+                ts: 1_699_999_999_000,
+                metrics: [("temperature".to_string(), 21.0)].into(),
+            }],
+            ..Default::default()
+        };
+        let payload = extract(PROTOBUF_CONTENT_TYPE, telemetry.encode_to_vec())
+            .await
+            .unwrap();
+        assert_eq!(payload.device_id, "sensor-1");
+        assert_eq!(payload.ts, Some(1_700_000_000_000));
+        assert_eq!(payload.metrics["temperature"], MetricValue::Number(21.5));
+        assert_eq!(payload.samples.len(), 1);
+        assert_eq!(payload.raw, None);
+
+        // Unset ts is left for the server to fill in, as with JSON
+        let telemetry = Telemetry {
+            device_id: "sensor-1".to_string(),
+            ..Default::default()
+        };
+        let payload = extract(
+            "application/x-protobuf; charset=binary",
+            telemetry.encode_to_vec(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload.ts, None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_bodies_are_client_errors() {
+        let status = extract(PROTOBUF_CONTENT_TYPE, vec![0xff, 0xff, 0xff])
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // JSON keeps going through axum's own extractor
+        let payload = extract("application/json", br#"{"device_id":"sensor-1"}"#.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(payload.device_id, "sensor-1");
+        let status = extract("text/plain", br#"{"device_id":"sensor-1"}"#.to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_undeclared_bodies_are_sniffed() {
+        let payload = sniffed(r#"{"device_id":"sensor-1","metrics":{"temperature":21.5}}"#)
+            .await
+            .unwrap();
+        assert_eq!(payload.metrics["temperature"], MetricValue::Number(21.5));
+        // Pretty-printed JSON starting on a new line also starts like protobuf
+        let payload = sniffed("\n {\"device_id\": \"sensor-1\"}\n").await.unwrap();
+        assert_eq!(payload.device_id, "sensor-1");
+
+        let payload = sniffed(protobuf_record()).await.unwrap();
+        assert_eq!(payload.device_id, "sensor-1");
+        assert_eq!(payload.ts, Some(1_700_000_000_000));
+        assert_eq!(payload.metrics["temperature"], MetricValue::Number(21.5));
+
+        let msgpack = rmp_serde::to_vec_named(&serde_json::json!({
+            "device_id": "sensor-1",
+            "ts": 1_700_000_000_000i64,
+            "metrics": {"temperature": 21.5, "door_open": true, "count": 7}
+        }))
+        .unwrap();
+        let payload = sniffed(msgpack.clone()).await.unwrap();
+        assert_eq!(payload.ts, Some(1_700_000_000_000));
+        assert_eq!(payload.metrics["temperature"], MetricValue::Number(21.5));
+        assert_eq!(payload.metrics["door_open"], MetricValue::Bool(true));
+        assert_eq!(payload.metrics["count"], MetricValue::Number(7.0));
+
+        // application/octet-stream says nothing about the format either
+        let payload = extract_with(Some("application/octet-stream"), true, msgpack)
+            .await
+            .unwrap();
+        assert_eq!(payload.device_id, "sensor-1");
+    }
+
+    #[tokio::test]
+    async fn test_unrecognised_or_unsupported_bodies_are_unsupported_media() {
+        let line = "weather,device_id=sensor-1 temperature=21.5 1700000000000000000";
+        assert_eq!(
+            sniffed(line).await.unwrap_err(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            sniffed("temperature 21.5").await.unwrap_err(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            sniffed(Vec::new()).await.unwrap_err(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        // A body that looks like a format but doesn't decode keeps that
+        // format's own rejection
+        assert_eq!(
+            sniffed(r#"{"metrics":{}}"#).await.unwrap_err(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_declared_content_type_wins_over_sniffing() {
+        // Protobuf bytes declared as JSON are bad JSON, not protobuf
+        let status = extract_with(Some("application/json"), true, protobuf_record())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let status = extract_with(Some("text/plain"), true, protobuf_record())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // And nothing is sniffed with detection off
+        let status = extract_with(None, false, protobuf_record())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
@@ -1,67 +1,886 @@
-use crate::{kafka::send_message, proto::telemetry::Telemetry};
+use crate::{
+    ack::AckMode,
+    band_changes::BandTracker,
+    baseline::BaselineTracker,
+    cardinality::CardinalityGuard,
+    circuit_breaker::CircuitOpen,
+    content_dedup::ContentDedup,
+    dead_letter::DeadLetterQueue,
+    delayed_delivery::{DelayQueue, DelayedRecord},
+    device_attributes::DeviceAttributes,
+    device_types::DeviceClassifier,
+    duplicate_backoff::DuplicateBackoff,
+    encoding::EncodingConfig,
+    heartbeat::HeartbeatTracker,
+    histograms::PipelineHistograms,
+    imputation::Imputer,
+    ingest_sequence::IngestSequencer,
+    logging::LogSampler,
+    metric_precision::MetricPrecision,
+    metric_renames::MetricRenamer,
+    metric_values::{
+        large_integer, DuplicateKeyPolicy, LargeIntegerPolicy, MetricValue, ObjectEntries,
+    },
+    pipeline_retry::{PipelineRetry, TransientError},
+    priority::Priority,
+    proto::telemetry::Telemetry,
+    provisioning::DeviceProvisioner,
+    quality::QualityStream,
+    rate_of_change::RateOfChangeChecker,
+    receipts::ReceiptTicket,
+    request_metrics::RequestMetrics,
+    resend_dedup::ResendDedup,
+    sample_window::SampleWindow,
+    sink::{Position, SinkRecord, TelemetrySink},
+    size_budget::{self, SizeBudgets, TagLimitsConfig},
+    spillover::BufferFull,
+    time_grid::{Alignment, GridAligner},
+    unit_conversion::UnitConverter,
+    validation::{Validation, ValidationMode},
+    validation_profiles::{check_range, RangeRule, ValidationProfiles},
+    worker_pool::ValidationPool,
+};
 use anyhow::Result;
-use prost::Message;
-use rdkafka::producer::FutureProducer;
-use std::collections::HashMap;
-use tracing::{info, warn};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prometheus::Registry;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKeyCase {
+    #[default]
+    None,
+    Lower,
+    Upper,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WarningSeverity {
+    // Outside the expected range by less than the range's own width
+    Minor,
+    Major,
+}
+
+// A value that was accepted but falls outside its expected range
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValidationWarning {
+    pub metric: String,
+    pub value: f64,
+    pub expected_min: f64,
+    pub expected_max: f64,
+    pub severity: WarningSeverity,
+}
+
+impl ValidationWarning {
+    pub fn new(metric: &str, value: f64, expected_min: f64, expected_max: f64) -> Self {
+        let distance = (expected_min - value).max(value - expected_max);
+        let severity = if distance < expected_max - expected_min {
+            WarningSeverity::Minor
+        } else {
+            WarningSeverity::Major
+        };
+        Self {
+            metric: metric.to_string(),
+            value,
+            expected_min,
+            expected_max,
+            severity,
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} value {} outside expected range {}..{}",
+            self.metric, self.value, self.expected_min, self.expected_max
+        )
+    }
+}
+
+// Settings and shared state for the telemetry pipeline, built once from Config
+pub struct HandlerContext {
+    pub metric_key_case: MetricKeyCase,
+    pub metric_renamer: Option<MetricRenamer>,
+    pub unit_converter: Option<UnitConverter>,
+    pub baselines: Option<BaselineTracker>,
+    pub validation_pool: Option<ValidationPool>,
+    pub classifier: DeviceClassifier,
+    pub provisioner: Option<DeviceProvisioner>,
+    pub cardinality_guard: Option<CardinalityGuard>,
+    pub quality_stream: Option<QualityStream>,
+    pub dead_letters: Option<DeadLetterQueue>,
+    pub imputer: Option<Imputer>,
+    pub encoding: EncodingConfig,
+    pub resend_dedup: Option<ResendDedup>,
+    pub content_dedup: Option<ContentDedup>,
+    pub duplicate_backoff: Option<DuplicateBackoff>,
+    pub heartbeats: Option<HeartbeatTracker>,
+    pub time_grid: Option<GridAligner>,
+    pub size_budgets: Option<SizeBudgets>,
+    pub histograms: PipelineHistograms,
+    pub request_metrics: RequestMetrics,
+    pub delay_queue: Option<DelayQueue>,
+    pub band_changes: Option<BandTracker>,
+    pub validation_profiles: Option<ValidationProfiles>,
+    pub rate_of_change: Option<RateOfChangeChecker>,
+    pub sample_window: Option<SampleWindow>,
+    pub metric_rules: MetricRules,
+    pub metric_precision: Option<MetricPrecision>,
+    // Empty when every metric name is allowed
+    pub allowed_metrics: HashSet<String>,
+    pub max_metrics_per_message: Option<usize>,
+    pub tag_limits: TagLimitsConfig,
+    pub max_message_bytes: usize,
+    pub validation_mode: ValidationMode,
+    pub device_attributes: Option<DeviceAttributes>,
+    pub ingest_sequence: Option<IngestSequencer>,
+    pub pipeline_retry: Option<PipelineRetry>,
+    pub enrichment: Option<Enrichment>,
+    // Decides which records log their success-path info lines
+    pub log_sampler: LogSampler,
+    // Which ingestion node is handling the record
+    pub node_id: String,
+}
+
+impl HandlerContext {
+    // Drops everything the pipeline remembers about a device; returns how
+    // many stores held state for it
+    pub fn forget_device(&self, device_id: &str) -> usize {
+        let forgotten = [
+            self.baselines.as_ref().map(|s| s.forget(device_id)),
+            self.provisioner.as_ref().map(|s| s.forget(device_id)),
+            self.cardinality_guard.as_ref().map(|s| s.forget(device_id)),
+            self.quality_stream.as_ref().map(|s| s.forget(device_id)),
+            self.imputer.as_ref().map(|s| s.forget(device_id)),
+            self.resend_dedup.as_ref().map(|s| s.forget(device_id)),
+            self.content_dedup.as_ref().map(|s| s.forget(device_id)),
+            self.duplicate_backoff.as_ref().map(|s| s.forget(device_id)),
+            self.heartbeats.as_ref().map(|s| s.forget(device_id)),
+            self.time_grid.as_ref().map(|s| s.forget(device_id)),
+            self.band_changes.as_ref().map(|s| s.forget(device_id)),
+            self.rate_of_change.as_ref().map(|s| s.forget(device_id)),
+            self.delay_queue.as_ref().map(|s| s.forget(device_id)),
+        ];
+        forgotten.into_iter().flatten().filter(|had| *had).count()
+    }
+
+    // Adds the pipeline stages' own metrics to the registry
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        if let Some(dedup) = &self.resend_dedup {
+            dedup.register_metrics(registry)?;
+        }
+        if let Some(dedup) = &self.content_dedup {
+            dedup.register_metrics(registry)?;
+        }
+        if let Some(backoff) = &self.duplicate_backoff {
+            backoff.register_metrics(registry)?;
+        }
+        if let Some(heartbeats) = &self.heartbeats {
+            heartbeats.register_metrics(registry)?;
+        }
+        if let Some(queue) = &self.delay_queue {
+            queue.register_metrics(registry)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EnrichmentConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Also wrap `raw` in the old JSON envelope, for consumers that still
+    // read the ingestion metadata from there
+    #[serde(default)]
+    pub legacy_raw: bool,
+}
+
+// Ingestion metadata stamped on every record that goes out
+pub struct Enrichment {
+    pub legacy_raw: bool,
+}
+
+// How and when a record is to be sent
+#[derive(Clone, Default)]
+pub struct Delivery {
+    // TTL deadline in unix millis
+    pub expires_at: Option<i64>,
+    // Future unix millis to hold the record back until
+    pub deliver_at: Option<i64>,
+    pub ack: AckMode,
+    pub priority: Priority,
+    // Receipt to record a 202 request's outcome on
+    pub receipt: Option<ReceiptTicket>,
+    // Correlation id of the request, passed on to the sink
+    pub request_id: Option<String>,
+    // Units the request declared, by metric, for unit conversion
+    pub units: HashMap<String, String>,
+}
+
+// Returns the record as published, with the validation warnings it raised
 pub async fn handle_telemetry(
-    telemetry: Telemetry,
-    producer: &FutureProducer,
+    mut telemetry: Telemetry,
+    sink: &dyn TelemetrySink,
     topic: &str,
-) -> Result<()> {
+    ctx: &Arc<HandlerContext>,
+    delivery: Delivery,
+) -> Result<PreparedTelemetry> {
+    let Delivery {
+        expires_at,
+        deliver_at,
+        ack,
+        priority,
+        receipt,
+        request_id,
+        units,
+    } = delivery;
+
+    // Reject oversized records before spending any work on them
+    size_budget::check_metric_count(&telemetry, ctx.max_metrics_per_message)?;
+    size_budget::check_tags(&telemetry, &ctx.tag_limits)?;
+    if let Some(budgets) = &ctx.size_budgets {
+        let device_type = ctx
+            .classifier
+            .classify(&telemetry.device_id, telemetry.metrics.keys());
+        budgets.check(&telemetry, device_type.as_deref())?;
+    }
+
+    // Validation consumes the record, so keep the original for the dead-letter topic
+    let original = ctx.dead_letters.as_ref().map(|_| telemetry.clone());
+
+    // Into canonical units first, so validation ranges are in those units
+    let conversion = match &ctx.unit_converter {
+        Some(converter) => converter
+            .apply(&mut telemetry, &units)
+            .map_err(|e| TelemetryError::Validation(e).into()),
+        None => Ok(false),
+    };
+    let converted = matches!(conversion, Ok(true));
+
+    // The CPU-bound part runs on the validation pool when configured; the send stays async
+    let prepared = match (conversion, &ctx.validation_pool) {
+        (Err(e), _) => Err(e),
+        (Ok(_), Some(pool)) => {
+            let job_ctx = Arc::clone(ctx);
+            let job_topic = topic.to_string();
+            pool.run(move || prepare_telemetry(telemetry, &job_topic, &job_ctx))
+                .await?
+        }
+        (Ok(_), None) => prepare_telemetry(telemetry, topic, ctx),
+    };
+    let mut prepared = match (prepared, &ctx.dead_letters, original) {
+        (Ok(prepared), ..) => prepared,
+        (Err(e), Some(dead_letters), Some(original)) => {
+            dead_letters
+                .publish(sink, &original, &format!("{:#}", e))
+                .await;
+            return Err(e);
+        }
+        (Err(e), ..) => return Err(e),
+    };
+    if converted {
+        prepared.transforms.insert(0, "unit_conversion");
+    }
+    let telemetry = &prepared.telemetry;
+
+    // Queuing and validation take time; don't publish a reading that is already stale
+    if let Some(expires_at) = expires_at {
+        let now = chrono::Utc::now().timestamp_millis();
+        if now >= expires_at {
+            ctx.request_metrics.expired.inc();
+            let dropped = ctx.request_metrics.expired.get();
+            warn!(
+                device_id = %telemetry.device_id,
+                topic,
+                overdue_ms = now - expires_at,
+                expired_total = dropped,
+                "Dropped telemetry: TTL elapsed before send"
+            );
+            release_resend(ctx, telemetry);
+            return Err(Expired {
+                overdue_ms: now - expires_at,
+            }
+            .into());
+        }
+    }
+
+    if let Some(reason) = prepared.dropped_by {
+        debug!(
+            device_id = %telemetry.device_id,
+            topic,
+            dropped_by = reason,
+            "Skipped telemetry"
+        );
+        if let Some(heartbeats) = &ctx.heartbeats {
+            heartbeats.record_received(&telemetry.device_id);
+        }
+        return Ok(prepared);
+    }
+
+    if let (Some(deliver_at), Some(queue)) = (deliver_at, &ctx.delay_queue) {
+        let held = queue.hold(
+            DelayedRecord {
+                topic: topic.to_string(),
+                telemetry: telemetry.clone(),
+                payload: prepared.payload.clone(),
+                expires_at,
+                ack,
+                priority,
+                request_id: request_id.clone(),
+            },
+            deliver_at,
+        );
+        if let Err(e) = held {
+            release_resend(ctx, telemetry);
+            return Err(e.into());
+        }
+        debug!(
+            device_id = %telemetry.device_id,
+            topic,
+            deliver_at,
+            "Holding telemetry for delayed delivery"
+        );
+        return Ok(PreparedTelemetry {
+            scheduled_for: Some(deliver_at),
+            ..prepared
+        });
+    }
+
+    let position = OnceLock::new();
+    let started = Instant::now();
+    let publish = || {
+        sink.publish(SinkRecord {
+            topic,
+            key: &telemetry.device_id,
+            payload: &prepared.payload,
+            telemetry,
+            expires_at,
+            ack,
+            priority,
+            receipt: receipt.as_ref(),
+            request_id: request_id.as_deref(),
+            position: Some(&position),
+        })
+    };
+    // Only the send is retried: the steps before it keep per-device state
+    // and would see the record twice
+    let sent = match &ctx.pipeline_retry {
+        Some(retry) => retry.run(&telemetry.device_id, publish).await,
+        None => publish().await,
+    };
+    let send_time = started.elapsed();
+    ctx.histograms.send_latency.observe(send_time.as_secs_f64());
+    tracing::Span::current().record("send_ms", send_time.as_secs_f64() * 1000.0);
+    if let Err(e) = &sent {
+        if !e.is::<CircuitOpen>() {
+            ctx.request_metrics.send_failures.inc();
+        }
+        // Never delivered, so the device's retry must get through
+        release_resend(ctx, telemetry);
+    }
+    sent.map_err(TelemetryError::from_send)?;
+    if let Some(receipt) = &receipt {
+        receipt.sent();
+    }
+    ctx.histograms
+        .payload_size
+        .observe(prepared.payload.len() as f64);
+
+    if let Some(heartbeats) = &ctx.heartbeats {
+        heartbeats.record_forwarded(topic, telemetry);
+    }
+
+    if prepared.logged {
+        info!(
+            device_id = %telemetry.device_id,
+            topic,
+            sink = sink.name(),
+            "Successfully sent telemetry"
+        );
+    }
+
+    // Warnings go to the quality stream separately; a failure there doesn't fail the record
+    if let Some(quality) = &ctx.quality_stream {
+        quality
+            .emit(sink, &telemetry.device_id, telemetry.ts, &prepared.warnings)
+            .await;
+    }
+    if let Some(bands) = &ctx.band_changes {
+        bands.emit(sink, telemetry).await;
+    }
+
+    Ok(PreparedTelemetry {
+        position: position.into_inner(),
+        ..prepared
+    })
+}
+
+// Forgets that the reading was seen once it is known it won't go out, so
+// the device's retry isn't taken for a duplicate
+fn release_resend(ctx: &HandlerContext, telemetry: &Telemetry) {
+    if let Some(dedup) = &ctx.resend_dedup {
+        dedup.release(&telemetry.device_id, telemetry.ts);
+    }
+}
+
+// Why handle_telemetry failed, for the errors that aren't already typed,
+// so the API can tell bad data from an unavailable backend: clients retry
+// the latter but shouldn't resend the former
+#[derive(Debug)]
+pub enum TelemetryError {
+    // The record failed validation
+    Validation(anyhow::Error),
+    // The sink refused or failed the send
+    Kafka(anyhow::Error),
+}
+
+impl TelemetryError {
+    // Send failures the API already tells apart keep their own type
+    fn from_send(e: anyhow::Error) -> anyhow::Error {
+        if e.is::<CircuitOpen>() || e.is::<BufferFull>() || e.is::<TransientError>() {
+            e
+        } else {
+            Self::Kafka(e).into()
+        }
+    }
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Validation(e) | Self::Kafka(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TelemetryError {}
+
+// Returned when a record's TTL elapsed before it could be sent. Nothing was
+// published, and sending the same reading again would only expire again.
+#[derive(Debug)]
+pub struct Expired {
+    pub overdue_ms: i64,
+}
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "TTL elapsed {}ms before the record could be sent",
+            self.overdue_ms
+        )
+    }
+}
+
+impl std::error::Error for Expired {}
+
+// dropped_by of a reading the device already sent, which the client is told
+// was a duplicate
+pub const RESEND_DEDUP: &str = "resend_dedup";
+
+// A record that passed validation, ready to publish
+pub struct PreparedTelemetry {
+    pub telemetry: Telemetry,
+    pub payload: Vec<u8>,
+    pub warnings: Vec<ValidationWarning>,
+    // Pipeline steps that changed the record, in order
+    pub transforms: Vec<&'static str>,
+    // Set when a transform decided the record isn't worth sending
+    pub dropped_by: Option<&'static str>,
+    // Delivery time (unix millis) of a record held for later
+    pub scheduled_for: Option<i64>,
+    // Where the sink wrote the record, when it says
+    pub position: Option<Position>,
+    // Sampled for logging by log_sample_rate, once for all of its lines
+    pub logged: bool,
+}
+
+// Synchronous part of the pipeline: normalize, validate and encode in the
+// format configured for `topic`.
+pub fn prepare_telemetry(
+    mut telemetry: Telemetry,
+    topic: &str,
+    ctx: &HandlerContext,
+) -> Result<PreparedTelemetry> {
+    let mut transforms = Vec::new();
+    normalize_keys(&mut telemetry, ctx, &mut transforms);
+
+    let logged = ctx.log_sampler.sample();
+    let warnings = check_telemetry(&mut telemetry, topic, ctx, logged, &mut transforms)
+        .map_err(TelemetryError::Validation)?;
+
+    let mut dropped_by = None;
+
+    // Snap ts onto the device type's grid
+    if let Some(aligner) = &ctx.time_grid {
+        let device_type = ctx
+            .classifier
+            .classify(&telemetry.device_id, telemetry.metrics.keys());
+        match aligner.apply(&mut telemetry, device_type.as_deref()) {
+            Alignment::Unchanged => {}
+            Alignment::Aligned => transforms.push("time_grid"),
+            Alignment::Collided => dropped_by = Some("time_grid"),
+        }
+    }
+
+    // Drop a reading the device already sent with the same ts
+    if let (Some(dedup), None) = (&ctx.resend_dedup, dropped_by) {
+        if dedup.is_duplicate(&telemetry.device_id, telemetry.ts) {
+            dropped_by = Some(RESEND_DEDUP);
+        }
+    }
+
+    // Collapse a resend of recent content that only carries a new ts
+    if let (Some(dedup), None) = (&ctx.content_dedup, dropped_by) {
+        if dedup.is_duplicate(&telemetry) {
+            dropped_by = Some("content_dedup");
+        }
+    }
+
+    // Thin out values a frozen sensor keeps repeating
+    if let (Some(backoff), None) = (&ctx.duplicate_backoff, dropped_by) {
+        if backoff.apply(&telemetry.device_id, &mut telemetry.metrics) > 0 {
+            transforms.push("duplicate_backoff");
+            if telemetry.metrics.is_empty() && telemetry.samples.is_empty() {
+                dropped_by = Some("duplicate_backoff");
+            }
+        }
+    }
+
+    // Annotate with the device's static attributes from the lookup table
+    if let (Some(attributes), None) = (&ctx.device_attributes, dropped_by) {
+        if attributes.enrich(&mut telemetry) {
+            transforms.push("device_attributes");
+        }
+    }
+
+    // Last, so only records that are going out use up a sequence number
+    if let (Some(sequencer), None) = (&ctx.ingest_sequence, dropped_by) {
+        sequencer.stamp(&mut telemetry);
+        transforms.push("ingest_sequence");
+    }
+
+    if let (Some(enrichment), None) = (&ctx.enrichment, dropped_by) {
+        telemetry = enrich_telemetry(telemetry, &ctx.node_id, enrichment.legacy_raw);
+        transforms.push("enrichment");
+    }
+
+    if let (Some(precision), None) = (&ctx.metric_precision, dropped_by) {
+        if precision.apply(&mut telemetry) {
+            transforms.push("metric_precision");
+        }
+    }
+
+    // Encode telemetry for the sink in the destination topic's format
+    let encoded = match dropped_by {
+        Some(_) => Ok(Vec::new()),
+        None => ctx.encoding.encode_for(&telemetry, topic),
+    }
+    .and_then(|payload| {
+        size_budget::check_encoded_size(&payload, ctx.max_message_bytes)?;
+        Ok(payload)
+    });
+    let payload = match encoded {
+        Ok(payload) => payload,
+        Err(e) => {
+            release_resend(ctx, &telemetry);
+            return Err(e);
+        }
+    };
+
+    Ok(PreparedTelemetry {
+        telemetry,
+        payload,
+        warnings,
+        transforms,
+        dropped_by,
+        scheduled_for: None,
+        position: None,
+        logged,
+    })
+}
+
+// The checks that decide whether the record itself is acceptable; a
+// failure here is the client's to fix
+fn check_telemetry(
+    telemetry: &mut Telemetry,
+    topic: &str,
+    ctx: &HandlerContext,
+    logged: bool,
+    transforms: &mut Vec<&'static str>,
+) -> Result<Vec<ValidationWarning>> {
+    // Drop (or reject) metric names from devices that keep inventing new ones
+    if let Some(guard) = &ctx.cardinality_guard {
+        let before = telemetry.metrics.len();
+        guard.check(&telemetry.device_id, &mut telemetry.metrics)?;
+        if telemetry.metrics.len() < before {
+            transforms.push("cardinality_guard");
+        }
+    }
+
+    // Fill expected-but-missing metrics before validation sees the record
+    if let Some(imputer) = &ctx.imputer {
+        let device_type = ctx
+            .classifier
+            .classify(&telemetry.device_id, telemetry.metrics.keys());
+        imputer.apply(telemetry, device_type.as_deref());
+        if telemetry.tags.contains_key("imputed") {
+            transforms.push("imputation");
+        }
+    }
+
     // Log some basic info about the received telemetry
-    let metrics_summary: Vec<String> = telemetry
-        .metrics
-        .iter()
-        .map(|(k, v)| format!("{}={:.2}", k, v))
-        .collect();
+    if logged {
+        let metrics_summary: Vec<String> = telemetry
+            .metrics
+            .iter()
+            .map(|(k, v)| format!("{}={:.2}", k, v))
+            .collect();
 
-    info!(
-        "Processing telemetry: device_id={} ts={} metrics=[{}]",
-        telemetry.device_id,
-        telemetry.ts,
-        metrics_summary.join(", ")
-    );
+        info!(
+            device_id = %telemetry.device_id,
+            topic,
+            ts = telemetry.ts,
+            metrics = %metrics_summary.join(", "),
+            "Processing telemetry"
+        );
+    }
 
     // Validate telemetry data
     if telemetry.device_id.is_empty() {
-        warn!("Received telemetry with empty device_id");
+        warn!(topic, "Received telemetry with empty device_id");
         return Err(anyhow::anyhow!("Device ID cannot be empty"));
     }
 
-    if telemetry.metrics.is_empty() {
+    if telemetry.metrics.is_empty() && telemetry.samples.is_empty() {
         warn!(
-            "Received telemetry with no metrics for device {}",
-            telemetry.device_id
+            device_id = %telemetry.device_id,
+            topic,
+            "Received telemetry with no metrics"
         );
         return Err(anyhow::anyhow!("Metrics cannot be empty"));
     }
 
-    // Encode telemetry as protobuf and send to Kafka
-    let mut buf = Vec::new();
-    telemetry.encode(&mut buf)?;
+    let mut validation = Validation::new(ctx.validation_mode);
+    validation.check(|| check_allowed_metrics(telemetry, &ctx.allowed_metrics))?;
+    check_readings(telemetry, ctx, &mut validation)?;
+    if let Some(baselines) = &ctx.baselines {
+        // Learned per-device ranges replace the static ones for adaptive metrics
+        validation
+            .warnings_mut()
+            .retain(|warning| !baselines.is_adaptive(&warning.metric));
+        validation.check(|| baselines.check(&telemetry.device_id, &telemetry.metrics))?;
+    }
+    // After baselines, which only stand in for the static range checks
+    if let Some(checker) = &ctx.rate_of_change {
+        validation.check(|| checker.check(telemetry))?;
+    }
+    if let Some(window) = &ctx.sample_window {
+        validation.check(|| window.check(telemetry))?;
+    }
+
+    // Only readings that passed validation may provision or extend a device schema
+    if let Some(provisioner) = &ctx.provisioner {
+        validation.check_if_clean(|| {
+            provisioner.check(&telemetry.device_id, &telemetry.metrics, &ctx.classifier)?;
+            Ok(Vec::new())
+        })?;
+    }
+    validation.finish()
+}
 
-    send_message(producer, topic, &telemetry.device_id, buf).await?;
+// Normalize key case first so validation and everything downstream see the
+// same names, then apply the pattern-based renames to those
+fn normalize_keys(
+    telemetry: &mut Telemetry,
+    ctx: &HandlerContext,
+    transforms: &mut Vec<&'static str>,
+) {
+    if ctx.metric_key_case != MetricKeyCase::None {
+        let before: Vec<String> = telemetry.metrics.keys().cloned().collect();
+        telemetry.metrics = normalize_metric_keys(
+            std::mem::take(&mut telemetry.metrics),
+            ctx.metric_key_case,
+            &telemetry.device_id,
+        );
+        if before
+            .iter()
+            .any(|key| !telemetry.metrics.contains_key(key))
+        {
+            transforms.push("metric_key_case");
+        }
+        for sample in &mut telemetry.samples {
+            sample.metrics = normalize_metric_keys(
+                std::mem::take(&mut sample.metrics),
+                ctx.metric_key_case,
+                &telemetry.device_id,
+            );
+        }
+    }
 
-    info!(
-        "Successfully sent telemetry to Kafka for device {}",
-        telemetry.device_id
-    );
+    if let Some(renamer) = &ctx.metric_renamer {
+        if renamer.apply(telemetry) {
+            transforms.push("metric_renames");
+        }
+    }
+}
 
+// Rejects a record naming any metric outside the allowlist, listing every
+// such name at once so a device's typos can be fixed in one go
+fn check_allowed_metrics(
+    telemetry: &Telemetry,
+    allowed: &HashSet<String>,
+) -> Result<Vec<ValidationWarning>> {
+    if allowed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut unknown: Vec<&str> = telemetry
+        .metrics
+        .keys()
+        .chain(telemetry.flag_metrics.keys())
+        .chain(telemetry.text_metrics.keys())
+        .chain(telemetry.samples.iter().flat_map(|s| s.metrics.keys()))
+        .map(String::as_str)
+        .filter(|metric| !allowed.contains(*metric))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(Vec::new());
+    }
+    unknown.sort_unstable();
+    unknown.dedup();
+    Err(anyhow::anyhow!(
+        "Metrics not in the allowlist: {}",
+        unknown.join(", ")
+    ))
+}
+
+// Range checks on every reading, against the record's validation profile
+// or else the metric rules
+fn check_readings(
+    telemetry: &Telemetry,
+    ctx: &HandlerContext,
+    validation: &mut Validation,
+) -> Result<()> {
+    let profile = ctx
+        .validation_profiles
+        .as_ref()
+        .and_then(|profiles| profiles.select(telemetry, &ctx.classifier));
+    if let Some(profile) = profile {
+        return profile.validate(telemetry, validation);
+    }
+    let readings =
+        std::iter::once(&telemetry.metrics).chain(telemetry.samples.iter().map(|s| &s.metrics));
+    for metrics in readings {
+        for (key, value) in metrics {
+            validation.check(|| {
+                Ok(ctx
+                    .metric_rules
+                    .validate_metric(key, *value)?
+                    .into_iter()
+                    .collect())
+            })?;
+        }
+    }
     Ok(())
 }
 
+// What checking a record without sending it found
+#[derive(Debug, Default)]
+pub struct DryRun {
+    pub warnings: Vec<ValidationWarning>,
+    pub errors: Vec<String>,
+}
+
+// prepare_telemetry's checks for a record nobody will send: keys are
+// normalized the same way and every failure is collected whatever the
+// validation mode. Checks that learn from or commit per-device state
+// (cardinality guard, imputation, baselines, rate of change, provisioning)
+// are skipped, so a dry run can't change how later records are treated.
+pub fn dry_run(mut telemetry: Telemetry, ctx: &HandlerContext) -> DryRun {
+    let mut report = DryRun::default();
+    if let Err(over) = size_budget::check_metric_count(&telemetry, ctx.max_metrics_per_message) {
+        report.errors.push(over.to_string());
+    }
+    if let Err(over) = size_budget::check_tags(&telemetry, &ctx.tag_limits) {
+        report.errors.push(over.to_string());
+    }
+    if let Some(budgets) = &ctx.size_budgets {
+        let device_type = ctx
+            .classifier
+            .classify(&telemetry.device_id, telemetry.metrics.keys());
+        if let Err(over) = budgets.check(&telemetry, device_type.as_deref()) {
+            report.errors.push(over.to_string());
+        }
+    }
+
+    normalize_keys(&mut telemetry, ctx, &mut Vec::new());
+    if telemetry.device_id.is_empty() {
+        report.errors.push("Device ID cannot be empty".to_string());
+    }
+    if telemetry.metrics.is_empty() && telemetry.samples.is_empty() {
+        report.errors.push("Metrics cannot be empty".to_string());
+        return report;
+    }
+
+    let mut validation = Validation::new(ValidationMode::CollectAll);
+    // Never fails under collect_all
+    let _ = validation.check(|| check_allowed_metrics(&telemetry, &ctx.allowed_metrics));
+    if let Err(e) = check_readings(&telemetry, ctx, &mut validation) {
+        report.errors.push(e.to_string());
+    }
+    if let Some(window) = &ctx.sample_window {
+        // Never fails under collect_all
+        let _ = validation.check(|| window.check(&telemetry));
+    }
+    let (warnings, failures) = validation.into_parts();
+    report.warnings = warnings;
+    report.errors.extend(failures);
+    report
+}
+
 // Helper function to create telemetry from JSON (for testing/debugging)
-pub fn create_telemetry_from_json(json_data: &str, device_id: &str) -> Result<Telemetry> {
-    let parsed: serde_json::Value = serde_json::from_str(json_data)?;
+pub fn create_telemetry_from_json(
+    json_data: &str,
+    device_id: &str,
+    large_integers: LargeIntegerPolicy,
+    duplicate_keys: DuplicateKeyPolicy,
+) -> Result<Telemetry> {
+    let parsed: ObjectEntries = serde_json::from_str(json_data)?;
     let mut metrics = HashMap::new();
+    let mut flag_metrics = HashMap::new();
+    let mut text_metrics = HashMap::new();
 
-    if let Some(obj) = parsed.as_object() {
-        for (key, value) in obj {
-            if let Some(num) = value.as_f64() {
-                metrics.insert(key.clone(), num);
+    let entries = parsed
+        .into_unique(duplicate_keys)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    for (key, value) in entries {
+        // Nulls, arrays and objects aren't readings
+        match serde_json::from_value(value) {
+            Ok(MetricValue::Number(num)) => {
+                metrics.insert(key, num);
+            }
+            Ok(MetricValue::LargeInteger(value)) => {
+                let num =
+                    large_integer(&key, value, large_integers).map_err(|e| anyhow::anyhow!(e))?;
+                metrics.insert(key, num);
+            }
+            Ok(MetricValue::Bool(state)) => {
+                flag_metrics.insert(key, state);
             }
+            Ok(MetricValue::Text(text)) => {
+                text_metrics.insert(key, text);
+            }
+            Err(_) => continue,
         }
     }
 
@@ -70,57 +889,185 @@ pub fn create_telemetry_from_json(json_data: &str, device_id: &str) -> Result<Te
         ts: chrono::Utc::now().timestamp_millis(),
         metrics,
         raw: json_data.as_bytes().to_vec(),
+        tags: HashMap::new(),
+        metadata: None,
+        samples: Vec::new(),
+        flag_metrics,
+        text_metrics,
     })
 }
 
-// Helper function to validate metric values
-pub fn validate_metrics(metrics: &HashMap<String, f64>) -> Result<()> {
-    for (key, value) in metrics {
-        if key.is_empty() {
-            return Err(anyhow::anyhow!("Metric name cannot be empty"));
-        }
+// Rewrite metric keys into a single case. When several keys collapse onto the
+// same name, the one already in canonical form wins, otherwise the
+// lexicographically smallest original key, so the outcome is deterministic.
+pub fn normalize_metric_keys(
+    metrics: HashMap<String, f64>,
+    case: MetricKeyCase,
+    device_id: &str,
+) -> HashMap<String, f64> {
+    let normalize = |key: &str| match case {
+        MetricKeyCase::None => key.to_string(),
+        MetricKeyCase::Lower => key.to_lowercase(),
+        MetricKeyCase::Upper => key.to_uppercase(),
+    };
 
-        if !value.is_finite() {
-            return Err(anyhow::anyhow!(
-                "Invalid metric value for {}: {}",
-                key,
-                value
-            ));
-        }
-
-        // Add any specific validation rules here
-        match key.as_str() {
-            "temperature" => {
-                if !(-100.0..=200.0).contains(value) {
-                    warn!("Temperature value {} seems out of normal range", value);
+    let mut chosen: HashMap<String, (String, f64)> = HashMap::with_capacity(metrics.len());
+    for (key, value) in metrics {
+        let normalized = normalize(&key);
+        match chosen.get(&normalized) {
+            Some((existing, _)) => {
+                warn!(
+                    "Metric keys {} and {} collide after case normalization for device {}",
+                    existing, key, device_id
+                );
+                let existing_canonical = *existing == normalized;
+                if !existing_canonical && (key == normalized || key < *existing) {
+                    chosen.insert(normalized, (key, value));
                 }
             }
-            "humidity" => {
-                if !(0.0..=100.0).contains(value) {
-                    warn!("Humidity value {}% seems out of normal range", value);
-                }
+            None => {
+                chosen.insert(normalized, (key, value));
             }
-            "battery_level" => {
-                if !(0.0..=100.0).contains(value) {
-                    return Err(anyhow::anyhow!("Battery level must be between 0-100%"));
+        }
+    }
+
+    chosen
+        .into_iter()
+        .map(|(normalized, (_, value))| (normalized, value))
+        .collect()
+}
+
+// Unit the pipeline assumes for a metric, where it has one
+pub fn metric_unit(metric: &str) -> Option<&'static str> {
+    match metric {
+        "temperature" => Some("celsius"),
+        "humidity" | "battery_level" => Some("percent"),
+        _ => None,
+    }
+}
+
+// A range check for one metric. Values outside [min, max] raise a warning,
+// or fail the record with `reject_out_of_range`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricRule {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub reject_out_of_range: bool,
+}
+
+// Used for whatever metric_rules doesn't mention: metric, min, max, reject
+const DEFAULT_RULES: [(&str, f64, f64, bool); 3] = [
+    ("temperature", -100.0, 200.0, false),
+    ("humidity", 0.0, 100.0, false),
+    ("battery_level", 0.0, 100.0, true),
+];
+
+// The range checks for devices without a validation profile
+pub struct MetricRules {
+    ranges: HashMap<String, RangeRule>,
+}
+
+impl MetricRules {
+    // Configured rules add to the defaults, or replace one for the same metric
+    pub fn new(rules: &HashMap<String, MetricRule>) -> Result<Self> {
+        let mut ranges: HashMap<String, RangeRule> = DEFAULT_RULES
+            .iter()
+            .map(|&(metric, min, max, reject)| {
+                let rule = RangeRule {
+                    min: Some(min),
+                    max: Some(max),
+                    reject,
+                };
+                (metric.to_string(), rule)
+            })
+            .collect();
+        for (metric, rule) in rules {
+            if let (Some(min), Some(max)) = (rule.min, rule.max) {
+                if min > max {
+                    return Err(anyhow::anyhow!(
+                        "metric_rules.{} has min {} above max {}",
+                        metric,
+                        min,
+                        max
+                    ));
                 }
             }
-            _ => {} // Other metrics don't have specific validation
+            ranges.insert(
+                metric.clone(),
+                RangeRule {
+                    min: rule.min,
+                    max: rule.max,
+                    reject: rule.reject_out_of_range,
+                },
+            );
+        }
+        Ok(Self { ranges })
+    }
+
+    // The rule for one metric, if it has one. Failures name the metric, so
+    // the client can tell which reading was refused.
+    pub fn validate_metric(&self, key: &str, value: f64) -> Result<Option<ValidationWarning>> {
+        check_metric(key, value)?;
+        match self.ranges.get(key) {
+            Some(rule) => check_range(key, value, rule),
+            None => Ok(None),
         }
     }
+}
 
+impl Default for MetricRules {
+    fn default() -> Self {
+        Self::new(&HashMap::new()).expect("the default rules are valid")
+    }
+}
+
+// Checks every metric must pass, whatever its validation profile
+pub fn check_metric(key: &str, value: f64) -> Result<()> {
+    if key.is_empty() {
+        return Err(anyhow::anyhow!("Metric name cannot be empty"));
+    }
+
+    if !value.is_finite() {
+        return Err(anyhow::anyhow!(
+            "Invalid metric value for {}: {}",
+            key,
+            value
+        ));
+    }
     Ok(())
 }
 
-// Helper function to enrich telemetry with additional metadata
-pub fn enrich_telemetry(mut telemetry: Telemetry, node_id: &str) -> Telemetry {
-    // Add ingestion metadata
-    telemetry.raw = serde_json::to_vec(&serde_json::json!({
-        "ingested_at": chrono::Utc::now().to_rfc3339(),
-        "ingestion_node": node_id,
-        "original_raw": String::from_utf8_lossy(&telemetry.raw).to_string()
-    }))
-    .unwrap_or_default();
+// Helper function to enrich telemetry with additional metadata. The metadata
+// goes into the structured `metadata` field; `legacy_raw` additionally wraps
+// `raw` in the old JSON envelope for consumers that still parse it from there,
+// with the original bytes base64-encoded so binary payloads survive.
+pub fn enrich_telemetry(mut telemetry: Telemetry, node_id: &str, legacy_raw: bool) -> Telemetry {
+    use prost_types::{value::Kind, Value};
+
+    let ingested_at = chrono::Utc::now().to_rfc3339();
+    let metadata = telemetry.metadata.get_or_insert_with(Default::default);
+    for (key, value) in [
+        ("ingested_at", &ingested_at),
+        ("ingestion_node", &node_id.to_string()),
+    ] {
+        metadata.fields.insert(
+            key.to_string(),
+            Value {
+                kind: Some(Kind::StringValue(value.clone())),
+            },
+        );
+    }
+
+    if legacy_raw {
+        telemetry.raw = serde_json::to_vec(&serde_json::json!({
+            "ingested_at": ingested_at,
+            "ingestion_node": node_id,
+            "original_raw": BASE64.encode(&telemetry.raw),
+            "original_raw_encoding": "base64",
+        }))
+        .unwrap_or_default();
+    }
 
     telemetry
 }
@@ -128,11 +1075,447 @@ pub fn enrich_telemetry(mut telemetry: Telemetry, node_id: &str) -> Telemetry {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        delayed_delivery::{DelayRejection, DelayedDeliveryConfig},
+        encoding,
+        proto::telemetry::Sample,
+        validation::ValidationFailures,
+    };
+    use async_trait::async_trait;
+    use prost::Message;
+    use std::sync::Mutex;
+
+    fn test_context() -> HandlerContext {
+        HandlerContext {
+            metric_key_case: MetricKeyCase::None,
+            metric_renamer: None,
+            unit_converter: None,
+            baselines: None,
+            validation_pool: None,
+            classifier: DeviceClassifier::new(Default::default()),
+            provisioner: None,
+            cardinality_guard: None,
+            quality_stream: None,
+            dead_letters: None,
+            imputer: None,
+            encoding: EncodingConfig::default(),
+            resend_dedup: None,
+            content_dedup: None,
+            duplicate_backoff: None,
+            heartbeats: None,
+            time_grid: None,
+            size_budgets: None,
+            histograms: PipelineHistograms::new(&Default::default()),
+            request_metrics: RequestMetrics::register(&prometheus::Registry::new()).unwrap(),
+            delay_queue: None,
+            band_changes: None,
+            validation_profiles: None,
+            rate_of_change: None,
+            sample_window: None,
+            metric_rules: MetricRules::default(),
+            metric_precision: None,
+            allowed_metrics: HashSet::new(),
+            max_metrics_per_message: None,
+            tag_limits: TagLimitsConfig::default(),
+            max_message_bytes: 1_000_000,
+            validation_mode: ValidationMode::FailFast,
+            device_attributes: None,
+            ingest_sequence: None,
+            pipeline_retry: None,
+            enrichment: None,
+            log_sampler: LogSampler::new(1),
+            node_id: "test-node".to_string(),
+        }
+    }
+
+    // Keeps the device ids of published records, and raw records whole
+    #[derive(Default)]
+    struct RecordingSink {
+        published: Mutex<Vec<String>>,
+        raw: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    #[async_trait]
+    impl TelemetrySink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            self.published.lock().unwrap().push(record.key.to_string());
+            Ok(())
+        }
+
+        async fn publish_raw(&self, topic: &str, _key: &str, payload: &[u8]) -> Result<()> {
+            self.raw
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn reading(device_id: &str) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            ts: 0,
+            metrics: HashMap::from([("temperature".to_string(), 21.0)]),
+            raw: Vec::new(),
+            tags: HashMap::new(),
+            metadata: None,
+            samples: Vec::new(),
+            flag_metrics: HashMap::new(),
+            text_metrics: HashMap::new(),
+        }
+    }
+
+    fn expiring(expires_at: i64) -> Delivery {
+        Delivery {
+            expires_at: Some(expires_at),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_records_are_dead_lettered() {
+        let mut ctx = test_context();
+        ctx.dead_letters = Some(DeadLetterQueue::new("telemetry.dead".to_string()));
+        let ctx = Arc::new(ctx);
+        let sink = RecordingSink::default();
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics.insert("battery_level".to_string(), 140.0);
+
+        let Err(err) =
+            handle_telemetry(telemetry, &sink, "telemetry", &ctx, Delivery::default()).await
+        else {
+            panic!("out-of-range battery_level was accepted");
+        };
+        assert!(sink.published.lock().unwrap().is_empty());
+
+        {
+            let raw = sink.raw.lock().unwrap();
+            assert_eq!(raw.len(), 1);
+            assert_eq!(raw[0].0, "telemetry.dead");
+            let letter: serde_json::Value = serde_json::from_slice(&raw[0].1).unwrap();
+            assert_eq!(letter["device_id"], "sensor-1");
+            assert_eq!(letter["reason"], format!("{:#}", err));
+            assert_eq!(letter["record"]["metrics"]["battery_level"], 140.0);
+        }
+
+        // Accepted records don't go there
+        handle_telemetry(
+            reading("sensor-2"),
+            &sink,
+            "telemetry",
+            &ctx,
+            Delivery::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sink.raw.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_ttl_is_dropped_before_send() {
+        let ctx = Arc::new(test_context());
+        let sink = RecordingSink::default();
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let result = handle_telemetry(reading("stale"), &sink, "t", &ctx, expiring(now - 1)).await;
+        assert!(result.is_err_and(|e| e.is::<Expired>()));
+        assert_eq!(ctx.request_metrics.expired.get(), 1);
+
+        handle_telemetry(reading("fresh"), &sink, "t", &ctx, expiring(now + 60_000))
+            .await
+            .unwrap();
+        handle_telemetry(reading("no-ttl"), &sink, "t", &ctx, Delivery::default())
+            .await
+            .unwrap();
+        assert_eq!(*sink.published.lock().unwrap(), vec!["fresh", "no-ttl"]);
+        assert_eq!(ctx.request_metrics.expired.get(), 1);
+    }
+
+    // Fails every publish, with an open circuit for devices named "open"
+    struct FailingSink;
+
+    #[async_trait]
+    impl TelemetrySink for FailingSink {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        async fn publish(&self, record: SinkRecord<'_>) -> Result<()> {
+            if record.key == "open" {
+                return Err(CircuitOpen {
+                    topic: record.topic.to_string(),
+                    retry_after: std::time::Duration::from_secs(1),
+                }
+                .into());
+            }
+            Err(anyhow::anyhow!("broker unavailable"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_failures_are_counted() {
+        let ctx = Arc::new(test_context());
+        for device_id in ["a", "open", "b"] {
+            let result = handle_telemetry(
+                reading(device_id),
+                &FailingSink,
+                "t",
+                &ctx,
+                Delivery::default(),
+            )
+            .await;
+            assert!(result.is_err());
+        }
+        // The short-circuited send never reached the sink
+        assert_eq!(ctx.request_metrics.send_failures.get(), 2);
+    }
+
+    #[test]
+    fn test_resend_with_same_ts_is_dropped() {
+        let mut ctx = test_context();
+        ctx.resend_dedup = Some(ResendDedup::new(Default::default()));
+
+        let first = prepare_telemetry(reading("flaky"), "t", &ctx).unwrap();
+        assert!(first.dropped_by.is_none());
+        let resend = prepare_telemetry(reading("flaky"), "t", &ctx).unwrap();
+        assert_eq!(resend.dropped_by, Some(RESEND_DEDUP));
+        assert!(resend.payload.is_empty());
+
+        let mut next = reading("flaky");
+        next.ts += 1;
+        assert!(prepare_telemetry(next, "t", &ctx)
+            .unwrap()
+            .dropped_by
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reading_refused_after_dedup_is_published_on_retry() {
+        let mut ctx = test_context();
+        ctx.resend_dedup = Some(ResendDedup::new(Default::default()));
+        ctx.delay_queue = Some(DelayQueue::new(&DelayedDeliveryConfig {
+            enabled: true,
+            max_bytes: 1,
+            ..Default::default()
+        }));
+        ctx.max_message_bytes = 200;
+        let ctx = Arc::new(ctx);
+        let sink = RecordingSink::default();
+        let later = Delivery {
+            deliver_at: Some(chrono::Utc::now().timestamp_millis() + 60_000),
+            ..Default::default()
+        };
+
+        // The queue has no room, so the hold is refused
+        let Err(refused) = handle_telemetry(reading("flaky"), &sink, "t", &ctx, later).await else {
+            panic!("the hold was accepted");
+        };
+        assert!(refused.downcast_ref::<DelayRejection>().is_some());
+        let retry = handle_telemetry(reading("flaky"), &sink, "t", &ctx, Delivery::default())
+            .await
+            .unwrap();
+        assert!(retry.dropped_by.is_none());
+
+        // Same for a reading refused for its encoded size
+        let mut oversized = reading("bulky");
+        for i in 0..20 {
+            oversized.metrics.insert(format!("metric_{}", i), i as f64);
+        }
+        assert!(
+            handle_telemetry(oversized, &sink, "t", &ctx, Delivery::default())
+                .await
+                .is_err()
+        );
+        handle_telemetry(reading("bulky"), &sink, "t", &ctx, Delivery::default())
+            .await
+            .unwrap();
+
+        assert_eq!(*sink.published.lock().unwrap(), vec!["flaky", "bulky"]);
+    }
+
+    #[test]
+    fn test_forgotten_device_starts_afresh() {
+        let mut ctx = test_context();
+        ctx.content_dedup = Some(ContentDedup::new(Default::default()));
+        ctx.rate_of_change = Some(RateOfChangeChecker::new(Default::default()));
+
+        let first = prepare_telemetry(reading("retired"), "t", &ctx).unwrap();
+        assert!(first.dropped_by.is_none());
+        let resend = prepare_telemetry(reading("retired"), "t", &ctx).unwrap();
+        assert_eq!(resend.dropped_by, Some("content_dedup"));
+
+        assert_eq!(ctx.forget_device("retired"), 2);
+        assert_eq!(ctx.forget_device("retired"), 0);
+        let after = prepare_telemetry(reading("retired"), "t", &ctx).unwrap();
+        assert!(after.dropped_by.is_none());
+    }
+
+    #[test]
+    fn test_samples_only_record_is_validated() {
+        let ctx = test_context();
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics.clear();
+        telemetry.samples = vec![
+            Sample {
+                ts: 1,
+                metrics: HashMap::from([("humidity".to_string(), 40.0)]),
+            },
+            Sample {
+                ts: 2,
+                metrics: HashMap::from([("humidity".to_string(), 140.0)]),
+            },
+        ];
+        let prepared = prepare_telemetry(telemetry.clone(), "t", &ctx).unwrap();
+        assert_eq!(prepared.warnings.len(), 1);
+        assert_eq!(prepared.telemetry.samples.len(), 2);
+
+        telemetry.samples[0]
+            .metrics
+            .insert("battery_level".to_string(), 150.0);
+        assert!(prepare_telemetry(telemetry, "t", &ctx).is_err());
+    }
+
+    #[test]
+    fn test_collect_all_reports_every_bad_metric() {
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics.insert("battery_level".to_string(), 150.0);
+        telemetry.samples = vec![Sample {
+            ts: 1,
+            metrics: HashMap::from([("pressure".to_string(), f64::NAN)]),
+        }];
+
+        let err = prepare_telemetry(telemetry.clone(), "t", &test_context())
+            .err()
+            .unwrap();
+        let Some(TelemetryError::Validation(err)) = err.downcast_ref() else {
+            panic!("not a validation error: {}", err);
+        };
+        assert!(err.downcast_ref::<ValidationFailures>().is_none());
+
+        let ctx = HandlerContext {
+            validation_mode: ValidationMode::CollectAll,
+            ..test_context()
+        };
+        let err = prepare_telemetry(telemetry, "t", &ctx).err().unwrap();
+        let Some(TelemetryError::Validation(err)) = err.downcast_ref() else {
+            panic!("not a validation error: {}", err);
+        };
+        let failures = err.downcast_ref::<ValidationFailures>().unwrap();
+        assert_eq!(failures.0.len(), 2);
+    }
+
+    #[test]
+    fn test_allowlist_rejects_unknown_metrics() {
+        let ctx = HandlerContext {
+            allowed_metrics: ["temperature", "humidity"].map(String::from).into(),
+            ..test_context()
+        };
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics.insert("humidity".to_string(), 40.0);
+        assert!(prepare_telemetry(telemetry.clone(), "t", &ctx).is_ok());
+
+        telemetry.metrics.insert("temprature".to_string(), 21.0);
+        telemetry.samples = vec![Sample {
+            ts: 1,
+            metrics: HashMap::from([("presure".to_string(), 1013.0)]),
+        }];
+        let err = prepare_telemetry(telemetry.clone(), "t", &ctx)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<TelemetryError>(),
+            Some(TelemetryError::Validation(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Metrics not in the allowlist: presure, temprature"
+        );
+        assert_eq!(dry_run(telemetry.clone(), &ctx).errors.len(), 1);
+
+        // No allowlist: anything goes
+        assert!(prepare_telemetry(telemetry, "t", &test_context()).is_ok());
+    }
+
+    #[test]
+    fn test_metric_rules_from_config() {
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics.insert("battery_level".to_string(), 150.0);
+        telemetry.metrics.insert("co2_ppm".to_string(), 6000.0);
+
+        // Defaults only: battery_level fails, co2_ppm has no rule
+        let err = prepare_telemetry(telemetry.clone(), "t", &test_context())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("battery_level"), "{}", err);
+
+        let rule = |min, max, reject_out_of_range| MetricRule {
+            min,
+            max,
+            reject_out_of_range,
+        };
+        let ctx = HandlerContext {
+            metric_rules: MetricRules::new(&HashMap::from([
+                (
+                    "battery_level".to_string(),
+                    rule(Some(0.0), Some(100.0), false),
+                ),
+                ("co2_ppm".to_string(), rule(None, Some(5000.0), false)),
+                (
+                    "pressure".to_string(),
+                    rule(Some(800.0), Some(1200.0), true),
+                ),
+            ]))
+            .unwrap(),
+            ..test_context()
+        };
+        let prepared = prepare_telemetry(telemetry.clone(), "t", &ctx).unwrap();
+        let mut warned: Vec<_> = prepared
+            .warnings
+            .iter()
+            .map(|w| w.metric.as_str())
+            .collect();
+        warned.sort_unstable();
+        assert_eq!(warned, vec!["battery_level", "co2_ppm"]);
+
+        telemetry.metrics.insert("pressure".to_string(), 300.0);
+        let err = prepare_telemetry(telemetry, "t", &ctx).err().unwrap();
+        assert!(err.to_string().contains("pressure"), "{}", err);
+
+        let inverted = HashMap::from([("pressure".to_string(), rule(Some(2.0), Some(1.0), true))]);
+        assert!(MetricRules::new(&inverted).is_err());
+    }
+
+    #[test]
+    fn test_prepare_records_transforms() {
+        let ctx = HandlerContext {
+            metric_key_case: MetricKeyCase::Lower,
+            ..test_context()
+        };
+        let mut telemetry = reading("sensor-1");
+        telemetry.metrics = HashMap::from([("Temperature".to_string(), 21.0)]);
+        let prepared = prepare_telemetry(telemetry, "t", &ctx).unwrap();
+        assert_eq!(prepared.transforms, vec!["metric_key_case"]);
+        assert_eq!(metric_unit("temperature"), Some("celsius"));
+
+        // Already in the configured case: nothing to report
+        let prepared = prepare_telemetry(reading("sensor-1"), "t", &ctx).unwrap();
+        assert!(prepared.transforms.is_empty());
+    }
 
     #[test]
     fn test_create_telemetry_from_json() {
         let json = r#"{"temperature": 23.5, "humidity": 45.2}"#;
-        let telemetry = create_telemetry_from_json(json, "test-device").unwrap();
+        let telemetry = create_telemetry_from_json(
+            json,
+            "test-device",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
 
         assert_eq!(telemetry.device_id, "test-device");
         assert_eq!(telemetry.metrics.len(), 2);
@@ -140,24 +1523,274 @@ mod tests {
         assert_eq!(telemetry.metrics["humidity"], 45.2);
     }
 
+    #[test]
+    fn test_create_telemetry_from_json_keeps_typed_values() {
+        let json = r#"{
+            "temperature": 23.5,
+            "door_open": true,
+            "status": "charging",
+            "firmware": null,
+            "location": {"lat": 52.1}
+        }"#;
+        let telemetry = create_telemetry_from_json(
+            json,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
+
+        assert_eq!(
+            telemetry.metrics,
+            HashMap::from([("temperature".to_string(), 23.5)])
+        );
+        assert_eq!(
+            telemetry.flag_metrics,
+            HashMap::from([("door_open".to_string(), true)])
+        );
+        assert_eq!(
+            telemetry.text_metrics,
+            HashMap::from([("status".to_string(), "charging".to_string())])
+        );
+
+        // Survive the protobuf round trip
+        let decoded = Telemetry::decode(telemetry.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, telemetry);
+    }
+
+    #[test]
+    fn test_create_telemetry_from_json_catches_large_integers() {
+        let json = r#"{"uptime_ns": 1700000000123456789, "temperature": 23.5}"#;
+        let err = create_telemetry_from_json(
+            json,
+            "dev",
+            LargeIntegerPolicy::Reject,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("1700000000123456789"));
+
+        let telemetry = create_telemetry_from_json(
+            json,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
+        assert_eq!(
+            telemetry.metrics["uptime_ns"],
+            1_700_000_000_123_456_789u64 as f64
+        );
+    }
+
+    #[test]
+    fn test_create_telemetry_from_json_catches_duplicate_keys() {
+        let json = r#"{"temperature": 23.5, "humidity": 40, "temperature": 99.0}"#;
+        let err = create_telemetry_from_json(
+            json,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Reject,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "metric temperature is sent more than once");
+
+        let telemetry = create_telemetry_from_json(
+            json,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
+        assert_eq!(telemetry.metrics["temperature"], 99.0);
+        assert_eq!(telemetry.metrics["humidity"], 40.0);
+
+        assert!(create_telemetry_from_json(
+            "[23.5]",
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_enrich_telemetry_fills_structured_metadata() {
+        let telemetry = create_telemetry_from_json(
+            r#"{"temperature": 23.5}"#,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
+        let original_raw = telemetry.raw.clone();
+
+        let enriched = enrich_telemetry(telemetry.clone(), "node-1", false);
+        let metadata = encoding::struct_to_json(enriched.metadata.as_ref().unwrap());
+        assert_eq!(metadata["ingestion_node"], "node-1");
+        assert!(metadata["ingested_at"].is_string());
+        assert_eq!(enriched.raw, original_raw);
+
+        // Survives a protobuf round trip as typed fields
+        let decoded = Telemetry::decode(enriched.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded.metadata, enriched.metadata);
+
+        let legacy = enrich_telemetry(telemetry, "node-1", true);
+        let raw: serde_json::Value = serde_json::from_slice(&legacy.raw).unwrap();
+        assert_eq!(raw["ingestion_node"], "node-1");
+        assert!(legacy.metadata.is_some());
+    }
+
+    #[test]
+    fn test_enrichment_keeps_binary_raw_bytes() {
+        let original_raw = vec![0x0a, 0xff, 0x00, 0xfe, 0x80];
+        let telemetry = Telemetry {
+            device_id: "dev".to_string(),
+            raw: original_raw.clone(),
+            ..Default::default()
+        };
+
+        let enriched = enrich_telemetry(telemetry.clone(), "node-1", false);
+        let decoded = Telemetry::decode(enriched.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded.raw, original_raw);
+
+        let legacy = enrich_telemetry(telemetry, "node-1", true);
+        let raw: serde_json::Value = serde_json::from_slice(&legacy.raw).unwrap();
+        assert_eq!(raw["original_raw_encoding"], "base64");
+        let restored = BASE64
+            .decode(raw["original_raw"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(restored, original_raw);
+    }
+
+    #[test]
+    fn test_enrichment_runs_when_configured() {
+        let mut ctx = test_context();
+        ctx.node_id = "node-7".to_string();
+        ctx.enrichment = Some(Enrichment { legacy_raw: false });
+        let telemetry = create_telemetry_from_json(
+            r#"{"temperature": 23.5}"#,
+            "dev",
+            LargeIntegerPolicy::Warn,
+            DuplicateKeyPolicy::Warn,
+        )
+        .unwrap();
+        let original_raw = telemetry.raw.clone();
+
+        let prepared = prepare_telemetry(telemetry, "telemetry", &ctx).unwrap();
+        assert!(prepared.transforms.contains(&"enrichment"));
+        let metadata = encoding::struct_to_json(prepared.telemetry.metadata.as_ref().unwrap());
+        assert_eq!(metadata["ingestion_node"], "node-7");
+        assert_eq!(prepared.telemetry.raw, original_raw);
+    }
+
     #[test]
     fn test_validate_metrics() {
+        let rules = MetricRules::default();
+        assert!(rules
+            .validate_metric("temperature", 25.0)
+            .unwrap()
+            .is_none());
+        assert!(rules.validate_metric("humidity", 60.0).unwrap().is_none());
+
+        // Test invalid battery level
+        assert!(rules.validate_metric("battery_level", 150.0).is_err());
+    }
+
+    #[test]
+    fn test_normalized_keys_get_metric_rules() {
+        let rules = MetricRules::default();
         let mut metrics = HashMap::new();
-        metrics.insert("temperature".to_string(), 25.0);
-        metrics.insert("humidity".to_string(), 60.0);
+        metrics.insert("Temperature".to_string(), 500.0);
 
-        assert!(validate_metrics(&metrics).is_ok());
+        // Without normalization the mixed-case key escapes the temperature rule
+        assert!(rules
+            .validate_metric("Temperature", 500.0)
+            .unwrap()
+            .is_none());
 
-        // Test invalid battery level
-        metrics.insert("battery_level".to_string(), 150.0);
-        assert!(validate_metrics(&metrics).is_err());
+        let normalized = normalize_metric_keys(metrics, MetricKeyCase::Lower, "test-device");
+        let warning = rules
+            .validate_metric("temperature", normalized["temperature"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(warning.metric, "temperature");
+        assert_eq!(warning.severity, WarningSeverity::Major);
     }
 
     #[test]
-    fn test_validate_metrics_with_invalid_values() {
+    fn test_normalize_metric_keys_collisions_prefer_canonical() {
         let mut metrics = HashMap::new();
-        metrics.insert("temperature".to_string(), f64::NAN);
+        metrics.insert("HUMIDITY".to_string(), 1.0);
+        metrics.insert("humidity".to_string(), 2.0);
+        metrics.insert("Humidity".to_string(), 3.0);
 
-        assert!(validate_metrics(&metrics).is_err());
+        let normalized = normalize_metric_keys(metrics, MetricKeyCase::Lower, "test-device");
+        assert_eq!(normalized.len(), 1);
+        assert_eq!(normalized["humidity"], 2.0);
+
+        let mut metrics = HashMap::new();
+        metrics.insert("Battery_Level".to_string(), 50.0);
+        let normalized = normalize_metric_keys(metrics, MetricKeyCase::Upper, "test-device");
+        assert_eq!(normalized["BATTERY_LEVEL"], 50.0);
+    }
+
+    // A record being prepared on the validation pool leaves the runtime's
+    // only thread free for other tasks
+    #[test]
+    fn test_validation_pool_keeps_the_reactor_responsive() {
+        use crate::worker_pool::{ValidationPool, ValidationPoolConfig};
+        use std::{sync::mpsc, time::Duration};
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let ctx = Arc::new(HandlerContext {
+                validation_pool: Some(ValidationPool::new(&ValidationPoolConfig {
+                    enabled: true,
+                    size: Some(1),
+                })),
+                ..test_context()
+            });
+            let runtime_thread = std::thread::current().id();
+            let (started, job_started) = tokio::sync::oneshot::channel();
+            let (release, released) = mpsc::channel();
+            let job = tokio::spawn({
+                let ctx = Arc::clone(&ctx);
+                async move {
+                    let job_ctx = Arc::clone(&ctx);
+                    let pool = ctx.validation_pool.as_ref().unwrap();
+                    pool.run(move || {
+                        started.send(()).unwrap();
+                        // Only a safety net: were the job on the runtime's
+                        // thread, the task below could never run to release it
+                        let waited = released.recv_timeout(Duration::from_secs(10));
+                        prepare_telemetry(reading("pooled"), "t", &job_ctx).unwrap();
+                        (waited.is_ok(), std::thread::current().id())
+                    })
+                    .await
+                    .unwrap()
+                }
+            });
+
+            // Runs while the job is blocked
+            job_started.await.unwrap();
+            tokio::spawn(async move { release.send(()).unwrap() })
+                .await
+                .unwrap();
+            let (released, job_thread) = job.await.unwrap();
+            assert!(released, "the job was still holding the runtime's thread");
+            assert_ne!(job_thread, runtime_thread);
+        });
+    }
+
+    #[test]
+    fn test_validate_metrics_with_invalid_values() {
+        let rules = MetricRules::default();
+        assert!(rules.validate_metric("temperature", f64::NAN).is_err());
+        // Checked whether or not the metric has a range rule
+        assert!(rules.validate_metric("pressure", f64::INFINITY).is_err());
+        assert!(rules.validate_metric("", 1.0).is_err());
     }
 }
@@ -0,0 +1,381 @@
+use crate::rate_limit::TokenBucket;
+use chrono::{Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TenancyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // device_id prefix -> tenant id, longest prefix wins
+    #[serde(default)]
+    pub device_prefixes: HashMap<String, String>,
+    #[serde(default)]
+    pub default_tenant: Option<String>,
+    // Limits applied to tenants without an explicit entry in `tenants`
+    #[serde(default)]
+    pub default_limits: TenantLimits,
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantLimits>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TenantLimits {
+    pub rate_per_sec: Option<f64>,
+    pub burst: Option<f64>,
+    pub monthly_record_quota: Option<u64>,
+    pub monthly_byte_quota: Option<u64>,
+}
+
+impl TenancyConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.default_limits.validate("tenancy.default_limits")?;
+        for (tenant, limits) in &self.tenants {
+            limits.validate(&format!("tenancy.tenants.{}", tenant))?;
+        }
+        Ok(())
+    }
+}
+
+impl TenantLimits {
+    fn validate(&self, key: &str) -> anyhow::Result<()> {
+        if let Some(rate) = self
+            .rate_per_sec
+            .filter(|rate| rate.is_nan() || *rate <= 0.0)
+        {
+            return Err(anyhow::anyhow!(
+                "{}.rate_per_sec must be above 0, got {}; leave it unset for no rate limit",
+                key,
+                rate
+            ));
+        }
+        if let Some(burst) = self.burst.filter(|burst| burst.is_nan() || *burst < 1.0) {
+            return Err(anyhow::anyhow!(
+                "{}.burst must be at least 1, got {}",
+                key,
+                burst
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TenantRejection {
+    RateLimited {
+        retry_after: Duration,
+    },
+    QuotaExceeded {
+        quota: &'static str,
+        resets_in: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub period: String,
+    pub records: u64,
+    pub bytes: u64,
+    pub rate_limited: u64,
+    pub quota_rejected: u64,
+    pub monthly_record_quota: Option<u64>,
+    pub monthly_byte_quota: Option<u64>,
+}
+
+struct TenantState {
+    bucket: Option<TokenBucket>,
+    period: (i32, u32),
+    records: u64,
+    bytes: u64,
+    rate_limited: u64,
+    quota_rejected: u64,
+}
+
+pub struct TenantRegistry {
+    config: TenancyConfig,
+    // Keyed by tenant id; tenants only come from config so this stays bounded
+    state: Mutex<HashMap<String, TenantState>>,
+}
+
+impl TenantRegistry {
+    pub fn new(config: TenancyConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn resolve_tenant(&self, device_id: &str) -> Option<&str> {
+        self.config
+            .device_prefixes
+            .iter()
+            .filter(|(prefix, _)| device_id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, tenant)| tenant.as_str())
+            .or(self.config.default_tenant.as_deref())
+    }
+
+    fn limits_for(&self, tenant: &str) -> &TenantLimits {
+        self.config
+            .tenants
+            .get(tenant)
+            .unwrap_or(&self.config.default_limits)
+    }
+
+    pub fn admit(&self, tenant: &str, bytes: u64) -> Result<(), TenantRejection> {
+        let now = chrono::Utc::now();
+        self.admit_at(tenant, bytes, Instant::now(), (now.year(), now.month()))
+    }
+
+    fn admit_at(
+        &self,
+        tenant: &str,
+        bytes: u64,
+        now: Instant,
+        period: (i32, u32),
+    ) -> Result<(), TenantRejection> {
+        let limits = self.limits_for(tenant).clone();
+        let mut state = self.state.lock().unwrap();
+        let entry = state
+            .entry(tenant.to_string())
+            .or_insert_with(|| TenantState {
+                bucket: limits
+                    .rate_per_sec
+                    .map(|rate| TokenBucket::new(rate, limits.burst.unwrap_or(rate).max(1.0), now)),
+                period,
+                records: 0,
+                bytes: 0,
+                rate_limited: 0,
+                quota_rejected: 0,
+            });
+
+        // Quotas are per calendar month (UTC), reset lazily on first use in a new month
+        if entry.period != period {
+            entry.period = period;
+            entry.records = 0;
+            entry.bytes = 0;
+            entry.rate_limited = 0;
+            entry.quota_rejected = 0;
+        }
+
+        let over_quota = if limits
+            .monthly_record_quota
+            .is_some_and(|quota| entry.records + 1 > quota)
+        {
+            Some("records")
+        } else if limits
+            .monthly_byte_quota
+            .is_some_and(|quota| entry.bytes + bytes > quota)
+        {
+            Some("bytes")
+        } else {
+            None
+        };
+        if let Some(quota) = over_quota {
+            entry.quota_rejected += 1;
+            return Err(TenantRejection::QuotaExceeded {
+                quota,
+                resets_in: until_next_period(period),
+            });
+        }
+
+        if let Some(bucket) = entry.bucket.as_mut() {
+            if let Err(retry_after) = bucket.try_take(now) {
+                entry.rate_limited += 1;
+                return Err(TenantRejection::RateLimited { retry_after });
+            }
+        }
+
+        entry.records += 1;
+        entry.bytes += bytes;
+        Ok(())
+    }
+
+    pub fn usage(&self, tenant: &str) -> Option<TenantUsage> {
+        let state = self.state.lock().unwrap();
+        let known = state.contains_key(tenant) || self.config.tenants.contains_key(tenant);
+        if !known {
+            return None;
+        }
+        Some(self.usage_entry(tenant, state.get(tenant)))
+    }
+
+    pub fn all_usage(&self) -> Vec<TenantUsage> {
+        let state = self.state.lock().unwrap();
+        let mut tenants: Vec<&String> = state.keys().chain(self.config.tenants.keys()).collect();
+        tenants.sort();
+        tenants.dedup();
+        tenants
+            .into_iter()
+            .map(|tenant| self.usage_entry(tenant, state.get(tenant)))
+            .collect()
+    }
+
+    fn usage_entry(&self, tenant: &str, entry: Option<&TenantState>) -> TenantUsage {
+        let limits = self.limits_for(tenant);
+        let now = chrono::Utc::now();
+        let period = entry.map(|e| e.period).unwrap_or((now.year(), now.month()));
+        TenantUsage {
+            tenant: tenant.to_string(),
+            period: format!("{:04}-{:02}", period.0, period.1),
+            records: entry.map_or(0, |e| e.records),
+            bytes: entry.map_or(0, |e| e.bytes),
+            rate_limited: entry.map_or(0, |e| e.rate_limited),
+            quota_rejected: entry.map_or(0, |e| e.quota_rejected),
+            monthly_record_quota: limits.monthly_record_quota,
+            monthly_byte_quota: limits.monthly_byte_quota,
+        }
+    }
+}
+
+fn until_next_period((year, month): (i32, u32)) -> Duration {
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    Utc.with_ymd_and_hms(next_year, next_month, 1, 0, 0, 0)
+        .single()
+        .map(|reset| (reset - Utc::now()).to_std().unwrap_or_default())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> TenantRegistry {
+        let mut config = TenancyConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        config
+            .device_prefixes
+            .insert("acme-".to_string(), "acme".to_string());
+        config
+            .device_prefixes
+            .insert("acme-lab-".to_string(), "acme-lab".to_string());
+        config
+            .device_prefixes
+            .insert("globex-".to_string(), "globex".to_string());
+        config.tenants.insert(
+            "acme".to_string(),
+            TenantLimits {
+                rate_per_sec: Some(1.0),
+                burst: Some(2.0),
+                ..Default::default()
+            },
+        );
+        config.tenants.insert(
+            "globex".to_string(),
+            TenantLimits {
+                monthly_record_quota: Some(3),
+                monthly_byte_quota: Some(1000),
+                ..Default::default()
+            },
+        );
+        TenantRegistry::new(config)
+    }
+
+    #[test]
+    fn test_resolve_tenant_longest_prefix() {
+        let registry = registry();
+        assert_eq!(registry.resolve_tenant("acme-001"), Some("acme"));
+        assert_eq!(registry.resolve_tenant("acme-lab-001"), Some("acme-lab"));
+        assert_eq!(registry.resolve_tenant("initech-001"), None);
+    }
+
+    #[test]
+    fn test_limits_that_would_never_admit_are_rejected() {
+        let mut config = TenancyConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.default_limits.rate_per_sec = Some(0.0);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("tenancy.default_limits.rate_per_sec"),
+            "{}",
+            err
+        );
+
+        config.default_limits.rate_per_sec = None;
+        config.tenants.insert(
+            "acme".to_string(),
+            TenantLimits {
+                rate_per_sec: Some(5.0),
+                burst: Some(0.5),
+                ..Default::default()
+            },
+        );
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("tenancy.tenants.acme.burst"), "{}", err);
+
+        config.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_rate_limit_is_isolated_per_tenant() {
+        let registry = registry();
+        let now = Instant::now();
+
+        assert!(registry.admit_at("acme", 10, now, (2024, 1)).is_ok());
+        assert!(registry.admit_at("acme", 10, now, (2024, 1)).is_ok());
+        assert!(matches!(
+            registry.admit_at("acme", 10, now, (2024, 1)),
+            Err(TenantRejection::RateLimited { .. })
+        ));
+
+        // A burst from acme must not affect globex
+        assert!(registry.admit_at("globex", 10, now, (2024, 1)).is_ok());
+
+        // Tokens refill over time
+        let later = now + Duration::from_secs(1);
+        assert!(registry.admit_at("acme", 10, later, (2024, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_monthly_quota_and_reset() {
+        let registry = registry();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(registry.admit_at("globex", 10, now, (2024, 1)).is_ok());
+        }
+        assert!(matches!(
+            registry.admit_at("globex", 10, now, (2024, 1)),
+            Err(TenantRejection::QuotaExceeded {
+                quota: "records",
+                ..
+            })
+        ));
+
+        // A new month starts a fresh quota
+        assert!(registry.admit_at("globex", 10, now, (2024, 2)).is_ok());
+        assert!(matches!(
+            registry.admit_at("globex", 2000, now, (2024, 2)),
+            Err(TenantRejection::QuotaExceeded { quota: "bytes", .. })
+        ));
+
+        let usage = registry.usage("globex").unwrap();
+        assert_eq!(usage.period, "2024-02");
+        assert_eq!(usage.records, 1);
+        assert_eq!(usage.bytes, 10);
+        assert_eq!(usage.quota_rejected, 1);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{bounded_store::BoundedStore, proto::telemetry::Telemetry};
+use prost_types::{value::Kind, Struct, Value};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// What to do with a record that lands on a grid point already emitted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GridCollision {
+    #[default]
+    Keep,
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GridRule {
+    pub grid_ms: i64,
+    #[serde(default)]
+    pub on_collision: GridCollision,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimeGridConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Rule for devices whose type has no entry in `device_types`
+    #[serde(default)]
+    pub default: Option<GridRule>,
+    #[serde(default)]
+    pub device_types: HashMap<String, GridRule>,
+    #[serde(default = "default_max_devices")]
+    pub max_devices: usize,
+    #[serde(default = "default_idle_eviction_secs")]
+    pub idle_eviction_secs: u64,
+}
+
+impl Default for TimeGridConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default: None,
+            device_types: HashMap::new(),
+            max_devices: default_max_devices(),
+            idle_eviction_secs: default_idle_eviction_secs(),
+        }
+    }
+}
+
+fn default_max_devices() -> usize {
+    10_000
+}
+
+fn default_idle_eviction_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Alignment {
+    // No rule for this device, `ts` left alone
+    Unchanged,
+    Aligned,
+    // Snapped onto a grid point already emitted, and the rule says drop
+    Collided,
+}
+
+// Snaps `ts` to the nearest grid point for time-series stores that want
+// samples on fixed boundaries. The original timestamp is kept in the
+// record's metadata as `original_ts`.
+pub struct GridAligner {
+    config: TimeGridConfig,
+    // Last grid point emitted per device
+    emitted: Mutex<BoundedStore<i64>>,
+}
+
+impl GridAligner {
+    pub fn new(config: TimeGridConfig) -> Self {
+        let emitted = BoundedStore::new(
+            config.max_devices,
+            Duration::from_secs(config.idle_eviction_secs),
+        );
+        Self {
+            config,
+            emitted: Mutex::new(emitted),
+        }
+    }
+
+    // Drops the device's state; returns whether there was any
+    pub fn forget(&self, device_id: &str) -> bool {
+        self.emitted.lock().unwrap().remove(device_id)
+    }
+
+    pub fn apply(&self, telemetry: &mut Telemetry, device_type: Option<&str>) -> Alignment {
+        self.apply_at(telemetry, device_type, Instant::now())
+    }
+
+    fn apply_at(
+        &self,
+        telemetry: &mut Telemetry,
+        device_type: Option<&str>,
+        now: Instant,
+    ) -> Alignment {
+        let rule = device_type
+            .and_then(|t| self.config.device_types.get(t))
+            .or(self.config.default.as_ref());
+        let Some(rule) = rule.filter(|rule| rule.grid_ms > 0) else {
+            return Alignment::Unchanged;
+        };
+
+        let original = telemetry.ts;
+        let snapped = (original + rule.grid_ms / 2).div_euclid(rule.grid_ms) * rule.grid_ms;
+
+        let mut emitted = self.emitted.lock().unwrap();
+        let last = emitted.get_or_insert_with(&telemetry.device_id, now, || i64::MIN);
+        if snapped == *last && rule.on_collision == GridCollision::Drop {
+            return Alignment::Collided;
+        }
+        *last = (*last).max(snapped);
+        drop(emitted);
+
+        telemetry.ts = snapped;
+        telemetry
+            .metadata
+            .get_or_insert_with(Struct::default)
+            .fields
+            .insert(
+                "original_ts".to_string(),
+                Value {
+                    kind: Some(Kind::NumberValue(original as f64)),
+                },
+            );
+        Alignment::Aligned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aligner(on_collision: GridCollision) -> GridAligner {
+        GridAligner::new(TimeGridConfig {
+            enabled: true,
+            default: None,
+            device_types: HashMap::from([(
+                "meter".to_string(),
+                GridRule {
+                    grid_ms: 10_000,
+                    on_collision,
+                },
+            )]),
+            ..Default::default()
+        })
+    }
+
+    fn reading(ts: i64) -> Telemetry {
+        Telemetry {
+            device_id: "meter-1".to_string(),
+            ts,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ts_snaps_to_nearest_grid_point() {
+        let aligner = aligner(GridCollision::Keep);
+        let now = Instant::now();
+        for (ts, expected) in [
+            (1_004_999, 1_000_000),
+            (1_005_000, 1_010_000),
+            (999_990, 1_000_000),
+        ] {
+            let mut telemetry = reading(ts);
+            assert_eq!(
+                aligner.apply_at(&mut telemetry, Some("meter"), now),
+                Alignment::Aligned
+            );
+            assert_eq!(telemetry.ts, expected);
+            let original = &telemetry.metadata.unwrap().fields["original_ts"];
+            assert_eq!(original.kind, Some(Kind::NumberValue(ts as f64)));
+        }
+
+        // Other device types have no grid
+        let mut telemetry = reading(1_004_999);
+        assert_eq!(
+            aligner.apply_at(&mut telemetry, Some("gps"), now),
+            Alignment::Unchanged
+        );
+        assert_eq!(telemetry.ts, 1_004_999);
+    }
+
+    #[test]
+    fn test_collision_policy() {
+        let now = Instant::now();
+
+        let dropping = aligner(GridCollision::Drop);
+        assert_eq!(
+            dropping.apply_at(&mut reading(1_001_000), Some("meter"), now),
+            Alignment::Aligned
+        );
+        assert_eq!(
+            dropping.apply_at(&mut reading(1_003_000), Some("meter"), now),
+            Alignment::Collided
+        );
+        assert_eq!(
+            dropping.apply_at(&mut reading(1_009_000), Some("meter"), now),
+            Alignment::Aligned
+        );
+
+        let keeping = aligner(GridCollision::Keep);
+        keeping.apply_at(&mut reading(1_001_000), Some("meter"), now);
+        let mut second = reading(1_003_000);
+        assert_eq!(
+            keeping.apply_at(&mut second, Some("meter"), now),
+            Alignment::Aligned
+        );
+        assert_eq!(second.ts, 1_000_000);
+    }
+}
@@ -0,0 +1,157 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::fmt;
+
+// Integers below this are unix seconds, anything larger unix millis: a
+// 10-digit value is seconds until the year 2286, and a millis value only
+// drops under it for times in early 1973
+const SECONDS_BELOW: i64 = 100_000_000_000;
+
+// Unix millis from a timestamp as devices send it: integer or fractional
+// seconds, millis, either of those as a string, or an RFC 3339 time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    pub fn from_integer(value: i64) -> Option<Self> {
+        if value.unsigned_abs() < SECONDS_BELOW as u64 {
+            value.checked_mul(1000).map(Self)
+        } else {
+            Some(Self(value))
+        }
+    }
+
+    pub fn from_float(value: f64) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+        let millis = if value.abs() < SECONDS_BELOW as f64 {
+            value * 1000.0
+        } else {
+            value
+        };
+        // Outside i64, the cast would saturate rather than fail
+        (millis.abs() < i64::MAX as f64).then(|| Self(millis.round() as i64))
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if let Ok(integer) = value.parse::<i64>() {
+            return Self::from_integer(integer);
+        }
+        if let Ok(float) = value.parse::<f64>() {
+            return Self::from_float(float);
+        }
+        chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|time| Self(time.timestamp_millis()))
+    }
+}
+
+pub const EXPECTED: &str = "unix seconds, unix millis or an RFC 3339 time";
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(EXPECTED)
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Timestamp, E> {
+                Timestamp::from_integer(value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Timestamp, E> {
+                i64::try_from(value)
+                    .ok()
+                    .and_then(Timestamp::from_integer)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Timestamp, E> {
+                Timestamp::from_float(value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Float(value), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Timestamp, E> {
+                Timestamp::parse(value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+// For `deserialize_with` on unix millis fields
+pub fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    Timestamp::deserialize(deserializer).map(|ts| ts.0)
+}
+
+pub fn optional_millis<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<i64>, D::Error> {
+    Option::<Timestamp>::deserialize(deserializer).map(|ts| ts.map(|ts| ts.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_json(json: &str) -> Result<i64, serde_json::Error> {
+        serde_json::from_str::<Timestamp>(json).map(|ts| ts.0)
+    }
+
+    #[test]
+    fn test_integers_are_told_apart_by_magnitude() {
+        assert_eq!(from_json("1700000000").unwrap(), 1700000000000);
+        assert_eq!(from_json("1700000000123").unwrap(), 1700000000123);
+        assert_eq!(from_json("0").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fractional_seconds_keep_their_millis() {
+        assert_eq!(from_json("1700000000.25").unwrap(), 1700000000250);
+        assert_eq!(from_json("1700000000123.0").unwrap(), 1700000000123);
+    }
+
+    #[test]
+    fn test_strings_hold_numbers_or_rfc3339_times() {
+        assert_eq!(from_json(r#""1700000000""#).unwrap(), 1700000000000);
+        assert_eq!(from_json(r#""1700000000123""#).unwrap(), 1700000000123);
+        assert_eq!(
+            from_json(r#""2023-11-14T22:13:20.123Z""#).unwrap(),
+            1700000000123
+        );
+        assert_eq!(
+            from_json(r#""2023-11-14T23:13:20+01:00""#).unwrap(),
+            1700000000000
+        );
+    }
+
+    #[test]
+    fn test_unparseable_values_are_rejected() {
+        for json in [r#""yesterday""#, r#""2023-11-14""#, "true", "[1]"] {
+            let err = from_json(json).unwrap_err().to_string();
+            assert!(err.contains(EXPECTED), "{}: {}", json, err);
+        }
+        assert!(from_json("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn test_optional_field() {
+        #[derive(serde::Deserialize)]
+        struct Record {
+            #[serde(default, deserialize_with = "optional_millis")]
+            ts: Option<i64>,
+        }
+        let ts = |json: &str| serde_json::from_str::<Record>(json).unwrap().ts;
+        assert_eq!(ts("{}"), None);
+        assert_eq!(ts(r#"{"ts": null}"#), None);
+        assert_eq!(ts(r#"{"ts": 1700000000}"#), Some(1700000000000));
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+// Hashed timing wheel. Deadlines past the wheel's horizon land in its last
+// slot, so an entry can come up early: callers check the deadline of what
+// `advance` returns and schedule it again when it isn't due yet. That also
+// means rescheduling never has to find an entry's old slot.
+pub struct TimingWheel<T> {
+    slots: Vec<Vec<T>>,
+    tick: Duration,
+    cursor: usize,
+    // Start of the slot under the cursor
+    cursor_time: Instant,
+}
+
+impl<T> TimingWheel<T> {
+    pub fn new(tick: Duration, slots: usize, now: Instant) -> Self {
+        Self {
+            slots: (0..slots.max(2)).map(|_| Vec::new()).collect(),
+            tick: tick.max(Duration::from_millis(1)),
+            cursor: 0,
+            cursor_time: now,
+        }
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    pub fn schedule(&mut self, item: T, deadline: Instant) {
+        let ticks = deadline
+            .saturating_duration_since(self.cursor_time)
+            .as_nanos()
+            / self.tick.as_nanos();
+        let offset = (ticks as usize).min(self.slots.len() - 1);
+        let slot = (self.cursor + offset) % self.slots.len();
+        self.slots[slot].push(item);
+    }
+
+    // Takes out every entry `matches` picks, wherever it is scheduled
+    pub fn remove_where(&mut self, mut matches: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for slot in &mut self.slots {
+            let mut kept = Vec::with_capacity(slot.len());
+            for item in slot.drain(..) {
+                if matches(&item) {
+                    removed.push(item);
+                } else {
+                    kept.push(item);
+                }
+            }
+            *slot = kept;
+        }
+        removed
+    }
+
+    // Entries from every slot that ended by `now`
+    pub fn advance(&mut self, now: Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        for _ in 0..self.slots.len() {
+            if self.cursor_time + self.tick > now {
+                return due;
+            }
+            due.append(&mut self.slots[self.cursor]);
+            self.cursor = (self.cursor + 1) % self.slots.len();
+            self.cursor_time += self.tick;
+        }
+        // A whole turn behind (e.g. the task was starved): everything has
+        // been drained, so just catch the clock up
+        let behind =
+            now.saturating_duration_since(self.cursor_time).as_nanos() / self.tick.as_nanos();
+        self.cursor_time += self.tick * behind as u32;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_come_up_once_their_slot_ends() {
+        let start = Instant::now();
+        let ms = |n: u64| start + Duration::from_millis(n);
+        let mut wheel = TimingWheel::new(Duration::from_millis(10), 8, start);
+        wheel.schedule("a", ms(25));
+        wheel.schedule("b", ms(5));
+
+        assert_eq!(wheel.advance(ms(9)), Vec::<&str>::new());
+        assert_eq!(wheel.advance(ms(10)), vec!["b"]);
+        assert_eq!(wheel.advance(ms(29)), Vec::<&str>::new());
+        assert_eq!(wheel.advance(ms(30)), vec!["a"]);
+
+        // Beyond the horizon: comes up early, in the last slot
+        wheel.schedule("far", ms(1_000));
+        assert_eq!(wheel.advance(ms(110)), vec!["far"]);
+    }
+}
@@ -0,0 +1,259 @@
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::Span;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraceSamplingConfig {
+    // Share of requests traced, 0.0 to 1.0
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    // Requests carrying this header (any value but "0"/"false") are always traced
+    #[serde(default = "default_force_header")]
+    pub force_header: String,
+    // Records from these devices are always traced
+    #[serde(default)]
+    pub force_devices: Vec<String>,
+}
+
+impl Default for TraceSamplingConfig {
+    fn default() -> Self {
+        Self {
+            rate: default_rate(),
+            force_header: default_force_header(),
+            force_devices: Vec::new(),
+        }
+    }
+}
+
+fn default_rate() -> f64 {
+    1.0
+}
+
+fn default_force_header() -> String {
+    "x-debug-trace".to_string()
+}
+
+// Header a caller's W3C trace context comes in on
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// The caller's W3C trace context (version 00), so spans here can be tied
+// to the trace the originating gateway started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    // The caller recorded its side of the trace
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    // None when the header is missing or malformed; a bad traceparent
+    // starts a new trace rather than failing the request
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(TRACEPARENT_HEADER)?.to_str().ok()?;
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, parent_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+        let is_hex = |s: &str, len: usize| {
+            s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        // All-zero ids are invalid by the spec
+        if !is_hex(trace_id, 32)
+            || !is_hex(parent_id, 16)
+            || !is_hex(flags, 2)
+            || trace_id.bytes().all(|b| b == b'0')
+            || parent_id.bytes().all(|b| b == b'0')
+        {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+// Head-based sampling decision, made once when a request arrives and carried
+// in the request extensions so the request span and the handlers agree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceDecision {
+    pub sampled: bool,
+}
+
+pub struct TraceSampler {
+    rate: f64,
+    force_header: String,
+    force_devices: HashSet<String>,
+    requests: AtomicU64,
+}
+
+impl TraceSampler {
+    pub fn new(config: TraceSamplingConfig) -> Self {
+        Self {
+            rate: config.rate.clamp(0.0, 1.0),
+            force_header: config.force_header.to_ascii_lowercase(),
+            force_devices: config.force_devices.into_iter().collect(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn decide(&self, headers: &HeaderMap) -> TraceDecision {
+        let forced = headers
+            .get(self.force_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| !matches!(value, "0" | "false"));
+        // A trace the caller is recording shouldn't have a hole where this
+        // service is
+        let upstream = TraceParent::from_headers(headers).is_some_and(|parent| parent.sampled);
+        TraceDecision {
+            sampled: forced || upstream || self.sample(),
+        }
+    }
+
+    // Spreads the sampled share evenly over the request sequence
+    fn sample(&self) -> bool {
+        if self.rate >= 1.0 {
+            return true;
+        }
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut hash = n.wrapping_add(0x9e3779b97f4a7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+        ((hash >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+
+    // Whether a record is traced, given the request's decision
+    pub fn traces_device(&self, decision: TraceDecision, device_id: &str) -> bool {
+        decision.sampled || self.force_devices.contains(device_id)
+    }
+}
+
+pub async fn sample_request(
+    State(sampler): State<Arc<TraceSampler>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let decision = sampler.decide(request.headers());
+    request.extensions_mut().insert(decision);
+    next.run(request).await
+}
+
+// Span for the HTTP trace layer; unsampled requests get a disabled span
+pub fn request_span<B>(request: &axum::http::Request<B>) -> Span {
+    match request.extensions().get::<TraceDecision>() {
+        Some(TraceDecision { sampled: false }) => Span::none(),
+        _ => {
+            let span = tracing::info_span!(
+                "request",
+                method = %request.method(),
+                uri = %request.uri(),
+                // Filled in by request_id::assign_request_id on ingest routes
+                request_id = tracing::field::Empty,
+                trace_id = tracing::field::Empty,
+                parent_span_id = tracing::field::Empty,
+            );
+            if let Some(parent) = TraceParent::from_headers(request.headers()) {
+                span.record("trace_id", parent.trace_id.as_str());
+                span.record("parent_span_id", parent.parent_id.as_str());
+            }
+            span
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler(rate: f64) -> TraceSampler {
+        TraceSampler::new(TraceSamplingConfig {
+            rate,
+            force_devices: vec!["suspect-1".to_string()],
+            ..Default::default()
+        })
+    }
+
+    fn sampled_share(sampler: &TraceSampler, headers: &HeaderMap) -> f64 {
+        let sampled = (0..10_000)
+            .filter(|_| sampler.decide(headers).sampled)
+            .count();
+        sampled as f64 / 10_000.0
+    }
+
+    #[test]
+    fn test_rate_controls_sampled_share() {
+        let headers = HeaderMap::new();
+        assert_eq!(sampled_share(&sampler(0.0), &headers), 0.0);
+        assert_eq!(sampled_share(&sampler(1.0), &headers), 1.0);
+        assert!((sampled_share(&sampler(0.01), &headers) - 0.01).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_force_header_overrides_rate() {
+        let sampler = sampler(0.0);
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Debug-Trace", "1".parse().unwrap());
+        assert_eq!(sampled_share(&sampler, &headers), 1.0);
+
+        headers.insert("X-Debug-Trace", "false".parse().unwrap());
+        assert_eq!(sampled_share(&sampler, &headers), 0.0);
+    }
+
+    #[test]
+    fn test_traceparent_is_parsed() {
+        let with = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACEPARENT_HEADER, value.parse().unwrap());
+            headers
+        };
+        let parent = TraceParent::from_headers(&with(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ))
+        .unwrap();
+        assert_eq!(parent.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(parent.parent_id, "00f067aa0ba902b7");
+        assert!(parent.sampled);
+
+        for bad in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceParent::from_headers(&with(bad)), None, "{}", bad);
+        }
+
+        // The caller's sampling decision carries over
+        let sampler = sampler(0.0);
+        let sampled = with("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        let unsampled = with("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00");
+        assert_eq!(sampled_share(&sampler, &sampled), 1.0);
+        assert_eq!(sampled_share(&sampler, &unsampled), 0.0);
+    }
+
+    #[test]
+    fn test_forced_device_is_traced() {
+        let sampler = sampler(0.0);
+        let decision = sampler.decide(&HeaderMap::new());
+        assert!(!sampler.traces_device(decision, "sensor-1"));
+        assert!(sampler.traces_device(decision, "suspect-1"));
+    }
+}
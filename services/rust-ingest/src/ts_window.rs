@@ -0,0 +1,147 @@
+use serde::Deserialize;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutOfWindow {
+    // Fail the request with a 400
+    #[default]
+    Reject,
+    // Move the timestamp to the nearest edge of the window and carry on
+    Clamp,
+}
+
+// Plausible range for record timestamps relative to server time, to keep
+// devices with broken clocks from writing decades into the past or future.
+// The past limit is usually the larger one: devices legitimately upload
+// readings they buffered while offline.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimestampWindowConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_past_secs")]
+    pub max_past_secs: u64,
+    #[serde(default = "default_max_future_secs")]
+    pub max_future_secs: u64,
+    #[serde(default)]
+    pub out_of_window: OutOfWindow,
+}
+
+impl Default for TimestampWindowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_past_secs: default_max_past_secs(),
+            max_future_secs: default_max_future_secs(),
+            out_of_window: OutOfWindow::default(),
+        }
+    }
+}
+
+fn default_max_past_secs() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_max_future_secs() -> u64 {
+    300
+}
+
+pub struct TimestampWindow {
+    max_past_ms: i64,
+    max_future_ms: i64,
+    out_of_window: OutOfWindow,
+}
+
+impl TimestampWindow {
+    pub fn new(config: &TimestampWindowConfig) -> Self {
+        let millis = |secs: u64| i64::try_from(secs.saturating_mul(1000)).unwrap_or(i64::MAX);
+        Self {
+            max_past_ms: millis(config.max_past_secs),
+            max_future_ms: millis(config.max_future_secs),
+            out_of_window: config.out_of_window,
+        }
+    }
+
+    // The timestamp to use for a reading stamped `ts` (unix millis) and
+    // received at `now`, or why it was refused
+    pub fn apply(&self, device_id: &str, ts: i64, now: i64) -> Result<i64, String> {
+        let earliest = now.saturating_sub(self.max_past_ms);
+        let latest = now.saturating_add(self.max_future_ms);
+        if (earliest..=latest).contains(&ts) {
+            return Ok(ts);
+        }
+        let (edge, description) = if ts < earliest {
+            (
+                earliest,
+                format!(
+                    "ts {} is {}s in the past, more than the allowed {}s",
+                    ts,
+                    (now - ts) / 1000,
+                    self.max_past_ms / 1000
+                ),
+            )
+        } else {
+            (
+                latest,
+                format!(
+                    "ts {} is {}s in the future, more than the allowed {}s",
+                    ts,
+                    (ts - now) / 1000,
+                    self.max_future_ms / 1000
+                ),
+            )
+        };
+        match self.out_of_window {
+            OutOfWindow::Reject => Err(description),
+            OutOfWindow::Clamp => {
+                warn!(
+                    "Clamped timestamp for device {} to {}: {}",
+                    device_id, edge, description
+                );
+                Ok(edge)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn window(out_of_window: OutOfWindow) -> TimestampWindow {
+        TimestampWindow::new(&TimestampWindowConfig {
+            enabled: true,
+            max_past_secs: 3600,
+            max_future_secs: 60,
+            out_of_window,
+        })
+    }
+
+    #[test]
+    fn test_past_and_future_skew_are_rejected() {
+        let window = window(OutOfWindow::Reject);
+        assert_eq!(window.apply("d", NOW, NOW), Ok(NOW));
+        assert_eq!(window.apply("d", NOW - 3_600_000, NOW), Ok(NOW - 3_600_000));
+        assert_eq!(window.apply("d", NOW + 60_000, NOW), Ok(NOW + 60_000));
+
+        // A clock reset to the epoch
+        let err = window.apply("d", 0, NOW).unwrap_err();
+        assert!(err.contains("in the past"), "{}", err);
+        // Thirty years ahead
+        let err = window
+            .apply("d", NOW + 30 * 365 * 86_400_000, NOW)
+            .unwrap_err();
+        assert!(err.contains("in the future"), "{}", err);
+        assert!(window.apply("d", NOW + 60_001, NOW).is_err());
+    }
+
+    #[test]
+    fn test_clamping_moves_to_the_nearest_edge() {
+        let window = window(OutOfWindow::Clamp);
+        assert_eq!(window.apply("d", 0, NOW), Ok(NOW - 3_600_000));
+        assert_eq!(window.apply("d", i64::MAX, NOW), Ok(NOW + 60_000));
+        assert_eq!(window.apply("d", NOW - 5, NOW), Ok(NOW - 5));
+    }
+}
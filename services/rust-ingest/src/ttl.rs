@@ -0,0 +1,48 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Time-to-live for records that are only useful for a short while, such as
+// readings feeding real-time control loops. A record whose TTL has elapsed
+// by the time it reaches the send step is dropped instead of published.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TtlConfig {
+    // TTL for records sent to topics without an entry in `topics`
+    #[serde(default)]
+    pub default_ms: Option<u64>,
+    // topic -> TTL in milliseconds
+    #[serde(default)]
+    pub topics: HashMap<String, u64>,
+}
+
+impl TtlConfig {
+    // Unix millis after which the record must not be published. A TTL on
+    // the request wins over the topic's configured one.
+    pub fn expires_at(
+        &self,
+        request_ttl_ms: Option<u64>,
+        topic: &str,
+        received_at: i64,
+    ) -> Option<i64> {
+        let ttl_ms = request_ttl_ms
+            .or_else(|| self.topics.get(topic).copied())
+            .or(self.default_ms)?;
+        Some(received_at.saturating_add(i64::try_from(ttl_ms).unwrap_or(i64::MAX)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_ttl_overrides_topic_ttl() {
+        let config = TtlConfig {
+            default_ms: None,
+            topics: HashMap::from([("control".to_string(), 5_000)]),
+        };
+
+        assert_eq!(config.expires_at(None, "control", 1_000), Some(6_000));
+        assert_eq!(config.expires_at(Some(500), "control", 1_000), Some(1_500));
+        assert_eq!(config.expires_at(None, "telemetry", 1_000), None);
+    }
+}
@@ -0,0 +1,362 @@
+use crate::proto::telemetry::Telemetry;
+use anyhow::Result;
+use axum::http::HeaderMap;
+use prost_types::{value::Kind, Value};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+// Header a client declares the units of its metrics in, e.g.
+// `X-Units: temperature=fahrenheit, pressure=psi`
+pub const UNITS_HEADER: &str = "x-units";
+
+// Key in the record's metadata saying which metrics were converted from
+// which unit, as `metric=unit,...`
+pub const ORIGINAL_UNITS: &str = "original_units";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UnitConversionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<UnitRule>,
+    // Device id -> metric -> unit the device sends it in, for devices that
+    // can't set X-Units. The header wins where both say.
+    #[serde(default)]
+    pub devices: HashMap<String, HashMap<String, String>>,
+}
+
+// How to bring `metric` from unit `from` to the canonical unit `to`: add
+// `offset`, then multiply by `scale`. Both can be left out for the
+// conversions built in (fahrenheit or kelvin to celsius).
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnitRule {
+    pub metric: String,
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub offset: Option<f64>,
+    #[serde(default)]
+    pub scale: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Linear {
+    offset: f64,
+    scale: f64,
+}
+
+impl Linear {
+    fn apply(self, value: f64) -> f64 {
+        (value + self.offset) * self.scale
+    }
+}
+
+fn built_in(from: &str, to: &str) -> Option<Linear> {
+    match (from, to) {
+        ("fahrenheit", "celsius") => Some(Linear {
+            offset: -32.0,
+            scale: 5.0 / 9.0,
+        }),
+        ("kelvin", "celsius") => Some(Linear {
+            offset: -273.15,
+            scale: 1.0,
+        }),
+        _ => None,
+    }
+}
+
+// The units a request declares in X-Units, by metric
+pub fn declared_units(headers: &HeaderMap) -> Result<HashMap<String, String>, String> {
+    let mut units = HashMap::new();
+    for value in headers.get_all(UNITS_HEADER) {
+        let value = value
+            .to_str()
+            .map_err(|_| "X-Units must be ASCII".to_string())?;
+        for pair in value.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (metric, unit) = pair
+                .split_once('=')
+                .map(|(metric, unit)| (metric.trim(), unit.trim()))
+                .filter(|(metric, unit)| !metric.is_empty() && !unit.is_empty())
+                .ok_or_else(|| format!("X-Units entry {:?} is not metric=unit", pair.trim()))?;
+            units.insert(metric.to_string(), unit.to_ascii_lowercase());
+        }
+    }
+    Ok(units)
+}
+
+// Converts metrics sent in another unit to the canonical one their rule
+// names, before validation sees them, so ranges and consumers only ever
+// deal with one unit per metric
+pub struct UnitConverter {
+    // metric -> (canonical unit, sent unit -> conversion)
+    rules: HashMap<String, (String, HashMap<String, Linear>)>,
+    devices: HashMap<String, HashMap<String, String>>,
+}
+
+impl UnitConverter {
+    pub fn new(config: &UnitConversionConfig) -> Result<Self> {
+        let mut rules: HashMap<String, (String, HashMap<String, Linear>)> = HashMap::new();
+        for rule in &config.rules {
+            let from = rule.from.to_ascii_lowercase();
+            let to = rule.to.to_ascii_lowercase();
+            let linear = match (rule.offset, rule.scale) {
+                (None, None) => built_in(&from, &to).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "unit_conversion rule for {}: no built-in conversion from {} to {}; set offset and scale",
+                        rule.metric,
+                        from,
+                        to
+                    )
+                })?,
+                (offset, scale) => Linear {
+                    offset: offset.unwrap_or(0.0),
+                    scale: scale.unwrap_or(1.0),
+                },
+            };
+            if !linear.scale.is_normal() || !linear.offset.is_finite() {
+                return Err(anyhow::anyhow!(
+                    "unit_conversion rule for {}: scale must be a non-zero number and offset a number",
+                    rule.metric
+                ));
+            }
+            let (canonical, conversions) = rules
+                .entry(rule.metric.clone())
+                .or_insert_with(|| (to.clone(), HashMap::new()));
+            if *canonical != to {
+                return Err(anyhow::anyhow!(
+                    "unit_conversion rules for {} convert to both {} and {}",
+                    rule.metric,
+                    canonical,
+                    to
+                ));
+            }
+            conversions.insert(from, linear);
+        }
+        let mut devices = HashMap::new();
+        for (device, units) in &config.devices {
+            let mut lowered = HashMap::new();
+            for (metric, unit) in units {
+                let unit = unit.to_ascii_lowercase();
+                let convertible = rules.get(metric).is_some_and(|(canonical, conversions)| {
+                    *canonical == unit || conversions.contains_key(&unit)
+                });
+                if !convertible {
+                    return Err(anyhow::anyhow!(
+                        "unit_conversion.devices.{}: no rule converts {} in {}",
+                        device,
+                        metric,
+                        unit
+                    ));
+                }
+                lowered.insert(metric.clone(), unit);
+            }
+            devices.insert(device.clone(), lowered);
+        }
+        Ok(Self { rules, devices })
+    }
+
+    // Converts the record's metrics and those of its samples, recording the
+    // units they were sent in under `original_units` in its metadata.
+    // Metrics in their canonical unit, or with no unit declared, pass
+    // through; a declared unit the rules can't convert fails the record.
+    // Returns whether anything was converted.
+    pub fn apply(
+        &self,
+        telemetry: &mut Telemetry,
+        declared: &HashMap<String, String>,
+    ) -> Result<bool> {
+        let device_units = self.devices.get(&telemetry.device_id);
+        let mut converted = BTreeMap::new();
+        for (metric, (canonical, conversions)) in &self.rules {
+            let Some(unit) = declared
+                .get(metric)
+                .or_else(|| device_units.and_then(|units| units.get(metric)))
+            else {
+                continue;
+            };
+            if unit == canonical {
+                continue;
+            }
+            let linear = conversions.get(unit).ok_or_else(|| {
+                anyhow::anyhow!("{} in {} can't be converted to {}", metric, unit, canonical)
+            })?;
+            let metrics = std::iter::once(&mut telemetry.metrics).chain(
+                telemetry
+                    .samples
+                    .iter_mut()
+                    .map(|sample| &mut sample.metrics),
+            );
+            for metrics in metrics {
+                if let Some(value) = metrics.get_mut(metric) {
+                    *value = linear.apply(*value);
+                    converted.insert(metric.as_str(), unit.as_str());
+                }
+            }
+        }
+        if converted.is_empty() {
+            return Ok(false);
+        }
+
+        let original_units = converted
+            .iter()
+            .map(|(metric, unit)| format!("{}={}", metric, unit))
+            .collect::<Vec<_>>()
+            .join(",");
+        telemetry
+            .metadata
+            .get_or_insert_with(Default::default)
+            .fields
+            .insert(
+                ORIGINAL_UNITS.to_string(),
+                Value {
+                    kind: Some(Kind::StringValue(original_units)),
+                },
+            );
+        Ok(true)
+    }
+}
+
+// What `apply` recorded on the record, if it converted anything
+pub fn original_units(telemetry: &Telemetry) -> Option<&str> {
+    match telemetry
+        .metadata
+        .as_ref()?
+        .fields
+        .get(ORIGINAL_UNITS)?
+        .kind
+    {
+        Some(Kind::StringValue(ref units)) => Some(units),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::telemetry::Sample;
+    use serde_json::json;
+
+    fn config(rules: serde_json::Value, devices: serde_json::Value) -> UnitConversionConfig {
+        serde_json::from_value(json!({ "enabled": true, "rules": rules, "devices": devices }))
+            .unwrap()
+    }
+
+    fn fahrenheit() -> serde_json::Value {
+        json!([{ "metric": "temperature", "from": "fahrenheit", "to": "celsius" }])
+    }
+
+    fn converter(devices: serde_json::Value) -> UnitConverter {
+        UnitConverter::new(&config(fahrenheit(), devices)).unwrap()
+    }
+
+    fn reading(device_id: &str, temperature: f64) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            metrics: HashMap::from([
+                ("temperature".to_string(), temperature),
+                ("humidity".to_string(), 40.0),
+            ]),
+            samples: vec![Sample {
+                ts: 1,
+                metrics: HashMap::from([("temperature".to_string(), 32.0)]),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn declared(unit: &str) -> HashMap<String, String> {
+        HashMap::from([("temperature".to_string(), unit.to_string())])
+    }
+
+    #[test]
+    fn test_fahrenheit_is_converted_to_celsius() {
+        let converter = converter(json!({}));
+        let mut telemetry = reading("sensor-1", 212.0);
+        assert!(converter
+            .apply(&mut telemetry, &declared("fahrenheit"))
+            .unwrap());
+
+        assert!((telemetry.metrics["temperature"] - 100.0).abs() < 1e-9);
+        assert!(telemetry.samples[0].metrics["temperature"].abs() < 1e-9);
+        assert_eq!(telemetry.metrics["humidity"], 40.0);
+        assert_eq!(original_units(&telemetry), Some("temperature=fahrenheit"));
+    }
+
+    #[test]
+    fn test_canonical_or_undeclared_units_pass_through() {
+        let converter = converter(json!({}));
+        for declared in [HashMap::new(), declared("celsius")] {
+            let mut telemetry = reading("sensor-1", 21.5);
+            assert!(!converter.apply(&mut telemetry, &declared).unwrap());
+            assert_eq!(telemetry, reading("sensor-1", 21.5));
+        }
+
+        let err = converter
+            .apply(&mut reading("sensor-1", 21.5), &declared("rankine"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "temperature in rankine can't be converted to celsius"
+        );
+    }
+
+    #[test]
+    fn test_device_units_apply_unless_the_header_says_otherwise() {
+        let converter = converter(json!({ "legacy-1": { "temperature": "Fahrenheit" } }));
+
+        let mut telemetry = reading("legacy-1", 50.0);
+        assert!(converter.apply(&mut telemetry, &HashMap::new()).unwrap());
+        assert!((telemetry.metrics["temperature"] - 10.0).abs() < 1e-9);
+
+        let mut telemetry = reading("legacy-1", 50.0);
+        assert!(!converter
+            .apply(&mut telemetry, &declared("celsius"))
+            .unwrap());
+        assert_eq!(telemetry.metrics["temperature"], 50.0);
+    }
+
+    #[test]
+    fn test_rules_are_checked() {
+        let rejected = |rules: serde_json::Value, devices: serde_json::Value| {
+            UnitConverter::new(&config(rules, devices)).is_err()
+        };
+        assert!(rejected(
+            json!([{ "metric": "temperature", "from": "rankine", "to": "celsius" }]),
+            json!({})
+        ));
+        assert!(rejected(
+            json!([{ "metric": "level", "from": "ft", "to": "m", "scale": 0.0 }]),
+            json!({})
+        ));
+        assert!(rejected(
+            json!([
+                { "metric": "temperature", "from": "fahrenheit", "to": "celsius" },
+                { "metric": "temperature", "from": "celsius", "to": "kelvin", "offset": 273.15 }
+            ]),
+            json!({})
+        ));
+        assert!(rejected(
+            fahrenheit(),
+            json!({ "legacy-1": { "temperature": "rankine" } })
+        ));
+    }
+
+    #[test]
+    fn test_units_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            UNITS_HEADER,
+            "temperature=Fahrenheit, pressure = psi,".parse().unwrap(),
+        );
+        assert_eq!(
+            declared_units(&headers).unwrap(),
+            HashMap::from([
+                ("temperature".to_string(), "fahrenheit".to_string()),
+                ("pressure".to_string(), "psi".to_string()),
+            ])
+        );
+        headers.insert(UNITS_HEADER, "temperature".parse().unwrap());
+        assert!(declared_units(&headers).is_err());
+        assert!(declared_units(&HeaderMap::new()).unwrap().is_empty());
+    }
+}
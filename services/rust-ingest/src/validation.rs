@@ -0,0 +1,147 @@
+use crate::telemetry_handler::ValidationWarning;
+use anyhow::Result;
+use serde::Deserialize;
+use std::fmt;
+
+// How validation treats a hard failure
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    // Stop at the first failure, skipping the remaining checks
+    #[default]
+    FailFast,
+    // Run every check and report all failures together
+    CollectAll,
+}
+
+// Every hard failure a record hit under collect_all
+#[derive(Debug)]
+pub struct ValidationFailures(pub Vec<String>);
+
+impl fmt::Display for ValidationFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationFailures {}
+
+// Runs a record's validation checks one at a time, gathering their
+// warnings. Under fail_fast a failing check ends validation through `?`;
+// under collect_all it is noted and the next check still runs.
+pub struct Validation {
+    mode: ValidationMode,
+    failures: Vec<String>,
+    warnings: Vec<ValidationWarning>,
+}
+
+impl Validation {
+    pub fn new(mode: ValidationMode) -> Self {
+        Self {
+            mode,
+            failures: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn check(&mut self, check: impl FnOnce() -> Result<Vec<ValidationWarning>>) -> Result<()> {
+        match check() {
+            Ok(warnings) => {
+                self.warnings.extend(warnings);
+                Ok(())
+            }
+            Err(e) if self.mode == ValidationMode::FailFast => Err(e),
+            Err(e) => {
+                self.failures.push(e.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // For checks that commit state, e.g. provisioning a device schema, which
+    // must only happen for a record that has passed everything so far
+    pub fn check_if_clean(
+        &mut self,
+        check: impl FnOnce() -> Result<Vec<ValidationWarning>>,
+    ) -> Result<()> {
+        if !self.failures.is_empty() {
+            return Ok(());
+        }
+        self.check(check)
+    }
+
+    // Lets a later stage replace the warnings of an earlier one
+    pub fn warnings_mut(&mut self) -> &mut Vec<ValidationWarning> {
+        &mut self.warnings
+    }
+
+    // Warnings and failures both, for reporting on a record rather than
+    // deciding its fate
+    pub fn into_parts(self) -> (Vec<ValidationWarning>, Vec<String>) {
+        (self.warnings, self.failures)
+    }
+
+    pub fn finish(self) -> Result<Vec<ValidationWarning>> {
+        if self.failures.is_empty() {
+            Ok(self.warnings)
+        } else {
+            Err(ValidationFailures(self.failures).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three checks, the first two failing; returns which ones ran
+    fn run(validation: &mut Validation) -> (Result<()>, Vec<usize>) {
+        let mut ran = Vec::new();
+        let result = (|| {
+            validation.check(|| {
+                ran.push(0);
+                Err(anyhow::anyhow!("first"))
+            })?;
+            validation.check(|| {
+                ran.push(1);
+                Err(anyhow::anyhow!("second"))
+            })?;
+            validation.check(|| {
+                ran.push(2);
+                Ok(vec![ValidationWarning::new("humidity", 120.0, 0.0, 100.0)])
+            })?;
+            validation.check_if_clean(|| {
+                ran.push(3);
+                Ok(Vec::new())
+            })
+        })();
+        (result, ran)
+    }
+
+    #[test]
+    fn test_fail_fast_stops_at_the_first_failure() {
+        let mut validation = Validation::new(ValidationMode::FailFast);
+        let (result, ran) = run(&mut validation);
+        assert_eq!(result.unwrap_err().to_string(), "first");
+        assert_eq!(ran, vec![0]);
+    }
+
+    #[test]
+    fn test_collect_all_reports_every_failure() {
+        let mut validation = Validation::new(ValidationMode::CollectAll);
+        let (result, ran) = run(&mut validation);
+        assert!(result.is_ok());
+        // The state-committing check is skipped once the record has failed
+        assert_eq!(ran, vec![0, 1, 2]);
+
+        let err = validation.finish().unwrap_err();
+        let failures = err.downcast_ref::<ValidationFailures>().unwrap();
+        assert_eq!(failures.0, vec!["first", "second"]);
+
+        let mut clean = Validation::new(ValidationMode::CollectAll);
+        clean
+            .check(|| Ok(vec![ValidationWarning::new("humidity", 120.0, 0.0, 100.0)]))
+            .unwrap();
+        assert_eq!(clean.finish().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,356 @@
+use crate::{
+    device_types::DeviceClassifier,
+    proto::telemetry::Telemetry,
+    telemetry_handler::{check_metric, ValidationWarning},
+    validation::Validation,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+// Tag a record can carry to pick its device type instead of the classifier
+pub const DEVICE_TYPE_TAG: &str = "device_type";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ValidationProfilesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub profiles: HashMap<String, ValidationProfile>,
+    // device type -> profile name
+    #[serde(default)]
+    pub device_types: HashMap<String, String>,
+    // Profile for devices without a mapping; unset keeps the built-in rules
+    #[serde(default)]
+    pub default_profile: Option<String>,
+}
+
+// A named rule set replacing the built-in range checks for the devices it
+// is mapped to
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ValidationProfile {
+    #[serde(default)]
+    pub ranges: HashMap<String, RangeRule>,
+    // Metrics every record must carry, in its own metrics or a sample
+    #[serde(default)]
+    pub required: Vec<String>,
+    #[serde(default)]
+    pub constraints: Vec<MetricConstraint>,
+}
+
+// Values outside [min, max] raise a warning, or fail the record with `reject`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RangeRule {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub reject: bool,
+}
+
+// `metric op other` must hold whenever a reading has both,
+// e.g. supply_temp gt return_temp
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricConstraint {
+    pub metric: String,
+    pub op: Comparison,
+    pub other: String,
+    #[serde(default)]
+    pub reject: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn holds(self, left: f64, right: f64) -> bool {
+        match self {
+            Self::Lt => left < right,
+            Self::Le => left <= right,
+            Self::Gt => left > right,
+            Self::Ge => left >= right,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+}
+
+impl ValidationProfile {
+    // Each rule is a separate check, so collect_all reports every violation
+    pub fn validate(&self, telemetry: &Telemetry, validation: &mut Validation) -> Result<()> {
+        for metric in &self.required {
+            validation.check(|| {
+                let present = telemetry.metrics.contains_key(metric)
+                    || telemetry
+                        .samples
+                        .iter()
+                        .any(|sample| sample.metrics.contains_key(metric));
+                if !present {
+                    return Err(anyhow::anyhow!("Required metric {} is missing", metric));
+                }
+                Ok(Vec::new())
+            })?;
+        }
+
+        let readings =
+            std::iter::once(&telemetry.metrics).chain(telemetry.samples.iter().map(|s| &s.metrics));
+        for metrics in readings {
+            for (key, value) in metrics {
+                validation.check(|| {
+                    check_metric(key, *value)?;
+                    let warning = match self.ranges.get(key) {
+                        Some(rule) => check_range(key, *value, rule)?,
+                        None => None,
+                    };
+                    Ok(warning.into_iter().collect())
+                })?;
+            }
+            for constraint in &self.constraints {
+                validation
+                    .check(|| Ok(check_constraint(metrics, constraint)?.into_iter().collect()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn check_range(key: &str, value: f64, rule: &RangeRule) -> Result<Option<ValidationWarning>> {
+    let min = rule.min.unwrap_or(f64::NEG_INFINITY);
+    let max = rule.max.unwrap_or(f64::INFINITY);
+    if (min..=max).contains(&value) {
+        return Ok(None);
+    }
+    if rule.reject {
+        return Err(anyhow::anyhow!(
+            "{} value {} must be between {} and {}",
+            key,
+            value,
+            min,
+            max
+        ));
+    }
+    warn!("{} value {} seems out of normal range", key, value);
+    Ok(Some(ValidationWarning::new(key, value, min, max)))
+}
+
+fn check_constraint(
+    metrics: &HashMap<String, f64>,
+    constraint: &MetricConstraint,
+) -> Result<Option<ValidationWarning>> {
+    let (Some(&value), Some(&other)) = (
+        metrics.get(&constraint.metric),
+        metrics.get(&constraint.other),
+    ) else {
+        return Ok(None);
+    };
+    if constraint.op.holds(value, other) {
+        return Ok(None);
+    }
+    let message = format!(
+        "{} ({}) must be {} {} ({})",
+        constraint.metric,
+        value,
+        constraint.op.symbol(),
+        constraint.other,
+        other
+    );
+    if constraint.reject {
+        return Err(anyhow::anyhow!(message));
+    }
+    warn!("Constraint violated: {}", message);
+    // Reported as a range bounded by the other metric's value
+    let (min, max) = match constraint.op {
+        Comparison::Lt | Comparison::Le => (f64::NEG_INFINITY, other),
+        Comparison::Gt | Comparison::Ge => (other, f64::INFINITY),
+    };
+    Ok(Some(ValidationWarning::new(
+        &constraint.metric,
+        value,
+        min,
+        max,
+    )))
+}
+
+// Picks each record's validation profile from its device type: the
+// `device_type` tag when present, otherwise the classifier's answer.
+pub struct ValidationProfiles {
+    profiles: HashMap<String, ValidationProfile>,
+    device_types: HashMap<String, String>,
+    default_profile: Option<String>,
+}
+
+impl ValidationProfiles {
+    pub fn new(config: ValidationProfilesConfig) -> Result<Self> {
+        let mapped = config
+            .device_types
+            .values()
+            .chain(config.default_profile.as_ref());
+        for name in mapped {
+            if !config.profiles.contains_key(name) {
+                return Err(anyhow::anyhow!(
+                    "validation_profiles refers to unknown profile '{}'",
+                    name
+                ));
+            }
+        }
+        Ok(Self {
+            profiles: config.profiles,
+            device_types: config.device_types,
+            default_profile: config.default_profile,
+        })
+    }
+
+    // None means the built-in rules apply
+    pub fn select(
+        &self,
+        telemetry: &Telemetry,
+        classifier: &DeviceClassifier,
+    ) -> Option<&ValidationProfile> {
+        let device_type = match telemetry.tags.get(DEVICE_TYPE_TAG) {
+            Some(explicit) => Some(explicit.clone()),
+            None => classifier.classify(&telemetry.device_id, telemetry.metrics.keys()),
+        };
+        let name = device_type
+            .and_then(|device_type| self.device_types.get(&device_type))
+            .or(self.default_profile.as_ref())?;
+        self.profiles.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device_types::DeviceTypeConfig, validation::ValidationMode};
+
+    fn validate(
+        profile: &ValidationProfile,
+        telemetry: &Telemetry,
+    ) -> Result<Vec<ValidationWarning>> {
+        let mut validation = Validation::new(ValidationMode::FailFast);
+        profile.validate(telemetry, &mut validation)?;
+        validation.finish()
+    }
+
+    fn profiles(default_profile: Option<&str>) -> ValidationProfiles {
+        let thermostat = ValidationProfile {
+            ranges: HashMap::from([(
+                "temperature".to_string(),
+                RangeRule {
+                    min: Some(5.0),
+                    max: Some(35.0),
+                    reject: false,
+                },
+            )]),
+            required: vec!["setpoint".to_string()],
+            ..Default::default()
+        };
+        let controller = ValidationProfile {
+            ranges: HashMap::from([(
+                "pressure".to_string(),
+                RangeRule {
+                    min: Some(0.0),
+                    max: None,
+                    reject: true,
+                },
+            )]),
+            constraints: vec![MetricConstraint {
+                metric: "supply_temp".to_string(),
+                op: Comparison::Gt,
+                other: "return_temp".to_string(),
+                reject: false,
+            }],
+            ..Default::default()
+        };
+        ValidationProfiles::new(ValidationProfilesConfig {
+            enabled: true,
+            profiles: HashMap::from([
+                ("thermostat".to_string(), thermostat),
+                ("controller".to_string(), controller),
+            ]),
+            device_types: HashMap::from([
+                ("thermostat".to_string(), "thermostat".to_string()),
+                ("plc".to_string(), "controller".to_string()),
+            ]),
+            default_profile: default_profile.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    fn classifier() -> DeviceClassifier {
+        DeviceClassifier::new(DeviceTypeConfig {
+            prefixes: HashMap::from([("tstat-".to_string(), "thermostat".to_string())]),
+            ..Default::default()
+        })
+    }
+
+    fn reading(device_id: &str, metrics: &[(&str, f64)]) -> Telemetry {
+        Telemetry {
+            device_id: device_id.to_string(),
+            metrics: metrics
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_profile_follows_device_type() {
+        let profiles = profiles(None);
+        let classifier = classifier();
+
+        let tstat = reading("tstat-1", &[("temperature", 40.0), ("setpoint", 21.0)]);
+        let profile = profiles.select(&tstat, &classifier).unwrap();
+        let warnings = validate(profile, &tstat).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].expected_max, 35.0);
+        assert!(validate(profile, &reading("tstat-1", &[("temperature", 20.0)])).is_err());
+
+        // The tag overrides the classifier
+        let mut plc = reading("tstat-2", &[("pressure", -1.0)]);
+        plc.tags
+            .insert(DEVICE_TYPE_TAG.to_string(), "plc".to_string());
+        let profile = profiles.select(&plc, &classifier).unwrap();
+        assert!(validate(profile, &plc).is_err());
+
+        let backwards = reading("plc-1", &[("supply_temp", 40.0), ("return_temp", 45.0)]);
+        let warnings = validate(profile, &backwards).unwrap();
+        assert_eq!(warnings[0].metric, "supply_temp");
+        assert_eq!(warnings[0].expected_min, 45.0);
+    }
+
+    #[test]
+    fn test_unmapped_devices_fall_back() {
+        let classifier = classifier();
+        let unknown = reading("meter-1", &[("temperature", 20.0)]);
+        // No default profile: the built-in rules apply
+        assert!(profiles(None).select(&unknown, &classifier).is_none());
+
+        let profiles = profiles(Some("controller"));
+        let profile = profiles.select(&unknown, &classifier).unwrap();
+        assert!(profile.ranges.contains_key("pressure"));
+    }
+
+    #[test]
+    fn test_unknown_profile_names_are_rejected() {
+        let config = ValidationProfilesConfig {
+            enabled: true,
+            device_types: HashMap::from([("plc".to_string(), "missing".to_string())]),
+            ..Default::default()
+        };
+        assert!(ValidationProfiles::new(config).is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use crate::{
+    ack::AckMode,
+    batch::{self, BatchItemResult},
+    connections::ConnectionHandle,
+    priority::Priority,
+    proto::telemetry::Telemetry,
+    request_id::RequestId,
+    server::{ApiError, AppState, RequestContext, TelemetryRequest},
+    trace_sampling::TraceDecision,
+    unit_conversion,
+};
+use axum::{
+    extract::{Extension, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use hyper_util::rt::TokioIo;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{future::Future, io, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+// The RFC 6455 handshake and framing are done here by hand rather than with
+// axum's `ws` feature: it pulls in tokio-tungstenite and its dependencies,
+// which the offline build doesn't have. Only what a telemetry stream needs
+// is covered; there are no extensions (e.g. permessage-deflate).
+
+// Appended to the client's key to prove the server speaks WebSocket (RFC 6455)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebSocketConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Largest message accepted, after any fragments are put together
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    // Messages that can't be decoded before the connection is closed
+    #[serde(default = "default_max_malformed_messages")]
+    pub max_malformed_messages: u32,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_message_bytes: default_max_message_bytes(),
+            max_malformed_messages: default_max_malformed_messages(),
+        }
+    }
+}
+
+fn default_max_message_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_malformed_messages() -> u32 {
+    10
+}
+
+// Streams telemetry over one connection instead of a POST per reading. Each
+// text message is a JSON `TelemetryRequest`, each binary one a protobuf
+// `Telemetry`; every message gets a result back in the same shape as a batch
+// item, with `index` counting messages from 0. Ack mode and priority come
+// from the handshake request's headers and apply to the whole connection,
+// as does its request id.
+pub async fn ingest_websocket(
+    State(state): State<Arc<AppState>>,
+    Extension(trace): Extension<TraceDecision>,
+    Extension(request_id): Extension<RequestId>,
+    mut request: Request,
+) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    let accept = handshake_accept(headers)?;
+    let ack = AckMode::from_headers(headers, state.default_ack_mode)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let context = RequestContext {
+        api_key: state.api_keys.identify(headers),
+        trace,
+        ack,
+        priority: Priority::from_headers(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        topic: state
+            .topic_overrides
+            .requested(headers)
+            .map_err(|e| ApiError::new(StatusCode::FORBIDDEN, e))?,
+        units: unit_conversion::declared_units(headers)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        request_id,
+    };
+    // Every message is answered with its outcome, so there is nothing to skip
+    if ack == AckMode::None {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "ack mode none is not supported for WebSocket streams",
+        ));
+    }
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("WebSocket upgrade failed: {}", e);
+                return;
+            }
+        };
+        state.ack_modes.record(ack);
+        // The HTTP connection's own registration ended with the upgrade
+        let connection = state.connections.register();
+        let config = state.websocket.clone();
+        let session = run_session(
+            TokioIo::new(upgraded),
+            &config,
+            &connection,
+            |index, record| {
+                let paused = state
+                    .maintenance
+                    .as_ref()
+                    .and_then(|schedule| schedule.check(Utc::now()).err());
+                let (state, context) = (&state, &context);
+                async move {
+                    match paused {
+                        Some(e) => {
+                            BatchItemResult::failed(index, record.ok().map(|r| r.device_id), &e)
+                        }
+                        None => batch::process_item(state, index, record, context).await,
+                    }
+                }
+            },
+        );
+        if let Err(e) = session.await {
+            debug!("WebSocket connection ended: {}", e);
+        }
+    });
+
+    Ok((
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::CONNECTION, HeaderValue::from_static("upgrade")),
+            (header::UPGRADE, HeaderValue::from_static("websocket")),
+            (
+                header::SEC_WEBSOCKET_ACCEPT,
+                HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+            ),
+        ],
+    )
+        .into_response())
+}
+
+fn handshake_accept(headers: &HeaderMap) -> Result<String, ApiError> {
+    let has_token = |name: header::HeaderName, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token(header::UPGRADE, "websocket") || !has_token(header::CONNECTION, "upgrade") {
+        return Err(ApiError::new(
+            StatusCode::UPGRADE_REQUIRED,
+            "this endpoint only takes WebSocket connections",
+        ));
+    }
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return Err(
+            ApiError::new(StatusCode::BAD_REQUEST, "unsupported WebSocket version")
+                .with_details("Sec-WebSocket-Version must be 13"),
+        );
+    }
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|key| key.to_str().ok())
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "missing Sec-WebSocket-Key"))?;
+    Ok(accept_key(key.trim()))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(HANDSHAKE_GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+fn decode_message(opcode: u8, payload: &[u8]) -> Result<TelemetryRequest, String> {
+    if opcode == OP_TEXT {
+        serde_json::from_slice(payload).map_err(|e| format!("invalid JSON message: {}", e))
+    } else {
+        Telemetry::decode(payload)
+            .map(Into::into)
+            .map_err(|e| format!("invalid protobuf message: {}", e))
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+enum FrameError {
+    Io(io::Error),
+    // Close with this code and reason
+    Close(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Reads one frame a client sent; clients must mask theirs. `max_len` caps
+// the payload before any of it is read.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Frame, FrameError> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Close(
+            CLOSE_PROTOCOL_ERROR,
+            "no extensions were negotiated",
+        ));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(FrameError::Close(
+            CLOSE_PROTOCOL_ERROR,
+            "client frames must be masked",
+        ));
+    }
+    let len = match head[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(FrameError::Close(
+            CLOSE_PROTOCOL_ERROR,
+            "invalid control frame",
+        ));
+    }
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= max_len)
+        .ok_or(FrameError::Close(CLOSE_TOO_BIG, "message too large"))?;
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// Server frames are sent whole and unmasked
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+async fn write_close<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    code: u16,
+    reason: &str,
+) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    write_frame(writer, OP_CLOSE, &payload).await
+}
+
+// Reads messages until the client closes the connection or sends too many
+// it can't decode, handing each to `process` and sending back its result.
+// Messages are processed one at a time, in the order they arrive. Every
+// frame counts as activity on `connection`, and a message being processed
+// as a request in flight; when the reaper or a shutdown closes
+// `connection`, the session ends with a close frame once it is between
+// messages.
+async fn run_session<S, F, Fut, T>(
+    mut stream: S,
+    config: &WebSocketConfig,
+    connection: &ConnectionHandle,
+    mut process: F,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(usize, Result<TelemetryRequest, String>) -> Fut,
+    Fut: Future<Output = T>,
+    T: Serialize,
+{
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    let mut index = 0;
+    let mut malformed = 0;
+    loop {
+        // Dropping a half-read frame is fine, the session ends either way
+        let read = tokio::select! {
+            read = read_frame(&mut stream, config.max_message_bytes) => read,
+            _ = connection.closed() => {
+                return write_close(&mut stream, CLOSE_GOING_AWAY, "server closing idle connection")
+                    .await;
+            }
+        };
+        let frame = match read {
+            Ok(frame) => frame,
+            Err(FrameError::Close(code, reason)) => {
+                return write_close(&mut stream, code, reason).await;
+            }
+            Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(FrameError::Io(e)) => return Err(e),
+        };
+        connection.touch();
+        match (frame.opcode, message.as_mut()) {
+            (OP_PING, _) => {
+                write_frame(&mut stream, OP_PONG, &frame.payload).await?;
+                continue;
+            }
+            (OP_PONG, _) => continue,
+            // Echo the client's status code back, as the close handshake asks
+            (OP_CLOSE, _) => {
+                let code = frame
+                    .payload
+                    .get(..2)
+                    .map_or(CLOSE_NORMAL, |code| u16::from_be_bytes([code[0], code[1]]));
+                return write_close(&mut stream, code, "").await;
+            }
+            (OP_TEXT | OP_BINARY, None) => message = Some((frame.opcode, frame.payload)),
+            (OP_CONTINUATION, Some((_, payload))) => {
+                if payload.len() + frame.payload.len() > config.max_message_bytes {
+                    return write_close(&mut stream, CLOSE_TOO_BIG, "message too large").await;
+                }
+                payload.extend_from_slice(&frame.payload);
+            }
+            _ => {
+                return write_close(&mut stream, CLOSE_PROTOCOL_ERROR, "unexpected frame").await;
+            }
+        }
+        if !frame.fin {
+            continue;
+        }
+
+        let Some((opcode, payload)) = message.take() else {
+            continue;
+        };
+        // Checked once the fragments are together, since a character may
+        // be split across them
+        if opcode == OP_TEXT && std::str::from_utf8(&payload).is_err() {
+            return write_close(
+                &mut stream,
+                CLOSE_INVALID_DATA,
+                "text message is not valid UTF-8",
+            )
+            .await;
+        }
+        let record = decode_message(opcode, &payload);
+        if record.is_err() {
+            malformed += 1;
+        }
+        let in_flight = connection.start_request();
+        let result = serde_json::to_vec(&process(index, record).await).unwrap_or_default();
+        write_frame(&mut stream, OP_TEXT, &result).await?;
+        drop(in_flight);
+        index += 1;
+        if malformed >= config.max_malformed_messages {
+            return write_close(
+                &mut stream,
+                CLOSE_INVALID_DATA,
+                "too many malformed messages",
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connections::ConnectionTracker;
+    use serde_json::{json, Value};
+    use std::time::{Duration, Instant};
+    use tokio::io::DuplexStream;
+
+    // A frame as a client sends it: masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Runs a session over `input`, returning the frames the server sent
+    async fn session(input: Vec<u8>, max_malformed_messages: u32) -> Vec<(u8, Vec<u8>)> {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(&input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let config = WebSocketConfig {
+            enabled: true,
+            max_message_bytes: 1024,
+            max_malformed_messages,
+        };
+        let connection = Arc::new(ConnectionTracker::default()).register();
+        run_session(server, &config, &connection, |index, record| async move {
+            match record {
+                Ok(request) => json!({"index": index, "device_id": request.device_id}),
+                Err(error) => json!({"index": index, "error": error}),
+            }
+        })
+        .await
+        .unwrap();
+        server_frames(&mut client).await
+    }
+
+    async fn server_frames(client: &mut DuplexStream) -> Vec<(u8, Vec<u8>)> {
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        let mut frames = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let (len, start) = match rest[1] {
+                126 => (usize::from(u16::from_be_bytes([rest[2], rest[3]])), 4),
+                len => (usize::from(len), 2),
+            };
+            frames.push((rest[0] & 0x0f, rest[start..start + len].to_vec()));
+            rest = &rest[start + len..];
+        }
+        frames
+    }
+
+    fn result(payload: &[u8]) -> Value {
+        serde_json::from_slice(payload).unwrap()
+    }
+
+    #[test]
+    fn test_accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_each_message_is_answered() {
+        let protobuf = Telemetry {
+            device_id: "sensor-2".to_string(),
+            ts: 1_700_000_000_000,
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let json =
+            br#"{"device_id": "sensor-1", "ts": 1700000000000, "metrics": {"temperature": 21.5}}"#;
+        let mut input = client_frame(true, OP_TEXT, json);
+        input.extend(client_frame(true, OP_PING, b"hi"));
+        // Fragmented across three frames
+        input.extend(client_frame(false, OP_BINARY, &protobuf[..3]));
+        input.extend(client_frame(false, OP_CONTINUATION, &protobuf[3..6]));
+        input.extend(client_frame(true, OP_CONTINUATION, &protobuf[6..]));
+        input.extend(client_frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()));
+
+        let frames = session(input, 3).await;
+        assert_eq!(frames.len(), 4);
+        assert_eq!(
+            result(&frames[0].1),
+            json!({"index": 0, "device_id": "sensor-1"})
+        );
+        assert_eq!(frames[1], (OP_PONG, b"hi".to_vec()));
+        assert_eq!(
+            result(&frames[2].1),
+            json!({"index": 1, "device_id": "sensor-2"})
+        );
+        assert_eq!(frames[3], (OP_CLOSE, CLOSE_NORMAL.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_closes_after_too_many_malformed_messages() {
+        let mut input = Vec::new();
+        for _ in 0..3 {
+            input.extend(client_frame(true, OP_TEXT, b"not json"));
+        }
+        let frames = session(input, 2).await;
+        assert_eq!(frames.len(), 3);
+        let error = result(&frames[0].1)["error"].as_str().unwrap().to_string();
+        assert!(error.starts_with("invalid JSON message"), "{}", error);
+        assert_eq!(frames[2].0, OP_CLOSE);
+        assert_eq!(frames[2].1[..2], CLOSE_INVALID_DATA.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_text_messages_must_be_utf8() {
+        // A character split across fragments is fine
+        let json = r#"{"device_id": "capteur-é", "ts": 1700000000000}"#.as_bytes();
+        let split = json.iter().position(|&b| b == 0xc3).unwrap() + 1;
+        let mut input = client_frame(false, OP_TEXT, &json[..split]);
+        input.extend(client_frame(true, OP_CONTINUATION, &json[split..]));
+        // Not so a message that is still invalid once put together
+        input.extend(client_frame(false, OP_TEXT, b"{\"device_id\": \"\xff"));
+        input.extend(client_frame(true, OP_CONTINUATION, b"\"}"));
+
+        let frames = session(input, 10).await;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            result(&frames[0].1),
+            json!({"index": 0, "device_id": "capteur-é"})
+        );
+        assert_eq!(frames[1].0, OP_CLOSE);
+        assert_eq!(frames[1].1[..2], CLOSE_INVALID_DATA.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_and_unmasked_frames_close_the_connection() {
+        let frames = session(client_frame(true, OP_TEXT, &[b' '; 2000]), 10).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1[..2], CLOSE_TOO_BIG.to_be_bytes());
+
+        let frames = session(vec![0x81, 0x02, b'{', b'}'], 10).await;
+        assert_eq!(frames[0].1[..2], CLOSE_PROTOCOL_ERROR.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_idle_session_is_reaped() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let session = tokio::spawn({
+            let tracker = tracker.clone();
+            async move {
+                let connection = tracker.register();
+                run_session(
+                    server,
+                    &WebSocketConfig::default(),
+                    &connection,
+                    |_, _| async { json!({}) },
+                )
+                .await
+            }
+        });
+        while tracker.open() == 0 {
+            tokio::task::yield_now().await;
+        }
+        // A ping counts as activity
+        client
+            .write_all(&client_frame(true, OP_PING, b""))
+            .await
+            .unwrap();
+        let mut pong = [0; 2];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x80 | OP_PONG, 0]);
+        assert_eq!(
+            tracker.reap_idle(Duration::from_secs(60), Instant::now()),
+            0
+        );
+
+        assert_eq!(tracker.reap_idle(Duration::ZERO, Instant::now()), 1);
+        session.await.unwrap().unwrap();
+        assert_eq!(tracker.open(), 0);
+        drop(client.shutdown().await);
+        let frames = server_frames(&mut client).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1[..2], CLOSE_GOING_AWAY.to_be_bytes());
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ValidationPoolConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // Maximum concurrent validation jobs; defaults to the number of CPUs
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+// Runs CPU-bound pipeline work on Tokio's blocking threads so heavy
+// validation can't stall the reactor. A semaphore caps how many jobs run at
+// once; callers over the cap wait asynchronously for a slot.
+pub struct ValidationPool {
+    permits: Arc<Semaphore>,
+}
+
+impl ValidationPool {
+    pub fn new(config: &ValidationPoolConfig) -> Self {
+        let size = config
+            .size
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(4)
+            })
+            .max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(size)),
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await?;
+        Ok(result)
+    }
+}